@@ -1,6 +1,10 @@
-use std::{fs, path::Path};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use serde::Deserialize;
 
 use crate::paths::resolve_path;
@@ -12,6 +16,8 @@ pub struct Pref {
     pub common: Common,
     #[serde(default)]
     pub custom_groups: Vec<GroupImport>,
+    #[serde(default)]
+    pub groups: GroupDefaults,
     pub ruleset: Option<Ruleset>,
     #[serde(default)]
     pub rulesets: Vec<RulesetImport>,
@@ -22,6 +28,67 @@ pub struct Pref {
     pub server: Server,
     #[serde(default)]
     pub node_pref: NodePref,
+    #[serde(default)]
+    pub profile_sets: Vec<ProfileSet>,
+    /// Per-profile `type` remapping, keyed by the profile path as written in
+    /// `default_url`, `insert_url` or `profile_sets.profiles`.
+    #[serde(default)]
+    pub protocol_overrides: HashMap<String, HashMap<String, String>>,
+    /// Static nodes declared inline, added to every `/sub` response.
+    #[serde(default)]
+    pub custom_proxy: Vec<CustomProxy>,
+    /// One-off rules appended after the rulesets, ahead of `FINAL`.
+    #[serde(default)]
+    pub extra_rule: Vec<ExtraRule>,
+}
+
+impl Pref {
+    /// The profile set scoped to `token`, if any.
+    pub fn profile_set(&self, token: &str) -> Option<&ProfileSet> {
+        self.profile_sets.iter().find(|set| set.token == token)
+    }
+
+    /// `protocol_overrides` keyed by resolved profile path, so they can be
+    /// matched against the paths profiles are loaded from.
+    pub fn protocol_overrides(&self, base_dir: &Path) -> HashMap<PathBuf, HashMap<String, String>> {
+        self.protocol_overrides
+            .iter()
+            .map(|(profile, overrides)| (resolve_path(base_dir, profile), overrides.clone()))
+            .collect()
+    }
+
+    fn validate_profile_sets(&self) -> Result<()> {
+        let mut seen = std::collections::HashSet::new();
+        for set in &self.profile_sets {
+            if set.token.trim().is_empty() {
+                bail!("`profile_sets.token` must not be empty");
+            }
+            if !seen.insert(set.token.as_str()) {
+                bail!("duplicate `profile_sets.token` `{}`", set.token);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A node written inline under `[[custom_proxy]]`, in the same shape as a
+/// profile's `proxies` entry.
+#[derive(Debug, Deserialize)]
+pub struct CustomProxy {
+    /// Place the node before the loaded proxies instead of after them.
+    #[serde(default)]
+    pub prepend: bool,
+    #[serde(flatten)]
+    pub values: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Local profiles served to `/sub` requests whose `token` matches, in place
+/// of `common.default_url`.
+#[derive(Debug, Deserialize)]
+pub struct ProfileSet {
+    pub token: String,
+    #[serde(default)]
+    pub profiles: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,6 +96,8 @@ pub struct Common {
     pub api_access_token: Option<String>,
     #[serde(default)]
     pub default_url: Vec<String>,
+    /// URL of a JSON array of profile URLs merged after `default_url`.
+    pub remote_profiles_url: Option<String>,
     #[serde(default)]
     pub enable_insert: bool,
     #[serde(default)]
@@ -37,9 +106,42 @@ pub struct Common {
     pub prepend_insert_url: bool,
     #[serde(default)]
     pub sort: bool,
+    /// Render nodes a target cannot express as `REJECT` placeholders instead
+    /// of dropping them.
+    #[serde(default)]
+    pub keep_unsupported_as_reject: bool,
+    /// Rename nodes sharing a name with an earlier node to `name #2`, ...
+    #[serde(default)]
+    pub dedupe_names: bool,
+    /// Drop node fields the target template does not render. Turning it off
+    /// keeps defaults and passes undeclared fields through to Clash.
+    #[serde(default = "default_prune_fields")]
+    pub prune_fields: bool,
     pub schema: Option<String>,
-    pub clash_rule_base: Option<String>,
+    pub clash_rule_base: Option<RuleBase>,
     pub surge_rule_base: Option<String>,
+    pub loon_rule_base: Option<String>,
+    /// Targets rendered ahead of time so plain `/sub?target=...` hits are instant.
+    #[serde(default)]
+    pub warm_targets: Vec<String>,
+}
+
+/// A base config path, or a list of them merged in order so later files
+/// override earlier ones.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RuleBase {
+    Single(String),
+    Layered(Vec<String>),
+}
+
+impl RuleBase {
+    pub fn paths(&self) -> &[String] {
+        match self {
+            Self::Single(path) => std::slice::from_ref(path),
+            Self::Layered(paths) => paths,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -48,6 +150,18 @@ pub struct NodePref {
     pub tfo: Option<bool>,
     #[serde(rename = "skip-cert-verify")]
     pub skip_cert_verify: Option<bool>,
+    /// `[node_pref.override]`: fields set on every proxy whose schema
+    /// declares them, keeping their TOML types.
+    #[serde(default, rename = "override")]
+    pub overrides: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Health-check settings for `url-test`, `fallback` and `load-balance`
+/// groups that do not set their own `url` or `interval`.
+#[derive(Debug, Deserialize, Default)]
+pub struct GroupDefaults {
+    pub test_url: Option<String>,
+    pub test_interval: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,6 +173,18 @@ pub struct GroupImport {
 pub struct Ruleset {
     #[serde(default)]
     pub enabled: bool,
+    /// Cache TTL for remote rulesets, overriding `network.ttl_seconds`.
+    pub cache_ttl_seconds: Option<u64>,
+    /// Emit remote rulesets as Clash `rule-providers` instead of inlining them.
+    #[serde(default)]
+    pub as_providers: bool,
+    /// Order rules within the domain and IP buckets from most to least
+    /// specific match type.
+    #[serde(default)]
+    pub sort_by_specificity: bool,
+    /// Targets whose `DOMAIN-KEYWORD` rules are rewritten as `DOMAIN-REGEX`.
+    #[serde(default)]
+    pub keyword_as_regex: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -66,10 +192,61 @@ pub struct RulesetImport {
     pub import: String,
 }
 
+/// A rule written inline under `[[extra_rule]]`, in ruleset line syntax
+/// without the group.
+#[derive(Debug, Deserialize)]
+pub struct ExtraRule {
+    pub rule: String,
+    pub group: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Server {
     pub listen: String,
     pub port: u16,
+    #[serde(default = "default_http_keepalive_seconds")]
+    pub http_keepalive_seconds: u64,
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
+    /// Requests per minute allowed on `/sub` per client IP; unset or 0 disables.
+    pub rate_limit_per_minute: Option<u32>,
+    /// Enables `POST /api/test/node`, which opens TCP connections to
+    /// arbitrary hosts and could otherwise be used as a port scanner.
+    #[serde(default)]
+    pub allow_node_test: bool,
+    /// Prior versions kept per file edited through the API; 0 disables.
+    #[serde(default = "default_history_limit")]
+    pub history_limit: usize,
+}
+
+impl Server {
+    fn validate(&self) -> Result<()> {
+        if !(1..=3600).contains(&self.http_keepalive_seconds) {
+            bail!(
+                "`server.http_keepalive_seconds` must be between 1 and 3600, got {}",
+                self.http_keepalive_seconds
+            );
+        }
+        if self.shutdown_timeout_seconds > 3600 {
+            bail!(
+                "`server.shutdown_timeout_seconds` must be at most 3600, got {}",
+                self.shutdown_timeout_seconds
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_http_keepalive_seconds() -> u64 {
+    30
+}
+
+fn default_shutdown_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_history_limit() -> usize {
+    10
 }
 
 #[derive(Debug, Deserialize)]
@@ -99,6 +276,10 @@ fn default_managed_config_interval() -> u64 {
     86_400
 }
 
+fn default_prune_fields() -> bool {
+    true
+}
+
 fn default_managed_config_strict() -> bool {
     false
 }
@@ -113,6 +294,44 @@ pub struct NetworkConfig {
     pub ttl_seconds: u64,
     #[serde(default)]
     pub allowed_domain: Vec<String>,
+    /// Accept `url` on any host, ignoring `allowed_domain`. Insecure.
+    #[serde(default)]
+    pub allow_all: bool,
+    #[serde(default = "default_network_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_network_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    /// Refresh cached entries this many seconds before they expire.
+    #[serde(default)]
+    pub revalidate_before_expiry_seconds: Option<u64>,
+    /// Fail repeat fetches of a URL fast for this long after it failed; 0
+    /// disables.
+    #[serde(default)]
+    pub negative_ttl_seconds: u64,
+    /// Limit for a whole upstream request, body included.
+    #[serde(default = "default_network_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// Limit for establishing the connection alone.
+    #[serde(default = "default_network_connect_timeout_seconds")]
+    pub connect_timeout_seconds: u64,
+    /// Largest upstream body accepted, checked before and after decompression.
+    #[serde(default = "default_network_max_body_bytes")]
+    pub max_body_bytes: u64,
+}
+
+impl NetworkConfig {
+    fn validate(&self) -> Result<()> {
+        if self.timeout_seconds == 0 {
+            bail!("`network.timeout_seconds` must be greater than 0");
+        }
+        if self.connect_timeout_seconds == 0 {
+            bail!("`network.connect_timeout_seconds` must be greater than 0");
+        }
+        if self.max_body_bytes == 0 {
+            bail!("`network.max_body_bytes` must be greater than 0");
+        }
+        Ok(())
+    }
 }
 
 impl Default for NetworkConfig {
@@ -122,6 +341,14 @@ impl Default for NetworkConfig {
             dir: default_network_dir(),
             ttl_seconds: default_network_ttl_seconds(),
             allowed_domain: Vec::new(),
+            allow_all: false,
+            max_retries: default_network_max_retries(),
+            retry_base_delay_ms: default_network_retry_base_delay_ms(),
+            revalidate_before_expiry_seconds: None,
+            negative_ttl_seconds: 0,
+            timeout_seconds: default_network_timeout_seconds(),
+            connect_timeout_seconds: default_network_connect_timeout_seconds(),
+            max_body_bytes: default_network_max_body_bytes(),
         }
     }
 }
@@ -138,11 +365,191 @@ fn default_network_ttl_seconds() -> u64 {
     86_400
 }
 
+fn default_network_max_retries() -> u32 {
+    2
+}
+
+fn default_network_retry_base_delay_ms() -> u64 {
+    200
+}
+
+fn default_network_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_network_connect_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_network_max_body_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
 pub fn load_pref(path: impl AsRef<Path>) -> Result<Pref> {
+    load_pref_with_env(path, |name| std::env::var(name).ok())
+}
+
+/// Like [`load_pref`], resolving `${NAME}` references through `lookup`
+/// instead of the process environment.
+fn load_pref_with_env(
+    path: impl AsRef<Path>,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Pref> {
     let path = resolve_path(Path::new("."), path.as_ref());
     let text = fs::read_to_string(&path)
         .with_context(|| format!("failed to read pref file {}", path.display()))?;
-    let pref: Pref = toml::from_str(&text)
+    let mut value = toml::Value::Table(
+        toml::from_str(&text)
+            .with_context(|| format!("failed to parse pref file {}", path.display()))?,
+    );
+    expand_env_in_strings(&mut value, &lookup)
+        .with_context(|| format!("failed to expand pref file {}", path.display()))?;
+    let pref: Pref = value
+        .try_into()
         .with_context(|| format!("failed to parse pref file {}", path.display()))?;
+    pref.server.validate()?;
+    pref.network.validate()?;
+    pref.validate_profile_sets()?;
     Ok(pref)
 }
+
+/// Runs [`expand_env`] on every string in `value`. Expanding after parsing
+/// keeps comments out of it and lets values contain quotes or newlines.
+fn expand_env_in_strings(
+    value: &mut toml::Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    match value {
+        toml::Value::String(text) => *text = expand_env(text, lookup)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                expand_env_in_strings(item, lookup)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                expand_env_in_strings(item, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces `${NAME}` and `${NAME:-default}` with values from `lookup`, so
+/// secrets can come from the environment. A `$` not starting such a pattern is
+/// kept as is.
+fn expand_env(text: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some((name, default, len)) = parse_env_ref(after) else {
+            out.push_str("${");
+            rest = after;
+            continue;
+        };
+        match (lookup(name), default) {
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => bail!("environment variable `{name}` is not set"),
+        }
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Parses `NAME}` or `NAME:-default}` at the start of `text`, returning the
+/// name, the default and the length consumed.
+fn parse_env_ref(text: &str) -> Option<(&str, Option<&str>, usize)> {
+    let name_len = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    let name = &text[..name_len];
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let tail = &text[name_len..];
+    if tail.starts_with('}') {
+        return Some((name, None, name_len + 1));
+    }
+    let default_text = tail.strip_prefix(":-")?;
+    let end = default_text.find(['}', '\n'])?;
+    if !default_text[end..].starts_with('}') {
+        return None;
+    }
+    let default = &default_text[..end];
+    Some((name, Some(default), name_len + 2 + end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_references_are_expanded() {
+        let lookup = |name: &str| (name == "SET").then(|| "value".to_string());
+        let expanded = expand_env(
+            "a = \"${SET}\"\nb = \"${UNSET:-fallback}\"\nc = \"${SET:-unused}\"\nd = \"$HOME ${} ${1X} ${SET\"\n",
+            lookup,
+        )
+        .unwrap();
+        assert_eq!(
+            expanded,
+            "a = \"value\"\nb = \"fallback\"\nc = \"value\"\nd = \"$HOME ${} ${1X} ${SET\"\n"
+        );
+
+        let err = expand_env("token = \"${MISSING_TOKEN}\"", lookup).unwrap_err();
+        assert!(err.to_string().contains("`MISSING_TOKEN`"), "{err}");
+    }
+
+    #[test]
+    fn env_references_are_only_expanded_in_strings() {
+        let lookup =
+            |name: &str| (name == "SET").then(|| "a \"quoted\" \\ value\nline".to_string());
+        let mut value = toml::Value::Table(
+            toml::from_str(
+                "# token = \"${UNSET}\"\n[common]\nsecret = \"${SET}\"\nurls = [\"${UNSET:-https://a.example.com}\"]\n",
+            )
+            .unwrap(),
+        );
+        expand_env_in_strings(&mut value, &lookup).unwrap();
+        assert_eq!(
+            value["common"]["secret"].as_str(),
+            Some("a \"quoted\" \\ value\nline")
+        );
+        assert_eq!(
+            value["common"]["urls"][0].as_str(),
+            Some("https://a.example.com")
+        );
+    }
+
+    #[test]
+    fn api_token_can_come_from_the_environment() {
+        let path =
+            std::env::temp_dir().join(format!("subcon-pref-env-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[common]\napi_access_token = \"${SUBCON_API_TOKEN}\"\n\n[server]\nlisten = \"127.0.0.1\"\nport = 0\n",
+        )
+        .unwrap();
+
+        let lookup = |name: &str| (name == "SUBCON_API_TOKEN").then(|| "from-env".to_string());
+        let pref = load_pref_with_env(&path, lookup).unwrap();
+        assert_eq!(pref.common.api_access_token.as_deref(), Some("from-env"));
+    }
+
+    #[test]
+    fn zero_network_timeouts_are_rejected() {
+        let network: NetworkConfig = toml::from_str("timeout_seconds = 5").unwrap();
+        assert_eq!(network.timeout_seconds, 5);
+        assert_eq!(network.connect_timeout_seconds, 10);
+        network.validate().unwrap();
+
+        let network: NetworkConfig = toml::from_str("connect_timeout_seconds = 0").unwrap();
+        let err = network.validate().unwrap_err();
+        assert!(err.to_string().contains("connect_timeout_seconds"), "{err}");
+    }
+}
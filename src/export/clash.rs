@@ -1,7 +1,7 @@
 use anyhow::Result;
 use serde_json::{Map as JsonMap, Value};
 
-use super::Exporter;
+use super::{Exporter, special_target_name};
 use crate::groups::ProxyGroup;
 use crate::schema::TargetSchema;
 
@@ -32,16 +32,38 @@ pub fn render_proxy_group(group: &ProxyGroup) -> Value {
         "type".to_string(),
         Value::String(group.group_type.clone()),
     );
+    // With `include-all`, mihomo expands and filters the nodes itself.
     let mut proxies: Vec<Value> = group
         .proxies
         .iter()
+        .filter(|p| !group.include_all || p.starts_with("[]"))
         .map(|p| Value::String(normalize_proxy_name(p)))
         .collect();
-    if proxies.is_empty() {
-        proxies.push(Value::String("DIRECT".to_string()));
+    if proxies.is_empty() && !group.include_all {
+        proxies.push(Value::String(normalize_proxy_name("DIRECT")));
+    }
+    if !proxies.is_empty() {
+        map.insert("proxies".to_string(), Value::Array(proxies));
+    }
+    if group.include_all {
+        map.insert("include-all".to_string(), Value::Bool(true));
+        if let Some(filter) = &group.filter {
+            map.insert("filter".to_string(), Value::String(filter.clone()));
+        }
+        if let Some(exclude_filter) = &group.exclude_filter {
+            map.insert(
+                "exclude-filter".to_string(),
+                Value::String(exclude_filter.clone()),
+            );
+        }
     }
-    map.insert("proxies".to_string(), Value::Array(proxies));
 
+    if let Some(disable_udp) = group.disable_udp {
+        map.insert("disable-udp".to_string(), Value::Bool(disable_udp));
+    }
+    if let Some(hidden) = group.hidden {
+        map.insert("hidden".to_string(), Value::Bool(hidden));
+    }
     if let Some(url) = &group.url {
         map.insert("url".to_string(), Value::String(url.clone()));
     }
@@ -56,9 +78,80 @@ pub fn render_proxy_group(group: &ProxyGroup) -> Value {
 }
 
 fn normalize_proxy_name(name: &str) -> String {
-    if let Some(stripped) = name.strip_prefix("[]") {
-        stripped.trim().to_string()
-    } else {
-        name.to_string()
+    let name = match name.strip_prefix("[]") {
+        Some(stripped) => stripped.trim(),
+        None => name,
+    };
+    special_target_name(name, "clash").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_all_group_emits_filters() {
+        let group = ProxyGroup {
+            name: "HK".to_string(),
+            group_type: "select".to_string(),
+            proxies: vec!["[]DIRECT".to_string(), "HK 01".to_string()],
+            url: None,
+            interval: None,
+            include_all: true,
+            filter: Some("HK".to_string()),
+            exclude_filter: Some("IPLC".to_string()),
+            disable_udp: None,
+            hidden: None,
+        };
+
+        let rendered = render_proxy_group(&group);
+        assert_eq!(rendered["proxies"], serde_json::json!(["DIRECT"]));
+        assert_eq!(rendered["include-all"], true);
+        assert_eq!(rendered["filter"], "HK");
+        assert_eq!(rendered["exclude-filter"], "IPLC");
+    }
+
+    #[test]
+    fn built_in_policies_keep_their_clash_names() {
+        let group = ProxyGroup {
+            name: "Ads".to_string(),
+            group_type: "select".to_string(),
+            proxies: vec!["[]REJECT".to_string(), "[]DIRECT".to_string()],
+            url: None,
+            interval: None,
+            include_all: false,
+            filter: None,
+            exclude_filter: None,
+            disable_udp: None,
+            hidden: None,
+        };
+
+        let rendered = render_proxy_group(&group);
+        assert_eq!(rendered["proxies"], serde_json::json!(["REJECT", "DIRECT"]));
+    }
+
+    #[test]
+    fn disable_udp_and_hidden_are_emitted_only_when_set() {
+        let mut group = ProxyGroup {
+            name: "Streaming".to_string(),
+            group_type: "select".to_string(),
+            proxies: vec!["HK 01".to_string()],
+            url: None,
+            interval: None,
+            include_all: false,
+            filter: None,
+            exclude_filter: None,
+            disable_udp: None,
+            hidden: None,
+        };
+        let rendered = render_proxy_group(&group);
+        assert!(rendered.get("disable-udp").is_none());
+        assert!(rendered.get("hidden").is_none());
+
+        group.disable_udp = Some(true);
+        group.hidden = Some(false);
+        let yaml = serde_saphyr::to_string(&render_proxy_group(&group)).unwrap();
+        assert!(yaml.contains("disable-udp: true"), "{yaml}");
+        assert!(yaml.contains("hidden: false"), "{yaml}");
     }
 }
@@ -0,0 +1,248 @@
+use anyhow::{Result, bail};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use serde_json::{Map as JsonMap, Value, json};
+
+use super::{get_number, get_string, parse_opts};
+use crate::proxy::Proxy;
+
+/// RFC 3986 unreserved characters stay as-is, everything else is escaped.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Encodes a proxy as a share link straight from its profile values.
+pub fn share_link(proxy: &Proxy) -> Result<String> {
+    match proxy.protocol.as_str() {
+        "vmess" => vmess_link(proxy),
+        "trojan" => trojan_link(proxy),
+        "shadowsocks" => shadowsocks_link(proxy),
+        other => bail!("share links do not support protocol `{other}`"),
+    }
+}
+
+/// v2rayN style `vmess://` followed by base64 encoded JSON.
+fn vmess_link(proxy: &Proxy) -> Result<String> {
+    let values = &proxy.values;
+    let ws_opts = values.get("ws-opts").and_then(Value::as_object);
+    let host = ws_opts
+        .and_then(|opts| opts.get("headers"))
+        .and_then(|headers| headers.get("Host"))
+        .and_then(Value::as_str)
+        .unwrap_or("");
+    let path = ws_opts
+        .and_then(|opts| opts.get("path"))
+        .and_then(Value::as_str)
+        .unwrap_or("");
+    let tls = values.get("tls").and_then(Value::as_bool).unwrap_or(false);
+
+    let payload = json!({
+        "v": "2",
+        "ps": proxy.name,
+        "add": get_string(values, "server", "links")?,
+        "port": get_number(values, "port", "links")?,
+        "id": get_string(values, "uuid", "links")?,
+        "aid": values.get("alterId").and_then(Value::as_u64).unwrap_or(0).to_string(),
+        "scy": optional_str(values, "cipher").unwrap_or("auto"),
+        "net": optional_str(values, "network").unwrap_or("tcp"),
+        "type": "none",
+        "host": host,
+        "path": path,
+        "tls": if tls { "tls" } else { "" },
+        "sni": optional_str(values, "servername").unwrap_or(""),
+    });
+    Ok(format!("vmess://{}", STANDARD.encode(payload.to_string())))
+}
+
+fn trojan_link(proxy: &Proxy) -> Result<String> {
+    let values = &proxy.values;
+    let password = get_string(values, "password", "links")?;
+
+    let mut query = Vec::new();
+    if let Some(sni) = optional_str(values, "sni") {
+        query.push(("sni", sni.to_string()));
+    }
+    if values.get("skip-cert-verify").and_then(Value::as_bool) == Some(true) {
+        query.push(("allowInsecure", "1".to_string()));
+    }
+    if let Some(network) = optional_str(values, "network") {
+        query.push(("type", network.to_string()));
+        if let Some(path) = values
+            .get("ws-opts")
+            .and_then(|opts| opts.get("path"))
+            .and_then(Value::as_str)
+        {
+            query.push(("path", path.to_string()));
+        }
+    }
+
+    Ok(format!(
+        "trojan://{}@{}{}#{}",
+        encode(&password),
+        authority(values)?,
+        query_string(&query),
+        encode(&proxy.name)
+    ))
+}
+
+/// SIP002 `ss://` with the method and password in URL-safe base64.
+fn shadowsocks_link(proxy: &Proxy) -> Result<String> {
+    let values = &proxy.values;
+    let cipher = get_string(values, "cipher", "links")?;
+    let password = get_string(values, "password", "links")?;
+
+    let mut query = Vec::new();
+    if let Some(plugin) = optional_str(values, "plugin") {
+        let opts = parse_opts(values.get("plugin-opts").cloned())?;
+        match plugin {
+            "obfs" => {
+                let mut parts = vec!["obfs-local".to_string()];
+                if let Some(mode) = opts.get("mode").and_then(Value::as_str) {
+                    parts.push(format!("obfs={mode}"));
+                }
+                if let Some(host) = opts.get("host").and_then(Value::as_str) {
+                    parts.push(format!("obfs-host={host}"));
+                }
+                query.push(("plugin", parts.join(";")));
+            }
+            other => bail!("share links do not support shadowsocks plugin `{other}`"),
+        }
+    }
+
+    Ok(format!(
+        "ss://{}@{}{}#{}",
+        URL_SAFE_NO_PAD.encode(format!("{cipher}:{password}")),
+        authority(values)?,
+        query_string(&query),
+        encode(&proxy.name)
+    ))
+}
+
+fn authority(values: &JsonMap<String, Value>) -> Result<String> {
+    let server = get_string(values, "server", "links")?;
+    let port = get_number(values, "port", "links")?;
+    if server.contains(':') {
+        Ok(format!("[{server}]:{port}"))
+    } else {
+        Ok(format!("{server}:{port}"))
+    }
+}
+
+fn query_string(pairs: &[(&str, String)]) -> String {
+    if pairs.is_empty() {
+        return String::new();
+    }
+    let joined: Vec<String> = pairs
+        .iter()
+        .map(|(key, value)| format!("{key}={}", encode(value)))
+        .collect();
+    format!("?{}", joined.join("&"))
+}
+
+fn optional_str<'a>(values: &'a JsonMap<String, Value>, key: &str) -> Option<&'a str> {
+    values
+        .get(key)
+        .and_then(Value::as_str)
+        .filter(|s| !s.is_empty())
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, COMPONENT).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(value: Value) -> Proxy {
+        let values = value.as_object().cloned().unwrap();
+        Proxy {
+            name: values["name"].as_str().unwrap().to_string(),
+            protocol: values["type"].as_str().unwrap().to_string(),
+            values,
+        }
+    }
+
+    #[test]
+    fn trojan_link_format() {
+        let link = share_link(&proxy(json!({
+            "name": "hk 1",
+            "type": "trojan",
+            "server": "example.com",
+            "port": 443,
+            "password": "p@ss",
+            "sni": "sni.example.com",
+            "skip-cert-verify": true
+        })))
+        .unwrap();
+        assert_eq!(
+            link,
+            "trojan://p%40ss@example.com:443?sni=sni.example.com&allowInsecure=1#hk%201"
+        );
+    }
+
+    #[test]
+    fn shadowsocks_link_format() {
+        let link = share_link(&proxy(json!({
+            "name": "ss-node",
+            "type": "shadowsocks",
+            "server": "example.com",
+            "port": 8388,
+            "cipher": "aes-128-gcm",
+            "password": "secret",
+            "plugin": "obfs",
+            "plugin-opts": { "mode": "http", "host": "bing.com" }
+        })))
+        .unwrap();
+        assert_eq!(
+            link,
+            "ss://YWVzLTEyOC1nY206c2VjcmV0@example.com:8388\
+             ?plugin=obfs-local%3Bobfs%3Dhttp%3Bobfs-host%3Dbing.com#ss-node"
+        );
+    }
+
+    #[test]
+    fn vmess_link_format() {
+        let link = share_link(&proxy(json!({
+            "name": "vmess-node",
+            "type": "vmess",
+            "server": "example.com",
+            "port": 443,
+            "uuid": "b831381d-6324-4d53-ad4f-8cda48b30811",
+            "alterId": 0,
+            "cipher": "auto",
+            "tls": true,
+            "servername": "example.com",
+            "network": "ws",
+            "ws-opts": { "path": "/ws", "headers": { "Host": "cdn.example.com" } }
+        })))
+        .unwrap();
+        let encoded = link.strip_prefix("vmess://").unwrap();
+        let payload: Value = serde_json::from_slice(&STANDARD.decode(encoded).unwrap()).unwrap();
+        assert_eq!(payload["ps"], "vmess-node");
+        assert_eq!(payload["add"], "example.com");
+        assert_eq!(payload["port"], "443");
+        assert_eq!(payload["id"], "b831381d-6324-4d53-ad4f-8cda48b30811");
+        assert_eq!(payload["net"], "ws");
+        assert_eq!(payload["host"], "cdn.example.com");
+        assert_eq!(payload["path"], "/ws");
+        assert_eq!(payload["tls"], "tls");
+    }
+
+    #[test]
+    fn unsupported_protocol_is_rejected() {
+        let err = share_link(&proxy(json!({
+            "name": "tuic-node",
+            "type": "tuic",
+            "server": "example.com",
+            "port": 443
+        })))
+        .unwrap_err();
+        assert!(err.to_string().contains("`tuic`"), "{err}");
+    }
+}
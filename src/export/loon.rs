@@ -0,0 +1,151 @@
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::{Map as JsonMap, Value};
+
+use super::{Exporter, get_number, get_string, parse_opts, render_line};
+use crate::schema::TargetSchema;
+
+pub struct LoonExporter;
+
+impl Exporter for LoonExporter {
+    fn target(&self) -> &'static str {
+        "loon"
+    }
+
+    fn render(
+        &self,
+        protocol: &str,
+        _target_schema: &TargetSchema,
+        normalized: &JsonMap<String, Value>,
+        rendered: Value,
+    ) -> Result<Value> {
+        let mut rendered_map = rendered
+            .as_object()
+            .cloned()
+            .context("loon rendering expects object from template")?;
+
+        let name = normalized
+            .get("name")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("loon export requires `name`"))?;
+        let server = get_string(&rendered_map, "server", "loon")?;
+        let port = get_number(&rendered_map, "port", "loon")?;
+        let password = get_string(&rendered_map, "password", "loon")?;
+
+        // Loon lines are positional up to the credentials, then `key=value`.
+        let head = match protocol {
+            "shadowsocks" => {
+                let cipher = get_string(&rendered_map, "cipher", "loon")?;
+                apply_plugin(&mut rendered_map)?;
+                vec![
+                    "Shadowsocks".to_string(),
+                    server,
+                    port,
+                    cipher,
+                    format!("\"{password}\""),
+                ]
+            }
+            "trojan" => vec![
+                "trojan".to_string(),
+                server,
+                port,
+                format!("\"{password}\""),
+            ],
+            other => bail!("loon exporter does not support protocol `{other}`"),
+        };
+
+        let base_keys = ["name", "type", "server", "port", "password", "cipher"];
+        rendered_map.retain(|k, _| !base_keys.contains(&k.as_str()));
+
+        Ok(Value::String(render_line(name, &head, &rendered_map, ",")))
+    }
+}
+
+fn apply_plugin(map: &mut JsonMap<String, Value>) -> Result<()> {
+    let plugin = map.remove("plugin");
+    let plugin_opts = map.remove("plugin-opts");
+
+    let Some(plugin_value) = plugin else {
+        return Ok(());
+    };
+    let plugin_name = plugin_value
+        .as_str()
+        .ok_or_else(|| anyhow!("shadowsocks plugin must be a string"))?;
+    if plugin_name != "obfs" {
+        bail!("loon exporter does not support shadowsocks plugin `{plugin_name}`");
+    }
+
+    let opts = parse_opts(plugin_opts)?;
+    let mode = opts
+        .get("mode")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("shadowsocks obfs plugin requires `mode` (http/tls)"))?;
+    map.insert("obfs-name".to_string(), Value::String(mode.to_string()));
+
+    if let Some(host) = opts.get("host").and_then(|v| v.as_str()) {
+        map.insert("obfs-host".to_string(), Value::String(host.to_string()));
+    }
+    if let Some(uri) = opts
+        .get("uri")
+        .or_else(|| opts.get("path"))
+        .and_then(|v| v.as_str())
+    {
+        map.insert("obfs-uri".to_string(), Value::String(uri.to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::test_registry;
+
+    fn convert(protocol: &str, values: serde_json::Value) -> String {
+        let values = values.as_object().cloned().unwrap();
+        test_registry()
+            .convert(protocol, "loon", "test", &values)
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn shadowsocks_with_obfs() {
+        let line = convert(
+            "shadowsocks",
+            serde_json::json!({
+                "name": "ss-node",
+                "server": "example.com",
+                "port": 8388,
+                "cipher": "aes-128-gcm",
+                "password": "secret",
+                "udp": true,
+                "plugin": "obfs",
+                "plugin-opts": { "mode": "http", "host": "bing.com" },
+            }),
+        );
+        assert_eq!(
+            line,
+            "ss-node = Shadowsocks,example.com,8388,aes-128-gcm,\"secret\",obfs-host=bing.com,obfs-name=http,udp=true"
+        );
+    }
+
+    #[test]
+    fn trojan_with_sni() {
+        let line = convert(
+            "trojan",
+            serde_json::json!({
+                "name": "trojan-node",
+                "server": "example.com",
+                "port": 443,
+                "password": "secret",
+                "sni": "cdn.example.com",
+                "skip-cert-verify": true,
+            }),
+        );
+        assert_eq!(
+            line,
+            "trojan-node = trojan,example.com,443,\"secret\",skip-cert-verify=true,tls-name=cdn.example.com"
+        );
+    }
+}
@@ -1,13 +1,26 @@
 use std::collections::HashSet;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use serde_json::{Map as JsonMap, Value};
 
 use crate::schema::TargetSchema;
 
 pub mod clash;
+pub mod links;
+pub mod loon;
+pub mod singbox;
 pub mod surge;
 
+/// Spells the built-in `DIRECT` and `REJECT` policies the way `target`
+/// names them. Other names are returned unchanged.
+pub fn special_target_name<'a>(name: &'a str, target: &str) -> &'a str {
+    match (target, name) {
+        ("singbox", "DIRECT") => "direct",
+        ("singbox", "REJECT") => "block",
+        _ => name,
+    }
+}
+
 pub trait RenderPass: Send + Sync {
     fn render(
         &self,
@@ -40,10 +53,7 @@ impl RenderPass for TypeInjector {
         _normalized: &JsonMap<String, Value>,
         rendered: Value,
     ) -> Result<Value> {
-        let mut map = rendered
-            .as_object()
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("rendered target must be an object"))?;
+        let mut map = rendered_object(protocol, rendered)?;
         map.entry("type".to_string())
             .or_insert_with(|| Value::String(protocol.to_string()));
         Ok(Value::Object(map))
@@ -55,15 +65,12 @@ pub struct FieldPruner;
 impl RenderPass for FieldPruner {
     fn render(
         &self,
-        _protocol: &str,
+        protocol: &str,
         target_schema: &TargetSchema,
         normalized: &JsonMap<String, Value>,
         rendered: Value,
     ) -> Result<Value> {
-        let mut map = rendered
-            .as_object()
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("rendered target must be an object"))?;
+        let mut map = rendered_object(protocol, rendered)?;
 
         // Remove keys not present in the template mapping.
         let allowed: HashSet<&String> = target_schema.template.keys().collect();
@@ -88,3 +95,62 @@ impl RenderPass for FieldPruner {
         Ok(Value::Object(map))
     }
 }
+
+fn rendered_object(protocol: &str, rendered: Value) -> Result<JsonMap<String, Value>> {
+    match rendered {
+        Value::Object(map) => Ok(map),
+        other => Err(anyhow!(
+            "`{protocol}` template must render to an object, got {other}"
+        )),
+    }
+}
+
+/// Joins `name = head...` followed by the remaining options as sorted
+/// `key=value` pairs, as used by the Surge and Loon proxy lines.
+fn render_line(
+    name: &str,
+    head: &[String],
+    options: &JsonMap<String, Value>,
+    separator: &str,
+) -> String {
+    let mut keys: Vec<&String> = options.keys().collect();
+    keys.sort();
+
+    let mut parts = head.to_vec();
+    parts.extend(keys.into_iter().map(|key| format_value(key, &options[key])));
+    format!("{name} = {}", parts.join(separator))
+}
+
+fn format_value(key: &str, value: &Value) -> String {
+    match value {
+        Value::Bool(b) => format!("{key}={}", b),
+        Value::Number(n) => format!("{key}={}", n),
+        // Quote values that would otherwise split the comma separated line.
+        Value::String(s) if s.contains(',') || s.trim() != s => format!("{key}=\"{s}\""),
+        Value::String(s) => format!("{key}={s}"),
+        other => format!("{key}={}", other),
+    }
+}
+
+fn get_string(map: &JsonMap<String, Value>, key: &str, target: &str) -> Result<String> {
+    map.get(key)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow!("{target} export requires `{key}`"))
+}
+
+fn get_number(map: &JsonMap<String, Value>, key: &str, target: &str) -> Result<String> {
+    let num = map
+        .get(key)
+        .and_then(|v| v.as_i64().or_else(|| v.as_u64().map(|p| p as i64)))
+        .ok_or_else(|| anyhow!("{target} export requires numeric `{key}`"))?;
+    Ok(num.to_string())
+}
+
+fn parse_opts(value: Option<Value>) -> Result<JsonMap<String, Value>> {
+    match value {
+        Some(Value::Object(map)) => Ok(map),
+        Some(_) => Err(anyhow!("shadowsocks plugin-opts must be a map")),
+        None => Ok(JsonMap::new()),
+    }
+}
@@ -0,0 +1,318 @@
+use std::net::IpAddr;
+
+use anyhow::{Result, bail};
+use serde_json::{Map as JsonMap, Value, json};
+
+use super::special_target_name;
+use crate::groups::ProxyGroup;
+
+/// Tag of the server used when the Clash base has no usable DNS.
+const LOCAL_TAG: &str = "dns-local";
+const BOOTSTRAP_TAG: &str = "dns-bootstrap";
+const FAKEIP_TAG: &str = "dns-fakeip";
+
+/// Translates a Clash `dns` block into sing-box's `dns` object.
+///
+/// Supported: `nameserver` (the first one becomes `final`),
+/// `default-nameserver` (first entry resolves hostnames in the other
+/// servers), `nameserver-policy` with `+.suffix`, plain domain and
+/// `geosite:` keys, `ipv6: false`, and `enhanced-mode: fake-ip` with
+/// `fake-ip-range`. `fallback` and `fallback-filter` have no sing-box
+/// counterpart and are ignored. A disabled block or one without nameservers
+/// yields a single `local` server.
+#[allow(dead_code)]
+pub fn clash_dns_to_singbox(dns: &JsonMap<String, Value>) -> Value {
+    let enabled = dns.get("enable").and_then(Value::as_bool).unwrap_or(true);
+    let nameservers = addresses(dns.get("nameserver"));
+    if !enabled || nameservers.is_empty() {
+        return json!({
+            "servers": [{ "tag": LOCAL_TAG, "address": "local" }],
+            "final": LOCAL_TAG,
+        });
+    }
+
+    let bootstrap = addresses(dns.get("default-nameserver")).into_iter().next();
+    let server = |tag: String, address: String| {
+        let mut server = JsonMap::new();
+        if bootstrap.is_some() && needs_resolver(&address) {
+            server.insert("address_resolver".to_string(), json!(BOOTSTRAP_TAG));
+        }
+        server.insert("tag".to_string(), json!(tag));
+        server.insert("address".to_string(), json!(address));
+        Value::Object(server)
+    };
+
+    let mut servers: Vec<Value> = nameservers
+        .into_iter()
+        .enumerate()
+        .map(|(index, address)| server(format!("dns-{index}"), address))
+        .collect();
+    let mut rules = Vec::new();
+
+    let policies = dns.get("nameserver-policy").and_then(Value::as_object);
+    for (index, (pattern, target)) in policies.into_iter().flatten().enumerate() {
+        let Some(address) = addresses(Some(target)).into_iter().next() else {
+            continue;
+        };
+        let tag = format!("dns-policy-{index}");
+        let mut rule = policy_matcher(pattern);
+        rule.insert("server".to_string(), json!(tag));
+        rules.push(Value::Object(rule));
+        servers.push(server(tag, address));
+    }
+
+    let mut out = JsonMap::new();
+    if dns.get("enhanced-mode").and_then(Value::as_str) == Some("fake-ip") {
+        servers.push(json!({ "tag": FAKEIP_TAG, "address": "fakeip" }));
+        rules.push(json!({ "query_type": ["A", "AAAA"], "server": FAKEIP_TAG }));
+        let mut fakeip = JsonMap::new();
+        fakeip.insert("enabled".to_string(), json!(true));
+        if let Some(range) = dns.get("fake-ip-range").and_then(Value::as_str) {
+            fakeip.insert("inet4_range".to_string(), json!(range));
+        }
+        out.insert("fakeip".to_string(), Value::Object(fakeip));
+    }
+    if let Some(address) = bootstrap {
+        servers.push(json!({ "tag": BOOTSTRAP_TAG, "address": address }));
+    }
+
+    out.insert("servers".to_string(), Value::Array(servers));
+    if !rules.is_empty() {
+        out.insert("rules".to_string(), Value::Array(rules));
+    }
+    out.insert("final".to_string(), json!("dns-0"));
+    if dns.get("ipv6").and_then(Value::as_bool) == Some(false) {
+        out.insert("strategy".to_string(), json!("ipv4_only"));
+    }
+    Value::Object(out)
+}
+
+/// Renders a proxy group as a sing-box outbound: `select` becomes a
+/// `selector`, and `url-test` and `fallback` become `urltest`, sing-box's
+/// closest match for either. `load-balance` and other types have no sing-box
+/// counterpart and are rejected. `DIRECT` and `REJECT` become the `direct`
+/// and `block` outbound tags, which the config must define.
+#[allow(dead_code)]
+pub fn render_outbound_group(group: &ProxyGroup) -> Result<Value> {
+    let urltest = match group.group_type.as_str() {
+        "select" => false,
+        "url-test" | "fallback" => true,
+        other => bail!(
+            "sing-box has no outbound for group type `{other}` (group `{}`)",
+            group.name
+        ),
+    };
+
+    let mut outbounds: Vec<&str> = group
+        .proxies
+        .iter()
+        .map(|name| name.strip_prefix("[]").map_or(name.as_str(), str::trim))
+        .map(|name| special_target_name(name, "singbox"))
+        .collect();
+    if outbounds.is_empty() {
+        outbounds.push(special_target_name("DIRECT", "singbox"));
+    }
+
+    let mut out = JsonMap::new();
+    out.insert(
+        "type".to_string(),
+        json!(if urltest { "urltest" } else { "selector" }),
+    );
+    out.insert("tag".to_string(), json!(group.name));
+    out.insert("outbounds".to_string(), json!(outbounds));
+    if urltest {
+        if let Some(url) = &group.url {
+            out.insert("url".to_string(), json!(url));
+        }
+        if let Some(interval) = group.interval {
+            out.insert("interval".to_string(), json!(format!("{interval}s")));
+        }
+    }
+    Ok(Value::Object(out))
+}
+
+/// Server addresses from a string or list, with Clash's `#proxy` suffix
+/// removed and `system` mapped to sing-box's `local`.
+fn addresses(value: Option<&Value>) -> Vec<String> {
+    let items = match value {
+        Some(Value::String(address)) => vec![address.as_str()],
+        Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    items
+        .into_iter()
+        .map(|address| address.split('#').next().unwrap_or_default().trim())
+        .filter(|address| !address.is_empty())
+        .map(|address| match address {
+            "system" => "local".to_string(),
+            other => other.to_string(),
+        })
+        .collect()
+}
+
+/// Whether the server is named by a hostname that must be resolved first.
+fn needs_resolver(address: &str) -> bool {
+    let Some((_, rest)) = address.split_once("://") else {
+        // Bare addresses are IPs, `local` or `fakeip`.
+        return false;
+    };
+    if rest.starts_with('[') {
+        return false;
+    }
+    let host = rest.split(['/', ':']).next().unwrap_or_default();
+    !address.starts_with("dhcp://") && host.parse::<IpAddr>().is_err()
+}
+
+fn policy_matcher(pattern: &str) -> JsonMap<String, Value> {
+    let mut rule = JsonMap::new();
+    if let Some(category) = pattern.strip_prefix("geosite:") {
+        rule.insert("geosite".to_string(), json!([category]));
+    } else if let Some(suffix) = pattern.strip_prefix("+.") {
+        rule.insert("domain_suffix".to_string(), json!([suffix]));
+    } else {
+        rule.insert("domain".to_string(), json!([pattern]));
+    }
+    rule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dns(yaml: &str) -> JsonMap<String, Value> {
+        let base: Value = serde_saphyr::from_str(yaml).unwrap();
+        base["dns"].as_object().cloned().unwrap()
+    }
+
+    #[test]
+    fn nameservers_become_servers_with_bootstrap() {
+        let dns = dns(r#"
+dns:
+  enable: true
+  ipv6: false
+  default-nameserver: [223.5.5.5]
+  nameserver:
+    - https://doh.pub/dns-query
+    - tls://1.1.1.1#PROXY
+  nameserver-policy:
+    "+.corp.example.com": 10.0.0.53
+    "geosite:cn": [https://dns.alidns.com/dns-query]
+"#);
+
+        assert_eq!(
+            clash_dns_to_singbox(&dns),
+            json!({
+                "servers": [
+                    { "tag": "dns-0", "address": "https://doh.pub/dns-query", "address_resolver": "dns-bootstrap" },
+                    { "tag": "dns-1", "address": "tls://1.1.1.1" },
+                    { "tag": "dns-policy-0", "address": "10.0.0.53" },
+                    { "tag": "dns-policy-1", "address": "https://dns.alidns.com/dns-query", "address_resolver": "dns-bootstrap" },
+                    { "tag": "dns-bootstrap", "address": "223.5.5.5" },
+                ],
+                "rules": [
+                    { "domain_suffix": ["corp.example.com"], "server": "dns-policy-0" },
+                    { "geosite": ["cn"], "server": "dns-policy-1" },
+                ],
+                "final": "dns-0",
+                "strategy": "ipv4_only",
+            })
+        );
+    }
+
+    #[test]
+    fn fake_ip_mode_adds_a_fakeip_server() {
+        let dns = dns(r#"
+dns:
+  enhanced-mode: fake-ip
+  fake-ip-range: 198.18.0.1/16
+  nameserver: [223.5.5.5, system]
+"#);
+
+        let singbox = clash_dns_to_singbox(&dns);
+        assert_eq!(
+            singbox["servers"][1],
+            json!({ "tag": "dns-1", "address": "local" })
+        );
+        assert_eq!(
+            singbox["servers"][2],
+            json!({ "tag": "dns-fakeip", "address": "fakeip" })
+        );
+        assert_eq!(
+            singbox["rules"],
+            json!([{ "query_type": ["A", "AAAA"], "server": "dns-fakeip" }])
+        );
+        assert_eq!(
+            singbox["fakeip"],
+            json!({ "enabled": true, "inet4_range": "198.18.0.1/16" })
+        );
+    }
+
+    #[test]
+    fn missing_or_disabled_dns_falls_back_to_local() {
+        let local = json!({
+            "servers": [{ "tag": "dns-local", "address": "local" }],
+            "final": "dns-local",
+        });
+        assert_eq!(clash_dns_to_singbox(&JsonMap::new()), local);
+        assert_eq!(
+            clash_dns_to_singbox(&dns("dns:\n  enable: false\n  nameserver: [223.5.5.5]\n")),
+            local
+        );
+    }
+
+    #[test]
+    fn built_in_policies_map_to_singbox_outbounds() {
+        let group = ProxyGroup {
+            name: "Auto".to_string(),
+            group_type: "url-test".to_string(),
+            proxies: vec![
+                "HK 01".to_string(),
+                "[]REJECT".to_string(),
+                "[]DIRECT".to_string(),
+            ],
+            url: Some("https://www.gstatic.com/generate_204".to_string()),
+            interval: Some(300),
+            include_all: false,
+            filter: None,
+            exclude_filter: None,
+            disable_udp: None,
+            hidden: None,
+        };
+
+        assert_eq!(
+            render_outbound_group(&group).unwrap(),
+            json!({
+                "type": "urltest",
+                "tag": "Auto",
+                "outbounds": ["HK 01", "block", "direct"],
+                "url": "https://www.gstatic.com/generate_204",
+                "interval": "300s",
+            })
+        );
+    }
+
+    #[test]
+    fn fallback_groups_become_urltest_and_load_balance_is_rejected() {
+        let mut group = ProxyGroup {
+            name: "Backup".to_string(),
+            group_type: "fallback".to_string(),
+            proxies: vec!["HK 01".to_string(), "JP 01".to_string()],
+            url: None,
+            interval: None,
+            include_all: false,
+            filter: None,
+            exclude_filter: None,
+            disable_udp: None,
+            hidden: None,
+        };
+
+        assert_eq!(
+            render_outbound_group(&group).unwrap(),
+            json!({ "type": "urltest", "tag": "Backup", "outbounds": ["HK 01", "JP 01"] })
+        );
+
+        group.group_type = "load-balance".to_string();
+        let err = render_outbound_group(&group).unwrap_err();
+        assert!(err.to_string().contains("load-balance"), "{err}");
+    }
+}
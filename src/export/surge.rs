@@ -1,7 +1,7 @@
 use anyhow::{Context, Result, anyhow, bail};
 use serde_json::{Map as JsonMap, Value};
 
-use super::Exporter;
+use super::{Exporter, get_number, get_string, parse_opts, render_line};
 use crate::schema::TargetSchema;
 
 pub struct SurgeExporter;
@@ -26,15 +26,17 @@ impl Exporter for SurgeExporter {
         match protocol {
             "hysteria2" => normalize_hysteria2(&mut rendered_map)?,
             "shadowsocks" => normalize_shadowsocks(&mut rendered_map)?,
+            "snell" => normalize_snell(&mut rendered_map)?,
             _ => {}
         }
+        normalize_tls_fields(protocol, &mut rendered_map)?;
 
         let name = normalized
             .get("name")
             .and_then(|v| v.as_str())
             .ok_or_else(|| anyhow!("surge export requires `name`"))?;
-        let server = get_string(&rendered_map, "server")?;
-        let port = get_number(&rendered_map, "port")?;
+        let server = get_string(&rendered_map, "server", "surge")?;
+        let port = get_number(&rendered_map, "port", "surge")?;
 
         render_common_line(name, server, port, &rendered_map)
     }
@@ -46,27 +48,68 @@ fn render_common_line(
     port: String,
     rendered_map: &JsonMap<String, Value>,
 ) -> Result<Value> {
-    let type_value = get_string(rendered_map, "type")?;
-    let prefix = format!("{name} = {type_value}, {server}, {port}");
+    let type_value = get_string(rendered_map, "type", "surge")?;
 
     let base_keys = ["name", "type", "server", "port"];
-    let mut rest_keys: Vec<String> = rendered_map
-        .keys()
-        .filter(|k| !base_keys.contains(&k.as_str()))
-        .cloned()
-        .collect();
-    rest_keys.sort();
-
-    let mut parts = Vec::with_capacity(1 + rest_keys.len());
-    parts.push(prefix);
-
-    for key in rest_keys {
-        if let Some(value) = rendered_map.get(&key) {
-            parts.push(format_value(&key, value));
+    let mut options = rendered_map.clone();
+    options.retain(|k, _| !base_keys.contains(&k.as_str()));
+
+    Ok(Value::String(render_line(
+        name,
+        &[type_value, server, port],
+        &options,
+        ", ",
+    )))
+}
+
+/// Maps TLS options onto Surge's spellings. Surge rejects unknown keys, so
+/// `servername` becomes `sni` and a boolean `tls` turns into the TLS variant
+/// of the proxy type where Surge has one.
+fn normalize_tls_fields(protocol: &str, map: &mut JsonMap<String, Value>) -> Result<()> {
+    if let Some(servername) = map.remove("servername") {
+        map.entry("sni").or_insert(servername);
+    }
+
+    if let Some(alpn) = map.remove("alpn") {
+        let alpn = match alpn {
+            Value::String(s) => s,
+            Value::Array(items) => items
+                .iter()
+                .map(|item| {
+                    item.as_str()
+                        .ok_or_else(|| anyhow!("alpn entries must be strings"))
+                })
+                .collect::<Result<Vec<_>>>()?
+                .join(","),
+            other => bail!("alpn must be a list, got {other}"),
+        };
+        if !alpn.is_empty() {
+            map.insert("alpn".to_string(), Value::String(alpn));
         }
     }
 
-    Ok(Value::String(parts.join(", ")))
+    let tls = match map.remove("tls") {
+        Some(Value::Bool(tls)) => tls,
+        Some(other) => bail!("tls must be a boolean, got {other}"),
+        None => false,
+    };
+    if tls {
+        match protocol {
+            "http" => {
+                map.insert("type".to_string(), Value::String("https".to_string()));
+            }
+            "socks5" => {
+                map.insert("type".to_string(), Value::String("socks5-tls".to_string()));
+            }
+            "vmess" => {
+                map.insert("tls".to_string(), Value::Bool(true));
+            }
+            // Trojan and Hysteria2 always use TLS and take no `tls` key.
+            _ => {}
+        }
+    }
+
+    Ok(())
 }
 
 fn normalize_hysteria2(map: &mut JsonMap<String, Value>) -> Result<()> {
@@ -99,6 +142,7 @@ fn normalize_shadowsocks(map: &mut JsonMap<String, Value>) -> Result<()> {
 
         match plugin_name {
             "obfs" => apply_obfs(opts, map)?,
+            "shadow-tls" => apply_shadow_tls(opts, map)?,
             other => bail!("surge exporter does not support shadowsocks plugin `{other}`"),
         }
     }
@@ -106,11 +150,21 @@ fn normalize_shadowsocks(map: &mut JsonMap<String, Value>) -> Result<()> {
     Ok(())
 }
 
+/// Surge takes Snell obfuscation as flat `obfs`/`obfs-host` options instead
+/// of Clash's `obfs-opts` map.
+fn normalize_snell(map: &mut JsonMap<String, Value>) -> Result<()> {
+    match map.remove("obfs-opts") {
+        Some(Value::Object(opts)) => apply_obfs(opts, map),
+        Some(_) => bail!("snell obfs-opts must be a map"),
+        None => Ok(()),
+    }
+}
+
 fn apply_obfs(opts: JsonMap<String, Value>, map: &mut JsonMap<String, Value>) -> Result<()> {
     let mode = opts
         .get("mode")
         .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow!("shadowsocks obfs plugin requires `mode` (http/tls)"))?;
+        .ok_or_else(|| anyhow!("obfs requires `mode` (http/tls)"))?;
 
     map.insert("obfs".to_string(), Value::String(mode.to_string()));
 
@@ -129,12 +183,29 @@ fn apply_obfs(opts: JsonMap<String, Value>, map: &mut JsonMap<String, Value>) ->
     Ok(())
 }
 
-fn parse_opts(value: Option<Value>) -> Result<JsonMap<String, Value>> {
-    match value {
-        Some(Value::Object(map)) => Ok(map),
-        Some(_) => Err(anyhow!("shadowsocks plugin-opts must be a map")),
-        None => Ok(JsonMap::new()),
+/// Surge runs ShadowTLS natively, configured through flat `shadow-tls-*`
+/// options instead of a plugin.
+fn apply_shadow_tls(opts: JsonMap<String, Value>, map: &mut JsonMap<String, Value>) -> Result<()> {
+    let password = opts
+        .get("password")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("shadow-tls requires `password`"))?;
+    map.insert(
+        "shadow-tls-password".to_string(),
+        Value::String(password.to_string()),
+    );
+
+    if let Some(host) = opts.get("host").and_then(|v| v.as_str()) {
+        map.insert(
+            "shadow-tls-sni".to_string(),
+            Value::String(host.to_string()),
+        );
     }
+    if let Some(version) = opts.get("version").and_then(|v| v.as_u64()) {
+        map.insert("shadow-tls-version".to_string(), Value::from(version));
+    }
+
+    Ok(())
 }
 
 fn parse_bandwidth(value: &Value) -> Result<Option<serde_json::Number>> {
@@ -171,26 +242,118 @@ fn parse_bandwidth(value: &Value) -> Result<Option<serde_json::Number>> {
     Ok(serde_json::Number::from_f64(mbps))
 }
 
-fn format_value(key: &str, value: &Value) -> String {
-    match value {
-        Value::Bool(b) => format!("{key}={}", b),
-        Value::Number(n) => format!("{key}={}", n),
-        Value::String(s) => format!("{key}={s}"),
-        other => format!("{key}={}", other),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+    use serde_json::json;
+
+    #[test]
+    fn trojan_line_uses_surge_tls_keys() {
+        let node = json!({
+            "name": "trojan-node",
+            "type": "trojan",
+            "server": "example.com",
+            "port": 443,
+            "password": "secret",
+            "tls": true,
+            "sni": "sni.example.com",
+            "alpn": ["h2", "http/1.1"],
+            "skip-cert-verify": true,
+        });
+        let rendered = test_registry()
+            .convert("trojan", "surge", "trojan-node", node.as_object().unwrap())
+            .unwrap();
+        assert_eq!(
+            rendered,
+            "trojan-node = trojan, example.com, 443, alpn=\"h2,http/1.1\", password=secret, \
+             skip-cert-verify=true, sni=sni.example.com"
+        );
     }
-}
 
-fn get_string(map: &JsonMap<String, Value>, key: &str) -> Result<String> {
-    map.get(key)
-        .and_then(|v| v.as_str())
-        .map(|s| s.to_string())
-        .ok_or_else(|| anyhow!("surge export requires `{key}`"))
-}
+    #[test]
+    fn tls_flag_selects_surge_type() {
+        let mut map = json!({ "type": "http", "tls": true, "servername": "example.com" })
+            .as_object()
+            .cloned()
+            .unwrap();
+        normalize_tls_fields("http", &mut map).unwrap();
+        assert_eq!(
+            Value::Object(map),
+            json!({ "type": "https", "sni": "example.com" })
+        );
+    }
+
+    fn v2ray_plugin_node() -> Value {
+        json!({
+            "name": "ss-ws",
+            "type": "ss",
+            "server": "example.com",
+            "port": 443,
+            "cipher": "aes-128-gcm",
+            "password": "secret",
+            "plugin": "v2ray-plugin",
+            "plugin-opts": {
+                "mode": "websocket",
+                "tls": true,
+                "host": "cdn.example.com",
+                "path": "/ws",
+                "headers": { "X-Edge": "1" },
+            },
+        })
+    }
+
+    #[test]
+    fn v2ray_plugin_opts_reach_clash_but_not_surge() {
+        let registry = test_registry();
+        let node = v2ray_plugin_node();
+        let values = node.as_object().unwrap();
+
+        let clash = registry
+            .convert("shadowsocks", "clash", "ss-ws", values)
+            .unwrap();
+        assert_eq!(clash["plugin"], "v2ray-plugin");
+        assert_eq!(clash["plugin-opts"], node["plugin-opts"]);
+
+        let err = registry
+            .convert("shadowsocks", "surge", "ss-ws", values)
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("does not support shadowsocks plugin `v2ray-plugin`"),
+            "{err:#}"
+        );
+    }
 
-fn get_number(map: &JsonMap<String, Value>, key: &str) -> Result<String> {
-    let num = map
-        .get(key)
-        .and_then(|v| v.as_i64().or_else(|| v.as_u64().map(|p| p as i64)))
-        .ok_or_else(|| anyhow!("surge export requires numeric `{key}`"))?;
-    Ok(num.to_string())
+    #[test]
+    fn shadow_tls_plugin_maps_to_surge_options() {
+        let node = json!({
+            "name": "ss-stls",
+            "type": "ss",
+            "server": "example.com",
+            "port": 443,
+            "cipher": "2022-blake3-aes-128-gcm",
+            "password": "AAECAwQFBgcICQoLDA0ODw==",
+            "client-fingerprint": "chrome",
+            "plugin": "shadow-tls",
+            "plugin-opts": { "host": "cloud.tencent.com", "password": "stls", "version": 3 },
+        });
+        let registry = test_registry();
+        let values = node.as_object().unwrap();
+
+        let clash = registry
+            .convert("shadowsocks", "clash", "ss-stls", values)
+            .unwrap();
+        assert_eq!(clash["plugin-opts"], node["plugin-opts"]);
+        assert_eq!(clash["client-fingerprint"], "chrome");
+
+        let surge = registry
+            .convert("shadowsocks", "surge", "ss-stls", values)
+            .unwrap();
+        assert_eq!(
+            surge,
+            "ss-stls = ss, example.com, 443, encrypt-method=2022-blake3-aes-128-gcm, \
+             password=AAECAwQFBgcICQoLDA0ODw==, shadow-tls-password=stls, shadow-tls-sni=cloud.tencent.com, \
+             shadow-tls-version=3"
+        );
+    }
 }
@@ -6,7 +6,8 @@ use anyhow::{Context, Result, bail};
 use fancy_regex::Regex;
 use serde::Deserialize;
 
-use crate::proxy::Proxy;
+use crate::config::GroupDefaults;
+use crate::proxy::{Proxy, region};
 
 #[derive(Debug, Deserialize)]
 struct GroupsToml {
@@ -23,6 +24,27 @@ pub struct GroupSpec {
     pub rule: Vec<String>,
     pub url: Option<String>,
     pub interval: Option<u64>,
+    #[serde(default, rename = "include-all")]
+    pub include_all: bool,
+    pub filter: Option<String>,
+    #[serde(rename = "exclude-filter")]
+    pub exclude_filter: Option<String>,
+    #[serde(rename = "disable-udp")]
+    pub disable_udp: Option<bool>,
+    pub hidden: Option<bool>,
+    #[serde(default, rename = "match-mode", alias = "match_mode")]
+    pub match_mode: MatchMode,
+}
+
+/// How `rule` patterns are matched against node names.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// The pattern may match anywhere in the name, so `HK` matches `SHK 01`.
+    #[default]
+    Substring,
+    /// The pattern must match the whole name.
+    Full,
 }
 
 #[derive(Debug, Clone)]
@@ -32,6 +54,12 @@ pub struct ProxyGroup {
     pub proxies: Vec<String>,
     pub url: Option<String>,
     pub interval: Option<u64>,
+    pub include_all: bool,
+    pub filter: Option<String>,
+    pub exclude_filter: Option<String>,
+    /// Clash-only flags, left out of the output when unset.
+    pub disable_udp: Option<bool>,
+    pub hidden: Option<bool>,
 }
 
 pub fn load_group_specs(path: impl AsRef<Path>) -> Result<Vec<GroupSpec>> {
@@ -43,37 +71,236 @@ pub fn load_group_specs(path: impl AsRef<Path>) -> Result<Vec<GroupSpec>> {
     Ok(parsed.groups)
 }
 
-pub fn build_groups(specs: &[GroupSpec], proxies: &[Proxy]) -> Result<Vec<ProxyGroup>> {
+/// Group types that health-check their members and so take `url`/`interval`.
+const TESTED_GROUP_TYPES: [&str; 3] = ["url-test", "fallback", "load-balance"];
+
+/// Expands into a `select` group over one `url-test` group per detected region.
+const AUTO_REGION: &str = "auto-region";
+
+/// Group types accepted in groups files. Targets rename them as needed, such
+/// as Surge's `smart` for `url-test`.
+const GROUP_TYPES: [&str; 6] = [
+    "select",
+    "url-test",
+    "fallback",
+    "load-balance",
+    "relay",
+    AUTO_REGION,
+];
+
+pub fn build_groups(
+    specs: &[GroupSpec],
+    proxies: &[Proxy],
+    defaults: &GroupDefaults,
+) -> Result<Vec<ProxyGroup>> {
     let proxy_names: Vec<String> = proxies.iter().map(|p| p.name.clone()).collect();
     let proxy_lookup: HashSet<String> = proxy_names.iter().cloned().collect();
     let spec_map: HashMap<String, GroupSpec> =
         specs.iter().map(|s| (s.name.clone(), s.clone())).collect();
 
-    let mut groups = Vec::new();
-    let mut resolved_names = HashSet::new();
-
     let mut allowed_groups: HashSet<String> = spec_map.keys().cloned().collect::<HashSet<String>>();
     allowed_groups.insert("DIRECT".to_string());
     allowed_groups.insert("REJECT".to_string());
 
+    // Build referenced groups before their referencers, then emit in file order.
+    let mut built: HashMap<String, Vec<ProxyGroup>> = HashMap::new();
+    for spec in build_order(specs)? {
+        let group = build_group(spec, defaults, &allowed_groups, &proxy_names, &proxy_lookup)?;
+        let expanded = if spec.group_type == AUTO_REGION {
+            split_by_region(spec, group, defaults)
+        } else {
+            vec![group]
+        };
+        built.insert(spec.name.clone(), expanded);
+    }
+
+    let mut groups = Vec::new();
     for spec in specs {
-        if resolved_names.contains(&spec.name) {
-            continue;
+        if let Some(expanded) = built.remove(&spec.name) {
+            groups.extend(expanded);
+        }
+    }
+
+    let mut names = HashSet::new();
+    for group in &groups {
+        if !names.insert(group.name.as_str()) {
+            bail!("duplicate group name `{}`", group.name);
         }
-        let group = build_group(spec, &allowed_groups, &proxy_names, &proxy_lookup)?;
-        resolved_names.insert(group.name.clone());
-        groups.push(group);
     }
 
     Ok(groups)
 }
 
+/// A `groups` filter named a group the config does not define.
+#[derive(Debug)]
+pub struct UnknownGroup(pub String);
+
+impl std::fmt::Display for UnknownGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown group `{}` in groups filter", self.0)
+    }
+}
+
+impl std::error::Error for UnknownGroup {}
+
+/// Keeps the groups named in `names` plus every group they reach through
+/// `[]` references, so the kept groups never point at a dropped one. File
+/// order is preserved.
+pub fn retain_groups(groups: &mut Vec<ProxyGroup>, names: &[String]) -> Result<()> {
+    let by_name: HashMap<&str, &ProxyGroup> = groups
+        .iter()
+        .map(|group| (group.name.as_str(), group))
+        .collect();
+    let mut pending = Vec::new();
+    for name in names {
+        if !by_name.contains_key(name.as_str()) {
+            return Err(UnknownGroup(name.clone()).into());
+        }
+        pending.push(name.as_str());
+    }
+
+    let mut keep = HashSet::new();
+    while let Some(name) = pending.pop() {
+        if !keep.insert(name.to_string()) {
+            continue;
+        }
+        for member in &by_name[name].proxies {
+            if let Some(target) = member.strip_prefix("[]")
+                && by_name.contains_key(target)
+            {
+                pending.push(target);
+            }
+        }
+    }
+    groups.retain(|group| keep.contains(&group.name));
+    Ok(())
+}
+
+/// Turns the members of an `auto-region` group into a parent `select` group
+/// followed by a `url-test` group per region, in order of first appearance.
+/// Nodes without a recognized region and group references stay in the parent
+/// after the regional groups.
+fn split_by_region(
+    spec: &GroupSpec,
+    group: ProxyGroup,
+    defaults: &GroupDefaults,
+) -> Vec<ProxyGroup> {
+    let mut regions: Vec<(&region::Region, Vec<String>)> = Vec::new();
+    let mut rest = Vec::new();
+    for member in group.proxies {
+        let detected = (!member.starts_with("[]"))
+            .then(|| region::detect(&member))
+            .flatten();
+        let Some(detected) = detected else {
+            rest.push(member);
+            continue;
+        };
+        match regions
+            .iter_mut()
+            .find(|(seen, _)| std::ptr::eq(*seen, detected))
+        {
+            Some((_, members)) => members.push(member),
+            None => regions.push((detected, vec![member])),
+        }
+    }
+
+    let url = spec.url.clone().or_else(|| defaults.test_url.clone());
+    let interval = spec.interval.or(defaults.test_interval);
+    let regional: Vec<ProxyGroup> = regions
+        .into_iter()
+        .map(|(region, members)| ProxyGroup {
+            name: format!("{} {}", region.emoji, region.name),
+            group_type: "url-test".to_string(),
+            proxies: members,
+            url: url.clone(),
+            interval,
+            include_all: false,
+            filter: None,
+            exclude_filter: None,
+            disable_udp: spec.disable_udp,
+            hidden: spec.hidden,
+        })
+        .collect();
+
+    let mut proxies: Vec<String> = regional
+        .iter()
+        .map(|group| format!("[]{}", group.name))
+        .collect();
+    proxies.extend(rest);
+    let parent = ProxyGroup {
+        name: spec.name.clone(),
+        group_type: "select".to_string(),
+        proxies,
+        url: None,
+        interval: None,
+        include_all: false,
+        filter: None,
+        exclude_filter: None,
+        disable_udp: spec.disable_udp,
+        hidden: spec.hidden,
+    };
+
+    let mut groups = vec![parent];
+    groups.extend(regional);
+    groups
+}
+
+fn build_order(specs: &[GroupSpec]) -> Result<Vec<&GroupSpec>> {
+    let mut by_name: HashMap<&str, &GroupSpec> = HashMap::new();
+    for spec in specs {
+        by_name.entry(spec.name.as_str()).or_insert(spec);
+    }
+
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut path = Vec::new();
+    for spec in specs {
+        visit_group(spec, &by_name, &mut visited, &mut path, &mut order)?;
+    }
+    Ok(order)
+}
+
+fn visit_group<'a>(
+    spec: &'a GroupSpec,
+    by_name: &HashMap<&str, &'a GroupSpec>,
+    visited: &mut HashSet<&'a str>,
+    path: &mut Vec<&'a str>,
+    order: &mut Vec<&'a GroupSpec>,
+) -> Result<()> {
+    let name = spec.name.as_str();
+    if visited.contains(name) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|entry| *entry == name) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(name);
+        bail!("group reference cycle: {}", cycle.join(" -> "));
+    }
+
+    path.push(name);
+    for rule in &spec.rule {
+        let Some(target) = rule.strip_prefix("[]").map(str::trim) else {
+            continue;
+        };
+        if let Some(dependency) = by_name.get(target) {
+            visit_group(dependency, by_name, visited, path, order)?;
+        }
+    }
+    path.pop();
+
+    visited.insert(name);
+    order.push(spec);
+    Ok(())
+}
+
 fn build_group(
     spec: &GroupSpec,
+    defaults: &GroupDefaults,
     allowed_groups: &HashSet<String>,
     proxy_names: &[String],
     proxy_lookup: &HashSet<String>,
 ) -> Result<ProxyGroup> {
+    check_group_type(spec)?;
     let mut proxies = Vec::new();
     let mut seen = HashSet::new();
 
@@ -99,7 +326,15 @@ fn build_group(
             continue;
         }
 
-        let pattern = Regex::new(rule).with_context(|| {
+        let anchored;
+        let source = match spec.match_mode {
+            MatchMode::Substring => rule.as_str(),
+            MatchMode::Full => {
+                anchored = format!("^(?:{rule})$");
+                anchored.as_str()
+            }
+        };
+        let pattern = Regex::new(source).with_context(|| {
             format!(
                 "failed to compile regex `{}` for group `{}`",
                 rule, spec.name
@@ -127,12 +362,105 @@ fn build_group(
         }
     }
 
+    // An `auto-region` group without rules spreads every node over regions.
+    if spec.include_all || (spec.group_type == AUTO_REGION && spec.rule.is_empty()) {
+        push_all_unique(&mut proxies, &mut seen, proxy_names);
+    }
+
+    let filter = compile_group_filter(spec, "filter", spec.filter.as_deref())?;
+    let exclude_filter =
+        compile_group_filter(spec, "exclude-filter", spec.exclude_filter.as_deref())?;
+    if filter.is_some() || exclude_filter.is_some() {
+        // Filters only narrow proxy nodes; group references are always kept.
+        let mut kept = Vec::with_capacity(proxies.len());
+        for name in proxies {
+            if name.starts_with("[]")
+                || (matches_filter(spec, filter.as_ref(), &name, true)?
+                    && !matches_filter(spec, exclude_filter.as_ref(), &name, false)?)
+            {
+                kept.push(name);
+            }
+        }
+        proxies = kept;
+    }
+
+    let tested = TESTED_GROUP_TYPES.contains(&spec.group_type.as_str());
+    let url = spec
+        .url
+        .clone()
+        .or_else(|| defaults.test_url.clone().filter(|_| tested));
+    let interval = spec.interval.or(defaults.test_interval.filter(|_| tested));
+
     Ok(ProxyGroup {
         name: spec.name.clone(),
         group_type: spec.group_type.clone(),
         proxies,
-        url: spec.url.clone(),
-        interval: spec.interval,
+        url,
+        interval,
+        include_all: spec.include_all,
+        filter: spec.filter.clone(),
+        exclude_filter: spec.exclude_filter.clone(),
+        disable_udp: spec.disable_udp,
+        hidden: spec.hidden,
+    })
+}
+
+fn check_group_type(spec: &GroupSpec) -> Result<()> {
+    let group_type = spec.group_type.as_str();
+    if GROUP_TYPES.contains(&group_type) {
+        return Ok(());
+    }
+    // Catches `url_test`, `URL-Test` and `Load Balance`.
+    let normalized = group_type
+        .trim()
+        .to_ascii_lowercase()
+        .replace(['_', ' '], "-");
+    match GROUP_TYPES.iter().find(|known| **known == normalized) {
+        Some(known) => bail!(
+            "group `{}` has unknown type `{group_type}`, did you mean `{known}`?",
+            spec.name
+        ),
+        None => bail!(
+            "group `{}` has unknown type `{group_type}`, expected one of {}",
+            spec.name,
+            GROUP_TYPES.join(", ")
+        ),
+    }
+}
+
+fn compile_group_filter(
+    spec: &GroupSpec,
+    key: &str,
+    pattern: Option<&str>,
+) -> Result<Option<Regex>> {
+    pattern
+        .map(|pattern| {
+            Regex::new(pattern).with_context(|| {
+                format!(
+                    "failed to compile {} `{}` for group `{}`",
+                    key, pattern, spec.name
+                )
+            })
+        })
+        .transpose()
+}
+
+fn matches_filter(
+    spec: &GroupSpec,
+    filter: Option<&Regex>,
+    name: &str,
+    default: bool,
+) -> Result<bool> {
+    let Some(filter) = filter else {
+        return Ok(default);
+    };
+    filter.is_match(name).with_context(|| {
+        format!(
+            "failed to apply filter `{}` in group `{}` against proxy `{}`",
+            filter.as_str(),
+            spec.name,
+            name
+        )
     })
 }
 
@@ -147,3 +475,215 @@ fn push_all_unique(out: &mut Vec<String>, seen: &mut HashSet<String>, values: &[
         push_unique(out, seen, value);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str, rule: &[&str]) -> GroupSpec {
+        GroupSpec {
+            name: name.to_string(),
+            group_type: "select".to_string(),
+            rule: rule.iter().map(|r| r.to_string()).collect(),
+            url: None,
+            interval: None,
+            include_all: false,
+            filter: None,
+            exclude_filter: None,
+            disable_udp: None,
+            hidden: None,
+            match_mode: MatchMode::Substring,
+        }
+    }
+
+    fn proxy(name: &str) -> Proxy {
+        Proxy {
+            name: name.to_string(),
+            protocol: "http".to_string(),
+            values: Default::default(),
+        }
+    }
+
+    #[test]
+    fn retained_groups_keep_their_references() {
+        let specs = vec![
+            spec("Proxy", &["[]Auto", "node"]),
+            spec("Auto", &["node"]),
+            spec("Streaming", &["[]Proxy"]),
+            spec("Other", &["node"]),
+        ];
+        let mut groups = build_groups(&specs, &[proxy("node")], &Default::default()).unwrap();
+
+        retain_groups(&mut groups, &["Proxy".to_string()]).unwrap();
+        let names: Vec<&str> = groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, ["Proxy", "Auto"]);
+
+        let err = retain_groups(&mut groups, &["Missing".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Missing"), "{err}");
+    }
+
+    #[test]
+    fn chained_references_keep_file_order() {
+        let specs = vec![
+            spec("A", &["[]B"]),
+            spec("B", &["[]C"]),
+            spec("C", &["node"]),
+        ];
+        let order: Vec<&str> = build_order(&specs)
+            .unwrap()
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(order, ["C", "B", "A"]);
+
+        let groups = build_groups(&specs, &[proxy("node")], &GroupDefaults::default()).unwrap();
+        let names: Vec<&str> = groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, ["A", "B", "C"]);
+        assert_eq!(groups[0].proxies, ["[]B"]);
+        assert_eq!(groups[2].proxies, ["node"]);
+    }
+
+    #[test]
+    fn include_all_applies_filters_to_nodes_only() {
+        let mut group = spec("HK", &["[]DIRECT"]);
+        group.include_all = true;
+        group.filter = Some("HK".to_string());
+        group.exclude_filter = Some("IPLC".to_string());
+        let proxies = [proxy("HK 01"), proxy("HK IPLC"), proxy("JP 01")];
+
+        let groups = build_groups(&[group], &proxies, &GroupDefaults::default()).unwrap();
+        assert_eq!(groups[0].proxies, ["[]DIRECT", "HK 01"]);
+    }
+
+    #[test]
+    fn reference_cycle_is_reported() {
+        let specs = vec![spec("A", &["[]B"]), spec("B", &["[]A"])];
+        let err = build_groups(&specs, &[], &GroupDefaults::default()).unwrap_err();
+        assert!(err.to_string().contains("A -> B -> A"), "{err}");
+    }
+
+    #[test]
+    fn unknown_group_types_are_rejected_with_a_suggestion() {
+        let typed = |group_type: &str| GroupSpec {
+            group_type: group_type.to_string(),
+            ..spec("Auto", &["node"])
+        };
+        let build = |spec: GroupSpec| build_groups(&[spec], &[proxy("node")], &defaults());
+
+        let groups = build(typed("load-balance")).unwrap();
+        assert_eq!(groups[0].group_type, "load-balance");
+
+        let err = build(typed("url_test")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "group `Auto` has unknown type `url_test`, did you mean `url-test`?"
+        );
+        let err = build(typed("smart")).unwrap_err();
+        assert!(
+            err.to_string().contains("expected one of select, url-test"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn full_match_mode_anchors_rule_patterns() {
+        let proxies = [proxy("HK 01"), proxy("SHK 01"), proxy("HK 02 IPLC")];
+        let names = |match_mode: MatchMode| {
+            let spec = GroupSpec {
+                match_mode,
+                ..spec("G", &["HK \\d+"])
+            };
+            build_groups(&[spec], &proxies, &defaults()).unwrap()[0]
+                .proxies
+                .clone()
+        };
+
+        // Substring matching is the default.
+        assert_eq!(
+            names(MatchMode::default()),
+            ["HK 01", "SHK 01", "HK 02 IPLC"]
+        );
+        assert_eq!(names(MatchMode::Full), ["HK 01"]);
+
+        let spec: GroupSpec =
+            toml::from_str("name = \"G\"\ntype = \"select\"\nmatch-mode = \"full\"\n").unwrap();
+        assert_eq!(spec.match_mode, MatchMode::Full);
+    }
+
+    fn url_test(name: &str) -> GroupSpec {
+        GroupSpec {
+            group_type: "url-test".to_string(),
+            ..spec(name, &["node"])
+        }
+    }
+
+    fn defaults() -> GroupDefaults {
+        GroupDefaults {
+            test_url: Some("http://www.gstatic.com/generate_204".to_string()),
+            test_interval: Some(300),
+        }
+    }
+
+    #[test]
+    fn tested_groups_fall_back_to_pref_defaults() {
+        let specs = vec![url_test("Auto"), spec("Select", &["node"])];
+        let groups = build_groups(&specs, &[proxy("node")], &defaults()).unwrap();
+
+        assert_eq!(
+            groups[0].url.as_deref(),
+            Some("http://www.gstatic.com/generate_204")
+        );
+        assert_eq!(groups[0].interval, Some(300));
+        // `select` groups do not health-check, so they stay untouched.
+        assert_eq!(groups[1].url, None);
+        assert_eq!(groups[1].interval, None);
+    }
+
+    #[test]
+    fn group_values_override_pref_defaults() {
+        let mut group = url_test("Auto");
+        group.url = Some("http://cp.cloudflare.com".to_string());
+        group.interval = Some(60);
+
+        let groups = build_groups(&[group], &[proxy("node")], &defaults()).unwrap();
+        assert_eq!(groups[0].url.as_deref(), Some("http://cp.cloudflare.com"));
+        assert_eq!(groups[0].interval, Some(60));
+    }
+
+    #[test]
+    fn auto_region_builds_a_group_per_region() {
+        let specs = vec![GroupSpec {
+            group_type: AUTO_REGION.to_string(),
+            ..spec("Regions", &[])
+        }];
+        let proxies = [
+            proxy("HK 01"),
+            proxy("US Seattle"),
+            proxy("香港 02"),
+            proxy("JP 01"),
+            proxy("Traffic: 10GB"),
+        ];
+
+        let groups = build_groups(&specs, &proxies, &defaults()).unwrap();
+        let names: Vec<&str> = groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["Regions", "🇭🇰 Hong Kong", "🇺🇸 United States", "🇯🇵 Japan"]
+        );
+        assert_eq!(groups[0].group_type, "select");
+        assert_eq!(
+            groups[0].proxies,
+            [
+                "[]🇭🇰 Hong Kong",
+                "[]🇺🇸 United States",
+                "[]🇯🇵 Japan",
+                "Traffic: 10GB"
+            ]
+        );
+        assert_eq!(groups[1].group_type, "url-test");
+        assert_eq!(groups[1].proxies, ["HK 01", "香港 02"]);
+        assert_eq!(groups[2].proxies, ["US Seattle"]);
+        assert_eq!(groups[3].proxies, ["JP 01"]);
+        assert_eq!(groups[3].interval, Some(300));
+    }
+}
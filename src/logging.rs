@@ -4,50 +4,152 @@ use std::{
     sync::{Arc, Mutex, OnceLock},
 };
 
+use fancy_regex::Regex;
+use tokio::sync::broadcast;
+use tracing::Level;
 use tracing_subscriber::{EnvFilter, fmt, fmt::MakeWriter};
 
 const MAX_LOG_LINES: usize = 2000;
+const LOG_STREAM_CAPACITY: usize = 256;
 
-pub type LogBuffer = Arc<Mutex<VecDeque<String>>>;
+/// Keys whose values are masked in logs and error responses.
+const SENSITIVE_KEYS: &str = "password|uuid|private-key|api_access_token";
+const REDACTED: &str = "***";
+
+pub type LogBuffer = Arc<Mutex<VecDeque<LogEntry>>>;
+
+/// A captured log line, numbered from 1 in the order lines were written.
+#[derive(Clone)]
+pub struct LogEntry {
+    pub line: u64,
+    /// Parsed from the formatted line; continuation lines have none.
+    pub level: Option<Level>,
+    pub text: String,
+}
+
+/// Narrows [`get_logs`]. `level` keeps that level and anything more severe.
+#[derive(Default)]
+pub struct LogFilter {
+    pub limit: Option<usize>,
+    pub level: Option<Level>,
+    pub contains: Option<String>,
+    /// Only lines numbered above this, for polling what is new.
+    pub since_line: Option<u64>,
+}
 
 static LOG_BUFFER: OnceLock<LogBuffer> = OnceLock::new();
+static LOG_STREAM: OnceLock<broadcast::Sender<String>> = OnceLock::new();
 
-pub fn init_logging() {
+/// Writes logs to stdout, or to stderr when `to_stderr` is set so they stay
+/// out of output a subcommand prints on stdout.
+pub fn init_logging(to_stderr: bool) {
     let buffer = LOG_BUFFER
         .get_or_init(|| Arc::new(Mutex::new(VecDeque::with_capacity(MAX_LOG_LINES))))
         .clone();
 
-    let make_writer = LogMakeWriter { buffer };
+    let make_writer = LogMakeWriter { buffer, to_stderr };
     fmt()
         .with_env_filter(EnvFilter::from_default_env().add_directive("info".parse().unwrap()))
         .with_writer(make_writer)
         .init();
 }
 
-pub fn get_logs(limit: Option<usize>) -> Vec<String> {
-    let limit = limit.unwrap_or(200).min(MAX_LOG_LINES);
+/// Masks values of sensitive keys in `key: value`, `key=value` and JSON forms.
+pub fn redact_secrets(text: &str) -> String {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(&format!(
+            r#"(?i)(\b(?:{SENSITIVE_KEYS})\\?["']?\s*[:=]\s*)(?:(\\?")[^"\\]*\\?"|(')[^']*'|[^\s,;{{}}\[\]()"']+)"#
+        ))
+        .expect("redaction pattern must compile")
+    });
+    pattern
+        .replace_all(text, |caps: &fancy_regex::Captures| {
+            // Quoted values keep their quotes so JSON stays well-formed.
+            let quote = caps.get(2).or(caps.get(3)).map_or("", |m| m.as_str());
+            format!("{}{quote}{REDACTED}{quote}", &caps[1])
+        })
+        .into_owned()
+}
+
+/// Subscribe to log lines appended after this call.
+pub fn subscribe_logs() -> broadcast::Receiver<String> {
+    log_stream().subscribe()
+}
+
+fn log_stream() -> &'static broadcast::Sender<String> {
+    LOG_STREAM.get_or_init(|| broadcast::channel(LOG_STREAM_CAPACITY).0)
+}
+
+/// The newest `filter.limit` matching lines, oldest first, and the number of
+/// the last line captured so far.
+pub fn get_logs(filter: &LogFilter) -> (Vec<LogEntry>, u64) {
     let Some(buffer) = LOG_BUFFER.get() else {
-        return Vec::new();
+        return (Vec::new(), 0);
     };
-    let guard = buffer.lock().unwrap();
-    let total = guard.len();
-    let start = total.saturating_sub(limit);
-    guard.iter().skip(start).cloned().collect()
+    filter_logs(&buffer.lock().unwrap(), filter)
+}
+
+fn filter_logs(entries: &VecDeque<LogEntry>, filter: &LogFilter) -> (Vec<LogEntry>, u64) {
+    let limit = filter.limit.unwrap_or(200).min(MAX_LOG_LINES);
+    let mut matched: Vec<LogEntry> = entries
+        .iter()
+        .rev()
+        .take_while(|entry| filter.since_line.is_none_or(|since| entry.line > since))
+        .filter(|entry| {
+            filter
+                .level
+                .is_none_or(|level| entry.level.is_some_and(|own| own <= level))
+        })
+        .filter(|entry| {
+            filter
+                .contains
+                .as_deref()
+                .is_none_or(|needle| entry.text.contains(needle))
+        })
+        .take(limit)
+        .cloned()
+        .collect();
+    matched.reverse();
+    (matched, entries.back().map_or(0, |entry| entry.line))
+}
+
+fn push_entry(entries: &mut VecDeque<LogEntry>, text: String) {
+    if entries.len() >= MAX_LOG_LINES {
+        entries.pop_front();
+    }
+    let line = entries.back().map_or(1, |entry| entry.line + 1);
+    entries.push_back(LogEntry {
+        line,
+        level: parse_level(&text),
+        text,
+    });
+}
+
+/// The level written by the default `fmt` layout, `<timestamp> <LEVEL> ...`.
+fn parse_level(text: &str) -> Option<Level> {
+    text.split_whitespace().nth(1)?.parse().ok()
 }
 
 #[derive(Clone)]
 struct LogMakeWriter {
     buffer: LogBuffer,
+    to_stderr: bool,
 }
 
 impl<'a> MakeWriter<'a> for LogMakeWriter {
     type Writer = LogWriter;
 
     fn make_writer(&'a self) -> Self::Writer {
+        let out: Box<dyn Write> = if self.to_stderr {
+            Box::new(io::stderr())
+        } else {
+            Box::new(io::stdout())
+        };
         LogWriter {
             buffer: self.buffer.clone(),
             line: Vec::new(),
-            stdout: io::stdout(),
+            out,
         }
     }
 }
@@ -55,7 +157,7 @@ impl<'a> MakeWriter<'a> for LogMakeWriter {
 struct LogWriter {
     buffer: LogBuffer,
     line: Vec<u8>,
-    stdout: io::Stdout,
+    out: Box<dyn Write>,
 }
 
 impl LogWriter {
@@ -63,12 +165,10 @@ impl LogWriter {
         if line.is_empty() {
             return;
         }
-        let mut guard = self.buffer.lock().unwrap();
-        if guard.len() >= MAX_LOG_LINES {
-            guard.pop_front();
-        }
-        let cleaned = strip_ansi(line);
-        guard.push_back(cleaned.trim_end().to_string());
+        let cleaned = redact_secrets(&strip_ansi(line)).trim_end().to_string();
+        push_entry(&mut self.buffer.lock().unwrap(), cleaned.clone());
+        // Sending only fails when nobody is subscribed.
+        let _ = log_stream().send(cleaned);
     }
 
     fn capture(&mut self, buf: &[u8]) {
@@ -85,13 +185,14 @@ impl LogWriter {
 
 impl Write for LogWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.stdout.write_all(buf)?;
+        let redacted = redact_secrets(&String::from_utf8_lossy(buf));
+        self.out.write_all(redacted.as_bytes())?;
         self.capture(buf);
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.stdout.flush()?;
+        self.out.flush()?;
         if !self.line.is_empty() {
             self.push_line(&self.line);
             self.line.clear();
@@ -131,3 +232,89 @@ fn strip_ansi(input: &[u8]) -> String {
     }
     String::from_utf8_lossy(&output).to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_common_formats() {
+        assert_eq!(
+            redact_secrets("password: hunter2, server: example.com"),
+            "password: ***, server: example.com"
+        );
+        assert_eq!(
+            redact_secrets(r#"{"uuid":"d3b1a1f2","port":443}"#),
+            r#"{"uuid":"***","port":443}"#
+        );
+        assert_eq!(
+            redact_secrets(r#"password='a b', uuid=\"d3b1\""#),
+            r#"password='***', uuid=\"***\""#
+        );
+        assert_eq!(
+            redact_secrets("wg = wireguard, private-key=abc+/=, mtu=1280"),
+            "wg = wireguard, private-key=***, mtu=1280"
+        );
+        assert_eq!(redact_secrets("server: example.com"), "server: example.com");
+    }
+
+    #[test]
+    fn logs_filter_by_level_text_and_line() {
+        let mut entries = VecDeque::new();
+        for text in [
+            "2026-01-01T00:00:00.000000Z  INFO subcon::server: listening on 127.0.0.1:25500",
+            "2026-01-01T00:00:01.000000Z  WARN subcon::server::api: failed to build proxy groups",
+            "    continuation of the warning",
+            "2026-01-01T00:00:02.000000Z ERROR subcon::network: fetch failed url=https://a.example.com",
+            "2026-01-01T00:00:03.000000Z DEBUG subcon::network: cache hit url=https://a.example.com",
+        ] {
+            push_entry(&mut entries, text.to_string());
+        }
+        let lines = |filter: LogFilter| -> Vec<u64> {
+            let (matched, last) = filter_logs(&entries, &filter);
+            assert_eq!(last, 5);
+            matched.iter().map(|entry| entry.line).collect()
+        };
+
+        assert_eq!(lines(LogFilter::default()), [1, 2, 3, 4, 5]);
+        let level = |name: &str| Some(name.parse().unwrap());
+        assert_eq!(
+            lines(LogFilter {
+                level: level("warn"),
+                ..LogFilter::default()
+            }),
+            [2, 4]
+        );
+        assert_eq!(
+            lines(LogFilter {
+                level: level("error"),
+                ..LogFilter::default()
+            }),
+            [4]
+        );
+
+        let contains = Some("a.example.com".to_string());
+        assert_eq!(
+            lines(LogFilter {
+                contains: contains.clone(),
+                ..LogFilter::default()
+            }),
+            [4, 5]
+        );
+        assert_eq!(
+            lines(LogFilter {
+                contains,
+                limit: Some(1),
+                ..LogFilter::default()
+            }),
+            [5]
+        );
+        assert_eq!(
+            lines(LogFilter {
+                since_line: Some(3),
+                ..LogFilter::default()
+            }),
+            [4, 5]
+        );
+    }
+}
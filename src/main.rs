@@ -9,10 +9,10 @@ mod network;
 mod server;
 mod paths;
 mod logging;
+mod metrics;
 
 use anyhow::Result;
 #[tokio::main]
 async fn main() -> Result<()> {
-    logging::init_logging();
     server::run().await
 }
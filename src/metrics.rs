@@ -0,0 +1,172 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Upper bounds, in seconds, of the render duration histogram buckets.
+const RENDER_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters served at `/metrics`. The server keeps one set in its state and
+/// hands it to every network layer a reload builds, so counts survive
+/// reloads.
+#[derive(Default)]
+pub struct Metrics {
+    sub_requests: Mutex<BTreeMap<String, u64>>,
+    /// Renders per bucket, not cumulative; the last slot is `+Inf`.
+    render_buckets: [AtomicU64; RENDER_BUCKETS.len() + 1],
+    render_micros: AtomicU64,
+    fetch_success: AtomicU64,
+    fetch_failure: AtomicU64,
+    cache_hit: AtomicU64,
+    cache_miss: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a `/sub` request for a known target.
+    pub fn record_sub_request(&self, target: &str) {
+        *self
+            .sub_requests
+            .lock()
+            .unwrap()
+            .entry(target.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_render(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = RENDER_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(RENDER_BUCKETS.len());
+        self.render_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.render_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_fetch(&self, success: bool) {
+        let counter = if success {
+            &self.fetch_success
+        } else {
+            &self.fetch_failure
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hit
+        } else {
+            &self.cache_miss
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        out.push_str("# HELP subcon_sub_requests_total /sub requests by target.\n");
+        out.push_str("# TYPE subcon_sub_requests_total counter\n");
+        for (target, count) in self.sub_requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "subcon_sub_requests_total{{target=\"{target}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP subcon_render_duration_seconds Time spent rendering /sub bodies.\n");
+        out.push_str("# TYPE subcon_render_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (index, bucket) in self.render_buckets.iter().enumerate() {
+            cumulative += load(bucket);
+            let bound = RENDER_BUCKETS
+                .get(index)
+                .map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(
+                out,
+                "subcon_render_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let sum = load(&self.render_micros) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "subcon_render_duration_seconds_sum {sum}");
+        let _ = writeln!(out, "subcon_render_duration_seconds_count {cumulative}");
+
+        out.push_str(
+            "# HELP subcon_upstream_fetches_total Upstream subscription fetches by result.\n",
+        );
+        out.push_str("# TYPE subcon_upstream_fetches_total counter\n");
+        let _ = writeln!(
+            out,
+            "subcon_upstream_fetches_total{{result=\"success\"}} {}",
+            load(&self.fetch_success)
+        );
+        let _ = writeln!(
+            out,
+            "subcon_upstream_fetches_total{{result=\"failure\"}} {}",
+            load(&self.fetch_failure)
+        );
+
+        out.push_str("# HELP subcon_cache_lookups_total Fetch cache lookups by result.\n");
+        out.push_str("# TYPE subcon_cache_lookups_total counter\n");
+        let _ = writeln!(
+            out,
+            "subcon_cache_lookups_total{{result=\"hit\"}} {}",
+            load(&self.cache_hit)
+        );
+        let _ = writeln!(
+            out,
+            "subcon_cache_lookups_total{{result=\"miss\"}} {}",
+            load(&self.cache_miss)
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_histogram_and_counters() {
+        let metrics = Metrics::default();
+        metrics.record_sub_request("clash");
+        metrics.record_sub_request("clash");
+        metrics.record_sub_request("surge");
+        metrics.record_render(Duration::from_millis(3));
+        metrics.record_render(Duration::from_millis(40));
+        metrics.record_render(Duration::from_secs(30));
+        metrics.record_fetch(true);
+        metrics.record_fetch(false);
+        metrics.record_cache_lookup(true);
+
+        let text = metrics.render();
+        for line in [
+            "subcon_sub_requests_total{target=\"clash\"} 2",
+            "subcon_sub_requests_total{target=\"surge\"} 1",
+            "subcon_render_duration_seconds_bucket{le=\"0.005\"} 1",
+            "subcon_render_duration_seconds_bucket{le=\"0.025\"} 1",
+            "subcon_render_duration_seconds_bucket{le=\"0.05\"} 2",
+            "subcon_render_duration_seconds_bucket{le=\"10\"} 2",
+            "subcon_render_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "subcon_render_duration_seconds_sum 30.043",
+            "subcon_render_duration_seconds_count 3",
+            "subcon_upstream_fetches_total{result=\"success\"} 1",
+            "subcon_upstream_fetches_total{result=\"failure\"} 1",
+            "subcon_cache_lookups_total{result=\"hit\"} 1",
+            "subcon_cache_lookups_total{result=\"miss\"} 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing `{line}` in\n{text}"
+            );
+        }
+    }
+}
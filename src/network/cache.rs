@@ -10,27 +10,44 @@ use sha2::{Digest, Sha256};
 use tokio::sync::Mutex;
 use tracing::info;
 
+use super::FetchMeta;
 use crate::config::NetworkConfig;
 use crate::paths::resolve_path;
 
+/// Entries are keyed by URL and the User-Agent that fetched them, since
+/// providers serve a different format to each client.
+type CacheKey = (String, String);
+
 #[derive(Clone)]
 pub struct CacheStore {
     dir: PathBuf,
     ttl: Duration,
-    entries: Arc<Mutex<HashMap<String, CacheEntry>>>,
+    entries: Arc<Mutex<HashMap<CacheKey, CacheEntry>>>,
 }
 
 #[derive(Clone)]
 pub struct CacheSnapshot {
     pub url: String,
+    pub user_agent: String,
     pub ttl_seconds: u64,
 }
 
+/// A cached URL that is about to expire, with what is needed to refetch it.
+pub struct RevalidationTarget {
+    pub url: String,
+    pub user_agent: String,
+    pub etag: Option<String>,
+    pub ttl: Duration,
+}
+
 #[derive(Clone)]
 struct CacheEntry {
+    fetched_at: SystemTime,
     expires_at: SystemTime,
+    ttl: Duration,
     sha256: String,
     path: PathBuf,
+    meta: FetchMeta,
 }
 
 impl CacheStore {
@@ -50,8 +67,26 @@ impl CacheStore {
         })
     }
 
-    pub async fn read(&self, url: &str) -> Result<Option<String>> {
-        let entry = match self.get_valid_entry(url).await {
+    /// The cached body of `url` fetched with one of `user_agents`, trying them
+    /// in order. Entries fetched with any other User-Agent are not served.
+    pub async fn read(
+        &self,
+        url: &str,
+        user_agents: &[&str],
+    ) -> Result<Option<(String, FetchMeta)>> {
+        for user_agent in user_agents {
+            if let Some(hit) = self
+                .read_entry(&(url.to_string(), user_agent.to_string()))
+                .await?
+            {
+                return Ok(Some(hit));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn read_entry(&self, key: &CacheKey) -> Result<Option<(String, FetchMeta)>> {
+        let entry = match self.get_valid_entry(key).await {
             Some(entry) => entry,
             None => return Ok(None),
         };
@@ -59,21 +94,21 @@ impl CacheStore {
         let bytes = match tokio::fs::read(&entry.path).await {
             Ok(bytes) => bytes,
             Err(_) => {
-                self.evict(url).await;
+                self.evict(key).await;
                 return Ok(None);
             }
         };
 
         let hash = sha256_hex(&bytes);
         if hash != entry.sha256 {
-            self.evict(url).await;
+            self.evict(key).await;
             return Ok(None);
         }
 
         let text = match String::from_utf8(bytes) {
             Ok(text) => text,
             Err(_) => {
-                self.evict(url).await;
+                self.evict(key).await;
                 return Ok(None);
             }
         };
@@ -83,15 +118,31 @@ impl CacheStore {
             .duration_since(SystemTime::now())
             .unwrap_or_default()
             .as_secs();
-        info!(url, ttl_seconds = ttl_secs, "cache hit");
+        info!(url = %key.0, user_agent = %key.1, ttl_seconds = ttl_secs, "cache hit");
 
-        Ok(Some(text))
+        let mut meta = entry.meta;
+        meta.age = Some(
+            SystemTime::now()
+                .duration_since(entry.fetched_at)
+                .unwrap_or_default(),
+        );
+        Ok(Some((text, meta)))
     }
 
-    pub async fn store(&self, url: &reqwest::Url, text: &str) -> Result<()> {
+    /// Stores `text` for `url` as fetched with `user_agent`, expiring after
+    /// `ttl_override` seconds when set and after the configured network TTL
+    /// otherwise. `user_agent` is reused when the entry is revalidated.
+    pub async fn store(
+        &self,
+        url: &reqwest::Url,
+        text: &str,
+        meta: &FetchMeta,
+        user_agent: &str,
+        ttl_override: Option<u64>,
+    ) -> Result<()> {
         let bytes = text.as_bytes();
         let content_hash = sha256_hex(bytes);
-        let path = self.cache_path_for_url(url);
+        let path = self.cache_path_for_url(url, user_agent);
         let tmp_path = path.with_extension("tmp");
 
         tokio::fs::write(&tmp_path, bytes)
@@ -101,24 +152,62 @@ impl CacheStore {
             .await
             .with_context(|| format!("failed to finalize cache file {}", path.display()))?;
 
+        let ttl = ttl_override.map(Duration::from_secs).unwrap_or(self.ttl);
+        let now = SystemTime::now();
         let entry = CacheEntry {
-            expires_at: SystemTime::now() + self.ttl,
+            fetched_at: now,
+            expires_at: now + ttl,
+            ttl,
             sha256: content_hash,
             path,
+            meta: meta.clone(),
         };
 
         let mut entries = self.entries.lock().await;
-        entries.insert(url.as_str().to_string(), entry);
+        entries.insert((url.as_str().to_string(), user_agent.to_string()), entry);
         Ok(())
     }
 
-    async fn get_valid_entry(&self, url: &str) -> Option<CacheEntry> {
+    /// Entries that are still valid but expire within `window`.
+    pub async fn due_for_revalidation(&self, window: Duration) -> Vec<RevalidationTarget> {
+        let now = SystemTime::now();
+        let entries = self.entries.lock().await;
+        entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_at > now && entry.expires_at <= now + window)
+            .map(|((url, user_agent), entry)| RevalidationTarget {
+                url: url.clone(),
+                user_agent: user_agent.clone(),
+                etag: entry.meta.etag.clone(),
+                ttl: entry.ttl,
+            })
+            .collect()
+    }
+
+    /// Restarts the TTL of an entry the upstream reported as unchanged.
+    pub async fn renew(&self, url: &str, user_agent: &str) {
+        let now = SystemTime::now();
+        let mut entries = self.entries.lock().await;
+        if let Some(entry) = entries.get_mut(&(url.to_string(), user_agent.to_string())) {
+            entry.fetched_at = now;
+            entry.expires_at = now + entry.ttl;
+        }
+    }
+
+    /// Drops the entries of `url` fetched with any of `user_agents`.
+    pub async fn remove(&self, url: &str, user_agents: &[&str]) {
+        for user_agent in user_agents {
+            self.evict(&(url.to_string(), user_agent.to_string())).await;
+        }
+    }
+
+    async fn get_valid_entry(&self, key: &CacheKey) -> Option<CacheEntry> {
         let (entry, expired) = {
             let mut entries = self.entries.lock().await;
-            match entries.get(url).cloned() {
+            match entries.get(key).cloned() {
                 Some(entry) if entry.expires_at > SystemTime::now() => (Some(entry), None),
                 Some(entry) => {
-                    entries.remove(url);
+                    entries.remove(key);
                     (None, Some(entry))
                 }
                 None => (None, None),
@@ -132,18 +221,19 @@ impl CacheStore {
         entry
     }
 
-    async fn evict(&self, url: &str) {
+    async fn evict(&self, key: &CacheKey) {
         let entry = {
             let mut entries = self.entries.lock().await;
-            entries.remove(url)
+            entries.remove(key)
         };
         if let Some(entry) = entry {
             let _ = tokio::fs::remove_file(&entry.path).await;
         }
     }
 
-    fn cache_path_for_url(&self, url: &reqwest::Url) -> PathBuf {
-        let key = sha256_hex(url.as_str().as_bytes());
+    fn cache_path_for_url(&self, url: &reqwest::Url, user_agent: &str) -> PathBuf {
+        // Neither part can contain a newline, so distinct pairs never collide.
+        let key = sha256_hex(format!("{user_agent}\n{url}").as_bytes());
         self.dir.join(format!("{key}.cache"))
     }
 
@@ -152,8 +242,9 @@ impl CacheStore {
         let entries = self.entries.lock().await;
         entries
             .iter()
-            .map(|(url, entry)| CacheSnapshot {
+            .map(|((url, user_agent), entry)| CacheSnapshot {
                 url: url.clone(),
+                user_agent: user_agent.clone(),
                 ttl_seconds: entry
                     .expires_at
                     .duration_since(now)
@@ -168,3 +259,96 @@ fn sha256_hex(data: &[u8]) -> String {
     let digest = Sha256::digest(data);
     format!("{digest:x}")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> CacheStore {
+        let dir = std::env::temp_dir().join(format!("subcon-cache-{name}-{}", std::process::id()));
+        let config = NetworkConfig {
+            dir: dir.display().to_string(),
+            ttl_seconds: 3600,
+            ..NetworkConfig::default()
+        };
+        CacheStore::new(&config, Path::new(".")).unwrap()
+    }
+
+    async fn stored_ttl(cache: &CacheStore, url: &reqwest::Url) -> Duration {
+        let entries = cache.entries.lock().await;
+        let entry = &entries[&(url.to_string(), "test".to_string())];
+        entry.expires_at.duration_since(entry.fetched_at).unwrap()
+    }
+
+    #[tokio::test]
+    async fn store_honors_ttl_override() {
+        let cache = store("ttl");
+        let default_url = reqwest::Url::parse("https://example.com/sub").unwrap();
+        let ruleset_url = reqwest::Url::parse("https://example.com/geoip.list").unwrap();
+
+        cache
+            .store(
+                &default_url,
+                "proxies: []",
+                &FetchMeta::default(),
+                "test",
+                None,
+            )
+            .await
+            .unwrap();
+        cache
+            .store(
+                &ruleset_url,
+                "GEOIP,CN",
+                &FetchMeta::default(),
+                "test",
+                Some(604_800),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stored_ttl(&cache, &default_url).await,
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            stored_ttl(&cache, &ruleset_url).await,
+            Duration::from_secs(604_800)
+        );
+    }
+
+    #[tokio::test]
+    async fn entries_are_only_served_to_the_user_agent_that_fetched_them() {
+        let cache = store("user-agent");
+        let url = reqwest::Url::parse("https://example.com/sub").unwrap();
+        cache
+            .store(&url, "proxies: []", &FetchMeta::default(), "Clash/v1", None)
+            .await
+            .unwrap();
+        cache
+            .store(&url, "[Proxy]", &FetchMeta::default(), "Surge/5", None)
+            .await
+            .unwrap();
+
+        assert!(
+            cache
+                .read(url.as_str(), &["Quantumult"])
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let (text, _) = cache
+            .read(url.as_str(), &["Clash/v1"])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(text, "proxies: []");
+        // The first user agent with an entry wins.
+        let (text, _) = cache
+            .read(url.as_str(), &["Quantumult", "Surge/5", "Clash/v1"])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(text, "[Proxy]");
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::Read;
+
+use anyhow::{Context, Result, bail};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+/// Sent with every fetch so providers may compress their responses.
+pub const ACCEPTED_ENCODINGS: &str = "gzip, deflate, br";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Undoes `Content-Encoding` on a response body and decodes it as UTF-8.
+/// A gzip body without the header is recognized by its magic bytes, since
+/// some providers serve pre-compressed files as plain text. Decompressing
+/// stops with an error once the output passes `limit` bytes.
+pub fn decode_body(content_encoding: Option<&str>, body: &[u8], limit: u64) -> Result<String> {
+    let mut bytes = body.to_vec();
+    // Encodings are listed in the order they were applied.
+    let encodings = content_encoding.unwrap_or_default().split(',').rev();
+    for encoding in encodings.map(str::trim).filter(|e| !e.is_empty()) {
+        bytes = match encoding.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => read_all(GzDecoder::new(bytes.as_slice()), "gzip", limit)?,
+            "deflate" => inflate(&bytes, limit)?,
+            "br" => read_all(
+                brotli::Decompressor::new(bytes.as_slice(), 4096),
+                "brotli",
+                limit,
+            )?,
+            "identity" => bytes,
+            other => bail!("unsupported content encoding `{other}`"),
+        };
+    }
+    if bytes.starts_with(&GZIP_MAGIC) {
+        bytes = read_all(GzDecoder::new(bytes.as_slice()), "gzip", limit)?;
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// HTTP `deflate` is meant to be zlib-wrapped, but some servers send a raw
+/// deflate stream instead. When both fail, the zlib error is reported.
+fn inflate(bytes: &[u8], limit: u64) -> Result<Vec<u8>> {
+    read_all(ZlibDecoder::new(bytes), "deflate", limit).or_else(|zlib_err| {
+        read_all(DeflateDecoder::new(bytes), "deflate", limit).map_err(|_| zlib_err)
+    })
+}
+
+fn read_all(reader: impl Read, encoding: &str, limit: u64) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    reader
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut out)
+        .with_context(|| format!("failed to decompress {encoding} body"))?;
+    if out.len() as u64 > limit {
+        bail!("decompressed {encoding} body exceeds the {limit} byte limit");
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::{
+        Compression,
+        write::{GzEncoder, ZlibEncoder},
+    };
+
+    const LIMIT: u64 = 1024;
+
+    #[test]
+    fn decompression_stops_at_the_limit() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&[b'a'; 64 * 1024]).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert!(gzip.len() < LIMIT as usize);
+
+        let err = decode_body(Some("gzip"), &gzip, LIMIT).unwrap_err();
+        assert!(err.to_string().contains("limit"), "{err}");
+        let err = decode_body(None, &gzip, LIMIT).unwrap_err();
+        assert!(err.to_string().contains("limit"), "{err}");
+    }
+
+    #[test]
+    fn deflate_and_brotli_bodies_are_decoded() {
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(b"proxies: []").unwrap();
+        let zlib = zlib.finish().unwrap();
+        assert_eq!(
+            decode_body(Some("deflate"), &zlib, LIMIT).unwrap(),
+            "proxies: []"
+        );
+
+        let mut br = Vec::new();
+        brotli::CompressorWriter::new(&mut br, 4096, 5, 22)
+            .write_all(b"proxies: []")
+            .unwrap();
+        assert_eq!(decode_body(Some("br"), &br, LIMIT).unwrap(), "proxies: []");
+
+        assert!(decode_body(Some("zstd"), b"proxies: []", LIMIT).is_err());
+    }
+}
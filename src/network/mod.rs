@@ -1,50 +1,132 @@
-use std::{path::Path, time::Duration};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use axum::http::StatusCode;
-use reqwest::header::USER_AGENT;
+use reqwest::header::{
+    ACCEPT_ENCODING, CONTENT_ENCODING, ETAG, HeaderMap, IF_NONE_MATCH, USER_AGENT,
+};
+use tokio::sync::Mutex;
+use tracing::warn;
 
 use crate::config::NetworkConfig;
+use crate::metrics::Metrics;
 
 mod cache;
+mod encoding;
 mod security;
 
-use cache::CacheStore;
 pub use cache::CacheSnapshot;
+use cache::{CacheStore, RevalidationTarget};
 use security::Security;
 
+const SUBSCRIPTION_USERINFO: &str = "subscription-userinfo";
+
+/// Response metadata kept next to a fetched body, including cache hits.
+#[derive(Clone, Debug, Default)]
+pub struct FetchMeta {
+    /// Raw `Subscription-Userinfo` header reported by the upstream provider.
+    pub subscription_userinfo: Option<String>,
+    /// Time since the body was fetched, set only when served from cache.
+    pub age: Option<Duration>,
+    /// `ETag` of the response, sent back as `If-None-Match` on revalidation.
+    pub etag: Option<String>,
+}
+
+impl FetchMeta {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            subscription_userinfo: headers
+                .get(SUBSCRIPTION_USERINFO)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            age: None,
+            etag: headers
+                .get(ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Network {
     client: reqwest::Client,
     cache: CacheStore,
     security: Security,
     cache_enabled: bool,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    negative_ttl: Duration,
+    max_body_bytes: u64,
+    /// When each recently failed URL may be fetched again.
+    failures: Arc<Mutex<HashMap<String, Instant>>>,
+    metrics: Arc<Metrics>,
 }
 
 impl Network {
     pub fn new(config: &NetworkConfig, base_dir: &Path) -> Result<Self> {
         let cache = CacheStore::new(config, base_dir)?;
         let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .connect_timeout(Duration::from_secs(config.connect_timeout_seconds))
             .build()
             .context("failed to build http client")?;
-        let security = Security::new(&config.allowed_domain);
+        let security = Security::new(&config.allowed_domain, config.allow_all);
 
         Ok(Self {
             client,
             cache,
             security,
             cache_enabled: config.enable,
+            max_retries: config.max_retries,
+            retry_base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            negative_ttl: Duration::from_secs(config.negative_ttl_seconds),
+            max_body_bytes: config.max_body_bytes,
+            failures: Arc::default(),
+            metrics: Arc::default(),
         })
     }
 
+    /// Reports fetches and cache lookups to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Fetches `url`, reusing the cache unless `no_cache` is set. Fresh bodies
+    /// are cached for `ttl_override` seconds, or the network TTL when `None`.
     pub async fn get_or_fetch_with<T, F>(
         &self,
         url: &reqwest::Url,
         user_agents: &[&str],
         no_cache: bool,
+        ttl_override: Option<u64>,
         parse: F,
     ) -> NetworkResult<T>
+    where
+        F: Fn(&str) -> Result<T>,
+    {
+        self.get_or_fetch_with_meta(url, user_agents, no_cache, ttl_override, parse)
+            .await
+            .map(|(value, _)| value)
+    }
+
+    /// Like [`Network::get_or_fetch_with`], but also returns the response
+    /// metadata, replayed from the cache entry on cache hits.
+    pub async fn get_or_fetch_with_meta<T, F>(
+        &self,
+        url: &reqwest::Url,
+        user_agents: &[&str],
+        no_cache: bool,
+        ttl_override: Option<u64>,
+        parse: F,
+    ) -> NetworkResult<(T, FetchMeta)>
     where
         F: Fn(&str) -> Result<T>,
     {
@@ -54,12 +136,36 @@ impl Network {
         let should_store = self.cache_enabled;
 
         if use_cache {
-            if let Some(text) = self.cache.read(url.as_str()).await.map_err(NetworkError::internal)?
-            {
-                return parse(&text).map_err(NetworkError::internal);
+            let cached = self
+                .cache
+                .read(url.as_str(), user_agents)
+                .await
+                .map_err(NetworkError::internal)?;
+            self.metrics.record_cache_lookup(cached.is_some());
+            if let Some((text, meta)) = cached {
+                match parse(&text) {
+                    Ok(value) => return Ok((value, meta)),
+                    // Revalidation stores bodies without parsing them, so an
+                    // error page served then is dropped here and refetched.
+                    Err(err) => {
+                        warn!(url = %url, error = %err, "cached body failed to parse; refetching");
+                        self.cache.remove(url.as_str(), user_agents).await;
+                    }
+                }
             }
         }
 
+        if !no_cache && let Some(retry_after) = self.recent_failure(url).await {
+            return Err(NetworkError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!(
+                    "subscription fetch failed recently; retry in {}s",
+                    retry_after.as_secs().max(1)
+                ),
+            )
+            .with_retry_after(retry_after));
+        }
+
         if user_agents.is_empty() {
             return Err(NetworkError::new(
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -70,8 +176,8 @@ impl Network {
         let mut last_error = None;
 
         for ua in user_agents {
-            let text = match self.fetch_text(url, ua).await {
-                Ok(text) => text,
+            let (text, meta) = match self.fetch_with_retry(url, ua).await {
+                Ok(fetched) => fetched,
                 Err(err) => {
                     last_error = Some(format!("request failed with UA {ua}: {err}"));
                     continue;
@@ -80,13 +186,15 @@ impl Network {
 
             match parse(&text) {
                 Ok(value) => {
+                    self.metrics.record_fetch(true);
+                    self.failures.lock().await.remove(url.as_str());
                     if should_store {
                         self.cache
-                            .store(url, &text)
+                            .store(url, &text, &meta, ua, ttl_override)
                             .await
                             .map_err(NetworkError::internal)?;
                     }
-                    return Ok(value);
+                    return Ok((value, meta));
                 }
                 Err(err) => {
                     last_error = Some(format!("failed to parse response with UA {ua}: {err}"));
@@ -94,6 +202,13 @@ impl Network {
             }
         }
 
+        self.metrics.record_fetch(false);
+        if !self.negative_ttl.is_zero() {
+            self.failures
+                .lock()
+                .await
+                .insert(url.to_string(), Instant::now() + self.negative_ttl);
+        }
         Err(NetworkError::new(
             StatusCode::BAD_GATEWAY,
             format!(
@@ -103,27 +218,193 @@ impl Network {
         ))
     }
 
-    async fn fetch_text(&self, url: &reqwest::Url, user_agent: &str) -> Result<String> {
+    /// Time left before `url` may be fetched again after a failed fetch.
+    async fn recent_failure(&self, url: &reqwest::Url) -> Option<Duration> {
+        let mut failures = self.failures.lock().await;
+        let now = Instant::now();
+        failures.retain(|_, until| *until > now);
+        failures
+            .get(url.as_str())
+            .map(|until| until.saturating_duration_since(now))
+    }
+
+    /// Retries timeouts and 5xx responses with jittered exponential backoff.
+    async fn fetch_with_retry(
+        &self,
+        url: &reqwest::Url,
+        user_agent: &str,
+    ) -> Result<(String, FetchMeta)> {
+        let mut attempt = 0;
+        loop {
+            match self.fetch_text(url, user_agent).await {
+                Ok(fetched) => return Ok(fetched),
+                Err(failure) if failure.retryable && attempt < self.max_retries => {
+                    let delay = retry_delay(self.retry_base_delay, attempt, random_unit());
+                    attempt += 1;
+                    warn!(
+                        url = %url,
+                        user_agent,
+                        attempt,
+                        error = %failure.error,
+                        "retrying remote fetch in {}ms",
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(failure) => return Err(failure.error),
+            }
+        }
+    }
+
+    async fn fetch_text(
+        &self,
+        url: &reqwest::Url,
+        user_agent: &str,
+    ) -> std::result::Result<(String, FetchMeta), FetchFailure> {
         let response = self
             .client
             .get(url.clone())
             .header(USER_AGENT, user_agent)
+            .header(ACCEPT_ENCODING, encoding::ACCEPTED_ENCODINGS)
             .send()
             .await
-            .context("request failed")?;
+            .map_err(FetchFailure::request)?;
 
         let status = response.status();
         if !status.is_success() {
-            bail!("status {status}");
+            return Err(FetchFailure {
+                error: anyhow!("status {status}"),
+                retryable: status.is_server_error(),
+            });
         }
 
-        let text = response.text().await.context("failed to read response")?;
-        Ok(text)
+        let meta = FetchMeta::from_headers(response.headers());
+        let text = read_body(response, self.max_body_bytes)
+            .await
+            .map_err(|error| FetchFailure {
+                error,
+                retryable: false,
+            })?;
+        Ok((text, meta))
     }
 
     pub async fn list_cache(&self) -> Vec<CacheSnapshot> {
         self.cache.list_entries().await
     }
+
+    /// Refetches cache entries expiring within `window`, using a conditional
+    /// request when the cached response had an `ETag`. Returns how many
+    /// entries were refreshed.
+    pub async fn revalidate_expiring(&self, window: Duration) -> usize {
+        if !self.cache_enabled {
+            return 0;
+        }
+        let mut refreshed = 0;
+        for target in self.cache.due_for_revalidation(window).await {
+            match self.revalidate(&target).await {
+                Ok(()) => refreshed += 1,
+                Err(err) => {
+                    warn!(url = %target.url, error = %err, "cache revalidation failed");
+                }
+            }
+        }
+        refreshed
+    }
+
+    async fn revalidate(&self, target: &RevalidationTarget) -> Result<()> {
+        let url = reqwest::Url::parse(&target.url).context("invalid cached url")?;
+        let mut request = self
+            .client
+            .get(url.clone())
+            .header(USER_AGENT, &target.user_agent)
+            .header(ACCEPT_ENCODING, encoding::ACCEPTED_ENCODINGS);
+        if let Some(etag) = &target.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await.context("request failed")?;
+
+        let status = response.status();
+        if status == StatusCode::NOT_MODIFIED {
+            self.cache.renew(&target.url, &target.user_agent).await;
+            return Ok(());
+        }
+        if !status.is_success() {
+            return Err(anyhow!("status {status}"));
+        }
+
+        let meta = FetchMeta::from_headers(response.headers());
+        let text = read_body(response, self.max_body_bytes).await?;
+        self.cache
+            .store(
+                &url,
+                &text,
+                &meta,
+                &target.user_agent,
+                Some(target.ttl.as_secs()),
+            )
+            .await
+    }
+}
+
+/// Reads a response body, decompressing it regardless of which encodings
+/// reqwest was built to handle. The body is read chunk by chunk and dropped
+/// as soon as it grows past `limit` bytes.
+async fn read_body(mut response: reqwest::Response, limit: u64) -> Result<String> {
+    let content_encoding = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    if let Some(length) = response.content_length()
+        && length > limit
+    {
+        bail!("response body of {length} bytes exceeds the {limit} byte limit");
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.context("failed to read response")? {
+        if (body.len() + chunk.len()) as u64 > limit {
+            bail!("response body exceeds the {limit} byte limit");
+        }
+        body.extend_from_slice(&chunk);
+    }
+    encoding::decode_body(content_encoding.as_deref(), &body, limit)
+}
+
+/// Backoff before retry number `attempt + 1`: a random point in the upper
+/// half of `base * 2^attempt`, picked by `jitter` in `[0, 1)`. Concurrent
+/// renders retrying the same upstream spread out, while each retry still
+/// waits at least as long as the previous one could have.
+fn retry_delay(base: Duration, attempt: u32, jitter: f64) -> Duration {
+    let ceiling = base.saturating_mul(1 << attempt.min(16));
+    ceiling / 2 + (ceiling / 2).mul_f64(jitter.clamp(0.0, 1.0))
+}
+
+/// A value in `[0, 1)` from the std hasher's random keys; good enough to
+/// desynchronize retries without pulling in an RNG crate.
+fn random_unit() -> f64 {
+    use std::hash::{BuildHasher, RandomState};
+    let bits = RandomState::new().hash_one(std::time::Instant::now());
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+struct FetchFailure {
+    error: anyhow::Error,
+    retryable: bool,
+}
+
+impl FetchFailure {
+    fn request(err: reqwest::Error) -> Self {
+        let retryable = err.is_timeout();
+        let context = if err.is_body() || err.is_decode() {
+            "failed to read response"
+        } else {
+            "request failed"
+        };
+        Self {
+            error: anyhow::Error::new(err).context(context),
+            retryable,
+        }
+    }
 }
 
 pub type NetworkResult<T> = std::result::Result<T, NetworkError>;
@@ -131,6 +412,8 @@ pub type NetworkResult<T> = std::result::Result<T, NetworkError>;
 #[derive(Debug)]
 pub struct NetworkError {
     pub status: StatusCode,
+    /// How long the client should wait before asking again, if known.
+    pub retry_after: Option<Duration>,
     message: String,
 }
 
@@ -138,10 +421,16 @@ impl NetworkError {
     fn new(status: StatusCode, message: impl Into<String>) -> Self {
         Self {
             status,
+            retry_after: None,
             message: message.into(),
         }
     }
 
+    fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
     fn internal(err: anyhow::Error) -> Self {
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
     }
@@ -154,3 +443,375 @@ impl std::fmt::Display for NetworkError {
 }
 
 impl std::error::Error for NetworkError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{Router, extract::State, response::IntoResponse, routing::get};
+
+    async fn spawn_upstream() -> reqwest::Url {
+        let app = Router::new().route(
+            "/sub",
+            get(|| async {
+                (
+                    [(
+                        SUBSCRIPTION_USERINFO,
+                        "upload=1; download=2; total=3; expire=4",
+                    )],
+                    "proxies: []",
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        reqwest::Url::parse(&format!("http://{addr}/sub")).unwrap()
+    }
+
+    async fn spawn_flaky_upstream(
+        failures: usize,
+        status: StatusCode,
+    ) -> (reqwest::Url, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/sub",
+                get(move |State(hits): State<Arc<AtomicUsize>>| async move {
+                    if hits.fetch_add(1, Ordering::SeqCst) < failures {
+                        (status, "upstream error")
+                    } else {
+                        (StatusCode::OK, "proxies: []")
+                    }
+                }),
+            )
+            .with_state(hits.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (
+            reqwest::Url::parse(&format!("http://{addr}/sub")).unwrap(),
+            hits,
+        )
+    }
+
+    fn network(name: &str) -> Network {
+        let dir = std::env::temp_dir().join(format!("subcon-{name}-{}", std::process::id()));
+        let config = NetworkConfig {
+            dir: dir.display().to_string(),
+            allowed_domain: vec!["127.0.0.1".to_string()],
+            retry_base_delay_ms: 10,
+            ..NetworkConfig::default()
+        };
+        Network::new(&config, Path::new(".")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn subscription_userinfo_survives_cache() {
+        let url = spawn_upstream().await;
+        let network = network("userinfo");
+
+        for _ in 0..2 {
+            let (text, meta) = network
+                .get_or_fetch_with_meta(&url, &["test"], false, None, |text| Ok(text.to_string()))
+                .await
+                .unwrap();
+            assert_eq!(text, "proxies: []");
+            assert_eq!(
+                meta.subscription_userinfo.as_deref(),
+                Some("upload=1; download=2; total=3; expire=4")
+            );
+        }
+        assert_eq!(network.list_cache().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_hits_report_age() {
+        let url = spawn_upstream().await;
+        let network = network("age");
+
+        let (_, fresh) = network
+            .get_or_fetch_with_meta(&url, &["test"], false, None, |text| Ok(text.to_string()))
+            .await
+            .unwrap();
+        assert!(fresh.age.is_none());
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (_, cached) = network
+            .get_or_fetch_with_meta(&url, &["test"], false, None, |text| Ok(text.to_string()))
+            .await
+            .unwrap();
+        let age = cached.age.expect("cache hit should carry an age");
+        assert!((1..=3).contains(&age.as_secs()), "unexpected age {age:?}");
+    }
+
+    #[tokio::test]
+    async fn expiring_entries_are_revalidated_with_etag() {
+        let conditional_hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/sub",
+                get(
+                    |State(conditional_hits): State<Arc<AtomicUsize>>, headers: HeaderMap| async move {
+                        if headers.get(IF_NONE_MATCH).is_some_and(|v| v == "\"v1\"") {
+                            conditional_hits.fetch_add(1, Ordering::SeqCst);
+                            return StatusCode::NOT_MODIFIED.into_response();
+                        }
+                        ([(ETAG, "\"v1\"")], "proxies: []").into_response()
+                    },
+                ),
+            )
+            .with_state(conditional_hits.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = reqwest::Url::parse(&format!("http://{addr}/sub")).unwrap();
+        let network = network("revalidate");
+
+        network
+            .get_or_fetch_with(&url, &["test"], false, Some(5), |text| Ok(text.to_string()))
+            .await
+            .unwrap();
+
+        // Not within the window yet.
+        assert_eq!(network.revalidate_expiring(Duration::from_secs(1)).await, 0);
+        assert_eq!(conditional_hits.load(Ordering::SeqCst), 0);
+
+        assert_eq!(
+            network.revalidate_expiring(Duration::from_secs(10)).await,
+            1
+        );
+        assert_eq!(conditional_hits.load(Ordering::SeqCst), 1);
+        let cached = network.list_cache().await;
+        assert_eq!(cached.len(), 1);
+        assert!(cached[0].ttl_seconds >= 4, "ttl was not renewed");
+    }
+
+    #[tokio::test]
+    async fn unparsable_cache_hits_are_refetched() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/sub",
+                get(|State(hits): State<Arc<AtomicUsize>>| async move {
+                    // The second response, fetched by revalidation, is an error page.
+                    match hits.fetch_add(1, Ordering::SeqCst) {
+                        1 => "<html>maintenance</html>",
+                        _ => "proxies: []",
+                    }
+                }),
+            )
+            .with_state(hits.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = reqwest::Url::parse(&format!("http://{addr}/sub")).unwrap();
+        let network = network("unparsable-hit");
+        let fetch = || {
+            network.get_or_fetch_with(&url, &["test"], false, Some(5), |text| {
+                anyhow::ensure!(text.starts_with("proxies:"), "not a profile");
+                Ok(text.to_string())
+            })
+        };
+
+        fetch().await.unwrap();
+        assert_eq!(
+            network.revalidate_expiring(Duration::from_secs(10)).await,
+            1
+        );
+        assert_eq!(fetch().await.unwrap(), "proxies: []");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // The refetched body replaced the bad entry.
+        assert_eq!(fetch().await.unwrap(), "proxies: []");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, hits) = spawn_flaky_upstream(2, StatusCode::BAD_GATEWAY).await;
+        let network = network("retry");
+
+        let text = network
+            .get_or_fetch_with(&url, &["test"], true, None, |text| Ok(text.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(text, "proxies: []");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn retry_delays_grow_with_bounded_jitter() {
+        let base = Duration::from_millis(200);
+        let mut previous = Duration::ZERO;
+        for (attempt, jitter) in [0.9, 0.0, 0.5, 0.99].into_iter().enumerate() {
+            let delay = retry_delay(base, attempt as u32, jitter);
+            let ceiling = base * (1 << attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?}");
+            assert!(delay >= previous, "{delay:?} < {previous:?}");
+            previous = delay;
+        }
+        assert_eq!(retry_delay(base, 1, 0.0), Duration::from_millis(200));
+        assert_eq!(retry_delay(base, 1, 1.0), Duration::from_millis(400));
+
+        let jitter = random_unit();
+        assert!((0.0..1.0).contains(&jitter), "{jitter}");
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, hits) = spawn_flaky_upstream(1, StatusCode::NOT_FOUND).await;
+        let network = network("no-retry");
+
+        let err = network
+            .get_or_fetch_with(&url, &["test"], true, None, |text| Ok(text.to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_GATEWAY);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_are_negatively_cached() {
+        let (url, hits) = spawn_flaky_upstream(1, StatusCode::NOT_FOUND).await;
+        let dir = std::env::temp_dir().join(format!("subcon-negative-{}", std::process::id()));
+        let config = NetworkConfig {
+            dir: dir.display().to_string(),
+            allowed_domain: vec!["127.0.0.1".to_string()],
+            negative_ttl_seconds: 60,
+            ..NetworkConfig::default()
+        };
+        let network = Network::new(&config, Path::new(".")).unwrap();
+        let fetch = |no_cache| {
+            network.get_or_fetch_with(&url, &["test"], no_cache, None, |text| Ok(text.to_string()))
+        };
+
+        let err = fetch(false).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_GATEWAY);
+        assert!(err.retry_after.is_none());
+
+        let err = fetch(false).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after = err.retry_after.unwrap();
+        assert!(retry_after > Duration::from_secs(55) && retry_after <= Duration::from_secs(60));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // Bypassing the cache reaches the upstream despite the recorded failure.
+        assert_eq!(fetch(true).await.unwrap(), "proxies: []");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn slow_upstreams_hit_the_configured_timeout() {
+        let app = Router::new().route(
+            "/sub",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                "proxies: []"
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = reqwest::Url::parse(&format!("http://{addr}/sub")).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("subcon-timeout-{}", std::process::id()));
+        let config = NetworkConfig {
+            dir: dir.display().to_string(),
+            allowed_domain: vec!["127.0.0.1".to_string()],
+            max_retries: 0,
+            timeout_seconds: 1,
+            ..NetworkConfig::default()
+        };
+        let network = Network::new(&config, Path::new(".")).unwrap();
+
+        let started = Instant::now();
+        let err = network
+            .get_or_fetch_with(&url, &["test"], true, None, |text| Ok(text.to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_GATEWAY);
+        assert!(
+            started.elapsed() < Duration::from_secs(4),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        // Streamed without a `Content-Length`, so the limit is hit mid-body.
+        let app = Router::new().route(
+            "/sub",
+            get(|| async {
+                let chunks = (0..64).map(|_| Ok::<_, std::io::Error>(vec![b'a'; 1024]));
+                axum::body::Body::from_stream(futures_util::stream::iter(chunks))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = reqwest::Url::parse(&format!("http://{addr}/sub")).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("subcon-body-limit-{}", std::process::id()));
+        let config = NetworkConfig {
+            dir: dir.display().to_string(),
+            allowed_domain: vec!["127.0.0.1".to_string()],
+            max_body_bytes: 16 * 1024,
+            ..NetworkConfig::default()
+        };
+        let network = Network::new(&config, Path::new(".")).unwrap();
+
+        let err = network
+            .get_or_fetch_with(&url, &["test"], true, None, |text| Ok(text.to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_GATEWAY);
+        assert!(err.to_string().contains("16384 byte limit"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn gzipped_bodies_are_decompressed() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(b"proxies:\n  - { name: a, type: ss, server: a.example.com, port: 443 }\n")
+            .unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let app =
+            Router::new()
+                .route(
+                    "/labelled",
+                    get(|State(body): State<Vec<u8>>| async move {
+                        ([(CONTENT_ENCODING, "gzip")], body)
+                    }),
+                )
+                .route(
+                    "/unlabelled",
+                    get(|State(body): State<Vec<u8>>| async move { body }),
+                )
+                .with_state(gzipped);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let network = network("gzip");
+
+        for path in ["labelled", "unlabelled"] {
+            let url = reqwest::Url::parse(&format!("http://{addr}/{path}")).unwrap();
+            let text = network
+                .get_or_fetch_with(&url, &["test"], true, None, |text| Ok(text.to_string()))
+                .await
+                .unwrap();
+            assert!(
+                text.starts_with("proxies:\n  - { name: a"),
+                "{path}: {text:?}"
+            );
+        }
+    }
+}
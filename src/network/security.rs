@@ -1,19 +1,27 @@
 use axum::http::StatusCode;
+use tracing::warn;
 
 use super::{NetworkError, NetworkResult};
 
 #[derive(Clone)]
 pub struct Security {
     allowed_domains: Vec<String>,
+    allow_all: bool,
 }
 
 impl Security {
-    pub fn new(allowed_domains: &[String]) -> Self {
+    /// `allow_all` skips the allowlist entirely and is only meant for
+    /// trusted, private deployments.
+    pub fn new(allowed_domains: &[String], allow_all: bool) -> Self {
+        if allow_all {
+            warn!("network.allow_all is enabled: `url` may point at any host, which is insecure");
+        }
         Self {
             allowed_domains: allowed_domains
                 .iter()
                 .map(|domain| domain.to_ascii_lowercase())
                 .collect(),
+            allow_all,
         }
     }
 
@@ -21,17 +29,21 @@ impl Security {
         let host = url.host_str().ok_or_else(|| {
             NetworkError::new(StatusCode::BAD_REQUEST, "url missing host")
         })?;
+        if self.allow_all {
+            return Ok(());
+        }
         if self.allowed_domains.is_empty() {
             return Err(NetworkError::new(
                 StatusCode::FORBIDDEN,
-                "allowed-domain list is empty",
+                "allowed-domain list is empty; add hosts to `network.allowed_domain` \
+                 or set `network.allow_all = true` to accept any host (insecure)",
             ));
         }
         let host_lower = host.to_ascii_lowercase();
         let allowed = self
             .allowed_domains
             .iter()
-            .any(|domain| domain == &host_lower);
+            .any(|domain| domain_matches(domain, &host_lower));
         if !allowed {
             return Err(NetworkError::new(
                 StatusCode::FORBIDDEN,
@@ -41,3 +53,78 @@ impl Security {
         Ok(())
     }
 }
+
+/// `*` allows any host, `.example.com` allows the domain and its subdomains,
+/// anything else must match exactly.
+fn domain_matches(entry: &str, host: &str) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    match entry.strip_prefix('.') {
+        Some(suffix) => {
+            host == suffix
+                || host
+                    .strip_suffix(suffix)
+                    .is_some_and(|rest| rest.ends_with('.'))
+        }
+        None => entry == host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(raw: &str) -> reqwest::Url {
+        reqwest::Url::parse(raw).unwrap()
+    }
+
+    #[test]
+    fn allow_all_permits_any_host() {
+        let security = Security::new(&[], true);
+        assert!(
+            security
+                .validate_url(&url("https://anything.example.net/sub"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn empty_allowlist_blocks_by_default() {
+        let err = Security::new(&[], false)
+            .validate_url(&url("https://example.com/sub"))
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(err.to_string().contains("network.allow_all"), "{err}");
+
+        let security = Security::new(&["example.com".to_string()], false);
+        assert!(
+            security
+                .validate_url(&url("https://EXAMPLE.com/sub"))
+                .is_ok()
+        );
+        assert!(
+            security
+                .validate_url(&url("https://other.com/sub"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn domain_entries_match_exact_suffix_and_wildcard() {
+        let allows = |entry: &str, host: &str| {
+            Security::new(&[entry.to_string()], false)
+                .validate_url(&url(&format!("https://{host}/sub")))
+                .is_ok()
+        };
+        assert!(allows("example.com", "example.com"));
+        assert!(!allows("example.com", "sub.example.com"));
+
+        assert!(allows(".example.com", "example.com"));
+        assert!(allows(".example.com", "a.b.example.com"));
+        assert!(!allows(".example.com", "badexample.com"));
+        assert!(!allows(".example.com", "example.org"));
+
+        assert!(allows("*", "anything.net"));
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::{Context, Result, anyhow};
+use base64::{
+    Engine, alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
+use serde_json::{Map as JsonMap, Value};
+
+/// SSR links use URL-safe base64, usually without padding.
+const URL_SAFE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// `ssr://base64(host:port:protocol:method:obfs:base64pass/?params)`, with the
+/// scheme stripped. `obfsparam`, `protoparam` and `remarks` are base64 encoded
+/// as well; other parameters such as `group` are ignored.
+pub fn parse_ssr(rest: &str) -> Result<JsonMap<String, Value>> {
+    let decoded = decode_base64(rest.trim())?;
+    let (main, query) = decoded
+        .split_once("/?")
+        .or_else(|| decoded.split_once('?'))
+        .unwrap_or((decoded.as_str(), ""));
+
+    // The host may be an IPv6 address, so the fields are split from the right.
+    let mut fields = main.trim_end_matches('/').rsplitn(6, ':');
+    let mut next = |what: &str| fields.next().ok_or_else(|| anyhow!("missing {what}"));
+    let password = decode_base64(next("password")?).context("invalid password")?;
+    let obfs = next("obfs")?.to_string();
+    let cipher = next("method")?.to_string();
+    let protocol = next("protocol")?.to_string();
+    let port = next("port")?;
+    let port = port
+        .parse::<u16>()
+        .with_context(|| format!("invalid port `{port}`"))?;
+    let server = next("server")?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+
+    let mut params = JsonMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if matches!(key, "obfsparam" | "protoparam" | "remarks") && !value.is_empty() {
+            let value = decode_base64(value).with_context(|| format!("invalid `{key}`"))?;
+            params.insert(key.to_string(), Value::String(value));
+        }
+    }
+    let param = |key: &str| params.get(key).and_then(Value::as_str);
+
+    let mut map = JsonMap::new();
+    let name = param("remarks")
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{server}:{port}"));
+    map.insert("name".to_string(), Value::String(name));
+    map.insert("type".to_string(), Value::String("ssr".to_string()));
+    map.insert("server".to_string(), Value::String(server));
+    map.insert("port".to_string(), Value::from(port));
+    map.insert("cipher".to_string(), Value::String(cipher));
+    map.insert("password".to_string(), Value::String(password));
+    map.insert("obfs".to_string(), Value::String(strip_compatible(&obfs)));
+    map.insert(
+        "protocol".to_string(),
+        Value::String(strip_compatible(&protocol)),
+    );
+    if let Some(obfs_param) = param("obfsparam") {
+        map.insert(
+            "obfs-param".to_string(),
+            Value::String(obfs_param.to_string()),
+        );
+    }
+    if let Some(protocol_param) = param("protoparam") {
+        map.insert(
+            "protocol-param".to_string(),
+            Value::String(protocol_param.to_string()),
+        );
+    }
+
+    Ok(map)
+}
+
+/// The `_compatible` variants fall back to `plain` or `origin` on servers
+/// without the plugin; Clash only knows the plain names.
+fn strip_compatible(value: &str) -> String {
+    value.trim_end_matches("_compatible").to_string()
+}
+
+fn decode_base64(value: &str) -> Result<String> {
+    // Some providers emit the standard alphabet despite the spec.
+    let normalized = value.replace('+', "-").replace('/', "_");
+    let bytes = URL_SAFE.decode(normalized).context("invalid base64")?;
+    String::from_utf8(bytes).context("base64 content is not UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Parser, uri::UriParser};
+    use crate::proxy;
+    use crate::schema::test_registry;
+
+    /// `ssr.example.com:8388:auth_aes128_md5:aes-256-cfb:tls1.2_ticket_auth:`
+    /// with password `p@ss/word`, obfs/protocol params and a CJK remark.
+    const LINK: &str = "ssr://c3NyLmV4YW1wbGUuY29tOjgzODg6YXV0aF9hZXMxMjhfbWQ1OmFlcy0yNTYtY2ZiOnRsczEuMl90aWNrZXRfYXV0aDpjRUJ6Y3k5M2IzSmsvP29iZnNwYXJhbT1ZMlJ1TG1WNFlXMXdiR1V1WTI5dCZwcm90b3BhcmFtPU16STZjMlZqY21WMCZyZW1hcmtzPTZhYVo1cml2SUZOVFVpQXdNUSZncm91cD1VSEp2ZG1sa1pYSQ";
+
+    #[test]
+    fn ssr_link_is_decoded() {
+        let parsed = UriParser.parse(LINK).unwrap();
+        let proxy = &parsed["proxies"][0];
+        assert_eq!(proxy["name"], "香港 SSR 01");
+        assert_eq!(proxy["type"], "ssr");
+        assert_eq!(proxy["server"], "ssr.example.com");
+        assert_eq!(proxy["port"], 8388);
+        assert_eq!(proxy["protocol"], "auth_aes128_md5");
+        assert_eq!(proxy["cipher"], "aes-256-cfb");
+        assert_eq!(proxy["obfs"], "tls1.2_ticket_auth");
+        assert_eq!(proxy["password"], "p@ss/word");
+        assert_eq!(proxy["obfs-param"], "cdn.example.com");
+        assert_eq!(proxy["protocol-param"], "32:secret");
+
+        let registry = test_registry();
+        let proxies = proxy::load_from_text(&registry, LINK, &Default::default()).unwrap();
+        let clash = proxies[0].to_target(&registry, "clash").unwrap();
+        assert_eq!(clash["type"], "ssr");
+        assert_eq!(clash["obfs-param"], "cdn.example.com");
+    }
+
+    #[test]
+    fn compatible_suffix_and_ipv6_host_are_handled() {
+        let inner = format!(
+            "[2001:db8::1]:443:auth_sha1_v4_compatible:chacha20:plain:{}",
+            URL_SAFE.encode("secret")
+        );
+        let map = parse_ssr(&URL_SAFE.encode(inner)).unwrap();
+        assert_eq!(map["server"], "2001:db8::1");
+        assert_eq!(map["port"], 443);
+        assert_eq!(map["protocol"], "auth_sha1_v4");
+        assert_eq!(map["name"], "2001:db8::1:443");
+        assert!(!map.contains_key("obfs-param"));
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{Context, Result, anyhow};
+use base64::{
+    Engine, alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
+use percent_encoding::percent_decode_str;
+use serde_json::{Map as JsonMap, Value};
+use tracing::warn;
+
+use super::Parser;
+
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Parses a share-link subscription (one URI per line, optionally base64
+/// wrapped) into a Clash-style `{ proxies: [...] }` value.
+pub struct UriParser;
+
+impl Parser for UriParser {
+    fn target(&self) -> &'static str {
+        "uri"
+    }
+
+    fn parse(&self, input: &str) -> Result<Value> {
+        let text = decode_subscription(input)
+            .ok_or_else(|| anyhow!("input is not a share-link subscription"))?;
+
+        let mut proxies = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match line.split_once("://") {
+                Some(("ssr", rest)) => {
+                    let proxy = super::ssr_uri::parse_ssr(rest)
+                        .with_context(|| format!("invalid ssr link `{line}`"))?;
+                    proxies.push(Value::Object(proxy));
+                }
+                Some(("trojan", rest)) => {
+                    let proxy = parse_trojan(rest)
+                        .with_context(|| format!("invalid trojan link `{line}`"))?;
+                    proxies.push(Value::Object(proxy));
+                }
+                Some((scheme, _)) => warn!(scheme, "skipping unsupported share link"),
+                None => warn!("skipping line that is not a share link"),
+            }
+        }
+
+        let mut root = JsonMap::new();
+        root.insert("proxies".to_string(), Value::Array(proxies));
+        Ok(Value::Object(root))
+    }
+}
+
+/// Whether `text` is a share-link subscription rather than a YAML profile.
+pub fn looks_like_links(text: &str) -> bool {
+    decode_subscription(text).is_some()
+}
+
+fn decode_subscription(text: &str) -> Option<String> {
+    let is_links = |text: &str| {
+        text.lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .is_some_and(|line| {
+                line.split_once("://").is_some_and(|(scheme, _)| {
+                    !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric())
+                })
+            })
+    };
+
+    if is_links(text) {
+        return Some(text.to_string());
+    }
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let decoded = BASE64
+        .decode(compact.replace('-', "+").replace('_', "/"))
+        .ok()?;
+    String::from_utf8(decoded)
+        .ok()
+        .filter(|text| is_links(text))
+}
+
+/// `trojan://password@server:port?sni=..#name`, with the scheme stripped.
+fn parse_trojan(rest: &str) -> Result<JsonMap<String, Value>> {
+    let (rest, fragment) = match rest.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(decode(fragment)?)),
+        None => (rest, None),
+    };
+    let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (userinfo, authority) = rest
+        .rsplit_once('@')
+        .ok_or_else(|| anyhow!("missing password"))?;
+    let (server, port) = split_authority(authority.trim_end_matches('/'))?;
+
+    let mut params = JsonMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.insert(key.to_string(), Value::String(decode(value)?));
+    }
+    let param = |key: &str| params.get(key).and_then(Value::as_str);
+
+    let mut map = JsonMap::new();
+    let name = fragment.unwrap_or_else(|| format!("{server}:{port}"));
+    map.insert("name".to_string(), Value::String(name));
+    map.insert("type".to_string(), Value::String("trojan".to_string()));
+    map.insert("server".to_string(), Value::String(server));
+    map.insert("port".to_string(), Value::from(port));
+    map.insert("password".to_string(), Value::String(decode(userinfo)?));
+
+    let network = param("type").filter(|network| *network != "tcp");
+    if let Some(sni) = param("sni")
+        .or_else(|| param("peer"))
+        .or_else(|| param("host").filter(|_| network.is_none()))
+    {
+        map.insert("sni".to_string(), Value::String(sni.to_string()));
+    }
+    if matches!(param("allowInsecure"), Some("1" | "true")) {
+        map.insert("skip-cert-verify".to_string(), Value::Bool(true));
+    }
+    if let Some(network) = network {
+        map.insert("network".to_string(), Value::String(network.to_string()));
+        if network == "ws" {
+            let mut ws_opts = JsonMap::new();
+            if let Some(path) = param("path") {
+                ws_opts.insert("path".to_string(), Value::String(path.to_string()));
+            }
+            if let Some(host) = param("host") {
+                let mut headers = JsonMap::new();
+                headers.insert("Host".to_string(), Value::String(host.to_string()));
+                ws_opts.insert("headers".to_string(), Value::Object(headers));
+            }
+            map.insert("ws-opts".to_string(), Value::Object(ws_opts));
+        }
+    }
+
+    Ok(map)
+}
+
+fn split_authority(authority: &str) -> Result<(String, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest
+                .split_once("]:")
+                .ok_or_else(|| anyhow!("missing port"))?;
+            (host, port)
+        }
+        None => authority
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("missing port"))?,
+    };
+    let port = port
+        .parse::<u16>()
+        .with_context(|| format!("invalid port `{port}`"))?;
+    Ok((decode(host)?, port))
+}
+
+fn decode(value: &str) -> Result<String> {
+    percent_decode_str(value)
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+        .context("invalid percent-encoding")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy;
+    use crate::schema::test_registry;
+
+    const LINK: &str = "trojan://p%40ss%2Cw%3Ard%23%25@example.com:443?sni=sni%2Eexample.com&allowInsecure=1#HK%20Node";
+
+    #[test]
+    fn trojan_link_is_percent_decoded() {
+        let parsed = UriParser.parse(LINK).unwrap();
+        let proxy = &parsed["proxies"][0];
+        assert_eq!(proxy["name"], "HK Node");
+        assert_eq!(proxy["server"], "example.com");
+        assert_eq!(proxy["port"], 443);
+        assert_eq!(proxy["password"], "p@ss,w:rd#%");
+        assert_eq!(proxy["sni"], "sni.example.com");
+        assert_eq!(proxy["skip-cert-verify"], true);
+    }
+
+    #[test]
+    fn base64_wrapped_subscription_is_detected() {
+        let wrapped = BASE64.encode(format!("{LINK}\nvmess://unsupported\n"));
+        assert!(looks_like_links(&wrapped));
+        assert!(!looks_like_links("proxies:\n  - name: a\n"));
+
+        let parsed = UriParser.parse(&wrapped).unwrap();
+        assert_eq!(parsed["proxies"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn decoded_password_reaches_clash_and_surge() {
+        let registry = test_registry();
+        let proxies = proxy::load_from_text(&registry, LINK, &Default::default()).unwrap();
+        assert_eq!(proxies.len(), 1);
+
+        let clash = proxies[0].to_target(&registry, "clash").unwrap();
+        assert_eq!(clash["password"], "p@ss,w:rd#%");
+
+        let surge = proxies[0].to_target(&registry, "surge").unwrap();
+        let line = surge.as_str().unwrap();
+        assert!(line.contains("password=\"p@ss,w:rd#%\""), "{line}");
+    }
+}
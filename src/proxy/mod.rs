@@ -1,11 +1,18 @@
-use std::{fs, path::Path};
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result, anyhow};
 use serde_json::{Map as JsonMap, Value};
+use tracing::warn;
 
+use crate::network::Network;
 use crate::schema::SchemaRegistry;
 
+pub mod region;
+
 #[derive(Debug, Clone)]
 pub struct Proxy {
     pub name: String,
@@ -15,41 +22,223 @@ pub struct Proxy {
 
 impl Proxy {
     pub fn to_target(&self, registry: &SchemaRegistry, target: &str) -> Result<Value> {
-        registry.convert(&self.protocol, target, &self.values)
+        registry.convert(&self.protocol, target, &self.name, &self.values)
+    }
+
+    /// Converts for `target`, ignoring `not-implemented` when `force` is set.
+    pub fn to_target_with(
+        &self,
+        registry: &SchemaRegistry,
+        target: &str,
+        force: bool,
+    ) -> Result<Value> {
+        if force {
+            registry.convert_forced(&self.protocol, target, &self.name, &self.values)
+        } else {
+            self.to_target(registry, target)
+        }
     }
 }
 
-pub fn load_from_profile(registry: &SchemaRegistry, path: impl AsRef<Path>) -> Result<Vec<Proxy>> {
+/// Maps a profile's raw proxy `type` to the protocol it should be parsed as.
+pub type ProtocolOverrides = HashMap<String, String>;
+
+/// Resolved profile path to the overrides that apply to it.
+pub type ProfileOverrides = HashMap<PathBuf, ProtocolOverrides>;
+
+pub fn load_from_profile(
+    registry: &SchemaRegistry,
+    path: impl AsRef<Path>,
+    overrides: &ProtocolOverrides,
+) -> Result<Vec<Proxy>> {
     let path = path.as_ref();
     let text = fs::read_to_string(path)
         .with_context(|| format!("failed to read profile {}", path.display()))?;
-    load_from_text(registry, &text)
+    parse_text(registry, &text, overrides)
         .with_context(|| format!("failed to parse profile {}", path.display()))
 }
 
-pub fn load_from_text(registry: &SchemaRegistry, text: &str) -> Result<Vec<Proxy>> {
-    let parsed = registry
-        .parse("clash", text)
-        .context("failed to parse clash profile")?;
-    extract_proxies(&parsed)
+/// Parses a Clash profile, or a share-link subscription when the text is one.
+pub fn load_from_text(
+    registry: &SchemaRegistry,
+    text: &str,
+    overrides: &ProtocolOverrides,
+) -> Result<Vec<Proxy>> {
+    load_from_text_with_fetcher(registry, text, overrides, |url| {
+        Err(anyhow!("remote proxy provider not supported: {url}"))
+    })
+}
+
+/// Like `load_from_text`, also appending the nodes of each
+/// `proxy-providers` entry, whose `url` is fetched with `fetcher`. Provider
+/// options such as `health-check` and `filter` are ignored. A provider that
+/// cannot be fetched or parsed is logged and skipped.
+pub fn load_from_text_with_fetcher<F>(
+    registry: &SchemaRegistry,
+    text: &str,
+    overrides: &ProtocolOverrides,
+    fetcher: F,
+) -> Result<Vec<Proxy>>
+where
+    F: Fn(&str) -> Result<String>,
+{
+    let parsed = parse_value(registry, text)?;
+    let mut proxies = extract_proxies(&parsed, overrides)?;
+    let providers = parsed.get("proxy-providers").and_then(Value::as_object);
+    for (name, provider) in providers.into_iter().flatten() {
+        let Some(url) = provider.get("url").and_then(Value::as_str) else {
+            warn!(provider = %name, "skipping proxy provider without `url`");
+            continue;
+        };
+        let loaded =
+            fetcher(url).and_then(|text| parse_text(registry, &text, &ProtocolOverrides::new()));
+        match loaded {
+            Ok(loaded) => proxies.extend(loaded),
+            Err(err) => warn!(provider = %name, error = %err, "failed to load proxy provider"),
+        }
+    }
+    Ok(proxies)
+}
+
+fn parse_text(
+    registry: &SchemaRegistry,
+    text: &str,
+    overrides: &ProtocolOverrides,
+) -> Result<Vec<Proxy>> {
+    extract_proxies(&parse_value(registry, text)?, overrides)
+}
+
+fn parse_value(registry: &SchemaRegistry, text: &str) -> Result<Value> {
+    if crate::parser::uri::looks_like_links(text) {
+        registry
+            .parse("uri", text)
+            .context("failed to parse share links")
+    } else {
+        registry
+            .parse("clash", text)
+            .context("failed to parse clash profile")
+    }
 }
 
 #[allow(dead_code)]
 pub fn load_from_dir(registry: &SchemaRegistry, dir: impl AsRef<Path>) -> Result<Vec<Proxy>> {
     let dir = dir.as_ref();
     let paths = collect_profile_files(dir)?;
-    load_from_paths(registry, paths)
+    load_from_paths(registry, paths, &ProfileOverrides::new())
 }
 
-pub fn load_from_paths(registry: &SchemaRegistry, paths: Vec<PathBuf>) -> Result<Vec<Proxy>> {
+pub fn load_from_paths(
+    registry: &SchemaRegistry,
+    paths: Vec<PathBuf>,
+    overrides: &ProfileOverrides,
+) -> Result<Vec<Proxy>> {
+    let none = ProtocolOverrides::new();
     let mut proxies: Vec<Proxy> = Vec::new();
     for path in paths {
-        proxies.extend(load_from_profile(registry, path)?);
+        let profile_overrides = overrides.get(&path).unwrap_or(&none);
+        proxies.extend(load_from_profile(registry, path, profile_overrides)?);
     }
     Ok(proxies)
 }
 
-fn parse_proxy(value: &Value) -> Result<Proxy> {
+/// Loads every profile listed by the JSON index at `index_url`, an array of
+/// profile URLs that may be relative to the index. An index or profile that
+/// cannot be fetched or parsed is logged and skipped, so local profiles still
+/// render. `overrides` is keyed by the resolved profile URL.
+pub async fn load_from_remote_index(
+    network: &Network,
+    registry: &SchemaRegistry,
+    index_url: &str,
+    user_agents: &[&str],
+    no_cache: bool,
+    overrides: &HashMap<String, ProtocolOverrides>,
+) -> Vec<Proxy> {
+    let index_url = match reqwest::Url::parse(index_url.trim()) {
+        Ok(url) => url,
+        Err(err) => {
+            warn!(url = %index_url, error = %err, "invalid remote profile index url");
+            return Vec::new();
+        }
+    };
+    let entries = network
+        .get_or_fetch_with(&index_url, user_agents, no_cache, None, |text| {
+            serde_json::from_str::<Vec<String>>(text)
+                .context("remote profile index must be a JSON array of URLs")
+        })
+        .await;
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!(url = %index_url, error = %err, "failed to load remote profile index");
+            return Vec::new();
+        }
+    };
+
+    let none = ProtocolOverrides::new();
+    let mut proxies = Vec::new();
+    for entry in entries {
+        let url = match index_url.join(&entry) {
+            Ok(url) => url,
+            Err(err) => {
+                warn!(url = %entry, error = %err, "invalid remote profile url");
+                continue;
+            }
+        };
+        let profile_overrides = overrides.get(url.as_str()).unwrap_or(&none);
+        let loaded = network
+            .get_or_fetch_with(&url, user_agents, no_cache, None, |text| {
+                load_from_text(registry, text, profile_overrides)
+            })
+            .await;
+        match loaded {
+            Ok(loaded) => proxies.extend(loaded),
+            Err(err) => warn!(url = %url, error = %err, "failed to load remote profile"),
+        }
+    }
+    proxies
+}
+
+/// Sort by name, then `server:port`, keeping the original order for exact ties.
+pub fn sort_proxies(proxies: &mut [Proxy]) {
+    fn endpoint(proxy: &Proxy) -> (&str, u64) {
+        let server = proxy.values.get("server").and_then(Value::as_str);
+        let port = proxy.values.get("port").and_then(Value::as_u64);
+        (server.unwrap_or(""), port.unwrap_or(0))
+    }
+    // `sort_by` is stable, so the original index is the final tie-breaker.
+    proxies.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then_with(|| endpoint(a).cmp(&endpoint(b)))
+    });
+}
+
+/// Renames nodes that repeat an earlier node's name to `name #2`, `name #3`
+/// and so on, skipping names already in use. The first node keeps its name.
+pub fn dedupe_names(proxies: &mut [Proxy]) {
+    let mut taken: HashSet<String> = proxies.iter().map(|p| p.name.clone()).collect();
+    let mut seen = HashSet::new();
+    let mut counters: HashMap<String, usize> = HashMap::new();
+    for proxy in proxies.iter_mut() {
+        if seen.insert(proxy.name.clone()) {
+            continue;
+        }
+        let counter = counters.entry(proxy.name.clone()).or_insert(1);
+        let renamed = loop {
+            *counter += 1;
+            let candidate = format!("{} #{}", proxy.name, counter);
+            if taken.insert(candidate.clone()) {
+                break candidate;
+            }
+        };
+        proxy
+            .values
+            .insert("name".to_string(), Value::String(renamed.clone()));
+        proxy.name = renamed;
+    }
+}
+
+fn parse_proxy(value: &Value, overrides: &ProtocolOverrides) -> Result<Proxy> {
     let map = value
         .as_object()
         .cloned()
@@ -66,7 +255,7 @@ fn parse_proxy(value: &Value) -> Result<Proxy> {
         .and_then(|v| v.as_str())
         .ok_or_else(|| anyhow!("proxy `{}` missing `type`", name))?;
 
-    let protocol = normalize_protocol(protocol);
+    let protocol = normalize_protocol(overrides.get(protocol).map_or(protocol, String::as_str));
 
     Ok(Proxy {
         name,
@@ -75,7 +264,7 @@ fn parse_proxy(value: &Value) -> Result<Proxy> {
     })
 }
 
-fn extract_proxies(parsed: &Value) -> Result<Vec<Proxy>> {
+fn extract_proxies(parsed: &Value, overrides: &ProtocolOverrides) -> Result<Vec<Proxy>> {
     let (field, proxies_value) = match parsed.get("proxies") {
         Some(v) => ("proxies", v),
         None => match parsed.get("proxy") {
@@ -85,13 +274,22 @@ fn extract_proxies(parsed: &Value) -> Result<Vec<Proxy>> {
     };
 
     match proxies_value {
-        Value::Array(items) => items.iter().map(parse_proxy).collect(),
-        Value::Object(_) => Ok(vec![parse_proxy(proxies_value)?]),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| parse_proxy(item, overrides))
+            .collect(),
+        Value::Object(_) => Ok(vec![parse_proxy(proxies_value, overrides)?]),
         Value::Null => Ok(Vec::new()),
         _ => Err(anyhow!("clash profile `{}` must be an array or map", field)),
     }
 }
 
+/// Parses a single proxy map written outside a profile, such as a
+/// `[[custom_proxy]]` entry.
+pub fn from_map(values: &JsonMap<String, Value>) -> Result<Proxy> {
+    parse_proxy(&Value::Object(values.clone()), &ProtocolOverrides::new())
+}
+
 fn normalize_protocol(protocol: &str) -> String {
     match protocol {
         "ss" => "shadowsocks".to_string(),
@@ -118,3 +316,183 @@ pub fn collect_profile_files(dir: &Path) -> Result<Vec<PathBuf>> {
     paths.sort();
     Ok(paths)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+
+    fn proxy(name: &str, server: &str, port: u64) -> Proxy {
+        let values = serde_json::json!({ "name": name, "server": server, "port": port });
+        Proxy {
+            name: name.to_string(),
+            protocol: "trojan".to_string(),
+            values: values.as_object().cloned().unwrap(),
+        }
+    }
+
+    #[test]
+    fn same_named_proxies_sort_by_server_and_port() {
+        let mut proxies = vec![
+            proxy("hk", "b.example.com", 443),
+            proxy("hk", "a.example.com", 8443),
+            proxy("hk", "a.example.com", 443),
+            proxy("jp", "a.example.com", 443),
+        ];
+        sort_proxies(&mut proxies);
+        let order: Vec<String> = proxies
+            .iter()
+            .map(|p| format!("{}@{}:{}", p.name, p.values["server"], p.values["port"]))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                r#"hk@"a.example.com":443"#,
+                r#"hk@"a.example.com":8443"#,
+                r#"hk@"b.example.com":443"#,
+                r#"jp@"a.example.com":443"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_names_get_a_suffix() {
+        let mut proxies = vec![
+            proxy("HK", "a.example.com", 443),
+            proxy("HK", "b.example.com", 443),
+        ];
+        dedupe_names(&mut proxies);
+        assert_eq!(proxies[0].name, "HK");
+        assert_eq!(proxies[1].name, "HK #2");
+        assert_eq!(proxies[1].values["name"], "HK #2");
+
+        // A suffix already used by another node is skipped.
+        let mut proxies = vec![
+            proxy("HK", "a.example.com", 443),
+            proxy("HK", "b.example.com", 443),
+            proxy("HK #2", "c.example.com", 443),
+            proxy("HK", "d.example.com", 443),
+        ];
+        dedupe_names(&mut proxies);
+        let names: Vec<(&str, &Value)> = proxies
+            .iter()
+            .map(|p| (p.name.as_str(), &p.values["name"]))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("HK", &Value::from("HK")),
+                ("HK #3", &Value::from("HK #3")),
+                ("HK #2", &Value::from("HK #2")),
+                ("HK #4", &Value::from("HK #4")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_index_profiles_are_loaded() {
+        use axum::{Json, Router, routing::get};
+
+        let app = Router::new()
+            .route(
+                "/index.json",
+                get(|| async { Json(["profiles/a.yaml", "/profiles/b.yaml", "/missing.yaml"]) }),
+            )
+            .route(
+                "/profiles/a.yaml",
+                get(|| async {
+                    "proxies:\n  - { name: remote-a, type: trojan, server: a.example.com, port: 443, password: secret }\n"
+                }),
+            )
+            .route(
+                "/profiles/b.yaml",
+                get(|| async {
+                    "proxies:\n  - { name: remote-b, type: custom-trojan, server: b.example.com, port: 443, password: secret }\n"
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = std::env::temp_dir().join(format!("subcon-remote-index-{}", std::process::id()));
+        let network = Network::new(
+            &crate::config::NetworkConfig {
+                dir: dir.display().to_string(),
+                allowed_domain: vec!["127.0.0.1".to_string()],
+                retry_base_delay_ms: 10,
+                ..Default::default()
+            },
+            Path::new("."),
+        )
+        .unwrap();
+        let registry = test_registry();
+        let overrides = HashMap::from([(
+            format!("http://{addr}/profiles/b.yaml"),
+            ProtocolOverrides::from([("custom-trojan".to_string(), "trojan".to_string())]),
+        )]);
+
+        let proxies = load_from_remote_index(
+            &network,
+            &registry,
+            &format!("http://{addr}/index.json"),
+            &["test"],
+            true,
+            &overrides,
+        )
+        .await;
+        let names: Vec<&str> = proxies.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["remote-a", "remote-b"]);
+        assert_eq!(proxies[1].protocol, "trojan");
+
+        // An unreachable index leaves nothing to merge instead of failing.
+        let proxies = load_from_remote_index(
+            &network,
+            &registry,
+            &format!("http://{addr}/nope.json"),
+            &["test"],
+            true,
+            &overrides,
+        )
+        .await;
+        assert!(proxies.is_empty());
+    }
+
+    #[test]
+    fn profile_override_maps_custom_type() {
+        let dir =
+            std::env::temp_dir().join(format!("subcon-protocol-override-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let profile = "proxies:\n  - { name: odd, type: custom-ss, server: a.example.com, port: 8388, cipher: aes-128-gcm, password: secret }\n";
+        fs::write(dir.join("provider.yaml"), profile).unwrap();
+        fs::write(dir.join("other.yaml"), profile).unwrap();
+
+        let pref: crate::config::Pref = toml::from_str(
+            r#"
+            [common]
+            default_url = ["provider.yaml", "other.yaml"]
+
+            [server]
+            listen = "127.0.0.1"
+            port = 0
+
+            [protocol_overrides."provider.yaml"]
+            custom-ss = "ss"
+            "#,
+        )
+        .unwrap();
+        let registry = test_registry();
+        let proxies = load_from_paths(
+            &registry,
+            vec![dir.join("provider.yaml"), dir.join("other.yaml")],
+            &pref.protocol_overrides(&dir),
+        )
+        .unwrap();
+
+        assert_eq!(proxies[0].protocol, "shadowsocks");
+        let clash = proxies[0].to_target(&registry, "clash").unwrap();
+        assert_eq!(clash["type"], "ss");
+        assert_eq!(clash["cipher"], "aes-128-gcm");
+        // The override is scoped to its profile.
+        assert_eq!(proxies[1].protocol, "custom-ss");
+    }
+}
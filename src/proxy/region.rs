@@ -0,0 +1,109 @@
+use std::sync::OnceLock;
+
+use fancy_regex::Regex;
+
+/// A country or region a node can be recognized as serving from.
+#[derive(Debug)]
+pub struct Region {
+    pub name: &'static str,
+    pub emoji: &'static str,
+    /// Short codes matched as standalone letters, so `HK01` and `HK-IPLC`
+    /// match but `THK` and the `GB` in `10GB` do not.
+    codes: &'static str,
+    /// Place names matched anywhere in the node name.
+    words: &'static str,
+}
+
+/// Checked in order; the first match wins.
+const REGIONS: [Region; 8] = [
+    Region {
+        name: "Hong Kong",
+        emoji: "🇭🇰",
+        codes: "HK|HKG",
+        words: "香港|Hong ?Kong",
+    },
+    Region {
+        name: "Taiwan",
+        emoji: "🇹🇼",
+        codes: "TW|TWN",
+        words: "台湾|臺灣|台北|Taiwan|Taipei",
+    },
+    Region {
+        name: "Japan",
+        emoji: "🇯🇵",
+        codes: "JP|JPN",
+        words: "日本|东京|東京|大阪|Japan|Tokyo|Osaka",
+    },
+    Region {
+        name: "Singapore",
+        emoji: "🇸🇬",
+        codes: "SG|SGP",
+        words: "新加坡|狮城|獅城|Singapore",
+    },
+    Region {
+        name: "United States",
+        emoji: "🇺🇸",
+        codes: "US|USA",
+        words: "美国|美國|洛杉矶|圣何塞|United States|America|Los Angeles|San Jose|Seattle",
+    },
+    Region {
+        name: "Korea",
+        emoji: "🇰🇷",
+        codes: "KR|KOR",
+        words: "韩国|韓國|首尔|Korea|Seoul",
+    },
+    Region {
+        name: "United Kingdom",
+        emoji: "🇬🇧",
+        codes: "UK|GB|GBR",
+        words: "英国|英國|伦敦|United Kingdom|Britain|London",
+    },
+    Region {
+        name: "Germany",
+        emoji: "🇩🇪",
+        codes: "DE|DEU",
+        words: "德国|德國|法兰克福|Germany|Frankfurt",
+    },
+];
+
+/// The region a node name points at, recognized by flag emoji, country
+/// code or place name.
+pub fn detect(name: &str) -> Option<&'static Region> {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        REGIONS
+            .iter()
+            .map(|region| {
+                Regex::new(&format!(
+                    "(?i){}|(?<![A-Za-z0-9])(?:{})(?![A-Za-z])|{}",
+                    region.emoji, region.codes, region.words
+                ))
+                .expect("region pattern must compile")
+            })
+            .collect()
+    });
+    REGIONS
+        .iter()
+        .zip(patterns)
+        .find(|(_, pattern)| pattern.is_match(name).unwrap_or(false))
+        .map(|(region, _)| region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_are_detected_from_codes_words_and_flags() {
+        let region = |name: &str| detect(name).map(|region| region.name);
+        assert_eq!(region("HK01 IPLC"), Some("Hong Kong"));
+        assert_eq!(region("香港 02"), Some("Hong Kong"));
+        assert_eq!(region("🇯🇵 Osaka"), Some("Japan"));
+        assert_eq!(region("us-west-1"), Some("United States"));
+        assert_eq!(region("Los Angeles 03"), Some("United States"));
+        // Codes inside longer words do not count.
+        assert_eq!(region("Bonus Traffic"), None);
+        assert_eq!(region("Thkfast"), None);
+        assert_eq!(region("Remaining: 10GB"), None);
+    }
+}
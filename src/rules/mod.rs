@@ -1,11 +1,13 @@
 use std::{
     fmt,
     fs,
+    net::IpAddr,
     path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use serde::Deserialize;
+use serde_saphyr as serde_yaml;
 use tracing::warn;
 
 use crate::paths::resolve_path;
@@ -38,9 +40,20 @@ impl RulesetRef {
     }
 }
 
+/// Trailing rule modifiers such as `no-resolve` or `src`, kept in the order
+/// they were written.
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct RuleFlags {
-    pub no_resolve: bool,
+    pub modifiers: Vec<String>,
+}
+
+#[cfg(test)]
+impl RuleFlags {
+    pub fn no_resolve(&self) -> bool {
+        self.modifiers
+            .iter()
+            .any(|modifier| modifier.eq_ignore_ascii_case("no-resolve"))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -75,9 +88,7 @@ impl Rule {
         }
         parts.push(self.group.clone());
 
-        if self.flags.no_resolve {
-            parts.push("no-resolve".to_string());
-        }
+        parts.extend(self.flags.modifiers.iter().cloned());
 
         parts.join(",")
     }
@@ -154,42 +165,16 @@ where
                 RuleSource::File(path) => {
                     let text = fs::read_to_string(&path)
                         .with_context(|| format!("failed to read ruleset {}", path.display()))?;
-                    for (idx, line) in text.lines().enumerate() {
-                        let line_no = idx + 1;
-                        match parse_rule_line(line, &group) {
-                            Ok(Some(rule)) => rules.push(rule),
-                            Ok(None) => {}
-                            Err(err) => {
-                                return Err(err).with_context(|| {
-                                    format!(
-                                        "failed to parse rule at {}:{} (group `{}`)",
-                                        path.display(),
-                                        line_no,
-                                        group
-                                    )
-                                })
-                            }
-                        }
-                    }
+                    rules.extend(parse_ruleset_text(
+                        &text,
+                        &group,
+                        &path.display().to_string(),
+                    )?);
                 }
                 RuleSource::Url(url) => {
                     let text = fetcher(&url)
                         .with_context(|| format!("failed to fetch ruleset {}", url))?;
-                    for (idx, line) in text.lines().enumerate() {
-                        let line_no = idx + 1;
-                        match parse_rule_line(line, &group) {
-                            Ok(Some(rule)) => rules.push(rule),
-                            Ok(None) => {}
-                            Err(err) => {
-                                return Err(err).with_context(|| {
-                                    format!(
-                                        "failed to parse rule at {}:{} (group `{}`)",
-                                        url, line_no, group
-                                    )
-                                })
-                            }
-                        }
-                    }
+                    rules.extend(parse_ruleset_text(&text, &group, &url)?);
                 }
             }
         }
@@ -207,6 +192,109 @@ where
     Ok(rules)
 }
 
+/// A Clash rule-provider file.
+#[derive(Deserialize)]
+struct RuleProviderFile {
+    payload: Vec<String>,
+}
+
+/// Parses a ruleset body with one rule per line, or a Clash rule-provider
+/// file whose `payload` lists the rules. `source` names the ruleset in errors.
+fn parse_ruleset_text(text: &str, group: &str, source: &str) -> Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    if let Some(payload) = provider_payload(text) {
+        for (idx, entry) in payload.iter().enumerate() {
+            let line = payload_rule_line(entry);
+            if let Some(rule) = parse_rule_line(&line, group).with_context(|| {
+                format!(
+                    "failed to parse payload entry {} of {} (group `{}`)",
+                    idx + 1,
+                    source,
+                    group
+                )
+            })? {
+                rules.push(rule);
+            }
+        }
+        return Ok(rules);
+    }
+
+    for (idx, line) in text.lines().enumerate() {
+        let line_no = idx + 1;
+        if let Some(rule) = parse_rule_line(line, group).with_context(|| {
+            format!(
+                "failed to parse rule at {}:{} (group `{}`)",
+                source, line_no, group
+            )
+        })? {
+            rules.push(rule);
+        }
+    }
+    Ok(rules)
+}
+
+/// The `payload` entries when `text` is a YAML rule-provider file.
+fn provider_payload(text: &str) -> Option<Vec<String>> {
+    if !text
+        .lines()
+        .any(|line| line.trim_start().starts_with("payload:"))
+    {
+        return None;
+    }
+    serde_yaml::from_str::<RuleProviderFile>(text)
+        .ok()
+        .map(|file| file.payload)
+}
+
+/// Turns a payload entry into a rule line. `classical` entries already are
+/// one; `domain` entries (`+.suffix`, wildcards or plain names) and
+/// `ipcidr` entries get the matching rule type.
+fn payload_rule_line(entry: &str) -> String {
+    let entry = entry.trim();
+    if entry.contains(',') {
+        return entry.to_string();
+    }
+    let (addr, prefix) = match entry.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (entry, None),
+    };
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        let (rule_type, full) = if ip.is_ipv4() {
+            ("IP-CIDR", "32")
+        } else {
+            ("IP-CIDR6", "128")
+        };
+        return format!("{rule_type},{addr}/{}", prefix.unwrap_or(full));
+    }
+    if let Some(suffix) = entry.strip_prefix("+.") {
+        format!("DOMAIN-SUFFIX,{suffix}")
+    } else if entry.contains('*') {
+        format!("DOMAIN-WILDCARD,{entry}")
+    } else {
+        format!("DOMAIN,{entry}")
+    }
+}
+
+/// Parses a rule given outside a ruleset file, where a line that would be
+/// skipped or a `FINAL` is an error.
+pub fn parse_extra_rule(line: &str, group: &str) -> Result<Rule> {
+    let rule =
+        parse_rule_line(line, group)?.ok_or_else(|| anyhow!("`{line}` is not a supported rule"))?;
+    if is_fallback_rule(&rule) {
+        bail!("`{line}` is a fallback rule; use `[]FINAL` in a ruleset instead");
+    }
+    Ok(rule)
+}
+
+/// Inserts `extra` ahead of the first `FINAL` or `MATCH` rule, or at the end.
+pub fn insert_before_fallback(rules: &mut Vec<Rule>, extra: Vec<Rule>) {
+    let at = rules
+        .iter()
+        .position(is_fallback_rule)
+        .unwrap_or(rules.len());
+    rules.splice(at..at, extra);
+}
+
 const IP_RULE_TYPES: [&str; 9] = [
     "IP-CIDR",
     "IP-CIDR6",
@@ -219,6 +307,9 @@ const IP_RULE_TYPES: [&str; 9] = [
     "SRC-IP-SUFFIX",
 ];
 
+/// Trailing tokens Clash and Surge accept after a rule's content.
+const RULE_MODIFIERS: [&str; 4] = ["no-resolve", "src", "extended-matching", "pre-matching"];
+
 const DOMAIN_RULE_TYPES: [&str; 6] = [
     "DOMAIN",
     "DOMAIN-SUFFIX",
@@ -265,6 +356,71 @@ pub fn reorder_rules_domain_before_ip(rules: &[Rule]) -> Vec<Rule> {
     output
 }
 
+/// Stable-sorts the domain rules and the IP rules among the slots each
+/// bucket already occupies, so exact matches come before broader ones.
+/// `FINAL` and `MATCH` rules are moved to the end.
+pub fn sort_rules_by_specificity(rules: &[Rule]) -> Vec<Rule> {
+    let mut domain_rules: Vec<Rule> = rules
+        .iter()
+        .filter(|r| is_domain_rule(r))
+        .cloned()
+        .collect();
+    let mut ip_rules: Vec<Rule> = rules.iter().filter(|r| is_ip_rule(r)).cloned().collect();
+    domain_rules.sort_by_key(specificity);
+    ip_rules.sort_by_key(specificity);
+
+    let mut domain_iter = domain_rules.into_iter();
+    let mut ip_iter = ip_rules.into_iter();
+    let mut output = Vec::with_capacity(rules.len());
+    let mut fallbacks = Vec::new();
+    for rule in rules {
+        if is_domain_rule(rule) {
+            output.extend(domain_iter.next());
+        } else if is_ip_rule(rule) {
+            output.extend(ip_iter.next());
+        } else if is_fallback_rule(rule) {
+            fallbacks.push(rule.clone());
+        } else {
+            output.push(rule.clone());
+        }
+    }
+    output.extend(fallbacks);
+    output
+}
+
+/// Keeps rules that point at one of `groups` or a built-in policy. A
+/// `FINAL` or `MATCH` rule whose group is dropped is pointed at the first of
+/// `groups` instead, or `DIRECT` when none is kept.
+pub fn retain_rules_for_groups(rules: &mut Vec<Rule>, groups: &[&str]) {
+    let fallback = groups.first().copied().unwrap_or("DIRECT");
+    rules.retain_mut(|rule| {
+        if ["DIRECT", "REJECT"].contains(&rule.group.as_str())
+            || groups.contains(&rule.group.as_str())
+        {
+            return true;
+        }
+        if is_fallback_rule(rule) {
+            rule.group = fallback.to_string();
+            return true;
+        }
+        false
+    });
+}
+
+/// Lower is more specific: exact > suffix > wildcard > keyword > regex >
+/// GEOSITE for domains, and CIDR > suffix > ASN > GEOIP for IPs.
+fn specificity(rule: &Rule) -> u8 {
+    let rule_type = rule.rule_type.0.to_ascii_uppercase();
+    match rule_type.strip_prefix("SRC-").unwrap_or(&rule_type) {
+        "DOMAIN" | "IP-CIDR" | "IP-CIDR6" => 0,
+        "DOMAIN-SUFFIX" | "IP-SUFFIX" => 1,
+        "DOMAIN-WILDCARD" | "IP-ASN" => 2,
+        "DOMAIN-KEYWORD" | "GEOIP" => 3,
+        "DOMAIN-REGEX" => 4,
+        _ => 5,
+    }
+}
+
 fn parse_rule_line(line: &str, group: &str) -> Result<Option<Rule>> {
     let stripped = if let Some(idx) = line.find("//") {
         &line[..idx]
@@ -295,14 +451,17 @@ fn parse_rule_line(line: &str, group: &str) -> Result<Option<Rule>> {
             content_parts.pop();
             continue;
         }
-        match last.to_ascii_lowercase().as_str() {
-            "no-resolve" => {
-                flags.no_resolve = true;
-                content_parts.pop();
-            }
-            _ => break,
+        if !RULE_MODIFIERS
+            .iter()
+            .any(|modifier| modifier.eq_ignore_ascii_case(last))
+        {
+            break;
+        }
+        if let Some(modifier) = content_parts.pop() {
+            flags.modifiers.push(modifier);
         }
     }
+    flags.modifiers.reverse();
 
     let content = if content_parts.is_empty() {
         None
@@ -393,6 +552,12 @@ fn is_ip_rule(rule: &Rule) -> bool {
         .any(|ty| ty.eq_ignore_ascii_case(&rule.rule_type.0))
 }
 
+fn is_fallback_rule(rule: &Rule) -> bool {
+    ["FINAL", "MATCH"]
+        .iter()
+        .any(|ty| ty.eq_ignore_ascii_case(&rule.rule_type.0))
+}
+
 fn is_domain_rule(rule: &Rule) -> bool {
     DOMAIN_RULE_TYPES
         .iter()
@@ -403,6 +568,42 @@ fn is_domain_rule(rule: &Rule) -> bool {
 mod tests {
     use super::*;
 
+    #[test]
+    fn rules_for_dropped_groups_are_omitted() {
+        let mut rules: Vec<Rule> = [
+            ("DOMAIN-SUFFIX,google.com", "Proxy"),
+            ("DOMAIN-SUFFIX,netflix.com", "Streaming"),
+            ("DOMAIN-SUFFIX,ads.example", "REJECT"),
+        ]
+        .into_iter()
+        .map(|(line, group)| parse_rule_line(line, group).unwrap().unwrap())
+        .collect();
+        rules.push(Rule {
+            rule_type: RuleType::new("FINAL"),
+            content: None,
+            group: "Streaming".to_string(),
+            flags: RuleFlags::default(),
+        });
+
+        retain_rules_for_groups(&mut rules, &["Proxy"]);
+        let rendered: Vec<String> = rules.iter().map(Rule::render).collect();
+        assert_eq!(
+            rendered,
+            [
+                "DOMAIN-SUFFIX,google.com,Proxy",
+                "DOMAIN-SUFFIX,ads.example,REJECT",
+                "FINAL,Proxy",
+            ]
+        );
+
+        retain_rules_for_groups(&mut rules, &[]);
+        let rendered: Vec<String> = rules.iter().map(Rule::render).collect();
+        assert_eq!(
+            rendered,
+            ["DOMAIN-SUFFIX,ads.example,REJECT", "FINAL,DIRECT"]
+        );
+    }
+
     #[test]
     fn parse_rule_with_flag() {
         let rule = parse_rule_line("IP-CIDR,1.1.1.1/32,no-resolve", "Test")
@@ -410,10 +611,26 @@ mod tests {
             .unwrap();
         assert_eq!(rule.rule_type.to_string(), "IP-CIDR");
         assert_eq!(rule.content.as_deref(), Some("1.1.1.1/32"));
-        assert!(rule.flags.no_resolve);
+        assert!(rule.flags.no_resolve());
         assert_eq!(rule.render(), "IP-CIDR,1.1.1.1/32,Test,no-resolve");
     }
 
+    #[test]
+    fn multiple_flags_round_trip_in_order() {
+        let rule = parse_rule_line("IP-CIDR,10.0.0.0/8,src,no-resolve", "LAN")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.content.as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(rule.flags.modifiers, ["src", "no-resolve"]);
+        assert!(rule.flags.no_resolve());
+        assert_eq!(rule.render(), "IP-CIDR,10.0.0.0/8,LAN,src,no-resolve");
+
+        let rule = parse_rule_line("GEOIP,CN,no-resolve,src", "CN")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.render(), "GEOIP,CN,CN,no-resolve,src");
+    }
+
     #[test]
     fn parse_rule_with_comment() {
         let rule = parse_rule_line("DOMAIN-SUFFIX,example.com // comment here", "G")
@@ -477,4 +694,89 @@ mod tests {
             "AND,((DOMAIN-KEYWORD,example),(DOMAIN-SUFFIX,example.com)),G"
         );
     }
+
+    #[test]
+    fn specificity_sort_orders_within_buckets() {
+        let rule = |line: &str| parse_rule_line(line, "G").unwrap().unwrap();
+        let rules = vec![
+            rule("DOMAIN-KEYWORD,google"),
+            rule("GEOIP,CN"),
+            rule("MATCH"),
+            rule("DOMAIN-SUFFIX,google.com"),
+            rule("RULE-SET,ads"),
+            rule("IP-CIDR,1.1.1.1/32,no-resolve"),
+            rule("DOMAIN-REGEX,^ads?\\."),
+            rule("DOMAIN,www.google.com"),
+            rule("DOMAIN-SUFFIX,youtube.com"),
+        ];
+
+        let sorted: Vec<String> = sort_rules_by_specificity(&rules)
+            .iter()
+            .map(Rule::render)
+            .collect();
+        assert_eq!(
+            sorted,
+            [
+                "DOMAIN,www.google.com,G",
+                "IP-CIDR,1.1.1.1/32,G,no-resolve",
+                "DOMAIN-SUFFIX,google.com,G",
+                "RULE-SET,ads,G",
+                "GEOIP,CN,G",
+                "DOMAIN-SUFFIX,youtube.com,G",
+                "DOMAIN-KEYWORD,google,G",
+                "DOMAIN-REGEX,^ads?\\.,G",
+                "MATCH,G",
+            ]
+        );
+    }
+
+    #[test]
+    fn rule_provider_payloads_are_loaded() {
+        let dir = std::env::temp_dir().join(format!("subcon-rule-provider-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("classical.yaml"),
+            "# NAME: ads\npayload:\n  - DOMAIN-SUFFIX,ads.example\n  - IP-CIDR,10.0.0.0/8,no-resolve\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("rulesets.toml"),
+            r#"
+[[rulesets]]
+group = "REJECT"
+ruleset = "classical.yaml"
+
+[[rulesets]]
+group = "Proxy"
+ruleset = ["https://rules.example.com/domain.yaml", "https://rules.example.com/ipcidr.yaml"]
+"#,
+        )
+        .unwrap();
+
+        let rules = load_rules_with_fetcher(dir.join("rulesets.toml"), &dir, |url| {
+            Ok(if url.ends_with("domain.yaml") {
+                "payload:\n  - '+.google.com'\n  - '*.cdn.example'\n  - www.example.com\n"
+            } else {
+                "payload:\n  - 1.1.1.0/24\n  - '2001:db8::/32'\n  - 8.8.8.8\n"
+            }
+            .to_string())
+        })
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let rendered: Vec<String> = rules.iter().map(Rule::render).collect();
+        assert_eq!(
+            rendered,
+            [
+                "DOMAIN-SUFFIX,ads.example,REJECT",
+                "IP-CIDR,10.0.0.0/8,REJECT,no-resolve",
+                "DOMAIN-SUFFIX,google.com,Proxy",
+                "DOMAIN-WILDCARD,*.cdn.example,Proxy",
+                "DOMAIN,www.example.com,Proxy",
+                "IP-CIDR,1.1.1.0/24,Proxy",
+                "IP-CIDR6,2001:db8::/32,Proxy",
+                "IP-CIDR,8.8.8.8/32,Proxy",
+            ]
+        );
+    }
 }
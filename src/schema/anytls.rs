@@ -0,0 +1,108 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Map as JsonMap, Value};
+
+use super::ProtocolModule;
+
+pub struct AnytlsModule;
+
+impl ProtocolModule for AnytlsModule {
+    fn protocol(&self) -> &'static str {
+        "anytls"
+    }
+
+    fn validate(&self, normalized: &JsonMap<String, Value>) -> Result<()> {
+        if let Some(port) = normalized.get("port").and_then(|v| v.as_i64())
+            && !(1..=65535).contains(&port)
+        {
+            bail!("anytls port out of range: {port}");
+        }
+
+        let password = normalized
+            .get("password")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("anytls requires `password`"))?;
+        if password.is_empty() {
+            bail!("anytls password must not be empty");
+        }
+
+        if let Some(alpn) = normalized.get("alpn") {
+            let valid = alpn
+                .as_array()
+                .is_some_and(|items| items.iter().all(Value::is_string));
+            if !valid {
+                bail!("anytls alpn must be a list of strings, got {alpn}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+
+    fn node() -> JsonMap<String, Value> {
+        serde_json::json!({
+            "name": "anytls-node",
+            "type": "anytls",
+            "server": "example.com",
+            "port": 443,
+            "password": "secret",
+            "alpn": ["h2", "http/1.1"],
+            "idle-session-check-interval": "30",
+            "idle-session-timeout": 30,
+            "min-idle-session": 0,
+        })
+        .as_object()
+        .cloned()
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_node_renders_for_clash() {
+        let rendered = test_registry()
+            .convert("anytls", "clash", "anytls-node", &node())
+            .unwrap();
+        assert_eq!(rendered["type"], "anytls");
+        assert_eq!(rendered["password"], "secret");
+        assert_eq!(rendered["alpn"], serde_json::json!(["h2", "http/1.1"]));
+        assert_eq!(rendered["idle-session-check-interval"], 30);
+        assert_eq!(rendered["idle-session-timeout"], 30);
+        assert_eq!(rendered["min-idle-session"], 0);
+    }
+
+    #[test]
+    fn rejects_empty_password_and_bad_alpn() {
+        let mut values = node();
+        values.insert("password".to_string(), Value::String(String::new()));
+        let err = AnytlsModule.validate(&values).unwrap_err();
+        assert!(err.to_string().contains("password"), "{err}");
+
+        let mut values = node();
+        values.insert("alpn".to_string(), serde_json::json!(["h2", 2]));
+        let err = AnytlsModule.validate(&values).unwrap_err();
+        assert!(err.to_string().contains("alpn"), "{err}");
+
+        let mut values = node();
+        values.insert("port".to_string(), Value::from(70000));
+        assert!(AnytlsModule.validate(&values).is_err());
+        assert!(
+            test_registry()
+                .convert("anytls", "clash", "anytls-node", &values)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn surge_target_is_not_implemented() {
+        let registry = test_registry();
+        assert!(registry.target_not_implemented("anytls", "surge"));
+        assert!(
+            registry
+                .convert("anytls", "surge", "anytls-node", &node())
+                .is_err()
+        );
+    }
+}
@@ -14,7 +14,13 @@ use crate::export::{Exporter, FieldPruner, RenderPass, TypeInjector};
 use crate::parser::Parser;
 
 pub mod trojan;
+pub mod anytls;
 pub mod shadowsocks;
+pub mod snell;
+pub mod ssr;
+pub mod tls;
+pub mod tuic;
+pub mod vless;
 
 /// Protocol-specific hook for validation or other pre-render checks.
 pub trait ProtocolModule: Send + Sync {
@@ -28,9 +34,14 @@ pub trait ProtocolModule: Send + Sync {
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
 pub struct ProtocolSchema {
+    /// Empty for fragment files, which can only be pulled in through `includes`.
+    #[serde(default)]
     pub protocol: String,
     #[serde(default)]
     pub includes: Vec<String>,
+    /// Port filled in by `normalize` when a node omits `port`.
+    #[serde(default, rename = "default-port")]
+    pub default_port: Option<u16>,
     #[serde(default)]
     pub fields: BTreeMap<String, FieldSpec>,
     #[serde(default)]
@@ -42,6 +53,24 @@ pub struct ProtocolSchema {
 pub struct FieldSpec {
     #[serde(rename = "type")]
     pub ty: FieldType,
+    /// Coercion applied by `normalize` before the type check.
+    #[serde(default)]
+    pub transform: Option<Transform>,
+    /// Reason or replacement, warned about whenever a node still uses the field.
+    #[serde(default)]
+    pub deprecated: Option<String>,
+    /// Values the field may take, required for `type: enum`.
+    #[serde(default)]
+    pub allowed: Option<Vec<Value>>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transform {
+    ToInt,
+    ToBool,
+    ToString,
+    Lowercase,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -52,6 +81,8 @@ pub enum FieldType {
     Boolean,
     List,
     Map,
+    /// A string limited to the field's `allowed` values.
+    Enum,
 }
 
 #[allow(dead_code)]
@@ -73,10 +104,24 @@ pub struct FieldRef {
     pub default: Option<Value>,
 }
 
+/// Checks a normalized field: equality when `equals` is set, presence otherwise.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Condition {
+    pub field: String,
+    pub equals: Option<Value>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum ValueTemplate {
     Field(FieldRef),
+    /// Renders `then` only when the condition holds, e.g.
+    /// `{ when: { field: tls, equals: true }, then: { from: sni } }`.
+    When {
+        #[serde(rename = "when")]
+        condition: Condition,
+        then: Box<ValueTemplate>,
+    },
     Object(BTreeMap<String, ValueTemplate>),
     Sequence(Vec<ValueTemplate>),
     Literal(Value),
@@ -84,6 +129,10 @@ pub enum ValueTemplate {
 
 impl ProtocolSchema {
     fn absorb(&mut self, other: &ProtocolSchema, override_existing: bool) {
+        if other.default_port.is_some() && (override_existing || self.default_port.is_none()) {
+            self.default_port = other.default_port;
+        }
+
         for (field_name, field) in &other.fields {
             if override_existing || !self.fields.contains_key(field_name) {
                 self.fields.insert(field_name.clone(), field.clone());
@@ -101,19 +150,34 @@ impl ProtocolSchema {
     }
 
     pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let file = fs::File::open(&path)
+        let text = fs::read_to_string(&path)
             .with_context(|| format!("failed to open schema file {}", path.as_ref().display()))?;
-        serde_yaml::from_reader(file)
+        Self::from_yaml(&text)
             .with_context(|| format!("failed to parse schema file {}", path.as_ref().display()))
     }
 
+    pub fn from_yaml(text: &str) -> Result<Self> {
+        Ok(serde_yaml::from_str(text)?)
+    }
+
     pub fn normalize(&self, values: &JsonMap<String, Value>) -> Result<JsonMap<String, Value>> {
         let mut normalized = JsonMap::new();
 
         for (field_name, spec) in &self.fields {
             if let Some(value) = values.get(field_name) {
-                spec.validate(field_name, value)?;
-                normalized.insert(field_name.clone(), value.clone());
+                if let Some(reason) = &spec.deprecated {
+                    warn!(
+                        protocol = %self.protocol,
+                        field = %field_name,
+                        "field `{field_name}` is deprecated: {reason}"
+                    );
+                }
+                let value = match spec.transform {
+                    Some(transform) => transform.apply(field_name, value)?,
+                    None => value.clone(),
+                };
+                spec.validate(field_name, &value)?;
+                normalized.insert(field_name.clone(), value);
             }
         }
 
@@ -123,6 +187,16 @@ impl ProtocolSchema {
                 .or_insert_with(|| value.clone());
         }
 
+        if let Some(port) = self.default_port
+            && !normalized.contains_key("port")
+        {
+            let value = Value::from(port);
+            if let Some(spec) = self.fields.get("port") {
+                spec.validate("port", &value)?;
+            }
+            normalized.insert("port".to_string(), value);
+        }
+
         Ok(normalized)
     }
 
@@ -153,6 +227,22 @@ impl ProtocolSchema {
     }
 
     fn validate_templates(&self) -> Result<()> {
+        if self.default_port == Some(0) {
+            bail!(
+                "default-port of `{}` must be between 1 and 65535",
+                self.protocol
+            );
+        }
+        for (field_name, spec) in &self.fields {
+            if matches!(spec.ty, FieldType::Enum) && spec.allowed.as_ref().is_none_or(Vec::is_empty)
+            {
+                bail!(
+                    "enum field `{}` of `{}` must list its `allowed` values",
+                    field_name,
+                    self.protocol
+                );
+            }
+        }
         for (target_name, target) in &self.targets {
             validate_template_map(
                 &target.template,
@@ -166,16 +256,26 @@ impl ProtocolSchema {
 
 impl FieldSpec {
     fn validate(&self, name: &str, value: &Value) -> Result<()> {
-        if self.ty.matches(value) {
-            Ok(())
-        } else {
-            Err(anyhow!(
+        if !self.ty.matches(value) {
+            return Err(anyhow!(
                 "field `{}` expected type {:?}, got {}",
                 name,
                 self.ty,
                 describe_value(value)
-            ))
+            ));
+        }
+        if let Some(allowed) = &self.allowed
+            && !allowed.contains(value)
+        {
+            let allowed: Vec<String> = allowed.iter().map(display_value).collect();
+            return Err(anyhow!(
+                "field `{}` must be one of {}, got {}",
+                name,
+                allowed.join(", "),
+                display_value(value)
+            ));
         }
+        Ok(())
     }
 
     fn validate_value(&self, name: &str, value: &Value) -> Result<()> {
@@ -183,6 +283,56 @@ impl FieldSpec {
     }
 }
 
+impl Transform {
+    fn apply(self, name: &str, value: &Value) -> Result<Value> {
+        let converted = match (self, value) {
+            (Transform::ToInt, Value::Number(n)) if n.is_i64() || n.is_u64() => Some(value.clone()),
+            (Transform::ToInt, Value::String(s)) => {
+                let s = s.trim();
+                s.parse::<i64>()
+                    .map(Value::from)
+                    .or_else(|_| s.parse::<u64>().map(Value::from))
+                    .ok()
+            }
+            (Transform::ToBool, Value::Bool(_)) => Some(value.clone()),
+            (Transform::ToBool, Value::String(s)) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "1" | "yes" => Some(Value::Bool(true)),
+                "false" | "0" | "no" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (Transform::ToBool, Value::Number(n)) => match n.as_u64() {
+                Some(0) => Some(Value::Bool(false)),
+                Some(1) => Some(Value::Bool(true)),
+                _ => None,
+            },
+            (Transform::ToString, Value::String(_)) => Some(value.clone()),
+            (Transform::ToString, Value::Number(n)) => Some(Value::String(n.to_string())),
+            (Transform::ToString, Value::Bool(b)) => Some(Value::String(b.to_string())),
+            (Transform::Lowercase, Value::String(s)) => Some(Value::String(s.to_lowercase())),
+            _ => None,
+        };
+        converted.ok_or_else(|| {
+            anyhow!(
+                "field `{}` cannot apply transform {:?} to {} {}",
+                name,
+                self,
+                describe_value(value),
+                value
+            )
+        })
+    }
+}
+
+impl Condition {
+    fn matches(&self, ctx: &JsonMap<String, Value>) -> bool {
+        match (&self.equals, ctx.get(&self.field)) {
+            (Some(expected), Some(value)) => value == expected,
+            (None, Some(value)) => !value.is_null(),
+            (_, None) => false,
+        }
+    }
+}
+
 impl FieldType {
     fn matches(self, value: &Value) -> bool {
         match self {
@@ -191,6 +341,7 @@ impl FieldType {
             FieldType::Boolean => value.is_boolean(),
             FieldType::List => value.is_array(),
             FieldType::Map => value.is_object(),
+            FieldType::Enum => value.is_string(),
         }
     }
 }
@@ -228,7 +379,9 @@ fn render_object(
     let mut out = JsonMap::new();
 
     for (key, tmpl) in template {
-        if let Some(rendered) = render_template(tmpl, ctx)? {
+        let rendered =
+            render_template(tmpl, ctx).with_context(|| format!("failed to render `{key}`"))?;
+        if let Some(rendered) = rendered {
             out.insert(key.clone(), rendered);
         }
     }
@@ -283,6 +436,13 @@ fn render_template(
                 }
             }
         }
+        ValueTemplate::When { condition, then } => {
+            if condition.matches(ctx) {
+                render_template(then, ctx)
+            } else {
+                Ok(None)
+            }
+        }
         ValueTemplate::Object(map) => render_object(map, ctx),
         ValueTemplate::Sequence(items) => render_sequence(items, ctx),
     }
@@ -314,6 +474,18 @@ fn validate_template(
             }
             Ok(())
         }
+        ValueTemplate::When { condition, then } => {
+            let spec = fields.get(&condition.field).ok_or_else(|| {
+                anyhow!(
+                    "{ctx} condition references unknown field `{}`",
+                    condition.field
+                )
+            })?;
+            if let Some(expected) = &condition.equals {
+                spec.validate_value(&condition.field, expected)?;
+            }
+            validate_template(then, fields, ctx)
+        }
         ValueTemplate::Object(map) => validate_template_map(map, fields, ctx),
         ValueTemplate::Sequence(items) => {
             for item in items {
@@ -325,6 +497,14 @@ fn validate_template(
     }
 }
 
+/// A value as written in a schema, with strings unquoted.
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
 fn describe_value(value: &Value) -> &'static str {
     if value.is_null() {
         "null"
@@ -343,33 +523,61 @@ fn describe_value(value: &Value) -> &'static str {
     }
 }
 
+/// Rendering choices fixed when the registry is built.
+#[derive(Debug, Clone, Copy)]
+pub struct SchemaOptions {
+    /// Drop keys the target template does not produce, along with optional
+    /// and default values. When off, Clash output also keeps fields the
+    /// schema does not declare.
+    pub prune_fields: bool,
+}
+
+impl Default for SchemaOptions {
+    fn default() -> Self {
+        Self { prune_fields: true }
+    }
+}
+
 pub struct SchemaRegistry {
     protocols: HashMap<String, ProtocolSchema>,
+    /// Schemas as loaded, before includes are resolved.
+    raw_protocols: HashMap<String, ProtocolSchema>,
     modules: HashMap<String, Box<dyn ProtocolModule>>,
     exporters: HashMap<String, Box<dyn Exporter>>,
     default_exporters: HashMap<String, Box<dyn Exporter>>,
     parsers: HashMap<String, Box<dyn Parser>>,
     prologues: Vec<Box<dyn RenderPass>>,
+    options: SchemaOptions,
 }
 
 impl SchemaRegistry {
     pub fn load_from_dir(path: impl AsRef<Path>) -> Result<Self> {
         let dir = path.as_ref();
         let raw_protocols = load_protocol_files(dir)?;
-        let protocols = resolve_protocols(raw_protocols)?;
+        let protocols = resolve_protocols(raw_protocols.clone())?;
 
         Ok(Self {
             protocols,
+            raw_protocols,
             modules: HashMap::new(),
             exporters: HashMap::new(),
             default_exporters: HashMap::new(),
             parsers: HashMap::new(),
             prologues: Vec::new(),
+            options: SchemaOptions::default(),
         })
     }
 
+    /// Registry with the default [`SchemaOptions`]; the server builds its
+    /// registry through [`SchemaRegistry::with_options`].
+    #[cfg(test)]
     pub fn with_builtin(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_options(path, SchemaOptions::default())
+    }
+
+    pub fn with_options(path: impl AsRef<Path>, options: SchemaOptions) -> Result<Self> {
         let mut registry = Self::load_from_dir(path)?;
+        registry.options = options;
         registry.register_builtin_modules();
         registry.register_builtin_default_exporters();
         registry.register_builtin_parsers();
@@ -402,32 +610,79 @@ impl SchemaRegistry {
 
     fn register_builtin_modules(&mut self) {
         let available: Vec<String> = self.protocols.keys().cloned().collect();
+        if available.iter().any(|p| p == "anytls") {
+            self.register_module(Box::new(anytls::AnytlsModule));
+        }
         if available.iter().any(|p| p == "trojan") {
             self.register_module(Box::new(trojan::TrojanModule));
         }
         if available.iter().any(|p| p == "shadowsocks") {
             self.register_module(Box::new(shadowsocks::ShadowsocksModule));
         }
+        if available.iter().any(|p| p == "snell") {
+            self.register_module(Box::new(snell::SnellModule));
+        }
+        if available.iter().any(|p| p == "ssr") {
+            self.register_module(Box::new(ssr::SsrModule));
+        }
+        if available.iter().any(|p| p == "tuic") {
+            self.register_module(Box::new(tuic::TuicModule));
+        }
+        if available.iter().any(|p| p == "vless") {
+            self.register_module(Box::new(vless::VlessModule));
+        }
     }
 
     fn register_builtin_default_exporters(&mut self) {
         self.register_default_exporter(Box::new(crate::export::clash::ClashExporter));
         self.register_default_exporter(Box::new(crate::export::surge::SurgeExporter));
+        self.register_default_exporter(Box::new(crate::export::loon::LoonExporter));
     }
 
     fn register_builtin_parsers(&mut self) {
         self.register_parser(Box::new(crate::parser::clash::ClashParser));
+        self.register_parser(Box::new(crate::parser::uri::UriParser));
     }
 
     fn register_builtin_prologues(&mut self) {
-        self.register_prologue(Box::new(FieldPruner));
+        if self.options.prune_fields {
+            self.register_prologue(Box::new(FieldPruner));
+        }
         self.register_prologue(Box::new(TypeInjector));
     }
 
+    /// Checks a schema file's contents as if it replaced the loaded schema of
+    /// the same protocol, resolving includes against the loaded files. A
+    /// fragment is checked through the protocols that include `name`.
+    pub fn check_schema(&self, name: &str, text: &str) -> Result<()> {
+        let schema = ProtocolSchema::from_yaml(text)?;
+        let key = if schema.protocol.is_empty() {
+            name.to_string()
+        } else {
+            schema.protocol.clone()
+        };
+        let mut raw = self.raw_protocols.clone();
+        raw.insert(key, schema);
+        resolve_protocols(raw).map(|_| ())
+    }
+
     pub fn get(&self, protocol: &str) -> Option<&ProtocolSchema> {
         self.protocols.get(protocol)
     }
 
+    /// The spec of `field` in the first protocol, by name, declaring it.
+    pub fn field_spec(&self, field: &str) -> Option<&FieldSpec> {
+        let mut protocols: Vec<&ProtocolSchema> = self.protocols.values().collect();
+        protocols.sort_by(|a, b| a.protocol.cmp(&b.protocol));
+        protocols
+            .into_iter()
+            .find_map(|schema| schema.fields.get(field))
+    }
+
+    pub fn protocol_count(&self) -> usize {
+        self.protocols.len()
+    }
+
     pub fn target_not_implemented(&self, protocol: &str, target: &str) -> bool {
         self.protocols
             .get(protocol)
@@ -436,6 +691,14 @@ impl SchemaRegistry {
             .unwrap_or(false)
     }
 
+    /// Whether `protocol` declares an implemented template for `target`.
+    pub fn supports_target(&self, protocol: &str, target: &str) -> bool {
+        self.protocols
+            .get(protocol)
+            .and_then(|schema| schema.targets.get(target))
+            .is_some_and(|schema| !schema.not_implemented.unwrap_or(false))
+    }
+
     fn module(&self, protocol: &str) -> Option<&dyn ProtocolModule> {
         self.modules.get(protocol).map(|m| m.as_ref())
     }
@@ -453,11 +716,39 @@ impl SchemaRegistry {
         self.parsers.get(target).map(|p| p.as_ref())
     }
 
+    /// Renders the proxy `name` for `target`. Errors name the proxy, its
+    /// protocol and the target so a bad node in a large subscription can be
+    /// found.
     pub fn convert(
         &self,
         protocol: &str,
         target: &str,
+        name: &str,
         values: &JsonMap<String, Value>,
+    ) -> Result<Value> {
+        self.convert_values(protocol, target, values, false)
+            .with_context(|| format!("failed to convert proxy `{name}` ({protocol}) for {target}"))
+    }
+
+    /// Like [`Self::convert`], but also renders targets marked
+    /// `not-implemented`, so unfinished templates can be inspected.
+    pub fn convert_forced(
+        &self,
+        protocol: &str,
+        target: &str,
+        name: &str,
+        values: &JsonMap<String, Value>,
+    ) -> Result<Value> {
+        self.convert_values(protocol, target, values, true)
+            .with_context(|| format!("failed to convert proxy `{name}` ({protocol}) for {target}"))
+    }
+
+    fn convert_values(
+        &self,
+        protocol: &str,
+        target: &str,
+        values: &JsonMap<String, Value>,
+        force: bool,
     ) -> Result<Value> {
         let schema = self
             .get(protocol)
@@ -468,7 +759,7 @@ impl SchemaRegistry {
                 schema.protocol
             )
         })?;
-        if target_schema.not_implemented.unwrap_or(false) {
+        if !force && target_schema.not_implemented.unwrap_or(false) {
             return Err(anyhow!(
                 "protocol `{}` target `{target}` is not implemented",
                 schema.protocol
@@ -486,6 +777,18 @@ impl SchemaRegistry {
         for pass in &self.prologues {
             rendered = pass.render(protocol, target_schema, &normalized, rendered)?;
         }
+        // Profiles are Clash proxies, so undeclared keys are already spelled
+        // the way Clash expects.
+        if !self.options.prune_fields
+            && target == "clash"
+            && let Value::Object(map) = &mut rendered
+        {
+            for (key, value) in &normalized {
+                if !schema.fields.contains_key(key) && !map.contains_key(key) {
+                    map.insert(key.clone(), value.clone());
+                }
+            }
+        }
 
         if let Some(exporter) = self.exporter(target) {
             exporter.render(protocol, target_schema, &normalized, rendered)
@@ -509,15 +812,20 @@ fn load_protocol_files(dir: &Path) -> Result<HashMap<String, ProtocolSchema>> {
     let mut protocols = HashMap::new();
     let mut protocol_paths: HashMap<String, PathBuf> = HashMap::new();
 
+    // Entries are visited in sorted order, a directory's files before its
+    // subdirectories, so the first definition of a protocol is stable.
     let mut stack = vec![dir.to_path_buf()];
     while let Some(current) = stack.pop() {
-        for entry in fs::read_dir(&current)
+        let mut paths = fs::read_dir(&current)
             .with_context(|| format!("failed to read schema directory {}", current.display()))?
-        {
-            let entry = entry?;
-            let path = entry.path();
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        paths.sort();
+
+        let mut subdirs = Vec::new();
+        for path in paths {
             if path.is_dir() {
-                stack.push(path);
+                subdirs.push(path);
                 continue;
             }
             if path.extension().and_then(|s| s.to_str()) != Some("yaml") {
@@ -525,21 +833,31 @@ fn load_protocol_files(dir: &Path) -> Result<HashMap<String, ProtocolSchema>> {
             }
 
             let schema = ProtocolSchema::load_from_file(&path)?;
-            if let Some(existing) = protocol_paths.get(&schema.protocol) {
+            // Fragments have no protocol and are included by file name.
+            let name = if schema.protocol.is_empty() {
+                path.file_stem()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or_default()
+                    .to_string()
+            } else {
+                schema.protocol.clone()
+            };
+            if let Some(existing) = protocol_paths.get(&name) {
                 warn!(
-                    protocol = %schema.protocol,
+                    protocol = %name,
                     path = %path.display(),
                     existing = %existing.display(),
                     "duplicate protocol schema ignored"
                 );
                 continue;
             }
-            protocol_paths.insert(schema.protocol.clone(), path.clone());
-            protocols.insert(schema.protocol.clone(), schema);
+            protocol_paths.insert(name.clone(), path.clone());
+            protocols.insert(name, schema);
         }
+        stack.extend(subdirs.into_iter().rev());
     }
 
-    if protocols.is_empty() {
+    if protocols.values().all(|schema| schema.protocol.is_empty()) {
         return Err(anyhow!("no protocol schemas found under {}", dir.display()));
     }
 
@@ -556,6 +874,7 @@ fn resolve_protocols(
     for name in names {
         resolve_protocol(&name, &raw, &mut resolving, &mut resolved)?;
     }
+    resolved.retain(|_, schema| !schema.protocol.is_empty());
 
     Ok(resolved)
 }
@@ -581,6 +900,7 @@ fn resolve_protocol(
     let mut combined = ProtocolSchema {
         protocol: schema.protocol.clone(),
         includes: Vec::new(),
+        default_port: None,
         fields: BTreeMap::new(),
         targets: BTreeMap::new(),
     };
@@ -592,8 +912,305 @@ fn resolve_protocol(
 
     combined.absorb(schema, true);
     combined.includes.clear();
-    combined.validate_templates()?;
+    // Fragments may reference fields that only the including protocol defines.
+    if !combined.protocol.is_empty() {
+        combined.validate_templates()?;
+    }
     resolving.remove(name);
     cache.insert(name.to_string(), combined.clone());
     Ok(combined)
 }
+
+/// Registry built from the bundled `schema/` directory, shared by tests.
+#[cfg(test)]
+pub fn test_registry() -> SchemaRegistry {
+    SchemaRegistry::with_builtin(concat!(env!("CARGO_MANIFEST_DIR"), "/schema")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(yaml: &str) -> ProtocolSchema {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn values(value: Value) -> JsonMap<String, Value> {
+        value.as_object().cloned().unwrap()
+    }
+
+    #[test]
+    fn default_port_fills_missing_port() {
+        let schema =
+            schema("protocol: demo\ndefault-port: 443\nfields:\n  port:\n    type: integer\n");
+        let normalized = schema
+            .normalize(&values(serde_json::json!({ "server": "example.com" })))
+            .unwrap();
+        assert_eq!(normalized["port"], 443);
+
+        let explicit = schema
+            .normalize(&values(serde_json::json!({ "port": 8443 })))
+            .unwrap();
+        assert_eq!(explicit["port"], 8443);
+    }
+
+    #[test]
+    fn transform_coerces_string_port() {
+        let schema =
+            schema("protocol: demo\nfields:\n  port:\n    type: integer\n    transform: to-int\n");
+        let normalized = schema
+            .normalize(&values(serde_json::json!({ "port": "443" })))
+            .unwrap();
+        assert_eq!(normalized["port"], Value::from(443));
+
+        let err = schema
+            .normalize(&values(serde_json::json!({ "port": "https" })))
+            .unwrap_err();
+        assert!(err.to_string().contains("field `port`"), "{err}");
+    }
+
+    #[test]
+    fn transform_coerces_bool_and_lowercase() {
+        let schema = schema(
+            "protocol: demo\nfields:\n  udp:\n    type: boolean\n    transform: to-bool\n  cipher:\n    type: string\n    transform: lowercase\n",
+        );
+        let normalized = schema
+            .normalize(&values(
+                serde_json::json!({ "udp": "true", "cipher": "AES-128-GCM" }),
+            ))
+            .unwrap();
+        assert_eq!(normalized["udp"], true);
+        assert_eq!(normalized["cipher"], "aes-128-gcm");
+    }
+
+    #[test]
+    fn enum_fields_accept_only_allowed_values() {
+        let demo = schema(
+            "protocol: demo\nfields:\n  network:\n    type: enum\n    allowed: [tcp, ws, grpc, h2]\n    transform: lowercase\n",
+        );
+        demo.validate_templates().unwrap();
+        let normalized = demo
+            .normalize(&values(serde_json::json!({ "network": "WS" })))
+            .unwrap();
+        assert_eq!(normalized["network"], "ws");
+
+        let err = demo
+            .normalize(&values(serde_json::json!({ "network": "quic" })))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "field `network` must be one of tcp, ws, grpc, h2, got quic"
+        );
+
+        let missing = schema("protocol: demo\nfields:\n  network:\n    type: enum\n")
+            .validate_templates()
+            .unwrap_err();
+        assert!(missing.to_string().contains("allowed"), "{missing}");
+    }
+
+    #[test]
+    fn deprecated_field_warns_and_still_renders() {
+        #[derive(Clone, Default)]
+        struct Capture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let schema = schema(
+            "protocol: demo\nfields:\n  peer:\n    type: string\n    deprecated: use `sni` instead\ntargets:\n  clash:\n    template:\n      sni:\n        from: peer\n",
+        );
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        let rendered = tracing::subscriber::with_default(subscriber, || {
+            let normalized = schema
+                .normalize(&values(serde_json::json!({ "peer": "example.com" })))
+                .unwrap();
+            schema
+                .render_target(&schema.targets["clash"], &normalized)
+                .unwrap()
+        });
+        assert_eq!(rendered["sni"], "example.com");
+
+        let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("field `peer` is deprecated: use `sni` instead"),
+            "{logs}"
+        );
+    }
+
+    #[test]
+    fn protocol_includes_shared_fragment() {
+        let dir = std::env::temp_dir().join(format!("subcon-fragment-{}", std::process::id()));
+        fs::create_dir_all(dir.join("include")).unwrap();
+        fs::write(
+            dir.join("include/tls-fields.yaml"),
+            "fields:\n  sni:\n    type: string\ntargets:\n  clash:\n    template:\n      sni:\n        from: sni\n        optional: true\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("demo.yaml"),
+            "protocol: demo\nincludes:\n  - tls-fields\nfields:\n  server:\n    type: string\n",
+        )
+        .unwrap();
+
+        let registry = SchemaRegistry::load_from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(registry.protocols.len(), 1);
+        let demo = &registry.protocols["demo"];
+        assert!(demo.fields.contains_key("sni"));
+        assert!(demo.fields.contains_key("server"));
+        assert!(demo.targets["clash"].template.contains_key("sni"));
+    }
+
+    #[test]
+    fn duplicate_protocols_resolve_in_path_order() {
+        let dir = std::env::temp_dir().join(format!("subcon-duplicate-{}", std::process::id()));
+        fs::create_dir_all(dir.join("a-nested")).unwrap();
+        fs::write(dir.join("b.yaml"), "protocol: demo\ndefault-port: 2\n").unwrap();
+        fs::write(dir.join("a.yaml"), "protocol: demo\ndefault-port: 1\n").unwrap();
+        fs::write(
+            dir.join("a-nested/0.yaml"),
+            "protocol: demo\ndefault-port: 3\n",
+        )
+        .unwrap();
+
+        let registry = SchemaRegistry::load_from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Files sort before nested directories, then by name.
+        assert_eq!(registry.protocols["demo"].default_port, Some(1));
+    }
+
+    const TLS_GATED: &str = r#"protocol: demo
+fields:
+  server:
+    type: string
+  tls:
+    type: boolean
+  skip-cert-verify:
+    type: boolean
+targets:
+  clash:
+    template:
+      server:
+        from: server
+      skip-cert-verify:
+        when:
+          field: tls
+          equals: true
+        then:
+          from: skip-cert-verify
+          optional: true
+"#;
+
+    #[test]
+    fn conditional_template_requires_tls() {
+        let schema = schema(TLS_GATED);
+        schema.validate_templates().unwrap();
+        let render = |input: Value| {
+            let normalized = schema.normalize(&values(input)).unwrap();
+            schema
+                .render_target(&schema.targets["clash"], &normalized)
+                .unwrap()
+        };
+
+        let with_tls = render(serde_json::json!({
+            "server": "example.com", "tls": true, "skip-cert-verify": true
+        }));
+        assert_eq!(with_tls["skip-cert-verify"], true);
+
+        for input in [
+            serde_json::json!({ "server": "example.com", "tls": false, "skip-cert-verify": true }),
+            serde_json::json!({ "server": "example.com", "skip-cert-verify": true }),
+        ] {
+            let rendered = render(input);
+            assert!(rendered.get("skip-cert-verify").is_none(), "{rendered}");
+        }
+    }
+
+    #[test]
+    fn conditional_template_checks_condition_field() {
+        let schema = schema(&TLS_GATED.replace("field: tls", "field: missing"));
+        let err = schema.validate_templates().unwrap_err();
+        assert!(err.to_string().contains("unknown field `missing`"), "{err}");
+    }
+
+    #[test]
+    fn default_port_is_validated() {
+        let schema = schema("protocol: demo\ndefault-port: 0\n");
+        assert!(schema.validate_templates().is_err());
+        assert!(
+            serde_yaml::from_str::<ProtocolSchema>("protocol: demo\ndefault-port: 70000\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn convert_errors_name_proxy_protocol_and_field() {
+        let registry = test_registry();
+        let node = values(serde_json::json!({
+            "name": "HK 07",
+            "server": "hk.example.com",
+            "port": 443,
+        }));
+
+        let err = registry
+            .convert("trojan", "clash", "HK 07", &node)
+            .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains("proxy `HK 07` (trojan) for clash"),
+            "{message}"
+        );
+        assert!(
+            message.contains("missing required field `password`"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn undeclared_fields_survive_without_pruning() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/schema");
+        let node = values(serde_json::json!({
+            "name": "HK 07",
+            "server": "hk.example.com",
+            "port": 443,
+            "password": "secret",
+            "x-provider-tag": "edge",
+        }));
+
+        let pruned = SchemaRegistry::with_builtin(dir)
+            .unwrap()
+            .convert("trojan", "clash", "HK 07", &node)
+            .unwrap();
+        assert!(pruned.get("x-provider-tag").is_none(), "{pruned}");
+
+        let registry = SchemaRegistry::with_options(
+            dir,
+            SchemaOptions {
+                prune_fields: false,
+            },
+        )
+        .unwrap();
+        let clash = registry.convert("trojan", "clash", "HK 07", &node).unwrap();
+        assert_eq!(clash["x-provider-tag"], "edge");
+        assert_eq!(clash["password"], "secret");
+        // Line-based targets only get what their template renders.
+        let surge = registry.convert("trojan", "surge", "HK 07", &node).unwrap();
+        assert!(!surge.to_string().contains("x-provider-tag"), "{surge}");
+    }
+}
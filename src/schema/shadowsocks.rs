@@ -1,8 +1,40 @@
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
 use serde_json::{Map as JsonMap, Value};
+use tracing::warn;
 
 use super::ProtocolModule;
 
+const AEAD_CIPHERS: [&str; 5] = [
+    "aes-128-gcm",
+    "aes-192-gcm",
+    "aes-256-gcm",
+    "chacha20-ietf-poly1305",
+    "xchacha20-ietf-poly1305",
+];
+
+/// SIP022 ciphers with the length in bytes of their base64 encoded keys.
+const AEAD_2022_CIPHERS: [(&str, usize); 3] = [
+    ("2022-blake3-aes-128-gcm", 16),
+    ("2022-blake3-aes-256-gcm", 32),
+    ("2022-blake3-chacha20-poly1305", 32),
+];
+
+/// Stream ciphers and `none`, still accepted for old nodes.
+const LEGACY_CIPHERS: [&str; 11] = [
+    "none",
+    "aes-128-cfb",
+    "aes-192-cfb",
+    "aes-256-cfb",
+    "aes-128-ctr",
+    "aes-192-ctr",
+    "aes-256-ctr",
+    "rc4-md5",
+    "chacha20",
+    "chacha20-ietf",
+    "xchacha20",
+];
+
 pub struct ShadowsocksModule;
 
 impl ProtocolModule for ShadowsocksModule {
@@ -26,6 +58,134 @@ impl ProtocolModule for ShadowsocksModule {
             }
         }
 
+        if let Some(cipher) = normalized.get("cipher").and_then(|v| v.as_str()) {
+            let password = normalized
+                .get("password")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default();
+            validate_cipher(cipher, password)?;
+        }
+
         Ok(())
     }
 }
+
+/// Checks the key of a `2022-blake3-*` cipher. Other ciphers outside the
+/// known lists are only warned about, since clients add methods over time.
+fn validate_cipher(cipher: &str, password: &str) -> Result<()> {
+    if let Some(&(_, key_len)) = AEAD_2022_CIPHERS.iter().find(|(name, _)| *name == cipher) {
+        // Multi-user servers take `server_key:user_key`; each must fit the cipher.
+        for key in password.split(':') {
+            let decoded = STANDARD.decode(key).map_err(|_| {
+                anyhow!(
+                    "shadowsocks cipher `{cipher}` needs a base64 encoded {key_len}-byte key \
+                     as password, e.g. from `openssl rand -base64 {key_len}`"
+                )
+            })?;
+            if decoded.len() != key_len {
+                bail!(
+                    "shadowsocks cipher `{cipher}` needs a {key_len}-byte key, \
+                     but the password decodes to {} bytes",
+                    decoded.len()
+                );
+            }
+        }
+        return Ok(());
+    }
+    if !AEAD_CIPHERS.contains(&cipher) && !LEGACY_CIPHERS.contains(&cipher) {
+        match suggest_cipher(cipher) {
+            Some(suggestion) => warn!(cipher, suggestion, "unknown shadowsocks cipher"),
+            None => warn!(cipher, "unknown shadowsocks cipher"),
+        }
+    }
+    Ok(())
+}
+
+/// The known AEAD cipher that `cipher` is a prefix of, for truncated names.
+fn suggest_cipher(cipher: &str) -> Option<&'static str> {
+    AEAD_CIPHERS
+        .into_iter()
+        .chain(AEAD_2022_CIPHERS.map(|(name, _)| name))
+        .find(|name| !cipher.is_empty() && name.starts_with(cipher))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+
+    #[test]
+    fn clash_keeps_smux_and_udp_over_tcp() {
+        let node = serde_json::json!({
+            "name": "ss-node",
+            "server": "example.com",
+            "port": 8388,
+            "cipher": "aes-128-gcm",
+            "password": "secret",
+            "udp-over-tcp": true,
+            "udp-over-tcp-version": 2,
+            "smux": {
+                "enabled": true,
+                "protocol": "h2mux",
+                "brutal-opts": { "enabled": true, "up": 50, "down": 100 },
+            },
+        });
+        let rendered = test_registry()
+            .convert("shadowsocks", "clash", "ss-node", node.as_object().unwrap())
+            .unwrap();
+        assert_eq!(rendered["udp-over-tcp"], true);
+        assert_eq!(rendered["udp-over-tcp-version"], 2);
+        assert_eq!(rendered["smux"], node["smux"]);
+
+        let mut values = node.as_object().cloned().unwrap();
+        values.insert("udp-over-tcp-version".to_string(), Value::from(0));
+        assert!(ShadowsocksModule.validate(&values).is_err());
+    }
+
+    fn validate(cipher: &str, password: &str) -> Result<()> {
+        let node = serde_json::json!({
+            "name": "ss-node",
+            "server": "example.com",
+            "port": 8388,
+            "cipher": cipher,
+            "password": password,
+        });
+        ShadowsocksModule.validate(node.as_object().unwrap())
+    }
+
+    #[test]
+    fn aead_2022_node_with_a_matching_key_is_valid() {
+        let key = STANDARD.encode([7u8; 32]);
+        validate("2022-blake3-aes-256-gcm", &key).unwrap();
+        let user_key = STANDARD.encode([9u8; 32]);
+        validate(
+            "2022-blake3-chacha20-poly1305",
+            &format!("{key}:{user_key}"),
+        )
+        .unwrap();
+        validate("aes-128-gcm", "any password").unwrap();
+    }
+
+    #[test]
+    fn unknown_cipher_is_accepted_with_a_suggestion() {
+        validate("2022-blake3-aes-256", "secret").unwrap();
+        validate("aes-256-gmc", "secret").unwrap();
+
+        assert_eq!(
+            suggest_cipher("2022-blake3-aes-256"),
+            Some("2022-blake3-aes-256-gcm")
+        );
+        assert_eq!(suggest_cipher("aes-256-gmc"), None);
+    }
+
+    #[test]
+    fn aead_2022_key_must_match_the_cipher_length() {
+        let key = STANDARD.encode([7u8; 32]);
+        let err = validate("2022-blake3-aes-128-gcm", &key).unwrap_err();
+        assert!(err.to_string().contains("16-byte key"), "{err}");
+        assert!(err.to_string().contains("32 bytes"), "{err}");
+
+        let err = validate("2022-blake3-aes-128-gcm", "not base64!").unwrap_err();
+        assert!(err.to_string().contains("base64"), "{err}");
+    }
+}
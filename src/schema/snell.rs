@@ -0,0 +1,92 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Map as JsonMap, Value};
+
+use super::ProtocolModule;
+
+pub struct SnellModule;
+
+impl ProtocolModule for SnellModule {
+    fn protocol(&self) -> &'static str {
+        "snell"
+    }
+
+    fn validate(&self, normalized: &JsonMap<String, Value>) -> Result<()> {
+        if let Some(port) = normalized.get("port").and_then(|v| v.as_i64())
+            && !(1..=65535).contains(&port)
+        {
+            bail!("snell port out of range: {port}");
+        }
+
+        let psk = normalized
+            .get("psk")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("snell requires `psk`"))?;
+        if psk.is_empty() {
+            bail!("snell psk must not be empty");
+        }
+
+        if let Some(version) = normalized.get("version").and_then(|v| v.as_i64())
+            && !(1..=3).contains(&version)
+        {
+            bail!("snell version must be 1, 2 or 3, got {version}");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+
+    fn node() -> JsonMap<String, Value> {
+        serde_json::json!({
+            "name": "snell-node",
+            "type": "snell",
+            "server": "example.com",
+            "port": 8443,
+            "psk": "secret",
+            "version": "3",
+            "obfs-opts": { "mode": "http", "host": "bing.com" },
+        })
+        .as_object()
+        .cloned()
+        .unwrap()
+    }
+
+    #[test]
+    fn v3_node_with_obfs_renders_for_surge() {
+        let rendered = test_registry()
+            .convert("snell", "surge", "snell-node", &node())
+            .unwrap();
+        assert_eq!(
+            rendered,
+            "snell-node = snell, example.com, 8443, obfs=http, obfs-host=bing.com, psk=secret, version=3"
+        );
+    }
+
+    #[test]
+    fn clash_passes_obfs_opts_through() {
+        let rendered = test_registry()
+            .convert("snell", "clash", "snell-node", &node())
+            .unwrap();
+        assert_eq!(rendered["type"], "snell");
+        assert_eq!(rendered["psk"], "secret");
+        assert_eq!(rendered["version"], 3);
+        assert_eq!(rendered["obfs-opts"]["mode"], "http");
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut values = node();
+        values.insert("version".to_string(), Value::from(4));
+        let err = SnellModule.validate(&values).unwrap_err();
+        assert!(err.to_string().contains("version"), "{err}");
+        assert!(
+            test_registry()
+                .convert("snell", "surge", "snell-node", &values)
+                .is_err()
+        );
+    }
+}
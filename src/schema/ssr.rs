@@ -0,0 +1,88 @@
+use anyhow::{Result, bail};
+use serde_json::{Map as JsonMap, Value};
+
+use super::ProtocolModule;
+
+/// Obfuscation plugins understood by Clash's `ssr` type.
+const OBFS: [&str; 6] = [
+    "plain",
+    "http_simple",
+    "http_post",
+    "random_head",
+    "tls1.2_ticket_auth",
+    "tls1.2_ticket_fastauth",
+];
+
+/// Protocol plugins understood by Clash's `ssr` type.
+const PROTOCOLS: [&str; 6] = [
+    "origin",
+    "auth_sha1_v4",
+    "auth_aes128_md5",
+    "auth_aes128_sha1",
+    "auth_chain_a",
+    "auth_chain_b",
+];
+
+pub struct SsrModule;
+
+impl ProtocolModule for SsrModule {
+    fn protocol(&self) -> &'static str {
+        "ssr"
+    }
+
+    fn validate(&self, normalized: &JsonMap<String, Value>) -> Result<()> {
+        if let Some(obfs) = normalized.get("obfs").and_then(|v| v.as_str())
+            && !OBFS.contains(&obfs)
+        {
+            bail!(
+                "unsupported ssr obfs `{obfs}`, expected one of {}",
+                OBFS.join(", ")
+            );
+        }
+        if let Some(protocol) = normalized.get("protocol").and_then(|v| v.as_str())
+            && !PROTOCOLS.contains(&protocol)
+        {
+            bail!(
+                "unsupported ssr protocol `{protocol}`, expected one of {}",
+                PROTOCOLS.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+
+    #[test]
+    fn unknown_obfs_is_rejected() {
+        let registry = test_registry();
+        let mut node = serde_json::json!({
+            "name": "ssr-node",
+            "type": "ssr",
+            "server": "example.com",
+            "port": 8388,
+            "cipher": "aes-256-cfb",
+            "password": "secret",
+            "obfs": "plain",
+            "protocol": "origin",
+        })
+        .as_object()
+        .cloned()
+        .unwrap();
+        let clash = registry.convert("ssr", "clash", "ssr-node", &node).unwrap();
+        assert_eq!(clash["type"], "ssr");
+        assert_eq!(clash["obfs"], "plain");
+
+        node.insert("obfs".to_string(), Value::String("simple_obfs".to_string()));
+        let err = registry
+            .convert("ssr", "clash", "ssr-node", &node)
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("unsupported ssr obfs `simple_obfs`"),
+            "{err:#}"
+        );
+    }
+}
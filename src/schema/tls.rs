@@ -0,0 +1,102 @@
+use anyhow::{Result, anyhow, bail};
+use base64::{
+    Engine, alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
+use serde_json::{Map as JsonMap, Value};
+
+/// REALITY public keys are X25519 keys, i.e. 32 raw bytes.
+const REALITY_PUBLIC_KEY_LEN: usize = 32;
+/// REALITY short ids are at most 8 bytes, written as hex.
+const REALITY_SHORT_ID_MAX_LEN: usize = 16;
+
+/// Public keys are written with either alphabet, padded or not.
+const ANY_PADDING: GeneralPurposeConfig =
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+const BASE64_ENGINES: [GeneralPurpose; 2] = [
+    GeneralPurpose::new(&alphabet::STANDARD, ANY_PADDING),
+    GeneralPurpose::new(&alphabet::URL_SAFE, ANY_PADDING),
+];
+
+/// Validate a `reality-opts` block shared by protocols that support REALITY.
+pub fn validate_reality(map: &JsonMap<String, Value>) -> Result<()> {
+    let public_key = map
+        .get("public-key")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("reality-opts requires `public-key`"))?;
+    match base64_decoded_len(public_key) {
+        Some(REALITY_PUBLIC_KEY_LEN) => {}
+        Some(len) => bail!(
+            "reality-opts public-key must decode to {REALITY_PUBLIC_KEY_LEN} bytes, got {len}"
+        ),
+        None => bail!("reality-opts public-key is not valid base64"),
+    }
+
+    if let Some(short_id) = map.get("short-id") {
+        let short_id = short_id
+            .as_str()
+            .ok_or_else(|| anyhow!("reality-opts short-id must be a string"))?;
+        if short_id.len() > REALITY_SHORT_ID_MAX_LEN || short_id.len() % 2 != 0 {
+            bail!(
+                "reality-opts short-id must be an even number of hex digits (max {REALITY_SHORT_ID_MAX_LEN}), got {}",
+                short_id.len()
+            );
+        }
+        if !short_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("reality-opts short-id must be hex, got `{short_id}`");
+        }
+    }
+
+    Ok(())
+}
+
+/// Length in bytes of a standard or URL-safe base64 string, padded or not.
+fn base64_decoded_len(value: &str) -> Option<usize> {
+    BASE64_ENGINES
+        .iter()
+        .find_map(|engine| engine.decode(value).ok())
+        .map(|bytes| bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reality(value: Value) -> JsonMap<String, Value> {
+        value.as_object().cloned().unwrap()
+    }
+
+    #[test]
+    fn accepts_valid_reality_block() {
+        // The same key URL-safe and unpadded, then standard and padded.
+        for public_key in [
+            "jNXHt1yRo0vDuchQlIP6Z0ZvjT3KtzVI-T4E7RoLJS0",
+            "jNXHt1yRo0vDuchQlIP6Z0ZvjT3KtzVI+T4E7RoLJS0=",
+        ] {
+            let opts = reality(serde_json::json!({
+                "public-key": public_key,
+                "short-id": "0123abcd",
+            }));
+            validate_reality(&opts).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_missing_public_key() {
+        let opts = reality(serde_json::json!({ "short-id": "0123abcd" }));
+        let err = validate_reality(&opts).unwrap_err();
+        assert!(err.to_string().contains("public-key"));
+    }
+
+    #[test]
+    fn rejects_bad_short_id() {
+        for short_id in ["xyz1", "abc", "0123456789abcdef00"] {
+            let opts = reality(serde_json::json!({
+                "public-key": "jNXHt1yRo0vDuchQlIP6Z0ZvjT3KtzVI-T4E7RoLJS0",
+                "short-id": short_id,
+            }));
+            let err = validate_reality(&opts).unwrap_err();
+            assert!(err.to_string().contains("short-id"), "{short_id}: {err}");
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Map as JsonMap, Value};
+
+use super::ProtocolModule;
+
+const CONGESTION_CONTROLLERS: [&str; 3] = ["cubic", "new_reno", "bbr"];
+
+pub struct TuicModule;
+
+impl ProtocolModule for TuicModule {
+    fn protocol(&self) -> &'static str {
+        "tuic"
+    }
+
+    fn validate(&self, normalized: &JsonMap<String, Value>) -> Result<()> {
+        if let Some(port) = normalized.get("port").and_then(|v| v.as_i64())
+            && !(1..=65535).contains(&port)
+        {
+            bail!("tuic port out of range: {port}");
+        }
+
+        let uuid = normalized
+            .get("uuid")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("tuic requires `uuid`"))?;
+        if !is_valid_uuid(uuid) {
+            bail!("tuic uuid is not a valid UUID: {uuid}");
+        }
+
+        let password = normalized
+            .get("password")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("tuic requires `password`"))?;
+        if password.is_empty() {
+            bail!("tuic password must not be empty");
+        }
+
+        if let Some(controller) = normalized
+            .get("congestion-controller")
+            .and_then(|v| v.as_str())
+            && !CONGESTION_CONTROLLERS.contains(&controller)
+        {
+            bail!(
+                "tuic congestion-controller must be one of {}, got `{controller}`",
+                CONGESTION_CONTROLLERS.join("/")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn is_valid_uuid(value: &str) -> bool {
+    let groups: Vec<&str> = value.split('-').collect();
+    let lengths = [8, 4, 4, 4, 12];
+    groups.len() == lengths.len()
+        && groups
+            .iter()
+            .zip(lengths)
+            .all(|(group, len)| group.len() == len && group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+
+    fn node() -> JsonMap<String, Value> {
+        serde_json::json!({
+            "name": "tuic-node",
+            "type": "tuic",
+            "server": "example.com",
+            "port": 443,
+            "uuid": "d3b1a1f2-5c6e-4f7a-8b9c-0d1e2f3a4b5c",
+            "password": "secret",
+            "alpn": ["h3"],
+            "congestion-controller": "bbr",
+        })
+        .as_object()
+        .cloned()
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_node_renders_for_clash() {
+        let rendered = test_registry()
+            .convert("tuic", "clash", "tuic-node", &node())
+            .unwrap();
+        assert_eq!(rendered["type"], "tuic");
+        assert_eq!(rendered["congestion-controller"], "bbr");
+        assert_eq!(rendered["alpn"], serde_json::json!(["h3"]));
+    }
+
+    #[test]
+    fn rejects_unknown_congestion_controller() {
+        let mut values = node();
+        values.insert(
+            "congestion-controller".to_string(),
+            Value::String("reno".to_string()),
+        );
+        let err = TuicModule.validate(&values).unwrap_err();
+        assert!(err.to_string().contains("congestion-controller"));
+        assert!(
+            test_registry()
+                .convert("tuic", "clash", "tuic-node", &values)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Map as JsonMap, Value};
+
+use super::{ProtocolModule, tls};
+
+pub struct VlessModule;
+
+impl ProtocolModule for VlessModule {
+    fn protocol(&self) -> &'static str {
+        "vless"
+    }
+
+    fn validate(&self, normalized: &JsonMap<String, Value>) -> Result<()> {
+        if let Some(port) = normalized.get("port").and_then(|v| v.as_i64())
+            && !(1..=65535).contains(&port)
+        {
+            bail!("vless port out of range: {port}");
+        }
+
+        if let Some(reality) = normalized.get("reality-opts") {
+            let reality = reality
+                .as_object()
+                .ok_or_else(|| anyhow!("vless reality-opts must be a map"))?;
+            tls::validate_reality(reality)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(extra: Value) -> JsonMap<String, Value> {
+        let mut node = serde_json::json!({
+            "name": "vless-node",
+            "type": "vless",
+            "server": "example.com",
+            "port": 443,
+            "uuid": "b831381d-6324-4d53-ad4f-8cda48b30811",
+        })
+        .as_object()
+        .cloned()
+        .unwrap();
+        node.extend(extra.as_object().cloned().unwrap());
+        node
+    }
+
+    #[test]
+    fn node_with_reality_opts_is_valid() {
+        let node = node(serde_json::json!({
+            "reality-opts": {
+                "public-key": "jNXHt1yRo0vDuchQlIP6Z0ZvjT3KtzVI-T4E7RoLJS0",
+                "short-id": "0123abcd",
+            },
+        }));
+        VlessModule.validate(&node).unwrap();
+    }
+
+    #[test]
+    fn rejects_out_of_range_port() {
+        let err = VlessModule
+            .validate(&node(serde_json::json!({ "port": 70000 })))
+            .unwrap_err();
+        assert!(err.to_string().contains("port out of range"), "{err}");
+    }
+
+    #[test]
+    fn rejects_malformed_reality_opts() {
+        let err = VlessModule
+            .validate(&node(serde_json::json!({ "reality-opts": "key" })))
+            .unwrap_err();
+        assert!(err.to_string().contains("must be a map"), "{err}");
+
+        // 16 bytes instead of an X25519 key.
+        let err = VlessModule
+            .validate(&node(serde_json::json!({
+                "reality-opts": { "public-key": "AAAAAAAAAAAAAAAAAAAAAA==" },
+            })))
+            .unwrap_err();
+        assert!(err.to_string().contains("got 16"), "{err}");
+    }
+}
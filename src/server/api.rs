@@ -4,27 +4,35 @@ use std::{
 };
 
 use anyhow::Context;
+use base64::{Engine, engine::general_purpose::STANDARD};
 use axum::{
     Json, Router,
     body::Body,
-    extract::{Path as AxumPath, State},
-    http::{HeaderMap, HeaderValue, StatusCode, header},
+    extract::{
+        Path as AxumPath, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
     middleware::Next,
     response::{IntoResponse, Response},
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
 };
 use serde::{Deserialize, Serialize};
-use toml_edit::{Array, DocumentMut, Item, Value};
+use toml_edit::{Array, ArrayOfTables, DocumentMut, Item, Table, Value};
 use tokio::fs;
+use tokio::sync::broadcast;
 use tracing::{info, warn};
 
 use crate::config::Pref;
 use crate::logging;
 use crate::paths::resolve_path;
-use crate::server::util::load_group_specs_from_pref;
+use crate::schema::SchemaRegistry;
+use crate::server::util::{apply_node_pref, is_remote_base, load_group_specs_from_pref};
 use crate::{groups, proxy};
 
-use super::{ApiError, AppState, build_runtime};
+use super::history::{self, HistoryEntry};
+use super::reachability::{self, Endpoint, ReachabilityResult};
+use super::{ApiError, AppState, RenderArgs, RuntimeSummary, render_cache};
 
 pub fn router(state: AppState) -> Router<AppState> {
     Router::new()
@@ -34,21 +42,36 @@ pub fn router(state: AppState) -> Router<AppState> {
         .route("/config/rule-base/{target}", get(get_rule_base).put(update_rule_base))
         .route("/profiles", get(list_profiles))
         .route("/profiles/{name}", get(get_profile).put(update_profile).delete(delete_profile))
+        .route("/profiles/order", post(reorder_profiles))
         .route("/profiles/{name}/rename", post(rename_profile))
         .route("/rules", get(list_rules))
         .route("/rules/{name}", get(get_rule).put(update_rule).delete(delete_rule))
         .route("/rules/{name}/rename", post(rename_rule))
         .route("/schema", get(list_schema))
+        .route("/schema/validate", post(validate_schema))
         .route("/schema/{*path}", get(get_schema).put(update_schema))
         .route("/logs", get(get_logs))
-        .route("/groups", get(get_groups))
+        .route("/logs/stream", get(stream_logs))
+        .route("/groups", get(get_groups).post(create_group))
+        .route("/groups/{name}", delete(delete_group))
         .route("/groups/members", post(update_group_members))
+        .route("/groups/order", post(reorder_groups))
         .route("/snippets/groups", get(get_groups_snippet).put(update_groups_snippet))
         .route("/snippets/rulesets", get(get_rulesets_snippet).put(update_rulesets_snippet))
         .route("/cache", get(get_cache))
+        .route("/history/{kind}/{*name}", get(list_history).post(restore_history))
+        .route("/proxies/reachability", get(check_reachability))
+        .route("/validate/proxies", get(validate_proxies))
+        .route("/render/diff", get(render_diff))
+        .route("/test/node", post(test_node))
+        .route("/convert", post(convert_node))
         .route("/control/reload", post(control_reload))
         .route("/control/token", post(control_set_api_token))
         .route("/control/restart", post(control_restart))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            refresh_render_cache,
+        ))
         .layer(axum::middleware::from_fn_with_state(state, api_auth))
         .layer(axum::middleware::from_fn(api_no_cache))
 }
@@ -65,6 +88,8 @@ struct ConfigResponse {
     managed_base_url: Option<String>,
     api_auth_required: bool,
     server: ServerInfo,
+    /// A reload failed, so the files on disk differ from what is served.
+    config_dirty: bool,
 }
 
 #[derive(Serialize)]
@@ -76,6 +101,38 @@ struct ServerInfo {
 #[derive(Serialize)]
 struct FileListResponse {
     items: Vec<FileEntry>,
+    /// Entries matching `q`, before `offset` and `limit` are applied.
+    total: usize,
+}
+
+impl FileListResponse {
+    /// Keeps the entries whose name contains `q`, ignoring case, and returns
+    /// the requested page of them in their existing order.
+    fn filtered(entries: Vec<FileEntry>, query: &FileListQuery) -> Self {
+        let needle = query.q.as_deref().map(str::to_lowercase);
+        let matching: Vec<FileEntry> = entries
+            .into_iter()
+            .filter(|entry| {
+                needle
+                    .as_deref()
+                    .is_none_or(|needle| entry.name.to_lowercase().contains(needle))
+            })
+            .collect();
+        let total = matching.len();
+        let items = matching
+            .into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+        Self { items, total }
+    }
+}
+
+#[derive(Deserialize)]
+struct FileListQuery {
+    q: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
 }
 
 #[derive(Serialize)]
@@ -100,6 +157,20 @@ struct UpdateFileRequest {
     content: String,
 }
 
+#[derive(Deserialize)]
+struct ValidateSchemaRequest {
+    content: String,
+    /// Where the content would be saved; names a fragment for its includers.
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ValidateSchemaResponse {
+    ok: bool,
+    errors: Vec<String>,
+}
+
 #[derive(Deserialize)]
 struct RenameFileRequest {
     name: String,
@@ -121,6 +192,25 @@ struct GroupMemberUpdate {
     proxies: Vec<String>,
 }
 
+#[derive(Deserialize)]
+struct CreateGroupRequest {
+    name: String,
+    group_type: String,
+    #[serde(default)]
+    rules: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ReorderRequest {
+    order: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ReorderResponse {
+    ok: bool,
+    order: Vec<String>,
+}
+
 #[derive(Serialize)]
 struct UpdateFileResponse {
     ok: bool,
@@ -152,11 +242,82 @@ struct UpdateGroupMembersResponse {
 #[derive(Serialize)]
 struct LogResponse {
     items: Vec<String>,
+    /// Number of the newest captured line, to pass back as `since_line`.
+    last_line: u64,
 }
 
 #[derive(Deserialize)]
 struct LogQuery {
     limit: Option<usize>,
+    level: Option<String>,
+    contains: Option<String>,
+    since_line: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ReachabilityQuery {
+    concurrency: Option<usize>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ReachabilityResponse {
+    items: Vec<ReachabilityResult>,
+}
+
+#[derive(Deserialize)]
+struct ValidateProxiesQuery {
+    target: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ValidateProxiesResponse {
+    target: String,
+    checked: usize,
+    errors: Vec<ProxyValidationError>,
+}
+
+#[derive(Serialize)]
+struct ProxyValidationError {
+    name: String,
+    protocol: String,
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct RenderDiffQuery {
+    a: String,
+    b: String,
+}
+
+#[derive(Serialize)]
+struct RenderDiffResponse {
+    a: String,
+    b: String,
+    /// Nodes rendered for `a` but dropped as not implemented for `b`.
+    only_in_a: Vec<RenderDiffNode>,
+    only_in_b: Vec<RenderDiffNode>,
+    common: usize,
+}
+
+#[derive(Serialize)]
+struct RenderDiffNode {
+    name: String,
+    protocol: String,
+}
+
+#[derive(Deserialize)]
+struct NodeTestRequest {
+    server: String,
+    port: u16,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct NodeTestResponse {
+    reachable: bool,
+    latency_ms: Option<u64>,
+    error: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -165,6 +326,13 @@ struct ControlResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct ReloadResponse {
+    ok: bool,
+    message: String,
+    summary: RuntimeSummary,
+}
+
 #[derive(Serialize)]
 struct GroupResponse {
     items: Vec<GroupEntry>,
@@ -181,6 +349,16 @@ struct GroupEntry {
     proxies: Vec<String>,
 }
 
+#[derive(Serialize)]
+struct HistoryResponse {
+    items: Vec<HistoryEntry>,
+}
+
+#[derive(Deserialize)]
+struct RestoreRequest {
+    version: u64,
+}
+
 #[derive(Serialize)]
 struct CacheResponse {
     items: Vec<CacheEntry>,
@@ -189,7 +367,9 @@ struct CacheResponse {
 #[derive(Serialize)]
 struct CacheEntry {
     url: String,
+    user_agent: String,
     ttl_seconds: u64,
+    ttl_human: String,
 }
 
 #[derive(Serialize)]
@@ -197,6 +377,21 @@ struct PingResponse {
     ok: bool,
 }
 
+#[derive(Deserialize)]
+struct ConvertRequest {
+    protocol: String,
+    target: String,
+    #[serde(default)]
+    values: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct ConvertResponse {
+    protocol: String,
+    target: String,
+    result: serde_json::Value,
+}
+
 async fn api_no_cache(req: axum::http::Request<Body>, next: Next) -> Response {
     let mut res = next.run(req).await;
     let headers = res.headers_mut();
@@ -211,6 +406,22 @@ async fn api_no_cache(req: axum::http::Request<Body>, next: Next) -> Response {
     res
 }
 
+/// Successful writes can change what `/sub` renders, so warm targets are
+/// re-rendered in the background afterwards.
+async fn refresh_render_cache(
+    State(state): State<AppState>,
+    req: axum::http::Request<Body>,
+    next: Next,
+) -> Response {
+    let is_write = req.method() != Method::GET;
+    let res = next.run(req).await;
+    if is_write && res.status().is_success() {
+        state.etag_cache.clear().await;
+        tokio::spawn(async move { render_cache::refresh(&state).await });
+    }
+    res
+}
+
 async fn api_auth(
     State(state): State<AppState>,
     req: axum::http::Request<Body>,
@@ -234,13 +445,24 @@ async fn api_auth(
     next.run(req).await
 }
 
+/// The API token from `Authorization: Bearer`, the password of
+/// `Authorization: Basic` (the user name is ignored), or `x-subcon-token`.
 fn extract_token(headers: &HeaderMap) -> Option<String> {
-    if let Some(value) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+    if let Some(value) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
         let mut parts = value.split_whitespace();
         if let (Some(scheme), Some(token)) = (parts.next(), parts.next()) {
             if scheme.eq_ignore_ascii_case("bearer") {
                 return Some(token.to_string());
             }
+            if scheme.eq_ignore_ascii_case("basic") {
+                let decoded = STANDARD.decode(token).ok()?;
+                let credentials = String::from_utf8(decoded).ok()?;
+                let (_, password) = credentials.split_once(':')?;
+                return Some(password.to_string());
+            }
         }
     }
     headers
@@ -351,6 +573,7 @@ async fn get_config(State(state): State<AppState>) -> Result<Json<ConfigResponse
         managed_base_url: pref.managed_config.base_url.clone(),
         api_auth_required,
         server,
+        config_dirty: runtime.is_dirty(),
     }))
 }
 
@@ -358,14 +581,27 @@ async fn update_pref(
     State(state): State<AppState>,
     Json(body): Json<UpdateFileRequest>,
 ) -> Result<Json<UpdateFileResponse>, ApiError> {
+    let bytes = replace_pref(&state, &body.content).await?;
+    let path = &state.pref_path;
+    info!(path = %path.display(), bytes, "pref updated");
+    Ok(Json(UpdateFileResponse {
+        ok: true,
+        path: path.display().to_string(),
+        bytes,
+    }))
+}
+
+/// Writes `pref.toml` and swaps in the rebuilt runtime, restoring the old
+/// file when the new one does not load.
+async fn replace_pref(state: &AppState, content: &str) -> Result<usize, ApiError> {
     let path = &state.pref_path;
     let previous = match fs::read_to_string(path).await {
         Ok(text) => Some(text),
         Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
         Err(err) => return Err(ApiError::internal(err)),
     };
-    let bytes = write_file(path, &body.content).await?;
-    let runtime = match build_runtime(path, &state.base_dir) {
+    let bytes = write_tracked(state, "config", "pref", path, content).await?;
+    let runtime = match state.load_runtime(path) {
         Ok(runtime) => runtime,
         Err(err) => {
             if let Some(previous) = previous {
@@ -376,14 +612,114 @@ async fn update_pref(
             return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
         }
     };
+    *state.runtime.write().await = runtime;
+    Ok(bytes)
+}
+
+async fn reorder_profiles(
+    State(state): State<AppState>,
+    Json(body): Json<ReorderRequest>,
+) -> Result<Json<ReorderResponse>, ApiError> {
+    let path = &state.pref_path;
+    let previous = read_file(path).await?;
+    let (output, order) = reorder_default_url(&previous, &body.order)?;
+    write_tracked(&state, "config", "pref", path, &output).await?;
+    let runtime = match state.load_runtime(path) {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            let _ = write_file(path, &previous).await;
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
+        }
+    };
     let mut guard = state.runtime.write().await;
     *guard = runtime;
-    info!(path = %path.display(), bytes, "pref updated");
-    Ok(Json(UpdateFileResponse {
-        ok: true,
-        path: path.display().to_string(),
-        bytes,
-    }))
+    info!(path = %path.display(), "profile order updated");
+    Ok(Json(ReorderResponse { ok: true, order }))
+}
+
+/// Reorders `common.default_url`, keeping comments and layout. Names match an
+/// entry exactly or by file name.
+fn reorder_default_url(text: &str, order: &[String]) -> Result<(String, Vec<String>), ApiError> {
+    let mut doc: DocumentMut = text.parse().map_err(|err| {
+        ApiError::new(StatusCode::BAD_REQUEST, format!("invalid pref.toml: {err}"))
+    })?;
+    let array = doc
+        .get_mut("common")
+        .and_then(|common| common.get_mut("default_url"))
+        .and_then(|item| item.as_array_mut())
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "`common.default_url` must be an array",
+            )
+        })?;
+    let entries: Vec<String> = array
+        .iter()
+        .map(|value| value.as_str().unwrap_or_default().to_string())
+        .collect();
+    let order: Vec<String> = order
+        .iter()
+        .map(|name| {
+            let name = name.trim();
+            entries
+                .iter()
+                .find(|entry| *entry == name)
+                .or_else(|| {
+                    entries.iter().find(|entry| {
+                        Path::new(entry).file_name().and_then(|file| file.to_str()) == Some(name)
+                    })
+                })
+                .cloned()
+                .unwrap_or_else(|| name.to_string())
+        })
+        .collect();
+    let indices = resolve_order(&entries, &order, "profile")?;
+
+    let values: Vec<Value> = array.iter().cloned().collect();
+    let multiline = values.iter().any(|value| {
+        let decor = value.decor();
+        [decor.prefix(), decor.suffix()]
+            .into_iter()
+            .flatten()
+            .any(|raw| raw.as_str().is_some_and(|raw| raw.contains('\n')))
+    });
+    array.clear();
+    for &index in &indices {
+        array.push_formatted(values[index].clone());
+    }
+    if !multiline {
+        // Inline arrays carry no comments; re-spacing keeps `[a, b]` tidy.
+        array.fmt();
+    }
+
+    let order = indices
+        .into_iter()
+        .map(|index| entries[index].clone())
+        .collect();
+    Ok((doc.to_string(), order))
+}
+
+/// Indices of `items` in the requested `order`. Items left out keep their
+/// relative order after the listed ones.
+fn resolve_order(items: &[String], order: &[String], kind: &str) -> Result<Vec<usize>, ApiError> {
+    let mut indices = Vec::with_capacity(items.len());
+    for name in order {
+        let index = items.iter().position(|item| item == name).ok_or_else(|| {
+            ApiError::new(StatusCode::BAD_REQUEST, format!("unknown {kind} `{name}`"))
+        })?;
+        if indices.contains(&index) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("duplicate {kind} `{name}`"),
+            ));
+        }
+        indices.push(index);
+    }
+    let rest: Vec<usize> = (0..items.len())
+        .filter(|index| !indices.contains(index))
+        .collect();
+    indices.extend(rest);
+    Ok(indices)
 }
 
 async fn get_rule_base(
@@ -407,7 +743,8 @@ async fn update_rule_base(
 ) -> Result<Json<UpdateFileResponse>, ApiError> {
     let runtime = state.runtime.read().await.clone();
     let file = resolve_rule_base_path(&runtime.pref, &state.base_dir, &target)?;
-    let bytes = write_file(&file, &body.content).await?;
+    let name = format!("rule-base/{target}");
+    let bytes = write_tracked(&state, "config", &name, &file, &body.content).await?;
     info!(path = %file.display(), bytes, target = %target, "rule base updated");
     Ok(Json(UpdateFileResponse {
         ok: true,
@@ -416,7 +753,10 @@ async fn update_rule_base(
     }))
 }
 
-async fn list_profiles(State(state): State<AppState>) -> Result<Json<FileListResponse>, ApiError> {
+async fn list_profiles(
+    State(state): State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<FileListQuery>,
+) -> Result<Json<FileListResponse>, ApiError> {
     let runtime = state.runtime.read().await.clone();
     let root = resolve_profiles_dir(&state.base_dir);
     let mut entries = list_files_flat(&root, &["yaml", "yml"]).await?;
@@ -424,16 +764,14 @@ async fn list_profiles(State(state): State<AppState>) -> Result<Json<FileListRes
     let usage = build_profile_usage(&runtime.pref, &state.base_dir);
     for entry in &mut entries {
         let path = root.join(&entry.name);
-        let key = path
-            .canonicalize()
-            .unwrap_or(path);
+        let key = path.canonicalize().unwrap_or(path);
         if let Some(tags) = usage.get(&key) {
             entry.in_use = true;
             entry.usage = tags.clone();
         }
     }
 
-    Ok(Json(FileListResponse { items: entries }))
+    Ok(Json(FileListResponse::filtered(entries, &query)))
 }
 
 async fn get_profile(
@@ -457,7 +795,7 @@ async fn update_profile(
 ) -> Result<Json<UpdateFileResponse>, ApiError> {
     let root = resolve_profiles_dir(&state.base_dir);
     let file = resolve_single_file(&root, &name, &["yaml", "yml"])?;
-    let bytes = write_file(&file, &body.content).await?;
+    let bytes = write_tracked(&state, "profiles", &name, &file, &body.content).await?;
     info!(path = %file.display(), bytes, "profile updated");
     Ok(Json(UpdateFileResponse {
         ok: true,
@@ -499,10 +837,13 @@ async fn delete_profile(
     }))
 }
 
-async fn list_rules(State(state): State<AppState>) -> Result<Json<FileListResponse>, ApiError> {
+async fn list_rules(
+    State(state): State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<FileListQuery>,
+) -> Result<Json<FileListResponse>, ApiError> {
     let root = resolve_rules_dir(&state.base_dir);
     let entries = list_files_flat(&root, &["list", "yaml", "yml"]).await?;
-    Ok(Json(FileListResponse { items: entries }))
+    Ok(Json(FileListResponse::filtered(entries, &query)))
 }
 
 async fn get_rule(
@@ -526,7 +867,7 @@ async fn update_rule(
 ) -> Result<Json<UpdateFileResponse>, ApiError> {
     let root = resolve_rules_dir(&state.base_dir);
     let file = resolve_single_file(&root, &name, &["list", "yaml", "yml"])?;
-    let bytes = write_file(&file, &body.content).await?;
+    let bytes = write_tracked(&state, "rules", &name, &file, &body.content).await?;
     info!(path = %file.display(), bytes, "rules file updated");
     Ok(Json(UpdateFileResponse {
         ok: true,
@@ -569,11 +910,14 @@ async fn delete_rule(
     }))
 }
 
-async fn list_schema(State(state): State<AppState>) -> Result<Json<FileListResponse>, ApiError> {
+async fn list_schema(
+    State(state): State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<FileListQuery>,
+) -> Result<Json<FileListResponse>, ApiError> {
     let runtime = state.runtime.read().await.clone();
     let root = resolve_schema_dir(&runtime.pref, &state.base_dir)?;
     let entries = list_files_recursive(&root, &["yaml", "yml"]).await?;
-    Ok(Json(FileListResponse { items: entries }))
+    Ok(Json(FileListResponse::filtered(entries, &query)))
 }
 
 async fn get_schema(
@@ -599,7 +943,7 @@ async fn update_schema(
     let runtime = state.runtime.read().await.clone();
     let root = resolve_schema_dir(&runtime.pref, &state.base_dir)?;
     let file = resolve_nested_file(&root, &path, &["yaml", "yml"])?;
-    let bytes = write_file(&file, &body.content).await?;
+    let bytes = write_tracked(&state, "schema", &path, &file, &body.content).await?;
     info!(path = %file.display(), bytes, "schema updated");
     Ok(Json(UpdateFileResponse {
         ok: true,
@@ -608,12 +952,241 @@ async fn update_schema(
     }))
 }
 
+async fn validate_schema(
+    State(state): State<AppState>,
+    Json(body): Json<ValidateSchemaRequest>,
+) -> Json<ValidateSchemaResponse> {
+    let runtime = state.runtime.read().await.clone();
+    let name = body
+        .path
+        .as_deref()
+        .and_then(|path| Path::new(path).file_stem())
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let errors = match runtime.registry.check_schema(name, &body.content) {
+        Ok(()) => Vec::new(),
+        Err(err) => vec![format!("{err:#}")],
+    };
+    Json(ValidateSchemaResponse {
+        ok: errors.is_empty(),
+        errors,
+    })
+}
+
 async fn get_logs(
     State(_state): State<AppState>,
     axum::extract::Query(query): axum::extract::Query<LogQuery>,
 ) -> Result<Json<LogResponse>, ApiError> {
-    let items = logging::get_logs(query.limit);
-    Ok(Json(LogResponse { items }))
+    let level = match query.level.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(level) => Some(level.parse().map_err(|_| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("unknown log level `{level}`, expected trace, debug, info, warn or error"),
+            )
+        })?),
+    };
+    let filter = logging::LogFilter {
+        limit: query.limit,
+        level,
+        contains: query.contains.filter(|needle| !needle.is_empty()),
+        since_line: query.since_line,
+    };
+    let (entries, last_line) = logging::get_logs(&filter);
+    let items = entries.into_iter().map(|entry| entry.text).collect();
+    Ok(Json(LogResponse { items, last_line }))
+}
+
+async fn check_reachability(
+    State(state): State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<ReachabilityQuery>,
+) -> Result<Json<ReachabilityResponse>, ApiError> {
+    require_node_test(&state).await?;
+    let runtime = state.runtime.read().await.clone();
+    let overrides = runtime.pref.protocol_overrides(&state.base_dir);
+    let proxies = proxy::collect_profile_files(&resolve_profiles_dir(&state.base_dir))
+        .and_then(|paths| proxy::load_from_paths(&runtime.registry, paths, &overrides))
+        .map_err(ApiError::internal)?;
+    let endpoints = proxies.iter().filter_map(Endpoint::from_proxy).collect();
+    let (concurrency, timeout) = reachability::limits(query.concurrency, query.timeout_ms);
+    let items = reachability::check_all(endpoints, concurrency, timeout).await;
+    state.latency_cache.record(&items).await;
+    Ok(Json(ReachabilityResponse { items }))
+}
+
+/// Converts every proxy in the profiles directory for `target` (default
+/// `clash`) and reports each failure instead of stopping at the first one.
+/// Protocols marked `not-implemented` for the target are skipped, as when
+/// rendering.
+async fn validate_proxies(
+    State(state): State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<ValidateProxiesQuery>,
+) -> Result<Json<ValidateProxiesResponse>, ApiError> {
+    let target = query.target.unwrap_or_else(|| "clash".to_string());
+    let runtime = state.runtime.read().await.clone();
+    let registry = &runtime.registry;
+    let overrides = runtime.pref.protocol_overrides(&state.base_dir);
+    let mut proxies = proxy::collect_profile_files(&resolve_profiles_dir(&state.base_dir))
+        .and_then(|paths| proxy::load_from_paths(registry, paths, &overrides))
+        .map_err(ApiError::internal)?;
+    apply_node_pref(&runtime.pref, registry, &mut proxies);
+    proxies.retain(|proxy| !registry.target_not_implemented(&proxy.protocol, &target));
+
+    let errors = proxies
+        .iter()
+        .filter_map(|proxy| {
+            let err = proxy.to_target(registry, &target).err()?;
+            Some(ProxyValidationError {
+                name: proxy.name.clone(),
+                protocol: proxy.protocol.clone(),
+                error: format!("{err:#}"),
+            })
+        })
+        .collect();
+    Ok(Json(ValidateProxiesResponse {
+        target,
+        checked: proxies.len(),
+        errors,
+    }))
+}
+
+async fn render_diff(
+    State(state): State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<RenderDiffQuery>,
+) -> Result<Json<RenderDiffResponse>, ApiError> {
+    let renderer = |target: &str| {
+        state.targets.get(target).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("unsupported target {target}"),
+            )
+        })
+    };
+    let (renderer_a, renderer_b) = (renderer(&query.a)?, renderer(&query.b)?);
+    let runtime = state.runtime.read().await.clone();
+    let registry = &runtime.registry;
+    let overrides = runtime.pref.protocol_overrides(&state.base_dir);
+    let mut proxies = proxy::collect_profile_files(&resolve_profiles_dir(&state.base_dir))
+        .and_then(|paths| proxy::load_from_paths(registry, paths, &overrides))
+        .map_err(ApiError::internal)?;
+
+    // Both targets must render; their bodies are not part of the report.
+    for (target, renderer) in [(&query.a, renderer_a), (&query.b, renderer_b)] {
+        renderer.render(RenderArgs {
+            runtime: &runtime,
+            base_dir: &state.base_dir,
+            proxies: proxies.clone(),
+            request_uri: Some(format!("/sub?target={target}")),
+            scope_token: None,
+            base64: false,
+            presorted: false,
+            force: false,
+            selected_groups: None,
+        })?;
+    }
+
+    apply_node_pref(&runtime.pref, registry, &mut proxies);
+    let mut report = RenderDiffResponse {
+        a: query.a,
+        b: query.b,
+        only_in_a: Vec::new(),
+        only_in_b: Vec::new(),
+        common: 0,
+    };
+    for proxy in proxies {
+        let in_a = renderer_a.keeps_proxy(registry, &proxy);
+        let in_b = renderer_b.keeps_proxy(registry, &proxy);
+        let node = RenderDiffNode {
+            name: proxy.name,
+            protocol: proxy.protocol,
+        };
+        match (in_a, in_b) {
+            (true, true) => report.common += 1,
+            (true, false) => report.only_in_a.push(node),
+            (false, true) => report.only_in_b.push(node),
+            (false, false) => {}
+        }
+    }
+    Ok(Json(report))
+}
+
+/// Both node probes open connections to hosts taken from the request or the
+/// profiles, so they stay off unless `server.allow_node_test` is set.
+async fn require_node_test(state: &AppState) -> Result<(), ApiError> {
+    if state.runtime.read().await.pref.server.allow_node_test {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        StatusCode::FORBIDDEN,
+        "node testing is disabled; set `server.allow_node_test = true` to enable it",
+    ))
+}
+
+async fn test_node(
+    State(state): State<AppState>,
+    Json(body): Json<NodeTestRequest>,
+) -> Result<Json<NodeTestResponse>, ApiError> {
+    require_node_test(&state).await?;
+    let server = body.server.trim();
+    if server.is_empty() || body.port == 0 {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "`server` and a non-zero `port` are required",
+        ));
+    }
+
+    let endpoint = Endpoint {
+        name: format!("{server}:{}", body.port),
+        server: server.to_string(),
+        port: body.port,
+    };
+    let (_, timeout) = reachability::limits(None, body.timeout_ms);
+    let result = reachability::check_all(vec![endpoint], 1, timeout)
+        .await
+        .pop()
+        .ok_or_else(|| ApiError::internal(anyhow::anyhow!("node test did not complete")))?;
+    state
+        .latency_cache
+        .record(std::slice::from_ref(&result))
+        .await;
+    info!(
+        endpoint = %result.name,
+        reachable = result.reachable,
+        latency_ms = ?result.latency_ms,
+        "node tested"
+    );
+    Ok(Json(NodeTestResponse {
+        reachable: result.reachable,
+        latency_ms: result.latency_ms,
+        error: result.error,
+    }))
+}
+
+async fn stream_logs(ws: WebSocketUpgrade) -> Response {
+    // Subscribe before upgrading so no line is missed once the client connects.
+    let lines = logging::subscribe_logs();
+    ws.on_upgrade(move |socket| forward_logs(socket, lines))
+}
+
+async fn forward_logs(mut socket: WebSocket, mut lines: broadcast::Receiver<String>) {
+    // Avoid logging here: every log line would be fed back into the stream.
+    loop {
+        tokio::select! {
+            line = lines.recv() => match line {
+                Ok(line) => {
+                    if socket.send(Message::Text(line.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -649,9 +1222,10 @@ async fn get_groups(State(state): State<AppState>) -> Result<Json<GroupResponse>
     let pref = &runtime.pref;
     let specs = load_group_specs_from_pref(pref, &state.base_dir).map_err(ApiError::internal)?;
     let ruleset_map = load_ruleset_groups(pref, &state.base_dir).await?;
+    let overrides = pref.protocol_overrides(&state.base_dir);
     let proxy_groups = match proxy::collect_profile_files(&resolve_profiles_dir(&state.base_dir))
-        .and_then(|paths| proxy::load_from_paths(&runtime.registry, paths))
-        .and_then(|proxies| groups::build_groups(&specs, &proxies))
+        .and_then(|paths| proxy::load_from_paths(&runtime.registry, paths, &overrides))
+        .and_then(|proxies| groups::build_groups(&specs, &proxies, &pref.groups))
     {
         Ok(groups) => groups,
         Err(err) => {
@@ -778,6 +1352,224 @@ async fn update_group_members(
     }))
 }
 
+/// Appends a group to the groups snippet. Names must be unique across every
+/// imported groups file.
+async fn create_group(
+    State(state): State<AppState>,
+    Json(body): Json<CreateGroupRequest>,
+) -> Result<Json<GroupResponse>, ApiError> {
+    let name = body.name.trim();
+    let group_type = body.group_type.trim();
+    if name.is_empty() || group_type.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "group name and type are required",
+        ));
+    }
+    let runtime = state.runtime.read().await.clone();
+    let specs =
+        load_group_specs_from_pref(&runtime.pref, &state.base_dir).map_err(ApiError::internal)?;
+    if specs.iter().any(|spec| spec.name == name) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("group `{name}` already exists"),
+        ));
+    }
+
+    let file = resolve_groups_snippet_path(&runtime.pref, &state.base_dir)?;
+    let text = read_file(&file).await?;
+    let output = append_group_table(&text, name, group_type, &body.rules)?;
+    write_tracked(&state, "snippets", "groups", &file, &output).await?;
+    info!(group = name, path = %file.display(), "group created");
+    get_groups(State(state)).await
+}
+
+/// Removes a group from the groups snippet, unless another group lists it
+/// as `[]name` or a ruleset routes to it.
+async fn delete_group(
+    State(state): State<AppState>,
+    AxumPath(name): AxumPath<String>,
+) -> Result<Json<GroupResponse>, ApiError> {
+    let runtime = state.runtime.read().await.clone();
+    let specs =
+        load_group_specs_from_pref(&runtime.pref, &state.base_dir).map_err(ApiError::internal)?;
+    if !specs.iter().any(|spec| spec.name == name) {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("group `{name}` not found"),
+        ));
+    }
+    let reference = format!("[]{name}");
+    let referrers: Vec<&str> = specs
+        .iter()
+        .filter(|spec| spec.rule.contains(&reference))
+        .map(|spec| spec.name.as_str())
+        .collect();
+    if !referrers.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("group `{name}` is used by group {}", referrers.join(", ")),
+        ));
+    }
+    let rulesets = load_ruleset_groups(&runtime.pref, &state.base_dir).await?;
+    if rulesets.get(&name).is_some_and(|items| !items.is_empty()) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("group `{name}` is used by rulesets"),
+        ));
+    }
+
+    let file = resolve_groups_snippet_path(&runtime.pref, &state.base_dir)?;
+    let text = read_file(&file).await?;
+    let output = remove_group_table(&text, &name)?.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("group `{name}` is not defined in the groups snippet"),
+        )
+    })?;
+    write_tracked(&state, "snippets", "groups", &file, &output).await?;
+    info!(group = %name, path = %file.display(), "group deleted");
+    get_groups(State(state)).await
+}
+
+async fn reorder_groups(
+    State(state): State<AppState>,
+    Json(body): Json<ReorderRequest>,
+) -> Result<Json<ReorderResponse>, ApiError> {
+    let runtime = state.runtime.read().await.clone();
+    let file = resolve_groups_snippet_path(&runtime.pref, &state.base_dir)?;
+    let text = read_file(&file).await?;
+    let (output, order) = reorder_group_tables(&text, &body.order)?;
+    write_tracked(&state, "snippets", "groups", &file, &output).await?;
+    info!(path = %file.display(), "groups reordered");
+    Ok(Json(ReorderResponse { ok: true, order }))
+}
+
+/// Reorders the `[[groups]]` tables by name, keeping each table's comments.
+fn reorder_group_tables(text: &str, order: &[String]) -> Result<(String, Vec<String>), ApiError> {
+    let mut doc = parse_groups_snippet(text)?;
+    let groups = doc
+        .get_mut("groups")
+        .and_then(|item| item.as_array_of_tables_mut())
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "groups snippet has no `[[groups]]`",
+            )
+        })?;
+    let names: Vec<String> = groups
+        .iter()
+        .map(|table| {
+            table
+                .get("name")
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect();
+    let indices = resolve_order(&names, order, "group")?;
+
+    let tables: Vec<Table> = groups.iter().cloned().collect();
+    // Tables render by document position, so the slots keep their positions
+    // and the blank lines before them. Leading comments move with the table.
+    let positions: Vec<Option<usize>> = tables.iter().map(Table::position).collect();
+    let (separators, comments): (Vec<String>, Vec<String>) =
+        tables.iter().map(split_leading_decor).unzip();
+    groups.clear();
+    for (slot, &index) in indices.iter().enumerate() {
+        let mut table = tables[index].clone();
+        if let Some(position) = positions[slot] {
+            table.set_position(position);
+        }
+        table
+            .decor_mut()
+            .set_prefix(format!("{}{}", separators[slot], comments[index]));
+        groups.push(table);
+    }
+
+    let order = indices
+        .into_iter()
+        .map(|index| names[index].clone())
+        .collect();
+    Ok((doc.to_string(), order))
+}
+
+/// Splits a table's leading decor into the blank lines separating it from the
+/// previous table and the comment lines that describe it.
+fn split_leading_decor(table: &Table) -> (String, String) {
+    let prefix = table
+        .decor()
+        .prefix()
+        .and_then(|prefix| prefix.as_str())
+        .unwrap_or_default();
+    let comment_start = match prefix.find(|c: char| !c.is_whitespace()) {
+        Some(first) => prefix[..first].rfind('\n').map_or(0, |newline| newline + 1),
+        None => prefix.len(),
+    };
+    let (separator, comment) = prefix.split_at(comment_start);
+    (separator.to_string(), comment.to_string())
+}
+
+/// Appends a `[[groups]]` table, leaving the rest of the snippet as written.
+fn append_group_table(
+    text: &str,
+    name: &str,
+    group_type: &str,
+    rules: &[String],
+) -> Result<String, ApiError> {
+    let mut doc = parse_groups_snippet(text)?;
+    let groups = doc
+        .entry("groups")
+        .or_insert(Item::ArrayOfTables(ArrayOfTables::new()))
+        .as_array_of_tables_mut()
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "groups snippet `groups` must be `[[groups]]` tables",
+            )
+        })?;
+    let mut table = Table::new();
+    table.insert("name", toml_edit::value(name));
+    table.insert("type", toml_edit::value(group_type));
+    let rules: Array = rules
+        .iter()
+        .map(|rule| rule.trim())
+        .filter(|rule| !rule.is_empty())
+        .collect();
+    if !rules.is_empty() {
+        table.insert("rule", toml_edit::value(rules));
+    }
+    groups.push(table);
+    Ok(doc.to_string())
+}
+
+/// Drops the `[[groups]]` table named `name`, or returns `None` when the
+/// snippet has no such table.
+fn remove_group_table(text: &str, name: &str) -> Result<Option<String>, ApiError> {
+    let mut doc = parse_groups_snippet(text)?;
+    let Some(groups) = doc
+        .get_mut("groups")
+        .and_then(|item| item.as_array_of_tables_mut())
+    else {
+        return Ok(None);
+    };
+    let before = groups.len();
+    groups.retain(|table| table.get("name").and_then(|value| value.as_str()) != Some(name));
+    if groups.len() == before {
+        return Ok(None);
+    }
+    Ok(Some(doc.to_string()))
+}
+
+fn parse_groups_snippet(text: &str) -> Result<DocumentMut, ApiError> {
+    text.parse().map_err(|err| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("invalid groups snippet: {err}"),
+        )
+    })
+}
+
 async fn get_groups_snippet(
     State(state): State<AppState>,
 ) -> Result<Json<FileContentResponse>, ApiError> {
@@ -801,7 +1593,7 @@ async fn update_groups_snippet(
 ) -> Result<Json<UpdateFileResponse>, ApiError> {
     let runtime = state.runtime.read().await.clone();
     let file = resolve_groups_snippet_path(&runtime.pref, &state.base_dir)?;
-    let bytes = write_file(&file, &body.content).await?;
+    let bytes = write_tracked(&state, "snippets", "groups", &file, &body.content).await?;
     info!(path = %file.display(), bytes, "groups snippet updated");
     Ok(Json(UpdateFileResponse {
         ok: true,
@@ -833,7 +1625,7 @@ async fn update_rulesets_snippet(
 ) -> Result<Json<UpdateFileResponse>, ApiError> {
     let runtime = state.runtime.read().await.clone();
     let file = resolve_rulesets_snippet_path(&runtime.pref, &state.base_dir)?;
-    let bytes = write_file(&file, &body.content).await?;
+    let bytes = write_tracked(&state, "snippets", "rulesets", &file, &body.content).await?;
     info!(path = %file.display(), bytes, "rulesets snippet updated");
     Ok(Json(UpdateFileResponse {
         ok: true,
@@ -842,6 +1634,80 @@ async fn update_rulesets_snippet(
     }))
 }
 
+async fn list_history(
+    State(state): State<AppState>,
+    AxumPath((kind, name)): AxumPath<(String, String)>,
+) -> Result<Json<HistoryResponse>, ApiError> {
+    resolve_history_file(&state, &kind, &name).await?;
+    let items = history::list(history_root(&state), &kind, &name)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(Json(HistoryResponse { items }))
+}
+
+async fn restore_history(
+    State(state): State<AppState>,
+    AxumPath((kind, name)): AxumPath<(String, String)>,
+    Json(body): Json<RestoreRequest>,
+) -> Result<Json<UpdateFileResponse>, ApiError> {
+    let file = resolve_history_file(&state, &kind, &name).await?;
+    let content = history::read(history_root(&state), &kind, &name, body.version)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("version {} of {kind}/{name} not found", body.version),
+            )
+        })?;
+    // The version being replaced goes to the history too, so a restore can
+    // itself be undone.
+    let bytes = if kind == "config" && name == "pref" {
+        replace_pref(&state, &content).await?
+    } else {
+        write_tracked(&state, &kind, &name, &file, &content).await?
+    };
+    info!(path = %file.display(), version = body.version, bytes, "file restored from history");
+    Ok(Json(UpdateFileResponse {
+        ok: true,
+        path: file.display().to_string(),
+        bytes,
+    }))
+}
+
+/// The file behind an editor endpoint; history is addressed by the same
+/// `{kind}/{name}` path as `/api/{kind}/{name}`.
+async fn resolve_history_file(
+    state: &AppState,
+    kind: &str,
+    name: &str,
+) -> Result<PathBuf, ApiError> {
+    let runtime = state.runtime.read().await.clone();
+    let pref = &runtime.pref;
+    let base_dir = &state.base_dir;
+    match (kind, name) {
+        ("config", "pref") => Ok(state.pref_path.clone()),
+        ("config", name) if name.starts_with("rule-base/") => {
+            resolve_rule_base_path(pref, base_dir, &name["rule-base/".len()..])
+        }
+        ("profiles", name) => {
+            resolve_single_file(&resolve_profiles_dir(base_dir), name, &["yaml", "yml"])
+        }
+        ("rules", name) => {
+            resolve_single_file(&resolve_rules_dir(base_dir), name, &["list", "yaml", "yml"])
+        }
+        ("schema", name) => {
+            resolve_nested_file(&resolve_schema_dir(pref, base_dir)?, name, &["yaml", "yml"])
+        }
+        ("snippets", "groups") => resolve_groups_snippet_path(pref, base_dir),
+        ("snippets", "rulesets") => resolve_rulesets_snippet_path(pref, base_dir),
+        _ => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("no history for {kind}/{name}"),
+        )),
+    }
+}
+
 async fn get_cache(State(state): State<AppState>) -> Result<Json<CacheResponse>, ApiError> {
     let runtime = state.runtime.read().await.clone();
     let items = runtime
@@ -851,12 +1717,59 @@ async fn get_cache(State(state): State<AppState>) -> Result<Json<CacheResponse>,
         .into_iter()
         .map(|entry| CacheEntry {
             url: entry.url,
+            user_agent: entry.user_agent,
             ttl_seconds: entry.ttl_seconds,
+            ttl_human: format_ttl(entry.ttl_seconds),
         })
         .collect();
     Ok(Json(CacheResponse { items }))
 }
 
+/// Remaining TTL as its two largest units, e.g. `23h 59m` or `1h 0m`.
+fn format_ttl(seconds: u64) -> String {
+    const UNITS: [(&str, u64); 4] = [("d", 86_400), ("h", 3_600), ("m", 60), ("s", 1)];
+    if seconds == 0 {
+        return "expired".to_string();
+    }
+    let index = UNITS
+        .iter()
+        .position(|(_, size)| seconds >= *size)
+        .unwrap_or(UNITS.len() - 1);
+    let (label, size) = UNITS[index];
+    let mut text = format!("{}{label}", seconds / size);
+    if let Some((next_label, next_size)) = UNITS.get(index + 1) {
+        text.push_str(&format!(" {}{next_label}", seconds % size / next_size));
+    }
+    text
+}
+
+async fn convert_node(
+    State(state): State<AppState>,
+    Json(body): Json<ConvertRequest>,
+) -> Result<Json<ConvertResponse>, ApiError> {
+    let runtime = state.runtime.read().await.clone();
+    convert_with_registry(&runtime.registry, body).map(Json)
+}
+
+fn convert_with_registry(
+    registry: &SchemaRegistry,
+    body: ConvertRequest,
+) -> Result<ConvertResponse, ApiError> {
+    let name = body
+        .values
+        .get("name")
+        .and_then(serde_json::Value::as_str)
+        .unwrap_or("<unnamed>");
+    let result = registry
+        .convert(&body.protocol, &body.target, name, &body.values)
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    Ok(ConvertResponse {
+        protocol: body.protocol,
+        target: body.target,
+        result,
+    })
+}
+
 async fn load_ruleset_groups(
     pref: &Pref,
     base_dir: &Path,
@@ -878,14 +1791,33 @@ async fn load_ruleset_groups(
     Ok(map)
 }
 
-async fn control_reload(State(state): State<AppState>) -> Result<Json<ControlResponse>, ApiError> {
-    let runtime = build_runtime(&state.pref_path, &state.base_dir).map_err(ApiError::internal)?;
+/// Builds a new runtime and checks its groups and rules before swapping it
+/// in. On failure the old runtime keeps serving and the error chain is
+/// returned as `details`.
+async fn control_reload(State(state): State<AppState>) -> Result<Json<ReloadResponse>, ApiError> {
+    let loaded = state.load_runtime(&state.pref_path).and_then(|runtime| {
+        let summary = runtime.summarize(&state.base_dir)?;
+        Ok((runtime, summary))
+    });
+    let (runtime, summary) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            mark_config_dirty(&state).await;
+            return Err(ApiError::with_causes(err));
+        }
+    };
     let mut guard = state.runtime.write().await;
     *guard = runtime;
-    info!("runtime configuration reloaded");
-    Ok(Json(ControlResponse {
+    info!(
+        protocols = summary.protocols,
+        groups = summary.groups,
+        rules = summary.rules,
+        "runtime configuration reloaded"
+    );
+    Ok(Json(ReloadResponse {
         ok: true,
         message: "configuration reloaded".to_string(),
+        summary,
     }))
 }
 
@@ -921,7 +1853,13 @@ async fn control_set_api_token(
         ));
     }
     update_pref_api_token(&state.pref_path, token).await?;
-    let runtime = build_runtime(&state.pref_path, &state.base_dir).map_err(ApiError::internal)?;
+    let runtime = match state.load_runtime(&state.pref_path) {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            mark_config_dirty(&state).await;
+            return Err(ApiError::internal(err));
+        }
+    };
     let mut guard = state.runtime.write().await;
     *guard = runtime;
     info!("api access token updated");
@@ -931,6 +1869,13 @@ async fn control_set_api_token(
     }))
 }
 
+/// Flags the serving runtime after the files on disk failed to load, so
+/// `/api/config` and `/healthz` show that the two have diverged.
+async fn mark_config_dirty(state: &AppState) {
+    state.runtime.read().await.mark_dirty();
+    warn!("configuration on disk failed to load; still serving the previous one");
+}
+
 async fn control_restart(State(_state): State<AppState>) -> Result<Json<ControlResponse>, ApiError> {
     tokio::spawn(async move {
         tokio::time::sleep(std::time::Duration::from_millis(200)).await;
@@ -945,27 +1890,39 @@ async fn control_restart(State(_state): State<AppState>) -> Result<Json<ControlR
 
 async fn update_pref_api_token(path: &Path, token: &str) -> Result<(), ApiError> {
     let text = fs::read_to_string(path).await.map_err(ApiError::internal)?;
-    let mut value: toml::Value = toml::from_str(&text)
-        .with_context(|| format!("failed to parse {}", path.display()))
-        .map_err(ApiError::internal)?;
-    let root = value.as_table_mut().ok_or_else(|| {
-        ApiError::new(StatusCode::BAD_REQUEST, "pref root must be a table")
-    })?;
-    let common = root.get_mut("common").ok_or_else(|| {
-        ApiError::new(StatusCode::BAD_REQUEST, "`[common]` section not found")
-    })?;
-    let common_table = common.as_table_mut().ok_or_else(|| {
-        ApiError::new(StatusCode::BAD_REQUEST, "`common` must be a table")
-    })?;
-    common_table.insert(
-        "api_access_token".to_string(),
-        toml::Value::String(token.to_string()),
-    );
-    let output = toml::to_string_pretty(&value).map_err(ApiError::internal)?;
+    let output = set_api_token(&text, token)?;
     fs::write(path, output).await.map_err(ApiError::internal)?;
     Ok(())
 }
 
+/// Sets `common.api_access_token`, keeping comments and layout. `[common]`
+/// and the key are added when missing; an existing value keeps its trailing
+/// comment.
+fn set_api_token(text: &str, token: &str) -> Result<String, ApiError> {
+    let mut doc: DocumentMut = text.parse().map_err(|err| {
+        ApiError::new(StatusCode::BAD_REQUEST, format!("invalid pref.toml: {err}"))
+    })?;
+    let common = doc
+        .entry("common")
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_like_mut()
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "`common` must be a table"))?;
+    match common
+        .get_mut("api_access_token")
+        .and_then(Item::as_value_mut)
+    {
+        Some(existing) => {
+            let decor = existing.decor().clone();
+            *existing = Value::from(token);
+            *existing.decor_mut() = decor;
+        }
+        None => {
+            common.insert("api_access_token", toml_edit::value(token));
+        }
+    }
+    Ok(doc.to_string())
+}
+
 const TOKEN_ALLOWED: &str =
     "ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz23456789!@#$%^&*_-+=";
 
@@ -1007,14 +1964,17 @@ fn resolve_rulesets_snippet_path(pref: &Pref, base_dir: &Path) -> Result<PathBuf
     Ok(resolve_path(base_dir, &entry.import))
 }
 
-fn resolve_rule_base_path(
-    pref: &Pref,
-    base_dir: &Path,
-    target: &str,
-) -> Result<PathBuf, ApiError> {
+fn resolve_rule_base_path(pref: &Pref, base_dir: &Path, target: &str) -> Result<PathBuf, ApiError> {
     let raw = match target {
-        "clash" => pref.common.clash_rule_base.as_deref(),
+        // A layered base opens its first (shared) file in the editor.
+        "clash" => pref
+            .common
+            .clash_rule_base
+            .as_ref()
+            .and_then(|base| base.paths().first())
+            .map(String::as_str),
         "surge" => pref.common.surge_rule_base.as_deref(),
+        "loon" => pref.common.loon_rule_base.as_deref(),
         _ => {
             return Err(ApiError::new(
                 StatusCode::BAD_REQUEST,
@@ -1022,10 +1982,19 @@ fn resolve_rule_base_path(
             ));
         }
     };
+    let key = format!("`common.{target}_rule_base`");
     let raw = raw.ok_or_else(|| {
-        let key = format!("`common.{target}_rule_base`");
-        ApiError::new(StatusCode::BAD_REQUEST, format!("{key} not set in pref.toml"))
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{key} not set in pref.toml"),
+        )
     })?;
+    if is_remote_base(raw) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{key} is a remote URL and cannot be edited"),
+        ));
+    }
     Ok(resolve_path(base_dir, raw))
 }
 
@@ -1138,6 +2107,28 @@ async fn read_file(path: &Path) -> Result<String, ApiError> {
     })
 }
 
+/// History lives next to `pref.toml`, which is kept writable.
+fn history_root(state: &AppState) -> &Path {
+    state.pref_path.parent().unwrap_or(&state.base_dir)
+}
+
+/// Writes a file edited through the API, first saving its current contents
+/// under `{kind}/{name}` in the history.
+async fn write_tracked(
+    state: &AppState,
+    kind: &str,
+    name: &str,
+    path: &Path,
+    content: &str,
+) -> Result<usize, ApiError> {
+    let limit = state.runtime.read().await.pref.server.history_limit;
+    if let Err(err) = history::record(history_root(state), kind, name, path, limit).await {
+        // Losing an undo step should not block the edit itself.
+        warn!(path = %path.display(), error = %err, "failed to save file history");
+    }
+    write_file(path, content).await
+}
+
 async fn write_file(path: &Path, content: &str) -> Result<usize, ApiError> {
     if let Some(parent) = path.parent() {
         if !parent.exists() {
@@ -1327,3 +2318,649 @@ fn ensure_within_root(root: &Path, path: &Path) -> Result<(), ApiError> {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+    use crate::server::test_state;
+
+    fn trojan_request(target: &str) -> ConvertRequest {
+        let values = serde_json::json!({
+            "name": "trojan-node",
+            "type": "trojan",
+            "server": "example.com",
+            "port": 443,
+            "password": "secret",
+            "sni": "example.com",
+        });
+        ConvertRequest {
+            protocol: "trojan".to_string(),
+            target: target.to_string(),
+            values: values.as_object().cloned().unwrap(),
+        }
+    }
+
+    #[test]
+    fn cache_ttl_is_formatted_for_display() {
+        assert_eq!(format_ttl(3600), "1h 0m");
+        assert_eq!(format_ttl(86_399), "23h 59m");
+        assert_eq!(format_ttl(90_061), "1d 1h");
+        assert_eq!(format_ttl(42), "42s");
+        assert_eq!(format_ttl(0), "expired");
+    }
+
+    #[test]
+    fn convert_trojan_to_clash() {
+        let response = convert_with_registry(&test_registry(), trojan_request("clash")).unwrap();
+        assert_eq!(response.result["type"], "trojan");
+        assert_eq!(response.result["password"], "secret");
+        assert_eq!(response.result["sni"], "example.com");
+    }
+
+    #[test]
+    fn convert_trojan_to_surge() {
+        let response = convert_with_registry(&test_registry(), trojan_request("surge")).unwrap();
+        assert_eq!(
+            response.result,
+            "trojan-node = trojan, example.com, 443, password=secret, sni=example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn log_stream_pushes_new_lines() {
+        use futures_util::StreamExt;
+
+        logging::init_logging(false);
+        let app: Router = Router::new().route("/logs/stream", get(stream_logs));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/logs/stream"))
+            .await
+            .unwrap();
+        info!("log stream test line");
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while let Some(message) = socket.next().await {
+                let text = message.unwrap().into_text().unwrap();
+                if text.contains("log stream test line") {
+                    return text.to_string();
+                }
+            }
+            panic!("log stream closed before the line arrived");
+        })
+        .await
+        .expect("log line should be streamed");
+        assert!(received.contains("INFO"), "{received}");
+    }
+
+    #[test]
+    fn reorder_default_url_keeps_comments() {
+        let text = r#"[common]
+default_url = [
+  # primary provider
+  "conf/profiles/a.yaml",
+  "conf/profiles/b.yaml",
+  "conf/profiles/c.yaml",
+]
+sort = true
+"#;
+        let order = vec!["c.yaml".to_string(), "conf/profiles/a.yaml".to_string()];
+        let (output, new_order) = reorder_default_url(text, &order).unwrap();
+        assert_eq!(
+            new_order,
+            [
+                "conf/profiles/c.yaml",
+                "conf/profiles/a.yaml",
+                "conf/profiles/b.yaml"
+            ]
+        );
+
+        let reread: toml::Value = toml::from_str(&output).unwrap();
+        let urls: Vec<&str> = reread["common"]["default_url"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| value.as_str().unwrap())
+            .collect();
+        assert_eq!(urls, new_order);
+        assert!(
+            output.contains("# primary provider\n  \"conf/profiles/a.yaml\""),
+            "{output}"
+        );
+
+        let err = reorder_default_url(text, &["missing.yaml".to_string()]).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn api_token_update_keeps_comments() {
+        let text = r#"# Deployment for the home router.
+[common]
+# Leave empty until the web UI sets it.
+api_access_token = "old" # rotated monthly
+sort = true
+
+[server]
+listen = "0.0.0.0" # all interfaces
+port = 25500
+"#;
+        let output = set_api_token(text, "new-token").unwrap();
+        assert_eq!(
+            output,
+            text.replace(r#""old""#, r#""new-token""#),
+            "{output}"
+        );
+
+        // `[common]` is added when missing, leaving the rest as written.
+        let text = "# server only
+[server]
+port = 25500
+";
+        let output = set_api_token(text, "new-token").unwrap();
+        assert!(output.starts_with(text), "{output}");
+        let reread: toml::Value = toml::from_str(&output).unwrap();
+        assert_eq!(
+            reread["common"]["api_access_token"].as_str(),
+            Some("new-token")
+        );
+    }
+
+    #[test]
+    fn reorder_groups_rewrites_table_order() {
+        let text = r#"# proxy selection
+[[groups]]
+name = "Proxy"
+type = "select"
+
+[[groups]]
+name = "Auto"
+type = "url-test"
+
+# streaming
+[[groups]]
+name = "Media"
+type = "select"
+"#;
+        let order = vec!["Media".to_string(), "Proxy".to_string()];
+        let (output, new_order) = reorder_group_tables(text, &order).unwrap();
+        assert_eq!(new_order, ["Media", "Proxy", "Auto"]);
+
+        let reread: toml::Value = toml::from_str(&output).unwrap();
+        let names: Vec<&str> = reread["groups"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|group| group["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, new_order);
+        assert!(
+            output.starts_with("# streaming\n[[groups]]\nname = \"Media\""),
+            "{output}"
+        );
+        assert!(
+            output.contains("\n\n# proxy selection\n[[groups]]\nname = \"Proxy\""),
+            "{output}"
+        );
+    }
+
+    fn node_test_state(allow_node_test: bool) -> AppState {
+        test_state(
+            &format!("node-test-{allow_node_test}"),
+            &format!("[server]\nallow_node_test = {allow_node_test}"),
+        )
+    }
+
+    #[tokio::test]
+    async fn node_test_measures_local_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let request = || NodeTestRequest {
+            server: "127.0.0.1".to_string(),
+            port,
+            timeout_ms: Some(1_000),
+        };
+
+        let Json(response) = test_node(State(node_test_state(true)), Json(request()))
+            .await
+            .unwrap();
+        assert!(response.reachable, "{:?}", response.error);
+        assert!(response.latency_ms.is_some());
+
+        drop(listener);
+        let Json(response) = test_node(State(node_test_state(true)), Json(request()))
+            .await
+            .unwrap();
+        assert!(!response.reachable);
+        assert!(response.error.is_some());
+
+        let Err(err) = test_node(State(node_test_state(false)), Json(request())).await else {
+            panic!("node test should be rejected when disabled");
+        };
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn reachability_check_requires_node_testing() {
+        let query = || {
+            axum::extract::Query(ReachabilityQuery {
+                concurrency: None,
+                timeout_ms: Some(100),
+            })
+        };
+
+        let Err(err) = check_reachability(State(node_test_state(false)), query()).await else {
+            panic!("reachability check should be rejected when node testing is disabled");
+        };
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = node_test_state(true);
+        let profiles = state.base_dir.join("conf/profiles");
+        std::fs::create_dir_all(&profiles).unwrap();
+        std::fs::write(
+            profiles.join("local.yaml"),
+            format!(
+                "proxies:\n  - {{ name: local, type: trojan, server: 127.0.0.1, port: {port}, password: secret }}\n"
+            ),
+        )
+        .unwrap();
+
+        let Json(response) = check_reachability(State(state), query()).await.unwrap();
+        let [item] = response.items.as_slice() else {
+            panic!("expected one result, got {:?}", response.items);
+        };
+        assert_eq!(item.name, "local");
+        assert!(item.reachable, "{:?}", item.error);
+    }
+
+    #[test]
+    fn convert_reports_errors_as_bad_request() {
+        let mut request = trojan_request("clash");
+        request.values.remove("password");
+        let Err(err) = convert_with_registry(&test_registry(), request) else {
+            panic!("a node without a password should not convert");
+        };
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains("password"));
+    }
+
+    #[tokio::test]
+    async fn profile_edits_are_kept_in_history() {
+        let state = test_state("history", "[server]\nhistory_limit = 5");
+        let profiles = state.base_dir.join("conf/profiles");
+        let _ = std::fs::remove_dir_all(state.base_dir.join(".history"));
+        std::fs::create_dir_all(&profiles).unwrap();
+        std::fs::write(profiles.join("a.yaml"), "proxies: []\n").unwrap();
+
+        let edit = |content: &str| {
+            update_profile(
+                State(state.clone()),
+                AxumPath("a.yaml".to_string()),
+                Json(UpdateFileRequest {
+                    content: content.to_string(),
+                }),
+            )
+        };
+        let _ = edit("# first edit\n").await.unwrap();
+        let _ = edit("# second edit\n").await.unwrap();
+
+        let history_path = || AxumPath(("profiles".to_string(), "a.yaml".to_string()));
+        let Json(history) = list_history(State(state.clone()), history_path())
+            .await
+            .unwrap();
+        let versions: Vec<u64> = history.items.iter().map(|entry| entry.version).collect();
+        assert_eq!(versions, [2, 1]);
+
+        let _ = restore_history(
+            State(state.clone()),
+            history_path(),
+            Json(RestoreRequest { version: 1 }),
+        )
+        .await
+        .unwrap();
+        let restored = std::fs::read_to_string(profiles.join("a.yaml")).unwrap();
+        assert_eq!(restored, "proxies: []\n");
+
+        // The content replaced by the restore can be restored in turn.
+        let Json(history) = list_history(State(state), history_path()).await.unwrap();
+        assert_eq!(history.items.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn schema_edits_are_validated_against_includes() {
+        let state = test_state("schema-validate", "");
+        let validate = |content: &str| {
+            validate_schema(
+                State(state.clone()),
+                Json(ValidateSchemaRequest {
+                    content: content.to_string(),
+                    path: Some("trojan.yaml".to_string()),
+                }),
+            )
+        };
+
+        // `server` comes from the included `common` fragment.
+        let Json(valid) = validate(
+            "protocol: trojan\nincludes: [common]\nfields:\n  password: { type: string }\ntargets:\n  clash:\n    template:\n      server: { from: server }\n      password: { from: password }\n",
+        )
+        .await;
+        assert!(valid.ok, "{:?}", valid.errors);
+        assert!(valid.errors.is_empty());
+
+        let Json(invalid) = validate(
+            "protocol: trojan\nincludes: [common]\ntargets:\n  clash:\n    template:\n      password: { from: passwd }\n",
+        )
+        .await;
+        assert!(!invalid.ok);
+        assert_eq!(invalid.errors.len(), 1);
+        assert!(
+            invalid.errors[0].contains("unknown field `passwd`"),
+            "{:?}",
+            invalid.errors
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_validation_reports_every_failure() {
+        let state = test_state("validate-proxies", "");
+        let profiles = state.base_dir.join("conf/profiles");
+        std::fs::create_dir_all(&profiles).unwrap();
+        std::fs::write(
+            profiles.join("mixed.yaml"),
+            "proxies:\n  - { name: good-a, type: trojan, server: a.example.com, port: 443, password: secret }\n  - { name: no-password, type: trojan, server: b.example.com, port: 443 }\n  - { name: good-c, type: trojan, server: c.example.com, port: 443, password: secret }\n  - { name: bad-port, type: trojan, server: d.example.com, port: [443], password: secret }\n",
+        )
+        .unwrap();
+
+        let Json(report) = validate_proxies(
+            State(state),
+            axum::extract::Query(ValidateProxiesQuery { target: None }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.target, "clash");
+        assert_eq!(report.checked, 4);
+        let failed: Vec<&str> = report.errors.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(failed, ["no-password", "bad-port"]);
+        assert!(report.errors.iter().all(|e| e.protocol == "trojan"));
+        assert!(
+            report.errors[0].error.contains("password"),
+            "{}",
+            report.errors[0].error
+        );
+    }
+
+    #[tokio::test]
+    async fn render_diff_reports_nodes_dropped_per_target() {
+        let state = test_state("render-diff", "");
+        let dir = state.base_dir.clone();
+        std::fs::write(dir.join("base.yaml"), "mode: rule\n").unwrap();
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        std::fs::write(dir.join("base.conf"), "[General]\n").unwrap();
+        let pref = std::fs::read_to_string(&state.pref_path).unwrap().replacen(
+            "[common]\n",
+            &format!(
+                "[common]\nclash_rule_base = \"{dir}/base.yaml\"\nsurge_rule_base = \"{dir}/base.cfg\"\nloon_rule_base = \"{dir}/base.conf\"\n",
+                dir = dir.display()
+            ),
+            1,
+        );
+        std::fs::write(&state.pref_path, pref).unwrap();
+        let _ = control_reload(State(state.clone())).await.unwrap();
+
+        let profiles = dir.join("conf/profiles");
+        std::fs::create_dir_all(&profiles).unwrap();
+        std::fs::write(
+            profiles.join("mixed.yaml"),
+            "proxies:\n  - { name: trojan-a, type: trojan, server: a.example.com, port: 443, password: secret }\n  - { name: vless-b, type: vless, server: b.example.com, port: 443, uuid: 00000000-0000-0000-0000-000000000000 }\n  - { name: trojan-c, type: trojan, server: c.example.com, port: 443, password: secret }\n",
+        )
+        .unwrap();
+
+        let diff = |a: &str, b: &str| {
+            render_diff(
+                State(state.clone()),
+                axum::extract::Query(RenderDiffQuery {
+                    a: a.to_string(),
+                    b: b.to_string(),
+                }),
+            )
+        };
+        let Json(report) = diff("clash", "surge").await.unwrap();
+        assert_eq!(report.common, 2);
+        let only_in_clash: Vec<(&str, &str)> = report
+            .only_in_a
+            .iter()
+            .map(|node| (node.name.as_str(), node.protocol.as_str()))
+            .collect();
+        assert_eq!(only_in_clash, [("vless-b", "vless")]);
+        assert!(report.only_in_b.is_empty());
+
+        let Json(reversed) = diff("surge", "clash").await.unwrap();
+        assert!(reversed.only_in_a.is_empty());
+        assert_eq!(reversed.only_in_b.len(), 1);
+
+        // Loon has no vless template, so it leaves the node out as well.
+        let Json(loon) = diff("clash", "loon").await.unwrap();
+        assert_eq!(loon.common, 2);
+        let only_in_clash: Vec<&str> = loon
+            .only_in_a
+            .iter()
+            .map(|node| node.name.as_str())
+            .collect();
+        assert_eq!(only_in_clash, ["vless-b"]);
+        assert!(loon.only_in_b.is_empty());
+
+        let Err(err) = diff("clash", "nope").await else {
+            panic!("unknown targets should be rejected");
+        };
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn failed_reload_marks_config_dirty() {
+        let state = test_state("config-dirty", "");
+        let good = std::fs::read_to_string(&state.pref_path).unwrap();
+        std::fs::write(&state.pref_path, "[common\n").unwrap();
+
+        assert!(control_reload(State(state.clone())).await.is_err());
+        // The previous runtime keeps serving, flagged as out of step with disk.
+        let runtime = state.runtime.read().await.clone();
+        assert!(runtime.registry.protocol_count() > 0);
+        assert!(runtime.is_dirty());
+        let (status, body) = crate::server::handle_healthz(State(state.clone())).await;
+        assert_eq!((status, body), (StatusCode::OK, "dirty"));
+        let Json(config) = get_config(State(state.clone())).await.unwrap();
+        assert!(config.config_dirty);
+
+        std::fs::write(&state.pref_path, good).unwrap();
+        let _ = control_reload(State(state.clone())).await.unwrap();
+        assert!(!state.runtime.read().await.is_dirty());
+    }
+
+    #[tokio::test]
+    async fn groups_can_be_created_and_deleted() {
+        let state = test_state(
+            "group-crud",
+            "\n[[custom_groups]]\nimport = \"groups.toml\"",
+        );
+        let snippet = state.base_dir.join("groups.toml");
+        std::fs::write(
+            &snippet,
+            "# main selector\n[[groups]]\nname = \"Proxy\"\ntype = \"select\"\nrule = [\"[]Auto\", \"[]DIRECT\"]\n\n[[groups]]\nname = \"Auto\"\ntype = \"url-test\"\nrule = [\".*\"]\n",
+        )
+        .unwrap();
+        let names = |response: &GroupResponse| -> Vec<String> {
+            response
+                .items
+                .iter()
+                .map(|group| group.name.clone())
+                .collect()
+        };
+        let create = |name: &str| {
+            create_group(
+                State(state.clone()),
+                Json(CreateGroupRequest {
+                    name: name.to_string(),
+                    group_type: "select".to_string(),
+                    rules: vec!["[]Proxy".to_string(), "[]DIRECT".to_string()],
+                }),
+            )
+        };
+        let remove = |name: &str| delete_group(State(state.clone()), AxumPath(name.to_string()));
+
+        let Json(groups) = create("Media").await.unwrap();
+        assert_eq!(names(&groups), ["Proxy", "Auto", "Media"]);
+        assert_eq!(groups.items[2].rules, ["[]Proxy", "[]DIRECT"]);
+        let text = std::fs::read_to_string(&snippet).unwrap();
+        assert!(text.starts_with("# main selector\n"), "{text}");
+
+        let Err(err) = create("Auto").await else {
+            panic!("duplicate group names should be rejected");
+        };
+        assert_eq!(err.status, StatusCode::CONFLICT);
+
+        // `Proxy` lists `[]Auto`, so removing it would leave a dangling member.
+        let Err(err) = remove("Auto").await else {
+            panic!("referenced groups should not be deleted");
+        };
+        assert_eq!(err.status, StatusCode::CONFLICT);
+
+        let Json(groups) = remove("Media").await.unwrap();
+        assert_eq!(names(&groups), ["Proxy", "Auto"]);
+        let Err(err) = remove("Media").await else {
+            panic!("missing groups should not be deleted");
+        };
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        let text = std::fs::read_to_string(&snippet).unwrap();
+        assert!(text.starts_with("# main selector\n"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn reload_reports_what_was_loaded() {
+        let state = test_state(
+            "reload-summary",
+            "\n[ruleset]\nenabled = true\n\n[[rulesets]]\nimport = \"rulesets.toml\"\n\n[[custom_groups]]\nimport = \"groups.toml\"",
+        );
+        std::fs::write(
+            state.base_dir.join("groups.toml"),
+            "[[groups]]\nname = \"Proxy\"\ntype = \"select\"\nrule = [\"[]Auto\"]\n\n[[groups]]\nname = \"Auto\"\ntype = \"url-test\"\nrule = [\".*\"]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            state.base_dir.join("rulesets.toml"),
+            "[[rulesets]]\ngroup = \"Proxy\"\nruleset = [\"https://rules.example.com/ai.list\", \"[]DOMAIN,a.example.com\"]\n\n[[rulesets]]\ngroup = \"DIRECT\"\nruleset = \"[]FINAL\"\n",
+        )
+        .unwrap();
+
+        let Json(reloaded) = control_reload(State(state.clone())).await.unwrap();
+        assert!(reloaded.ok);
+        let protocols = state.runtime.read().await.registry.protocol_count();
+        assert_eq!(reloaded.summary.protocols, protocols);
+        assert_eq!(reloaded.summary.groups, 2);
+        // The remote list is not fetched and counts as a single rule.
+        assert_eq!(reloaded.summary.rules, 3);
+
+        std::fs::write(state.base_dir.join("groups.toml"), "[[groups]]\nname = 1\n").unwrap();
+        let Err(err) = control_reload(State(state.clone())).await else {
+            panic!("a broken groups snippet should fail the reload");
+        };
+        assert!(err.details.len() > 1, "{:?}", err.details);
+        assert!(state.runtime.read().await.is_dirty());
+    }
+
+    #[tokio::test]
+    async fn file_lists_filter_by_name_and_paginate() {
+        let state = test_state("file-list", "");
+        let rules = state.base_dir.join("conf/rules");
+        std::fs::create_dir_all(&rules).unwrap();
+        for name in [
+            "Ads.list",
+            "ads-extra.list",
+            "direct.list",
+            "streaming.yaml",
+            "notes.txt",
+        ] {
+            std::fs::write(rules.join(name), "").unwrap();
+        }
+
+        let list = |q: Option<&str>, limit: Option<usize>, offset: Option<usize>| {
+            list_rules(
+                State(state.clone()),
+                axum::extract::Query(FileListQuery {
+                    q: q.map(str::to_string),
+                    limit,
+                    offset,
+                }),
+            )
+        };
+        let names = |response: &FileListResponse| -> Vec<String> {
+            response
+                .items
+                .iter()
+                .map(|entry| entry.name.clone())
+                .collect()
+        };
+
+        let Json(all) = list(None, None, None).await.unwrap();
+        assert_eq!(all.total, 4);
+        assert_eq!(
+            names(&all),
+            [
+                "ads-extra.list",
+                "Ads.list",
+                "direct.list",
+                "streaming.yaml"
+            ]
+        );
+
+        let Json(ads) = list(Some("ADS"), None, None).await.unwrap();
+        assert_eq!(ads.total, 2);
+        assert_eq!(names(&ads), ["ads-extra.list", "Ads.list"]);
+
+        let Json(page) = list(None, Some(2), Some(1)).await.unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(names(&page), ["Ads.list", "direct.list"]);
+
+        let Json(past_end) = list(Some("list"), Some(2), Some(5)).await.unwrap();
+        assert_eq!(past_end.total, 3);
+        assert!(past_end.items.is_empty());
+    }
+
+    #[tokio::test]
+    async fn basic_auth_password_is_the_api_token() {
+        let state = test_state("basic-auth", "");
+        let pref = std::fs::read_to_string(&state.pref_path).unwrap().replacen(
+            "[common]\n",
+            "[common]\napi_access_token = \"s3cret\"\n",
+            1,
+        );
+        std::fs::write(&state.pref_path, pref).unwrap();
+        let _ = control_reload(State(state.clone())).await.unwrap();
+
+        let app: Router = Router::new()
+            .nest("/api", router(state.clone()))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let ping = |authorization: String| {
+            reqwest::Client::new()
+                .get(format!("http://{addr}/api/ping"))
+                .header(header::ORIGIN, format!("http://{addr}"))
+                .header(header::AUTHORIZATION, authorization)
+                .send()
+        };
+        let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+
+        let response = ping(basic("admin:s3cret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = ping(basic("admin:wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = ping("Bearer s3cret".to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
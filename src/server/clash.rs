@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use axum::http::{HeaderMap, HeaderValue};
 use serde::Serialize;
 use serde::ser::SerializeMap;
 use serde_json::{Map as JsonMap, Value};
@@ -6,16 +7,42 @@ use serde_saphyr as serde_yaml;
 use serde_saphyr::FlowMap;
 use tracing::info;
 
+use crate::config::Pref;
 use crate::groups;
-use crate::paths::resolve_path;
-use super::util::{load_group_specs_from_pref, load_rules_from_pref};
+use crate::network::Network;
+use crate::proxy;
+use crate::rules;
+use crate::schema::SchemaRegistry;
+use super::util::{
+    RuleProvider, apply_keyword_as_regex, load_group_specs_from_pref, load_rules_with_providers,
+    read_base_text,
+};
 use super::{ApiError, RenderArgs};
 
 pub struct ClashRenderer;
 
 impl super::TargetRenderer for ClashRenderer {
     fn render(&self, args: RenderArgs<'_>) -> Result<String, ApiError> {
-        render_clash(args).map_err(ApiError::internal)
+        render_clash(args).map_err(ApiError::render)
+    }
+
+    fn filename(&self) -> &'static str {
+        "clash.yaml"
+    }
+
+    fn keeps_proxy(&self, registry: &SchemaRegistry, proxy: &proxy::Proxy) -> bool {
+        !registry.target_not_implemented(&proxy.protocol, "clash")
+    }
+
+    /// Clash clients read the update interval (in hours) from a response header
+    /// instead of Surge's inline `#!MANAGED-CONFIG` line.
+    fn apply_headers(&self, pref: &Pref, headers: &mut HeaderMap) {
+        let managed = &pref.managed_config;
+        if !managed.write_managed_config {
+            return;
+        }
+        let hours = managed.interval.div_ceil(3600).max(1);
+        headers.insert("profile-update-interval", HeaderValue::from(hours));
     }
 }
 
@@ -24,39 +51,29 @@ fn render_clash(args: RenderArgs<'_>) -> Result<String> {
         runtime,
         base_dir,
         mut proxies,
+        presorted,
+        force,
+        selected_groups,
         ..
     } = args;
     let pref = &runtime.pref;
     let registry = &runtime.registry;
 
-    let clash_base = pref
-        .common
-        .clash_rule_base
-        .as_deref()
-        .ok_or_else(|| anyhow::anyhow!("`common.clash_rule_base` must be set in pref.toml"))?;
-    let base_path = resolve_path(base_dir, clash_base);
-    let base_text = std::fs::read_to_string(&base_path)
-        .with_context(|| format!("failed to read base config {}", base_path.display()))?;
-    let mut base = serde_yaml::from_str::<Value>(&base_text)
-        .with_context(|| format!("failed to parse base config {}", base_path.display()))?
-        .as_object()
-        .cloned()
-        .context("base clash config must be a YAML map")?;
-
+    let mut base = load_clash_base(pref, &runtime.network, base_dir)?;
     base.remove("proxies");
     base.remove("proxy-groups");
     base.remove("rules");
 
     super::util::apply_node_pref(pref, registry, &mut proxies);
-    proxies.retain(|proxy| !registry.target_not_implemented(&proxy.protocol, "clash"));
-    if pref.common.sort {
-        proxies.sort_by(|a, b| a.name.cmp(&b.name));
+    let placeholders = super::util::take_unsupported(pref, registry, &mut proxies, "clash", force);
+    if pref.common.sort && !presorted {
+        proxy::sort_proxies(&mut proxies);
     }
 
     let clash_proxies: Vec<FlowMap<ProxyForYaml>> = proxies
         .iter()
         .map(|p| {
-            let rendered = p.to_target(registry, "clash")?;
+            let rendered = p.to_target_with(registry, "clash", force)?;
             let map = rendered
                 .as_object()
                 .cloned()
@@ -65,17 +82,50 @@ fn render_clash(args: RenderArgs<'_>) -> Result<String> {
         })
         .collect::<Result<_>>()?;
 
+    // Placeholders still take part in group matching, so groups keep
+    // referencing them by name.
+    let mut grouped = proxies.clone();
+    grouped.extend(placeholders.iter().cloned());
+    if pref.common.sort && !presorted {
+        proxy::sort_proxies(&mut grouped);
+    }
+
     let group_specs = load_group_specs_from_pref(pref, base_dir)?;
-    let proxy_groups =
-        groups::build_groups(&group_specs, &proxies).context("failed to build proxy groups")?;
+    let mut proxy_groups = groups::build_groups(&group_specs, &grouped, &pref.groups)
+        .context("failed to build proxy groups")?;
+    if let Some(selected) = &selected_groups {
+        groups::retain_groups(&mut proxy_groups, selected)?;
+    }
     info!(groups = proxy_groups.len(), "proxy groups built");
 
     let clash_groups: Vec<Value> = proxy_groups
         .iter()
         .map(crate::export::clash::render_proxy_group)
+        .chain(
+            placeholders
+                .iter()
+                .map(|proxy| reject_placeholder(&proxy.name)),
+        )
         .collect();
 
-    let rules = load_rules_from_pref(pref, &runtime.network, base_dir)?;
+    let as_providers = pref.ruleset.as_ref().is_some_and(|r| r.as_providers);
+    let (mut rules, mut providers) =
+        load_rules_with_providers(pref, &runtime.network, base_dir, as_providers)?;
+    if selected_groups.is_some() {
+        let kept: Vec<&str> = proxy_groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect();
+        rules::retain_rules_for_groups(&mut rules, &kept);
+        // Providers only referenced by dropped rules are not needed either.
+        providers.retain(|provider| {
+            rules
+                .iter()
+                .any(|rule| rule.content.as_deref() == Some(provider.name.as_str()))
+        });
+    }
+    apply_keyword_as_regex(pref, "clash", &mut rules);
+    let rule_providers = render_rule_providers(pref, &mut base, &providers);
     let rendered_rules: Vec<Value> = rules
         .iter()
         .map(|r| {
@@ -94,6 +144,7 @@ fn render_clash(args: RenderArgs<'_>) -> Result<String> {
         base,
         proxies: clash_proxies,
         proxy_groups: clash_groups,
+        rule_providers,
         rules: rendered_rules,
     };
 
@@ -101,6 +152,56 @@ fn render_clash(args: RenderArgs<'_>) -> Result<String> {
     Ok(strip_rule_quotes(&final_yaml))
 }
 
+/// Clash proxies cannot point at `REJECT`, so an unsupported node becomes a
+/// select group under its name whose only member is `REJECT`.
+fn reject_placeholder(name: &str) -> Value {
+    crate::export::clash::render_proxy_group(&groups::ProxyGroup {
+        name: name.to_string(),
+        group_type: "select".to_string(),
+        proxies: vec!["REJECT".to_string()],
+        url: None,
+        interval: None,
+        include_all: false,
+        filter: None,
+        exclude_filter: None,
+        disable_udp: None,
+        hidden: None,
+    })
+}
+
+/// `rule-providers` for remote rulesets, after any the base config declares.
+/// They are downloaded by the client as classical text lists and refreshed
+/// on the ruleset cache TTL.
+fn render_rule_providers(
+    pref: &Pref,
+    base: &mut JsonMap<String, Value>,
+    providers: &[RuleProvider],
+) -> JsonMap<String, Value> {
+    let mut rendered = match base.remove("rule-providers") {
+        Some(Value::Object(existing)) => existing,
+        _ => JsonMap::new(),
+    };
+    let interval = pref
+        .ruleset
+        .as_ref()
+        .and_then(|r| r.cache_ttl_seconds)
+        .unwrap_or(pref.network.ttl_seconds);
+    for provider in providers {
+        rendered.insert(
+            provider.name.clone(),
+            serde_json::json!({
+                "type": "http",
+                "behavior": "classical",
+                "format": "text",
+                "url": provider.url,
+                "path": format!("./rule-providers/{}.txt", provider.name),
+                "interval": interval,
+            }),
+        );
+    }
+    rendered
+}
+
 #[derive(Serialize)]
 struct ClashOutput {
     #[serde(flatten)]
@@ -108,6 +209,8 @@ struct ClashOutput {
     proxies: Vec<FlowMap<ProxyForYaml>>,
     #[serde(rename = "proxy-groups")]
     proxy_groups: Vec<Value>,
+    #[serde(rename = "rule-providers", skip_serializing_if = "JsonMap::is_empty")]
+    rule_providers: JsonMap<String, Value>,
     rules: Vec<Value>,
 }
 
@@ -182,3 +285,371 @@ fn strip_rule_quotes(yaml: &str) -> String {
 
     out
 }
+
+/// Reads every `common.clash_rule_base` file or URL and merges them in order.
+fn load_clash_base(
+    pref: &Pref,
+    network: &Network,
+    base_dir: &std::path::Path,
+) -> Result<JsonMap<String, Value>> {
+    let paths = pref
+        .common
+        .clash_rule_base
+        .as_ref()
+        .map(|base| base.paths())
+        .filter(|paths| !paths.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("`common.clash_rule_base` must be set in pref.toml"))?;
+
+    let mut merged = JsonMap::new();
+    for path in paths {
+        let base_text = read_base_text(network, base_dir, path)?;
+        let base = serde_yaml::from_str::<Value>(&base_text)
+            .with_context(|| format!("failed to parse base config {path}"))?
+            .as_object()
+            .cloned()
+            .with_context(|| format!("base clash config {path} must be a YAML map"))?;
+        merged = deep_merge(merged, base);
+    }
+    Ok(merged)
+}
+
+/// Merges `overlay` into `base`: nested maps are merged key by key, any other
+/// value in `overlay` replaces the one in `base`.
+fn deep_merge(
+    mut base: JsonMap<String, Value>,
+    overlay: JsonMap<String, Value>,
+) -> JsonMap<String, Value> {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(inner)), Value::Object(overlay)) => {
+                *inner = deep_merge(std::mem::take(inner), overlay);
+            }
+            // Replacing an existing key keeps its position in the output.
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+    use crate::server::{TargetRenderer, test_dir, test_pref};
+
+    fn pref(managed: &str) -> Pref {
+        toml::from_str(&format!(
+            "[common]\n\n[managed_config]\n{managed}\n\n[server]\nlisten = \"127.0.0.1\"\nport = 0\n"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn managed_config_sets_profile_update_interval() {
+        let mut headers = HeaderMap::new();
+        ClashRenderer.apply_headers(
+            &pref("write_managed_config = true\ninterval = 43200"),
+            &mut headers,
+        );
+        assert_eq!(headers["profile-update-interval"], "12");
+    }
+
+    #[test]
+    fn reject_placeholder_is_a_select_group() {
+        assert_eq!(
+            reject_placeholder("tuic-node"),
+            serde_json::json!({ "name": "tuic-node", "type": "select", "proxies": ["REJECT"] })
+        );
+    }
+
+    #[test]
+    fn nested_proxy_maps_survive_yaml_output() {
+        let smux = serde_json::json!({
+            "enabled": true,
+            "protocol": "h2mux",
+            "brutal-opts": { "enabled": true, "up": 50, "down": 100 },
+        });
+        let node = serde_json::json!({
+            "name": "ss-node",
+            "server": "example.com",
+            "port": 8388,
+            "cipher": "aes-128-gcm",
+            "password": "secret",
+            "smux": smux,
+        });
+        let registry = test_registry();
+        let rendered = registry
+            .convert("shadowsocks", "clash", "ss-node", node.as_object().unwrap())
+            .unwrap();
+        let proxies = vec![FlowMap(ProxyForYaml::new(
+            rendered.as_object().cloned().unwrap(),
+        ))];
+
+        let yaml = serde_yaml::to_string(&proxies).unwrap();
+        let reparsed: Vec<Value> = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(reparsed[0]["smux"], smux, "{yaml}");
+    }
+
+    #[test]
+    fn profile_update_interval_absent_when_disabled() {
+        let mut headers = HeaderMap::new();
+        ClashRenderer.apply_headers(&pref("write_managed_config = false"), &mut headers);
+        assert!(headers.get("profile-update-interval").is_none());
+    }
+
+    #[test]
+    fn later_base_overrides_nested_keys() {
+        let dir = test_dir("clash-base");
+        std::fs::write(
+            dir.join("shared.yml"),
+            "mode: rule\ndns:\n  enable: true\n  nameserver:\n    - 223.5.5.5\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("deploy.yml"),
+            "dns:\n  nameserver:\n    - 1.1.1.1\n",
+        )
+        .unwrap();
+        let pref: Pref = toml::from_str(
+            "[common]\nclash_rule_base = [\"shared.yml\", \"deploy.yml\"]\n\n[server]\nlisten = \"127.0.0.1\"\nport = 0\n",
+        )
+        .unwrap();
+
+        let network = Network::new(&pref.network, &dir).unwrap();
+        let base = load_clash_base(&pref, &network, &dir).unwrap();
+        assert_eq!(base["mode"], "rule");
+        assert_eq!(base["dns"]["enable"], true);
+        assert_eq!(base["dns"]["nameserver"], serde_json::json!(["1.1.1.1"]));
+    }
+
+    #[test]
+    fn single_base_path_is_still_accepted() {
+        let pref: Pref = toml::from_str(
+            "[common]\nclash_rule_base = \"base.yml\"\n\n[server]\nlisten = \"127.0.0.1\"\nport = 0\n",
+        )
+        .unwrap();
+        let base = pref.common.clash_rule_base.unwrap();
+        assert_eq!(base.paths(), ["base.yml"]);
+    }
+
+    #[test]
+    fn remote_rulesets_become_rule_providers() {
+        let dir = test_dir("rule-providers");
+        std::fs::write(dir.join("base.yml"), "mode: rule\n").unwrap();
+        std::fs::write(
+            dir.join("rulesets.toml"),
+            r#"
+[[rulesets]]
+group = "AI"
+ruleset = ["https://rules.example.com/Clash/ai.txt", "[]DOMAIN,local.example.com"]
+
+[[rulesets]]
+group = "DIRECT"
+ruleset = "[]FINAL"
+"#,
+        )
+        .unwrap();
+        let pref_path = test_pref(
+            "rule-providers",
+            r#"
+[common]
+clash_rule_base = "{dir}/base.yml"
+
+[ruleset]
+enabled = true
+as_providers = true
+cache_ttl_seconds = 3600
+
+[[rulesets]]
+import = "{dir}/rulesets.toml"
+"#,
+        );
+
+        let runtime = crate::server::build_runtime(&pref_path, &dir).unwrap();
+        let yaml = crate::server::render_offline(
+            &crate::server::default_targets(),
+            &runtime,
+            &dir,
+            "clash",
+        )
+        .unwrap();
+        let output: Value = serde_yaml::from_str(&yaml).unwrap();
+
+        let providers = output["rule-providers"].as_object().unwrap();
+        assert_eq!(providers.len(), 1);
+        let (name, provider) = providers.iter().next().unwrap();
+        assert!(name.starts_with("ai-"), "{name}");
+        assert_eq!(provider["url"], "https://rules.example.com/Clash/ai.txt");
+        assert_eq!(provider["behavior"], "classical");
+        assert_eq!(provider["interval"], 3600);
+
+        let rules: Vec<&str> = output["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                format!("RULE-SET,{name},AI").as_str(),
+                "DOMAIN,local.example.com,AI",
+                "MATCH,DIRECT",
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_filter_drops_other_groups_and_their_rules() {
+        let dir = test_dir("groups-filter");
+        std::fs::write(dir.join("base.yml"), "mode: rule\n").unwrap();
+        std::fs::write(
+            dir.join("groups.toml"),
+            r#"
+[[groups]]
+name = "Proxy"
+type = "select"
+rule = ["[]Auto", "node-a"]
+
+[[groups]]
+name = "Auto"
+type = "url-test"
+rule = ["node-a"]
+
+[[groups]]
+name = "Streaming"
+type = "select"
+rule = ["node-a"]
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("rulesets.toml"),
+            r#"
+[[rulesets]]
+group = "Proxy"
+ruleset = "[]DOMAIN-SUFFIX,google.com"
+
+[[rulesets]]
+group = "Streaming"
+ruleset = ["https://rules.example.com/Clash/netflix.txt", "[]DOMAIN-SUFFIX,netflix.com"]
+
+[[rulesets]]
+group = "Streaming"
+ruleset = "[]FINAL"
+"#,
+        )
+        .unwrap();
+        let pref_path = test_pref(
+            "groups-filter",
+            r#"
+[common]
+clash_rule_base = "{dir}/base.yml"
+
+[ruleset]
+enabled = true
+as_providers = true
+
+[[custom_groups]]
+import = "{dir}/groups.toml"
+
+[[rulesets]]
+import = "{dir}/rulesets.toml"
+"#,
+        );
+
+        let runtime = crate::server::build_runtime(&pref_path, &dir).unwrap();
+        let node = serde_json::json!({
+            "name": "node-a",
+            "server": "a.example.com",
+            "port": 443,
+            "password": "secret",
+        });
+        let yaml = ClashRenderer
+            .render(RenderArgs {
+                runtime: &runtime,
+                base_dir: &dir,
+                proxies: vec![proxy::Proxy {
+                    name: "node-a".to_string(),
+                    protocol: "trojan".to_string(),
+                    values: node.as_object().cloned().unwrap(),
+                }],
+                request_uri: None,
+                scope_token: None,
+                base64: false,
+                presorted: false,
+                force: false,
+                selected_groups: Some(vec!["Proxy".to_string()]),
+            })
+            .unwrap();
+        let output: Value = serde_yaml::from_str(&yaml).unwrap();
+
+        // `Auto` is kept because `Proxy` references it.
+        let groups: Vec<&str> = output["proxy-groups"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|group| group["name"].as_str())
+            .collect();
+        assert_eq!(groups, ["Proxy", "Auto"]);
+
+        let rules: Vec<&str> = output["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        assert_eq!(rules, ["DOMAIN-SUFFIX,google.com,Proxy", "MATCH,Proxy"]);
+        assert!(output.get("rule-providers").is_none(), "{yaml}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remote_base_is_fetched_once_and_cached() {
+        use axum::{Router, extract::State, routing::get};
+        use std::sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        };
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream = Router::new()
+            .route(
+                "/base.yml",
+                get(|State(hits): State<Arc<AtomicUsize>>| async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    "mode: rule\nmixed-port: 7890\n"
+                }),
+            )
+            .with_state(hits.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let dir = test_dir("remote-base");
+        let _ = std::fs::remove_dir_all(dir.join("cache"));
+        let pref_path = test_pref(
+            "remote-base",
+            &format!(
+                r#"
+[common]
+clash_rule_base = "http://{addr}/base.yml"
+
+[network]
+allowed_domain = ["127.0.0.1"]
+"#
+            ),
+        );
+
+        let runtime = crate::server::build_runtime(&pref_path, &dir).unwrap();
+        let targets = crate::server::default_targets();
+        for _ in 0..2 {
+            let yaml = crate::server::render_offline(&targets, &runtime, &dir, "clash").unwrap();
+            let output: Value = serde_yaml::from_str(&yaml).unwrap();
+            assert_eq!(output["mode"], "rule");
+            assert_eq!(output["mixed-port"], 7890);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as FmtWrite,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+
+use crate::paths::resolve_path;
+
+use super::{TargetRenderer, build_runtime_with_schema, render_offline};
+
+/// Lines of unchanged context around each hunk.
+const CONTEXT_LINES: usize = 3;
+/// Above this many line pairs the changed region is shown as a full replace.
+const MAX_LCS_CELLS: usize = 4_000_000;
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// Target to render, for example `clash` or `surge`
+    #[arg(long, default_value = "clash")]
+    target: String,
+    /// Candidate pref.toml to compare against `--pref`
+    #[arg(long)]
+    candidate_pref: Option<String>,
+    /// Candidate schema directory, overriding `common.schema` of the candidate
+    #[arg(long)]
+    candidate_schema: Option<String>,
+}
+
+pub fn run(
+    targets: &HashMap<String, Arc<dyn TargetRenderer>>,
+    pref_path: &Path,
+    base_dir: &Path,
+    args: &DiffArgs,
+) -> Result<String> {
+    if args.candidate_pref.is_none() && args.candidate_schema.is_none() {
+        bail!("nothing to compare: pass --candidate-pref and/or --candidate-schema");
+    }
+
+    let candidate_pref = args
+        .candidate_pref
+        .as_deref()
+        .map(|path| resolve_path(base_dir, path))
+        .unwrap_or_else(|| pref_path.to_path_buf());
+    let candidate_schema: Option<PathBuf> = args
+        .candidate_schema
+        .as_deref()
+        .map(|path| resolve_path(base_dir, path));
+
+    let current = build_runtime_with_schema(pref_path, base_dir, None)
+        .context("failed to load current config")?;
+    let current_output = render_offline(targets, &current, base_dir, &args.target)
+        .context("failed to render current config")?;
+
+    let candidate =
+        build_runtime_with_schema(&candidate_pref, base_dir, candidate_schema.as_deref())
+            .context("failed to load candidate config")?;
+    let candidate_output = render_offline(targets, &candidate, base_dir, &args.target)
+        .context("failed to render candidate config")?;
+
+    Ok(unified_diff(
+        &current_output,
+        &candidate_output,
+        &pref_path.display().to_string(),
+        &candidate_pref.display().to_string(),
+    ))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+impl Op<'_> {
+    fn in_old(self) -> bool {
+        !matches!(self, Op::Insert(_))
+    }
+
+    fn in_new(self) -> bool {
+        !matches!(self, Op::Delete(_))
+    }
+}
+
+/// Render a unified diff of two texts; empty when they are identical.
+pub fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = diff_lines(&old_lines, &new_lines);
+
+    let changes: Vec<usize> = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, Op::Equal(_)))
+        .map(|(idx, _)| idx)
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    let mut out = format!("--- {old_label}\n+++ {new_label}\n");
+    let mut idx = 0;
+    while idx < changes.len() {
+        // Merge changes whose context windows touch into a single hunk.
+        let first = changes[idx];
+        let mut last = first;
+        while idx + 1 < changes.len() && changes[idx + 1] - last <= 2 * CONTEXT_LINES {
+            idx += 1;
+            last = changes[idx];
+        }
+        let start = first.saturating_sub(CONTEXT_LINES);
+        let end = (last + CONTEXT_LINES + 1).min(ops.len());
+        idx += 1;
+
+        let old_start = ops[..start].iter().filter(|op| op.in_old()).count();
+        let new_start = ops[..start].iter().filter(|op| op.in_new()).count();
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|op| op.in_old()).count();
+        let new_len = hunk.iter().filter(|op| op.in_new()).count();
+        let _ = writeln!(
+            out,
+            "@@ -{},{} +{},{} @@",
+            hunk_start(old_start, old_len),
+            old_len,
+            hunk_start(new_start, new_len),
+            new_len
+        );
+        for op in hunk {
+            let _ = match op {
+                Op::Equal(line) => writeln!(out, " {line}"),
+                Op::Delete(line) => writeln!(out, "-{line}"),
+                Op::Insert(line) => writeln!(out, "+{line}"),
+            };
+        }
+    }
+    out
+}
+
+fn hunk_start(start: usize, len: usize) -> usize {
+    if len == 0 { start } else { start + 1 }
+}
+
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Op<'a>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<Op<'a>> = old[..prefix].iter().copied().map(Op::Equal).collect();
+    let (mut i, mut j) = (0, 0);
+    if a.len().saturating_mul(b.len()) <= MAX_LCS_CELLS {
+        // lcs[i][j] is the LCS length of a[i..] and b[j..].
+        let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i][j] = if a[i] == b[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        while i < a.len() && j < b.len() {
+            if a[i] == b[j] {
+                ops.push(Op::Equal(a[i]));
+                i += 1;
+                j += 1;
+            } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+                ops.push(Op::Delete(a[i]));
+                i += 1;
+            } else {
+                ops.push(Op::Insert(b[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(a[i..].iter().copied().map(Op::Delete));
+    ops.extend(b[j..].iter().copied().map(Op::Insert));
+    ops.extend(old[old.len() - suffix..].iter().copied().map(Op::Equal));
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{default_targets, test_dir, test_pref};
+
+    #[test]
+    fn identical_outputs_have_no_diff() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", "old", "new"), "");
+    }
+
+    #[test]
+    fn unified_diff_reports_hunk() {
+        let diff = unified_diff("a\nb\nc\n", "a\nB\nc\n", "old", "new");
+        assert_eq!(diff, "--- old\n+++ new\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n");
+    }
+
+    #[test]
+    fn changed_group_shows_in_diff() {
+        let dir = test_dir("diff");
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            r#"proxies:
+  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }
+  - { name: node-b, type: trojan, server: b.example.com, port: 443, password: secret }
+"#,
+        )
+        .unwrap();
+        let [current, candidate] =
+            [("current", "node-a"), ("candidate", "node-")].map(|(name, rule)| {
+                std::fs::write(
+                    dir.join(format!("groups-{name}.toml")),
+                    format!(
+                        "[[groups]]\nname = \"Proxy\"\ntype = \"select\"\nrule = [\"{rule}\"]\n"
+                    ),
+                )
+                .unwrap();
+                test_pref(
+                    &format!("diff-{name}"),
+                    &format!(
+                        r#"
+[common]
+surge_rule_base = "{dir}/base.cfg"
+default_url = ["{dir}/profile.yaml"]
+
+[[custom_groups]]
+import = "{dir}/groups-{name}.toml"
+"#,
+                        dir = dir.display(),
+                    ),
+                )
+            });
+
+        let args = DiffArgs {
+            target: "surge".to_string(),
+            candidate_pref: Some(candidate.display().to_string()),
+            candidate_schema: None,
+        };
+        let diff = run(&default_targets(), &current, &dir, &args).unwrap();
+        assert!(diff.contains("-Proxy = select,node-a\n"), "{diff}");
+        assert!(diff.contains("+Proxy = select,node-a,node-b\n"), "{diff}");
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+/// How long a stored ETag answers a matching `If-None-Match` without a
+/// render. Kept short so upstream changes show up quickly.
+const ETAG_TTL: Duration = Duration::from_secs(30);
+
+/// Last `/sub` ETag per request, so conditional requests that still match
+/// are answered with `304 Not Modified` before fetching or rendering.
+#[derive(Default)]
+pub struct EtagCache {
+    entries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl EtagCache {
+    /// The stored ETag for `key`, unless it is older than the TTL.
+    pub async fn get(&self, key: &str) -> Option<String> {
+        let entries = self.entries.lock().await;
+        let (etag, stored) = entries.get(key)?;
+        (stored.elapsed() < ETAG_TTL).then(|| etag.clone())
+    }
+
+    /// Stores the ETag of the latest render and drops expired entries.
+    pub async fn insert(&self, key: String, etag: String) {
+        let mut entries = self.entries.lock().await;
+        entries.retain(|_, (_, stored)| stored.elapsed() < ETAG_TTL);
+        entries.insert(key, (etag, Instant::now()));
+    }
+
+    /// Forgets every ETag, e.g. after the config changed through the API.
+    pub async fn clear(&self) {
+        self.entries.lock().await.clear();
+    }
+}
+
+/// Weak ETag of a rendered body: the quoted SHA-256 hex digest.
+pub fn weak_etag(body: &str) -> String {
+    format!("W/\"{:x}\"", Sha256::digest(body.as_bytes()))
+}
+
+/// Whether an `If-None-Match` header value matches `etag`, using the weak
+/// comparison from RFC 9110: `W/` prefixes are ignored.
+pub fn if_none_match(header: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    header
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{SubQuery, handle_sub, test_dir, test_state};
+    use axum::{
+        extract::{Query, State},
+        http::{HeaderMap, HeaderValue, StatusCode, Uri, header},
+    };
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        let etag = weak_etag("body");
+        assert!(etag.starts_with("W/\""));
+        assert!(if_none_match(&etag, &etag));
+        assert!(if_none_match(&etag[2..], &etag));
+        assert!(if_none_match(&format!("\"other\", {etag}"), &etag));
+        assert!(if_none_match("*", &etag));
+        assert!(!if_none_match(&weak_etag("changed"), &etag));
+    }
+
+    #[tokio::test]
+    async fn matching_if_none_match_returns_not_modified() {
+        let dir = test_dir("etag");
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            "proxies:\n  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }\n",
+        )
+        .unwrap();
+        let state = test_state(
+            "etag",
+            r#"
+[common]
+surge_rule_base = "{dir}/base.cfg"
+default_url = ["{dir}/profile.yaml"]
+"#,
+        );
+
+        let request = |headers: HeaderMap| {
+            handle_sub(
+                State(state.clone()),
+                Query(SubQuery::for_target("surge")),
+                Uri::from_static("/sub?target=surge"),
+                headers,
+            )
+        };
+
+        let response = request(HeaderMap::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            etag.to_str().unwrap(),
+            weak_etag(&String::from_utf8_lossy(&body))
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+        let response = request(headers).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_static("W/\"stale\""),
+        );
+        let response = request(headers).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
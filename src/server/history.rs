@@ -0,0 +1,158 @@
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::fs;
+
+/// Directory next to `pref.toml` holding prior versions of edited files.
+const HISTORY_DIR: &str = ".history";
+
+/// A saved version of a file, numbered in the order it was replaced.
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    pub version: u64,
+    pub bytes: u64,
+    /// Unix seconds when the version was saved.
+    pub saved_at: u64,
+}
+
+fn history_dir(config_dir: &Path, kind: &str, name: &str) -> PathBuf {
+    config_dir.join(HISTORY_DIR).join(kind).join(name)
+}
+
+/// Saves the current contents of `path` as a new version before it is
+/// overwritten, then drops the oldest versions beyond `limit`. A missing file
+/// or contents equal to the newest version record nothing.
+pub async fn record(
+    config_dir: &Path,
+    kind: &str,
+    name: &str,
+    path: &Path,
+    limit: usize,
+) -> Result<()> {
+    if limit == 0 {
+        return Ok(());
+    }
+    let current = match fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+
+    let dir = history_dir(config_dir, kind, name);
+    fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+    let mut versions = versions(&dir).await?;
+    if let Some(&newest) = versions.last()
+        && fs::read(dir.join(newest.to_string())).await.ok().as_ref() == Some(&current)
+    {
+        return Ok(());
+    }
+
+    let next = versions.last().map_or(1, |newest| newest + 1);
+    let file = dir.join(next.to_string());
+    fs::write(&file, current)
+        .await
+        .with_context(|| format!("failed to write {}", file.display()))?;
+    versions.push(next);
+
+    let excess = versions.len().saturating_sub(limit);
+    for version in &versions[..excess] {
+        let _ = fs::remove_file(dir.join(version.to_string())).await;
+    }
+    Ok(())
+}
+
+/// Saved versions of a file, newest first.
+pub async fn list(config_dir: &Path, kind: &str, name: &str) -> Result<Vec<HistoryEntry>> {
+    let dir = history_dir(config_dir, kind, name);
+    let mut entries = Vec::new();
+    for version in versions(&dir).await?.into_iter().rev() {
+        let metadata = fs::metadata(dir.join(version.to_string())).await?;
+        let saved_at = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |age| age.as_secs());
+        entries.push(HistoryEntry {
+            version,
+            bytes: metadata.len(),
+            saved_at,
+        });
+    }
+    Ok(entries)
+}
+
+/// Contents of a saved version, or `None` when it does not exist.
+pub async fn read(
+    config_dir: &Path,
+    kind: &str,
+    name: &str,
+    version: u64,
+) -> Result<Option<String>> {
+    let file = history_dir(config_dir, kind, name).join(version.to_string());
+    match fs::read_to_string(&file).await {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("failed to read {}", file.display())),
+    }
+}
+
+/// Version numbers stored in `dir`, oldest first.
+async fn versions(dir: &Path) -> Result<Vec<u64>> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", dir.display()));
+        }
+    };
+    let mut versions = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(version) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            versions.push(version);
+        }
+    }
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn oldest_versions_are_pruned() {
+        let dir = std::env::temp_dir().join(format!("subcon-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.yaml");
+
+        for content in ["v1", "v2", "v2", "v3"] {
+            std::fs::write(&file, content).unwrap();
+            record(&dir, "profiles", "a.yaml", &file, 2).await.unwrap();
+        }
+
+        let versions: Vec<u64> = list(&dir, "profiles", "a.yaml")
+            .await
+            .unwrap()
+            .iter()
+            .map(|entry| entry.version)
+            .collect();
+        // The repeated `v2` is stored once, and `v1` is pruned.
+        assert_eq!(versions, [3, 2]);
+        let oldest = read(&dir, "profiles", "a.yaml", 2).await.unwrap();
+        assert_eq!(oldest.as_deref(), Some("v2"));
+        assert_eq!(read(&dir, "profiles", "a.yaml", 1).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,60 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use tracing::{info, warn};
+
+use crate::export::links::share_link;
+use crate::proxy;
+use crate::schema::SchemaRegistry;
+
+use super::{ApiError, RenderArgs};
+
+/// Plain subscription of share links, one per line.
+pub struct LinksRenderer;
+
+impl super::TargetRenderer for LinksRenderer {
+    fn render(&self, args: RenderArgs<'_>) -> Result<String, ApiError> {
+        Ok(render_links(args))
+    }
+
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    fn filename(&self) -> &'static str {
+        "links.txt"
+    }
+
+    fn keeps_proxy(&self, _registry: &SchemaRegistry, proxy: &proxy::Proxy) -> bool {
+        share_link(proxy).is_ok()
+    }
+}
+
+fn render_links(args: RenderArgs<'_>) -> String {
+    let RenderArgs {
+        runtime,
+        mut proxies,
+        base64,
+        presorted,
+        ..
+    } = args;
+    let pref = &runtime.pref;
+
+    super::util::apply_node_pref(pref, &runtime.registry, &mut proxies);
+    if pref.common.sort && !presorted {
+        proxy::sort_proxies(&mut proxies);
+    }
+
+    let links: Vec<String> = proxies
+        .iter()
+        .filter_map(|proxy| match share_link(proxy) {
+            Ok(link) => Some(link),
+            Err(err) => {
+                warn!(name = %proxy.name, error = %err, "skipping proxy without share link");
+                None
+            }
+        })
+        .collect();
+    info!(count = links.len(), "proxies rendered as share links");
+
+    let body = links.join("\n");
+    if base64 { STANDARD.encode(body) } else { body }
+}
@@ -0,0 +1,173 @@
+use std::fmt::Write as FmtWrite;
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::groups;
+use crate::proxy::{self, Proxy};
+use crate::rules;
+use crate::schema::SchemaRegistry;
+
+use super::util::{
+    apply_keyword_as_regex, group_member_names, load_group_specs_from_pref, load_rules_from_pref,
+    push_section, read_rule_base, render_line_rules,
+};
+use super::{ApiError, RenderArgs};
+
+pub struct LoonRenderer;
+
+impl super::TargetRenderer for LoonRenderer {
+    fn render(&self, args: RenderArgs<'_>) -> Result<String, ApiError> {
+        render_loon(args).map_err(ApiError::render)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    fn filename(&self) -> &'static str {
+        "loon.conf"
+    }
+
+    fn keeps_proxy(&self, registry: &SchemaRegistry, proxy: &Proxy) -> bool {
+        registry.supports_target(&proxy.protocol, "loon")
+    }
+}
+
+fn render_loon(args: RenderArgs<'_>) -> Result<String> {
+    let RenderArgs {
+        runtime,
+        base_dir,
+        mut proxies,
+        presorted,
+        force,
+        selected_groups,
+        ..
+    } = args;
+    let pref = &runtime.pref;
+    let registry = &runtime.registry;
+
+    let mut out = read_rule_base(
+        &runtime.network,
+        base_dir,
+        pref.common.loon_rule_base.as_deref(),
+        "loon_rule_base",
+    )?;
+    out.push('\n');
+
+    super::util::apply_node_pref(pref, registry, &mut proxies);
+    proxies.retain(|proxy| {
+        let supported = registry.supports_target(&proxy.protocol, "loon")
+            || (force && registry.target_not_implemented(&proxy.protocol, "loon"));
+        if !supported {
+            warn!(name = %proxy.name, protocol = %proxy.protocol, "skipping proxy unsupported by loon");
+        }
+        supported
+    });
+    if pref.common.sort && !presorted {
+        proxy::sort_proxies(&mut proxies);
+    }
+    info!(count = proxies.len(), "proxies loaded for loon render");
+
+    let proxy_lines = proxies
+        .iter()
+        .map(|proxy| render_loon_proxy_line(registry, proxy, force))
+        .collect::<Result<Vec<_>>>()?;
+    push_section(&mut out, "Proxy", &proxy_lines);
+    if !proxy_lines.is_empty() {
+        out.push('\n');
+    }
+
+    let group_specs = load_group_specs_from_pref(pref, base_dir)?;
+    let mut proxy_groups = groups::build_groups(&group_specs, &proxies, &pref.groups)
+        .context("failed to build proxy groups")?;
+    if let Some(selected) = &selected_groups {
+        groups::retain_groups(&mut proxy_groups, selected)?;
+    }
+    info!(groups = proxy_groups.len(), "proxy groups built for loon");
+
+    let group_lines: Vec<String> = proxy_groups.iter().map(render_loon_group_line).collect();
+    push_section(&mut out, "Proxy Group", &group_lines);
+    if !group_lines.is_empty() {
+        out.push('\n');
+    }
+
+    let mut rules = load_rules_from_pref(pref, &runtime.network, base_dir)?;
+    if selected_groups.is_some() {
+        let kept: Vec<&str> = proxy_groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect();
+        rules::retain_rules_for_groups(&mut rules, &kept);
+    }
+    apply_keyword_as_regex(pref, "loon", &mut rules);
+    let rendered_rules = render_line_rules(&rules);
+    info!(rules = rendered_rules.len(), "rules rendered for loon");
+    push_section(&mut out, "Rule", &rendered_rules);
+
+    Ok(out)
+}
+
+fn render_loon_proxy_line(registry: &SchemaRegistry, proxy: &Proxy, force: bool) -> Result<String> {
+    let rendered = proxy.to_target_with(registry, "loon", force)?;
+    match rendered {
+        Value::String(s) => Ok(s),
+        other => Err(anyhow!("loon exporter must return string, got {other}")),
+    }
+}
+
+fn render_loon_group_line(group: &groups::ProxyGroup) -> String {
+    let group_type = if group.proxies.is_empty() {
+        "select"
+    } else {
+        group.group_type.as_str()
+    };
+    let mut line = format!("{} = {}", group.name, group_type);
+    for name in group_member_names(group) {
+        let _ = write!(line, ",{}", name);
+    }
+
+    // Loon only reads test options on the automatic group types.
+    if matches!(group_type, "url-test" | "fallback" | "load-balance") {
+        if let Some(url) = &group.url {
+            let _ = write!(line, ",url = {url}");
+        }
+        if let Some(interval) = group.interval {
+            let _ = write!(line, ",interval = {interval}");
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(group_type: &str, proxies: &[&str]) -> groups::ProxyGroup {
+        groups::ProxyGroup {
+            name: "Auto".to_string(),
+            group_type: group_type.to_string(),
+            proxies: proxies.iter().map(|p| p.to_string()).collect(),
+            url: Some("http://www.gstatic.com/generate_204".to_string()),
+            interval: Some(600),
+            include_all: false,
+            filter: None,
+            exclude_filter: None,
+            disable_udp: None,
+            hidden: None,
+        }
+    }
+
+    #[test]
+    fn url_test_group_keeps_test_options() {
+        assert_eq!(
+            render_loon_group_line(&group("url-test", &["HK 01", "[]DIRECT"])),
+            "Auto = url-test,HK 01,DIRECT,url = http://www.gstatic.com/generate_204,interval = 600"
+        );
+        assert_eq!(
+            render_loon_group_line(&group("url-test", &[])),
+            "Auto = select,DIRECT"
+        );
+    }
+}
@@ -1,33 +1,63 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
 use axum::{
     Json, Router,
     body::Body,
-    extract::{Query, State},
-    http::{Request, StatusCode, Uri},
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, HeaderValue, Request, StatusCode, Uri, header},
     middleware::Next,
     response::{IntoResponse, Response},
     routing::get,
 };
-use clap::Parser;
-use serde::Deserialize;
-use tokio::{net::TcpListener, sync::RwLock};
-use tracing::{info, warn};
+use clap::{Parser, Subcommand};
+use futures_util::future::try_join_all;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map as JsonMap, Value};
+use tokio::{
+    net::TcpListener,
+    sync::{RwLock, watch},
+    task::JoinSet,
+};
+use tracing::{debug, info, warn};
 
-use crate::config::{Pref, load_pref};
-use crate::network::Network;
+use crate::config::{Pref, ProfileSet, load_pref};
+use crate::logging::{self, redact_secrets};
+use crate::metrics::Metrics;
+use crate::network::{FetchMeta, Network};
 use crate::paths::resolve_path;
 use crate::proxy;
-use crate::schema::SchemaRegistry;
-use crate::server::util::{gather_insert_paths, gather_profile_paths};
+use crate::schema::{SchemaOptions, SchemaRegistry};
+use crate::server::util::{
+    apply_field_overrides, fetch_proxy_provider_text, gather_insert_paths, gather_profile_paths,
+    load_extra_rules, load_group_specs_from_pref, load_rules_with_providers, merge_custom_proxies,
+    parse_override_query, validate_custom_proxies,
+};
 
 mod api;
 mod clash;
+mod diff;
+mod etag;
+mod history;
+mod links;
+mod loon;
+mod rate_limit;
+mod reachability;
+mod render;
+mod render_cache;
 mod surge;
 mod util;
 mod web;
@@ -41,33 +71,75 @@ struct Cli {
     /// Path to pref.toml
     #[arg(long, default_value = "conf/pref.toml")]
     pref: String,
+    /// Directory that relative paths, including `--pref`, are resolved against
+    #[arg(long, visible_alias = "config-dir", default_value = ".")]
+    base_dir: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Render a target with the current and a candidate config and print a unified diff
+    Diff(diff::DiffArgs),
+    /// Render a target from the local profiles once and write it to a file or stdout
+    Render(render::RenderCommandArgs),
 }
 
 pub async fn run() -> Result<()> {
     let args = Cli::parse();
-    let base_dir = PathBuf::from(".");
+    // Subcommands print their result on stdout, so their logs go to stderr.
+    logging::init_logging(args.command.is_some());
+    let base_dir = args.base_dir.clone();
     let pref_path = resolve_path(&base_dir, &args.pref);
 
-    let mut targets: HashMap<String, Arc<dyn TargetRenderer>> = HashMap::new();
-    targets.insert("clash".to_string(), Arc::new(clash::ClashRenderer));
-    targets.insert("surge".to_string(), Arc::new(surge::SurgeRenderer));
+    let targets = default_targets();
 
-    let runtime = build_runtime(&pref_path, &base_dir)?;
+    if let Some(Command::Diff(diff_args)) = &args.command {
+        let output = diff::run(&targets, &pref_path, &base_dir, diff_args)?;
+        print!("{output}");
+        return Ok(());
+    }
+    if let Some(Command::Render(render_args)) = &args.command {
+        return render::run(&targets, &pref_path, &base_dir, render_args);
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    let runtime = build_runtime(&pref_path, &base_dir)?.with_metrics(metrics.clone());
 
     let listen_addr = format!(
         "{}:{}",
         runtime.pref.server.listen, runtime.pref.server.port
     );
+    let keepalive = Duration::from_secs(runtime.pref.server.http_keepalive_seconds);
+    let shutdown_timeout = Duration::from_secs(runtime.pref.server.shutdown_timeout_seconds);
 
     let state = AppState {
         runtime: Arc::new(RwLock::new(runtime)),
         targets,
         pref_path,
         base_dir,
+        rate_limiter: Arc::default(),
+        render_cache: Arc::default(),
+        latency_cache: Arc::default(),
+        etag_cache: Arc::default(),
+        metrics,
     };
 
+    web::warn_if_missing();
+    spawn_cache_revalidation(state.runtime.clone());
+    rate_limit::spawn_pruning(state.rate_limiter.clone());
+    render_cache::spawn_warming(state.clone());
     let app = Router::new()
-        .route("/sub", get(handle_sub))
+        .route(
+            "/sub",
+            get(handle_sub).layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit::limit_sub,
+            )),
+        )
+        .route(HEALTHZ_PATH, get(handle_healthz))
+        .route(METRICS_PATH, get(handle_metrics))
         .nest("/api", api::router(state.clone()))
         .fallback(web::handle_web)
         .layer(axum::middleware::from_fn(log_requests))
@@ -77,11 +149,209 @@ pub async fn run() -> Result<()> {
     let listener = TcpListener::bind(&listen_addr)
         .await
         .context(format!("failed to bind {listen_addr}"))?;
-    info!("server started on {listen_addr}");
-    axum::serve(listener, app).await?;
+    info!(
+        keepalive_seconds = keepalive.as_secs(),
+        "server started on {listen_addr}"
+    );
+    serve(
+        listener,
+        app,
+        keepalive,
+        shutdown_signal(),
+        shutdown_timeout,
+    )
+    .await
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!(error = %err, "failed to listen for ctrl-c");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                warn!(error = %err, "failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+const REVALIDATION_MAX_TICK: Duration = Duration::from_secs(60);
+
+/// Refreshes cache entries shortly before they expire so `/sub` requests
+/// rarely hit a cold fetch. Settings are re-read on every tick, so reloads
+/// take effect without restarting the task.
+fn spawn_cache_revalidation(runtime: Arc<RwLock<RuntimeState>>) {
+    tokio::spawn(async move {
+        loop {
+            let current = runtime.read().await.clone();
+            let window = current
+                .pref
+                .network
+                .revalidate_before_expiry_seconds
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs);
+            let tick = match window {
+                Some(window) => {
+                    let refreshed = current.network.revalidate_expiring(window).await;
+                    if refreshed > 0 {
+                        info!(refreshed, "revalidated expiring cache entries");
+                    }
+                    // Check at least twice per window so no entry slips past it.
+                    (window / 2).clamp(Duration::from_secs(1), REVALIDATION_MAX_TICK)
+                }
+                None => REVALIDATION_MAX_TICK,
+            };
+            tokio::time::sleep(tick).await;
+        }
+    });
+}
+
+/// Pause after an accept error that is not about a single connection.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Accept loop equivalent to `axum::serve`, with an idle keep-alive timeout.
+///
+/// Connections speak HTTP/1 or HTTP/2, whichever the client opens with. Idle
+/// HTTP/1 connections are closed once no new request header arrives within
+/// `keepalive`. Once `shutdown` resolves, no new connections are
+/// accepted and in-flight requests get `shutdown_timeout` to finish before
+/// the remaining connections are abandoned.
+async fn serve(
+    listener: TcpListener,
+    app: Router,
+    keepalive: Duration,
+    shutdown: impl Future<Output = ()>,
+    shutdown_timeout: Duration,
+) -> Result<()> {
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let mut connections = JoinSet::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let (stream, remote_addr) = match accepted {
+            Ok(conn) => conn,
+            Err(err) if is_connection_error(&err) => continue,
+            Err(err) => {
+                // Errors such as running out of file descriptors would come
+                // straight back, so back off instead of spinning.
+                warn!(error = %err, "failed to accept connection");
+                tokio::select! {
+                    _ = tokio::time::sleep(ACCEPT_ERROR_DELAY) => continue,
+                    _ = &mut shutdown => break,
+                }
+            }
+        };
+        // Same as `into_make_service_with_connect_info`, for the rate limiter.
+        let service =
+            TowerToHyperService::new(app.clone().layer(axum::Extension(ConnectInfo(remote_addr))));
+        let mut shutdown_rx = shutdown_rx.clone();
+        connections.spawn(async move {
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .keep_alive(true)
+                .header_read_timeout(keepalive);
+            builder.http2().timer(TokioTimer::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            let mut connection = std::pin::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown_rx.changed() => {
+                    // Let the in-flight request finish, then close.
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(err) = result {
+                debug!(remote = %remote_addr, error = %err, "connection closed with error");
+            }
+        });
+        while connections.try_join_next().is_some() {}
+    }
+
+    drop(listener);
+    let _ = shutdown_tx.send(());
+    info!(
+        connections = connections.len(),
+        timeout_seconds = shutdown_timeout.as_secs(),
+        "shutting down, draining in-flight requests"
+    );
+    let drain = async { while connections.join_next().await.is_some() {} };
+    if tokio::time::timeout(shutdown_timeout, drain).await.is_err() {
+        warn!(
+            remaining = connections.len(),
+            "shutdown timeout elapsed, closing remaining connections"
+        );
+        connections.abort_all();
+    }
     Ok(())
 }
 
+/// Accept errors that only concern the connection being accepted, like
+/// `axum::serve` treats them.
+fn is_connection_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+const HEALTHZ_PATH: &str = "/healthz";
+
+/// Unauthenticated readiness probe. The runtime is built before the server
+/// binds and reloads keep the previous one on failure, so this only has to
+/// confirm the schema registry is usable. The body carries no details beyond
+/// `dirty` when a failed reload left the files out of step with the server.
+async fn handle_healthz(State(state): State<AppState>) -> (StatusCode, &'static str) {
+    let runtime = state.runtime.read().await;
+    if runtime.registry.protocol_count() == 0 {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    } else if runtime.is_dirty() {
+        (StatusCode::OK, "dirty")
+    } else {
+        (StatusCode::OK, "ok")
+    }
+}
+
+const METRICS_PATH: &str = "/metrics";
+
+/// Unauthenticated Prometheus scrape target, kept outside `/api` so
+/// scrapers need no token.
+async fn handle_metrics(State(state): State<AppState>) -> Response {
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        state.metrics.render(),
+    )
+        .into_response()
+}
+
 async fn log_requests(req: Request<Body>, next: Next) -> Response {
     let path = req.uri().path().to_string();
     let method = req.method().to_string();
@@ -98,7 +368,8 @@ async fn log_requests(req: Request<Body>, next: Next) -> Response {
                 latency_ms = latency.as_millis(),
                 "http response"
             );
-        } else {
+        } else if path != HEALTHZ_PATH && path != METRICS_PATH {
+            // Probes and scrapes arrive every few seconds; keep them out of the log.
             info!(
                 method = %method,
                 path = %path,
@@ -117,6 +388,19 @@ pub struct AppState {
     targets: HashMap<String, Arc<dyn TargetRenderer>>,
     pref_path: PathBuf,
     base_dir: PathBuf,
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+    render_cache: Arc<render_cache::RenderCache>,
+    latency_cache: Arc<reachability::LatencyCache>,
+    etag_cache: Arc<etag::EtagCache>,
+    metrics: Arc<Metrics>,
+}
+
+impl AppState {
+    /// Builds a runtime from `pref_path` whose network reports to this
+    /// state's metrics.
+    fn load_runtime(&self, pref_path: &Path) -> Result<RuntimeState> {
+        Ok(build_runtime(pref_path, &self.base_dir)?.with_metrics(self.metrics.clone()))
+    }
 }
 
 #[derive(Clone)]
@@ -124,32 +408,196 @@ pub struct RuntimeState {
     pub pref: Arc<Pref>,
     pub registry: Arc<SchemaRegistry>,
     pub network: Network,
+    /// Set when reloading from disk failed, so the files no longer match what
+    /// is being served. A successful reload replaces the state with a clean one.
+    pub config_dirty: Arc<AtomicBool>,
+}
+
+impl RuntimeState {
+    pub fn is_dirty(&self) -> bool {
+        self.config_dirty.load(Ordering::Relaxed)
+    }
+
+    pub fn mark_dirty(&self) {
+        self.config_dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.network = self.network.with_metrics(metrics);
+        self
+    }
+
+    /// Loads the groups and rules the pref points at and counts them, so a
+    /// broken snippet fails a reload instead of the next `/sub`. Remote
+    /// rulesets are not fetched and count as one rule each.
+    pub fn summarize(&self, base_dir: &Path) -> Result<RuntimeSummary> {
+        let groups = load_group_specs_from_pref(&self.pref, base_dir)?;
+        let (rules, _) = load_rules_with_providers(&self.pref, &self.network, base_dir, true)?;
+        Ok(RuntimeSummary {
+            protocols: self.registry.protocol_count(),
+            groups: groups.len(),
+            rules: rules.len(),
+        })
+    }
+}
+
+/// What a freshly built runtime loaded, reported by `/api/control/reload`.
+#[derive(Debug, Serialize)]
+pub struct RuntimeSummary {
+    pub protocols: usize,
+    pub groups: usize,
+    pub rules: usize,
+}
+
+fn default_targets() -> HashMap<String, Arc<dyn TargetRenderer>> {
+    let mut targets: HashMap<String, Arc<dyn TargetRenderer>> = HashMap::new();
+    targets.insert("clash".to_string(), Arc::new(clash::ClashRenderer));
+    targets.insert("surge".to_string(), Arc::new(surge::SurgeRenderer));
+    targets.insert("loon".to_string(), Arc::new(loon::LoonRenderer));
+    targets.insert("links".to_string(), Arc::new(links::LinksRenderer));
+    targets
 }
 
 fn build_runtime(pref_path: &Path, base_dir: &Path) -> Result<RuntimeState> {
+    build_runtime_with_schema(pref_path, base_dir, None)
+}
+
+/// Like [`build_runtime`], optionally loading schemas from `schema_override`
+/// instead of `common.schema`.
+fn build_runtime_with_schema(
+    pref_path: &Path,
+    base_dir: &Path,
+    schema_override: Option<&Path>,
+) -> Result<RuntimeState> {
     let pref = load_pref(pref_path)?;
-    let schema_rel = pref
-        .common
-        .schema
-        .as_deref()
-        .ok_or_else(|| anyhow::anyhow!("`common.schema` must be set in pref.toml"))?;
-    let schema_path = resolve_path(base_dir, schema_rel);
+    let schema_path = match schema_override {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let schema_rel = pref
+                .common
+                .schema
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("`common.schema` must be set in pref.toml"))?;
+            resolve_path(base_dir, schema_rel)
+        }
+    };
 
-    let registry = SchemaRegistry::with_builtin(&schema_path)?;
+    let registry = SchemaRegistry::with_options(
+        &schema_path,
+        SchemaOptions {
+            prune_fields: pref.common.prune_fields,
+        },
+    )?;
+    validate_custom_proxies(&pref, &registry)?;
+    load_extra_rules(&pref)?;
     let network = Network::new(&pref.network, base_dir)?;
 
     Ok(RuntimeState {
         pref: Arc::new(pref),
         registry: Arc::new(registry),
         network,
+        config_dirty: Arc::default(),
     })
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(try_from = "Vec<(String, String)>")]
 struct SubQuery {
     target: String,
     token: Option<String>,
-    url: Option<String>,
+    /// Subscriptions to merge in order, from repeated `url=` parameters or a
+    /// comma-separated list.
+    url: Vec<String>,
+    base64: Option<String>,
+    /// `no_cache=1` fetches `url` fresh instead of reusing the cache.
+    no_cache: Option<String>,
+    /// `sort=latency` orders nodes by their last measured latency.
+    sort: Option<String>,
+    /// `force=1` renders protocols whose target is `not-implemented`. Only
+    /// honored together with the API token.
+    force: Option<String>,
+    /// `groups=Proxy,Auto` renders only these groups, the groups they
+    /// reference, and the rules pointing at them.
+    groups: Option<String>,
+    /// `override=skip-cert-verify:true,sni:example.com` sets fields on the
+    /// nodes fetched from `url`.
+    overrides: Option<String>,
+}
+
+/// Parsed from the raw pairs, since a derived struct rejects repeated keys.
+impl TryFrom<Vec<(String, String)>> for SubQuery {
+    type Error = String;
+
+    fn try_from(pairs: Vec<(String, String)>) -> Result<Self, Self::Error> {
+        let mut target = None;
+        let mut query = SubQuery {
+            target: String::new(),
+            token: None,
+            url: Vec::new(),
+            base64: None,
+            no_cache: None,
+            sort: None,
+            force: None,
+            groups: None,
+            overrides: None,
+        };
+        for (key, value) in pairs {
+            match key.as_str() {
+                "target" => target = Some(value),
+                "token" => query.token = Some(value),
+                "url" => query.url.extend(split_url_list(&value)),
+                "base64" => query.base64 = Some(value),
+                "no_cache" => query.no_cache = Some(value),
+                "sort" => query.sort = Some(value),
+                "force" => query.force = Some(value),
+                "groups" => query.groups = Some(value),
+                "override" => query.overrides = Some(value),
+                _ => {}
+            }
+        }
+        query.target = target.ok_or("missing field `target`")?;
+        Ok(query)
+    }
+}
+
+#[cfg(test)]
+impl SubQuery {
+    /// A request for `target` with every other parameter unset.
+    fn for_target(target: &str) -> Self {
+        SubQuery {
+            target: target.to_string(),
+            token: None,
+            url: Vec::new(),
+            base64: None,
+            no_cache: None,
+            sort: None,
+            force: None,
+            groups: None,
+            overrides: None,
+        }
+    }
+}
+
+/// Splits `a,b` into subscription URLs. A comma only starts a new URL when
+/// an `http(s)://` URL follows, so commas inside a URL are kept.
+fn split_url_list(value: &str) -> Vec<String> {
+    let mut urls: Vec<String> = Vec::new();
+    for piece in value.split(',') {
+        let starts_url = ["http://", "https://"]
+            .iter()
+            .any(|scheme| piece.trim_start().to_ascii_lowercase().starts_with(scheme));
+        match urls.last_mut() {
+            Some(last) if !starts_url => {
+                last.push(',');
+                last.push_str(piece);
+            }
+            _ => urls.push(piece.to_string()),
+        }
+    }
+    urls.into_iter()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect()
 }
 
 const SUBSCRIPTION_USER_AGENTS: [&str; 2] = ["Clash/v1.18.0", "mihomo/1.19.17"];
@@ -158,6 +606,7 @@ async fn handle_sub(
     State(state): State<AppState>,
     Query(params): Query<SubQuery>,
     uri: Uri,
+    headers: HeaderMap,
 ) -> Result<Response, ApiError> {
     let renderer = match state.targets.get(&params.target) {
         Some(r) => r,
@@ -169,70 +618,305 @@ async fn handle_sub(
             ));
         }
     };
+    state.metrics.record_sub_request(&params.target);
 
     let runtime = state.runtime.read().await.clone();
+    let warmable = params.url.is_empty()
+        && params.token.is_none()
+        && params.base64.is_none()
+        && params.no_cache.is_none()
+        && params.sort.is_none()
+        && params.force.is_none()
+        && params.groups.is_none()
+        && runtime.pref.common.remote_profiles_url.is_none()
+        && runtime.pref.common.warm_targets.contains(&params.target);
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    let gzip = accepts_gzip(&headers);
+    if warmable && let Some(body) = state.render_cache.get(&params.target).await {
+        debug!(target = %params.target, "serving /sub from render cache");
+        let etag = etag::weak_etag(&body);
+        if if_none_match.is_some_and(|header| etag::if_none_match(header, &etag)) {
+            return Ok(not_modified(&etag));
+        }
+        let mut response = sub_response(renderer.as_ref(), &runtime.pref, body, gzip);
+        apply_etag(response.headers_mut(), &etag);
+        response
+            .headers_mut()
+            .insert(RENDER_CACHE_HEADER, HeaderValue::from_static("hit"));
+        return Ok(response);
+    }
+
+    let no_cache = matches!(params.no_cache.as_deref(), Some("1" | "true"));
     let include_insert = params
         .token
         .as_ref()
         .zip(runtime.pref.common.api_access_token.as_ref())
         .map(|(provided, expected)| provided == expected)
         .unwrap_or(false);
+    let profile_set = params
+        .token
+        .as_deref()
+        .and_then(|token| runtime.pref.profile_set(token));
+    let force = include_insert && matches!(params.force.as_deref(), Some("1" | "true"));
+    if params.force.is_some() && !include_insert {
+        warn!("ignoring `force` without the API token");
+    }
     info!(
         target = %params.target,
         include_insert,
-        url_provided = params.url.is_some(),
+        scoped = profile_set.is_some(),
+        urls = params.url.len(),
+        no_cache,
+        force,
         "handling /sub request"
     );
 
-    let proxies = load_proxies_for_request(
+    let by_latency = match params.sort.as_deref() {
+        None => false,
+        Some("latency") => true,
+        Some(other) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("unsupported sort `{other}`; expected `latency`"),
+            ));
+        }
+    };
+    let selected_groups = match params.groups.as_deref() {
+        None => None,
+        Some(list) => {
+            let names: Vec<String> = list
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect();
+            if names.is_empty() {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "`groups` must name at least one group",
+                ));
+            }
+            Some(names)
+        }
+    };
+    let overrides = match params.overrides.as_deref() {
+        None => JsonMap::new(),
+        Some(_) if params.url.is_empty() => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "`override` only applies to nodes fetched from `url`",
+            ));
+        }
+        Some(raw) => parse_override_query(&runtime.registry, raw)
+            .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))?,
+    };
+    // The query covers target, url and token; the token only widens the
+    // output while it matches, so that is part of the key as well.
+    let etag_key = format!("{include_insert}\n{uri}");
+    if !no_cache
+        && let Some(header) = if_none_match
+        && let Some(etag) = state.etag_cache.get(&etag_key).await
+        && etag::if_none_match(header, &etag)
+    {
+        debug!(target = %params.target, "answering /sub with 304 from the etag cache");
+        return Ok(not_modified(&etag));
+    }
+
+    let (mut proxies, meta) = load_proxies_for_request(
         &runtime,
         &state.base_dir,
-        params.url.as_deref(),
+        &params.url,
+        profile_set,
         include_insert,
+        no_cache,
+        &overrides,
     )
     .await?;
+    if by_latency {
+        state.latency_cache.sort(&mut proxies).await;
+    }
 
-    let body = renderer.render(RenderArgs {
+    let started = std::time::Instant::now();
+    let rendered = renderer.render(RenderArgs {
         runtime: &runtime,
         base_dir: &state.base_dir,
         proxies,
         request_uri: Some(uri.to_string()),
-    })?;
+        scope_token: profile_set.map(|set| set.token.clone()),
+        base64: matches!(params.base64.as_deref(), Some("1" | "true")),
+        presorted: by_latency,
+        force,
+        selected_groups,
+    });
+    state.metrics.record_render(started.elapsed());
+    let body = rendered?;
 
-    Ok((
-        StatusCode::OK,
-        [(axum::http::header::CONTENT_TYPE, "text/yaml; charset=utf-8")],
-        body,
-    )
-        .into_response())
+    let etag = etag::weak_etag(&body);
+    state.etag_cache.insert(etag_key, etag.clone()).await;
+    if if_none_match.is_some_and(|header| etag::if_none_match(header, &etag)) {
+        return Ok(not_modified(&etag));
+    }
+    let mut response = sub_response(renderer.as_ref(), &runtime.pref, body, gzip);
+    apply_etag(response.headers_mut(), &etag);
+    apply_fetch_meta(response.headers_mut(), &meta);
+    if warmable {
+        response
+            .headers_mut()
+            .insert(RENDER_CACHE_HEADER, HeaderValue::from_static("miss"));
+    }
+    Ok(response)
+}
+
+/// Tells whether a warm target was served from the render cache.
+const RENDER_CACHE_HEADER: &str = "x-subcon-render-cache";
+
+/// Builds a `/sub` body response, gzipped when the client accepts it.
+fn sub_response(renderer: &dyn TargetRenderer, pref: &Pref, body: String, gzip: bool) -> Response {
+    let content_type = [(header::CONTENT_TYPE, renderer.content_type())];
+    let mut response = match gzip.then(|| gzip_body(body.as_bytes())).transpose() {
+        Ok(Some(compressed)) => (
+            StatusCode::OK,
+            content_type,
+            [(header::CONTENT_ENCODING, "gzip")],
+            compressed,
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::OK, content_type, body).into_response(),
+        Err(err) => {
+            warn!(error = %err, "failed to gzip /sub response");
+            (StatusCode::OK, content_type, body).into_response()
+        }
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", renderer.filename()))
+    {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    renderer.apply_headers(pref, headers);
+    response
+}
+
+fn gzip_body(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Whether `Accept-Encoding` lists `gzip` (or `*`) without `q=0`.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let rejected = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            matches!(name.to_ascii_lowercase().as_str(), "gzip" | "x-gzip" | "*") && !rejected
+        })
+}
+
+fn not_modified(etag: &str) -> Response {
+    let mut response = StatusCode::NOT_MODIFIED.into_response();
+    apply_etag(response.headers_mut(), etag);
+    response
+}
+
+fn apply_etag(headers: &mut HeaderMap, etag: &str) {
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        headers.insert(header::ETAG, value);
+    }
+}
+
+/// Surface upstream fetch metadata on the `/sub` response.
+fn apply_fetch_meta(headers: &mut HeaderMap, meta: &FetchMeta) {
+    if let Some(userinfo) = meta
+        .subscription_userinfo
+        .as_deref()
+        .and_then(|v| HeaderValue::from_str(v).ok())
+    {
+        headers.insert("subscription-userinfo", userinfo);
+    }
+    if let Some(age) = meta.age {
+        headers.insert(header::AGE, HeaderValue::from(age.as_secs()));
+    }
 }
 
 async fn load_proxies_for_request(
     runtime: &RuntimeState,
     base_dir: &Path,
-    url: Option<&str>,
+    urls: &[String],
+    profile_set: Option<&ProfileSet>,
     include_insert: bool,
-) -> Result<Vec<crate::proxy::Proxy>, ApiError> {
+    no_cache: bool,
+    overrides: &JsonMap<String, Value>,
+) -> Result<(Vec<crate::proxy::Proxy>, FetchMeta), ApiError> {
     let pref = &runtime.pref;
     let registry = &runtime.registry;
 
-    let mut proxies = if let Some(raw_url) = url {
-        let parsed_url = parse_subscription_url(raw_url)?;
-        fetch_proxies_from_url(&runtime.network, registry, &parsed_url).await?
+    let (mut proxies, meta) = if !urls.is_empty() {
+        let parsed_urls = urls
+            .iter()
+            .map(|raw_url| parse_subscription_url(raw_url))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Fetched concurrently, then merged in request order.
+        let fetched = try_join_all(parsed_urls.iter().map(|parsed_url| {
+            fetch_proxies_from_url(&runtime.network, registry, parsed_url, no_cache, overrides)
+        }))
+        .await?;
+        let mut proxies = Vec::new();
+        let mut first_meta = None;
+        for (fetched, meta) in fetched {
+            proxies.extend(fetched);
+            // Usage and age headers describe the first subscription.
+            first_meta.get_or_insert(meta);
+        }
+        (proxies, first_meta.unwrap_or_default())
     } else {
-        let profiles =
-            gather_profile_paths(pref, include_insert, base_dir).map_err(ApiError::internal)?;
-        proxy::load_from_paths(registry, profiles)
+        let profiles = gather_profile_paths(pref, profile_set, include_insert, base_dir)
+            .map_err(ApiError::internal)?;
+        let overrides = pref.protocol_overrides(base_dir);
+        let mut proxies = proxy::load_from_paths(registry, profiles, &overrides)
             .context("failed to load proxies from profiles")
-            .map_err(ApiError::internal)?
+            .map_err(ApiError::internal)?;
+        // Profile sets are scoped to their own files.
+        if profile_set.is_none()
+            && let Some(index_url) = &pref.common.remote_profiles_url
+        {
+            proxies.extend(
+                proxy::load_from_remote_index(
+                    &runtime.network,
+                    registry,
+                    index_url,
+                    &SUBSCRIPTION_USER_AGENTS,
+                    no_cache,
+                    &pref.protocol_overrides,
+                )
+                .await,
+            );
+        }
+        (proxies, FetchMeta::default())
     };
 
-    if url.is_some() && include_insert && pref.common.enable_insert {
+    if !urls.is_empty() && include_insert && pref.common.enable_insert {
         let insert_paths = gather_insert_paths(pref, base_dir);
         if insert_paths.is_empty() {
             warn!("insert enabled but no insert_url provided");
         } else {
-            let mut insert_proxies = proxy::load_from_paths(registry, insert_paths)
+            let overrides = pref.protocol_overrides(base_dir);
+            let mut insert_proxies = proxy::load_from_paths(registry, insert_paths, &overrides)
                 .context("failed to load proxies from insert profiles")
                 .map_err(ApiError::internal)?;
             if pref.common.prepend_insert_url {
@@ -243,8 +927,12 @@ async fn load_proxies_for_request(
             }
         }
     }
+    merge_custom_proxies(pref, &mut proxies).map_err(ApiError::internal)?;
+    if pref.common.dedupe_names {
+        proxy::dedupe_names(&mut proxies);
+    }
 
-    Ok(proxies)
+    Ok((proxies, meta))
 }
 
 fn parse_subscription_url(raw: &str) -> Result<reqwest::Url, ApiError> {
@@ -266,17 +954,62 @@ async fn fetch_proxies_from_url(
     network: &Network,
     registry: &SchemaRegistry,
     url: &reqwest::Url,
-) -> Result<Vec<crate::proxy::Proxy>, ApiError> {
-    network
-        .get_or_fetch_with(url, &SUBSCRIPTION_USER_AGENTS, false, |text| {
-            let proxies = proxy::load_from_text(registry, text)?;
+    no_cache: bool,
+    overrides: &JsonMap<String, Value>,
+) -> Result<(Vec<crate::proxy::Proxy>, FetchMeta), ApiError> {
+    let (mut proxies, meta) = network
+        .get_or_fetch_with_meta(url, &SUBSCRIPTION_USER_AGENTS, no_cache, None, |text| {
+            let proxies = proxy::load_from_text_with_fetcher(
+                registry,
+                text,
+                &proxy::ProtocolOverrides::new(),
+                |url| fetch_proxy_provider_text(network, url),
+            )?;
             if proxies.is_empty() {
                 anyhow::bail!("no proxies found");
             }
             Ok(proxies)
         })
         .await
-        .map_err(|err| ApiError::new(err.status, err.to_string()))
+        .map_err(|err| {
+            ApiError::new(err.status, err.to_string()).with_retry_after(err.retry_after)
+        })?;
+    apply_field_overrides(registry, &mut proxies, overrides);
+    Ok((proxies, meta))
+}
+
+/// Render `target` from the configured local profiles, outside of a request.
+/// Profiles listed by `common.remote_profiles_url` are not fetched here.
+fn render_offline(
+    targets: &HashMap<String, Arc<dyn TargetRenderer>>,
+    runtime: &RuntimeState,
+    base_dir: &Path,
+    target: &str,
+) -> Result<String> {
+    let renderer = targets
+        .get(target)
+        .ok_or_else(|| anyhow::anyhow!("unsupported target {target}"))?;
+    let profiles = gather_profile_paths(&runtime.pref, None, false, base_dir)?;
+    let overrides = runtime.pref.protocol_overrides(base_dir);
+    let mut proxies = proxy::load_from_paths(&runtime.registry, profiles, &overrides)
+        .context("failed to load proxies from profiles")?;
+    merge_custom_proxies(&runtime.pref, &mut proxies)?;
+    if runtime.pref.common.dedupe_names {
+        proxy::dedupe_names(&mut proxies);
+    }
+    renderer
+        .render(RenderArgs {
+            runtime,
+            base_dir,
+            proxies,
+            request_uri: Some(format!("/sub?target={target}")),
+            scope_token: None,
+            base64: false,
+            presorted: false,
+            force: false,
+            selected_groups: None,
+        })
+        .map_err(|err| anyhow::anyhow!(err.message))
 }
 
 pub struct RenderArgs<'a> {
@@ -284,31 +1017,95 @@ pub struct RenderArgs<'a> {
     pub base_dir: &'a Path,
     pub proxies: Vec<crate::proxy::Proxy>,
     pub request_uri: Option<String>,
+    /// Token of the profile set the request is scoped to, kept in the
+    /// managed config URL so refreshes stay scoped.
+    pub scope_token: Option<String>,
+    /// Wrap the whole body in base64, set by `/sub?base64=1`.
+    pub base64: bool,
+    /// Proxies were already ordered by the request (`/sub?sort=latency`), so
+    /// `common.sort` must not reorder them.
+    pub presorted: bool,
+    /// Render protocols marked `not-implemented` for the target anyway, set
+    /// by `/sub?force=1` with the API token.
+    pub force: bool,
+    /// Groups picked by `/sub?groups=`. Other groups, except those the
+    /// picked ones reference, are dropped along with their rules.
+    pub selected_groups: Option<Vec<String>>,
 }
 
 pub trait TargetRenderer: Send + Sync {
     fn render(&self, args: RenderArgs<'_>) -> Result<String, ApiError>;
+
+    fn content_type(&self) -> &'static str {
+        "text/yaml; charset=utf-8"
+    }
+
+    /// Suggested download name, sent in `Content-Disposition`.
+    fn filename(&self) -> &'static str;
+
+    /// Whether a render without `force` keeps `proxy` rather than leaving it
+    /// out, as reported by `/api/render/diff`.
+    fn keeps_proxy(&self, registry: &SchemaRegistry, proxy: &proxy::Proxy) -> bool;
+
+    /// Add target specific headers to the `/sub` response.
+    fn apply_headers(&self, _pref: &Pref, _headers: &mut HeaderMap) {}
 }
 
+#[derive(Debug)]
 pub struct ApiError {
     status: StatusCode,
     message: String,
+    /// Sent as `Retry-After`, rounded up to whole seconds.
+    retry_after: Option<Duration>,
+    /// Each cause of the error, outermost first, sent as `details`.
+    details: Vec<String>,
 }
 
 impl ApiError {
     fn new(status: StatusCode, message: impl Into<String>) -> Self {
         Self {
             status,
-            message: message.into(),
+            message: redact_secrets(&message.into()),
+            retry_after: None,
+            details: Vec::new(),
         }
     }
 
+    fn with_retry_after(mut self, retry_after: Option<Duration>) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
     fn internal(err: impl Into<anyhow::Error>) -> Self {
-        let msg = format!("{:?}", err.into());
+        let msg = redact_secrets(&format!("{:?}", err.into()));
         warn!(error = %msg, "internal error during render");
         Self {
             status: StatusCode::INTERNAL_SERVER_ERROR,
             message: msg,
+            retry_after: None,
+            details: Vec::new(),
+        }
+    }
+
+    /// Maps a render failure: a `groups` filter naming a group the config
+    /// lacks is the client's mistake, anything else is internal.
+    fn render(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<crate::groups::UnknownGroup>() {
+            Some(unknown) => Self::new(StatusCode::BAD_REQUEST, unknown.to_string()),
+            None => Self::internal(err),
+        }
+    }
+
+    /// Like [`ApiError::internal`], also listing the error chain so clients
+    /// can show what failed without reading the logs.
+    fn with_causes(err: anyhow::Error) -> Self {
+        let details = err
+            .chain()
+            .map(|cause| redact_secrets(&cause.to_string()))
+            .collect();
+        Self {
+            details,
+            ..Self::internal(err)
         }
     }
 }
@@ -318,9 +1115,882 @@ impl IntoResponse for ApiError {
         if self.status.is_client_error() {
             warn!(status = %self.status, message = %self.message, "client error");
         }
-        let body = Json(serde_json::json!({
+        let mut body = serde_json::json!({
             "error": self.message,
-        }));
-        (self.status, body).into_response()
+        });
+        if !self.details.is_empty() {
+            body["details"] = serde_json::json!(self.details);
+        }
+        let body = Json(body);
+        let mut response = (self.status, body).into_response();
+        if let Some(retry_after) = self.retry_after {
+            let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+/// Temp dir for the files of the test called `name`.
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("subcon-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes `pref.toml` into [`test_dir`]: `extra_pref` merged over a minimal
+/// pref, with `{dir}` standing for the test dir.
+#[cfg(test)]
+fn test_pref(name: &str, extra_pref: &str) -> PathBuf {
+    fn merge(base: &mut toml::Table, overlay: toml::Table) {
+        for (key, value) in overlay {
+            match (base.get_mut(&key), value) {
+                (Some(toml::Value::Table(inner)), toml::Value::Table(value)) => merge(inner, value),
+                (_, value) => {
+                    base.insert(key, value);
+                }
+            }
+        }
+    }
+
+    let dir = test_dir(name);
+    let parse = |text: &str| -> toml::Table {
+        toml::from_str(&text.replace("{dir}", &dir.display().to_string())).unwrap()
+    };
+    let mut pref = parse(&format!(
+        r#"[common]
+schema = "{schema}"
+
+[network]
+dir = "{{dir}}/cache"
+
+[server]
+listen = "127.0.0.1"
+port = 0
+"#,
+        schema = concat!(env!("CARGO_MANIFEST_DIR"), "/schema"),
+    ));
+    merge(&mut pref, parse(extra_pref));
+    let pref_path = dir.join("pref.toml");
+    std::fs::write(&pref_path, toml::to_string(&pref).unwrap()).unwrap();
+    pref_path
+}
+
+/// State loaded from [`test_pref`].
+#[cfg(test)]
+fn test_state(name: &str, extra_pref: &str) -> AppState {
+    let pref_path = test_pref(name, extra_pref);
+    let dir = test_dir(name);
+    let metrics = Arc::new(Metrics::default());
+    let runtime = build_runtime(&pref_path, &dir)
+        .unwrap()
+        .with_metrics(metrics.clone());
+    AppState {
+        runtime: Arc::new(RwLock::new(runtime)),
+        targets: default_targets(),
+        pref_path,
+        base_dir: dir,
+        rate_limiter: Arc::default(),
+        render_cache: Arc::default(),
+        latency_cache: Arc::default(),
+        etag_cache: Arc::default(),
+        metrics,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn retry_after_is_rounded_up_to_seconds() {
+        let response = ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "upstream failed")
+            .with_retry_after(Some(Duration::from_millis(1500)))
+            .into_response();
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+
+        let response = ApiError::new(StatusCode::BAD_GATEWAY, "upstream failed").into_response();
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+    }
+
+    #[tokio::test]
+    async fn internal_error_body_redacts_password() {
+        let err = anyhow::anyhow!("invalid proxy {{ name: node, password: hunter2 }}")
+            .context("failed to parse subscription");
+        let response = ApiError::internal(err).into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(!body.contains("hunter2"), "{body}");
+        assert!(body.contains("password: ***"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn idle_connection_closed_after_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(serve(
+            listener,
+            app,
+            Duration::from_secs(1),
+            std::future::pending(),
+            Duration::from_secs(1),
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200"));
+
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("idle connection should be closed by the server")
+            .unwrap();
+        assert_eq!(closed, 0);
+    }
+
+    #[tokio::test]
+    async fn http2_connections_are_accepted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(serve(
+            listener,
+            app,
+            Duration::from_secs(30),
+            std::future::pending(),
+            Duration::from_secs(1),
+        ));
+
+        // Client preface followed by an empty SETTINGS frame.
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .await
+            .unwrap();
+        let mut frame_header = [0u8; 9];
+        stream.read_exact(&mut frame_header).await.unwrap();
+        // The server answers with its own SETTINGS frame.
+        assert_eq!(frame_header[3], 0x4, "{frame_header:?}");
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_forced_after_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted_tx, accepted_rx) = tokio::sync::oneshot::channel::<()>();
+        let accepted_tx = Arc::new(std::sync::Mutex::new(Some(accepted_tx)));
+        let app = Router::new().route(
+            "/hang",
+            get(move || {
+                let accepted_tx = accepted_tx.clone();
+                async move {
+                    if let Some(tx) = accepted_tx.lock().unwrap().take() {
+                        let _ = tx.send(());
+                    }
+                    std::future::pending::<&str>().await
+                }
+            }),
+        );
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            app,
+            Duration::from_secs(30),
+            async {
+                let _ = shutdown_rx.await;
+            },
+            Duration::from_millis(500),
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /hang HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        accepted_rx.await.unwrap();
+
+        let started = std::time::Instant::now();
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("shutdown should be forced after the timeout")
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn cached_fetch_sets_age_header() {
+        let mut headers = HeaderMap::new();
+        apply_fetch_meta(&mut headers, &FetchMeta::default());
+        assert!(headers.get(header::AGE).is_none());
+
+        let meta = FetchMeta {
+            age: Some(Duration::from_millis(42_700)),
+            ..FetchMeta::default()
+        };
+        apply_fetch_meta(&mut headers, &meta);
+        assert_eq!(headers[header::AGE], "42");
+    }
+
+    #[tokio::test]
+    async fn no_cache_refetches_subscription() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream = Router::new()
+            .route(
+                "/sub",
+                get(|State(hits): State<Arc<AtomicUsize>>| async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    "proxies:\n  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }\n"
+                }),
+            )
+            .with_state(hits.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let dir = test_dir("no-cache");
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        let state = test_state(
+            "no-cache",
+            r#"
+[common]
+surge_rule_base = "{dir}/base.cfg"
+
+[network]
+allowed_domain = ["127.0.0.1"]
+"#,
+        );
+
+        let request = |no_cache: Option<&str>| {
+            let query = SubQuery {
+                url: vec![format!("http://{upstream_addr}/sub")],
+                no_cache: no_cache.map(str::to_string),
+                ..SubQuery::for_target("surge")
+            };
+            handle_sub(
+                State(state.clone()),
+                Query(query),
+                Uri::from_static("/sub?target=surge"),
+                HeaderMap::new(),
+            )
+        };
+
+        for no_cache in [None, None, Some("1")] {
+            let response = request(no_cache).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // The second request is a cache hit; `no_cache=1` goes upstream again.
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn sub_query_collects_repeated_and_comma_separated_urls() {
+        let uri = Uri::from_static(
+            "/sub?target=clash&url=https%3A%2F%2Fa.example.com%2Fsub&url=https://b.example.com/s?x=1,2,http://c.example.com/",
+        );
+        let Query(query) = Query::<SubQuery>::try_from_uri(&uri).unwrap();
+        assert_eq!(query.target, "clash");
+        assert_eq!(
+            query.url,
+            [
+                "https://a.example.com/sub",
+                "https://b.example.com/s?x=1,2",
+                "http://c.example.com/",
+            ]
+        );
+
+        assert!(Query::<SubQuery>::try_from_uri(&Uri::from_static("/sub?url=x")).is_err());
+    }
+
+    #[tokio::test]
+    async fn multiple_urls_are_merged_in_order() {
+        // `/a` only answers once `/b` was requested, so the URLs must be
+        // fetched concurrently.
+        let b_requested = Arc::new(tokio::sync::Notify::new());
+        let notify_b = b_requested.clone();
+        let upstream = Router::new()
+            .route(
+                "/a",
+                get(move || async move {
+                    b_requested.notified().await;
+                    "proxies:\n  - { name: HK, type: trojan, server: a.example.com, port: 443, password: secret }\n  - { name: JP, type: trojan, server: j.example.com, port: 443, password: secret }\n"
+                }),
+            )
+            .route(
+                "/b",
+                get(move || async move {
+                    notify_b.notify_one();
+                    "proxies:\n  - { name: HK, type: trojan, server: b.example.com, port: 443, password: secret }\n"
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let dir = test_dir("multi-url");
+        let pref_path = test_pref(
+            "multi-url",
+            r#"
+[common]
+dedupe_names = true
+
+[network]
+allowed_domain = ["127.0.0.1"]
+"#,
+        );
+        let runtime = build_runtime(&pref_path, &dir).unwrap();
+
+        let (proxies, _) = tokio::time::timeout(
+            Duration::from_secs(5),
+            load_proxies_for_request(
+                &runtime,
+                &dir,
+                &[
+                    format!("http://{upstream_addr}/a"),
+                    format!("http://{upstream_addr}/b"),
+                ],
+                None,
+                false,
+                false,
+                &JsonMap::new(),
+            ),
+        )
+        .await
+        .expect("subscriptions should be fetched concurrently")
+        .unwrap();
+
+        let nodes: Vec<(&str, &Value)> = proxies
+            .iter()
+            .map(|p| (p.name.as_str(), &p.values["server"]))
+            .collect();
+        assert_eq!(
+            nodes,
+            [
+                ("HK", &Value::from("a.example.com")),
+                ("JP", &Value::from("j.example.com")),
+                ("HK #2", &Value::from("b.example.com")),
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn proxy_providers_are_expanded() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        let profile = format!(
+            r#"proxies:
+  - {{ name: inline, type: trojan, server: i.example.com, port: 443, password: secret }}
+proxy-providers:
+  hk:
+    type: http
+    url: http://{upstream_addr}/providers/hk.yaml
+    interval: 3600
+    health-check: {{ enable: true, url: https://www.gstatic.com/generate_204, interval: 300 }}
+    filter: HK
+  broken:
+    type: http
+    url: http://{upstream_addr}/providers/missing.yaml
+  local:
+    type: file
+    path: ./hk.yaml
+"#
+        );
+        let upstream = Router::new()
+            .route(
+                "/sub",
+                get(|State(profile): State<String>| async move { profile }),
+            )
+            .route(
+                "/providers/hk.yaml",
+                get(|| async {
+                    "proxies:\n  - { name: HK 01, type: trojan, server: hk.example.com, port: 443, password: secret }\n"
+                }),
+            )
+            .with_state(profile);
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let dir = test_dir("providers");
+        let pref_path = test_pref(
+            "providers",
+            r#"
+[network]
+allowed_domain = ["127.0.0.1"]
+retry_base_delay_ms = 10
+"#,
+        );
+        let runtime = build_runtime(&pref_path, &dir).unwrap();
+
+        let (proxies, _) = fetch_proxies_from_url(
+            &runtime.network,
+            &runtime.registry,
+            &reqwest::Url::parse(&format!("http://{upstream_addr}/sub")).unwrap(),
+            false,
+            &JsonMap::new(),
+        )
+        .await
+        .unwrap();
+
+        let names: Vec<&str> = proxies.iter().map(|p| p.name.as_str()).collect();
+        // The unreachable provider and the file provider are skipped.
+        assert_eq!(names, ["inline", "HK 01"]);
+    }
+
+    #[tokio::test]
+    async fn url_overrides_apply_to_fetched_proxies() {
+        let upstream = Router::new().route(
+            "/sub",
+            get(|| async {
+                "proxies:\n  - { name: node-t, type: trojan, server: t.example.com, port: 443, password: secret, sni: t.example.com }\n  - { name: node-s, type: snell, server: s.example.com, port: 8443, psk: secret, version: 3 }\n"
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let dir = test_dir("override");
+        let pref_path = test_pref(
+            "override",
+            r#"
+[network]
+allowed_domain = ["127.0.0.1"]
+"#,
+        );
+        let runtime = build_runtime(&pref_path, &dir).unwrap();
+
+        let overrides = parse_override_query(
+            &runtime.registry,
+            "skip-cert-verify:true,sni:cdn.example.com",
+        )
+        .unwrap();
+        let (proxies, _) = load_proxies_for_request(
+            &runtime,
+            &dir,
+            &[format!("http://{upstream_addr}/sub")],
+            None,
+            false,
+            false,
+            &overrides,
+        )
+        .await
+        .unwrap();
+
+        let [trojan, snell] = proxies.as_slice() else {
+            panic!("expected two proxies");
+        };
+        assert_eq!(trojan.values["skip-cert-verify"], true);
+        assert_eq!(trojan.values["sni"], "cdn.example.com");
+        // snell declares neither field.
+        assert!(!snell.values.contains_key("skip-cert-verify"));
+        assert!(!snell.values.contains_key("sni"));
+    }
+
+    #[tokio::test]
+    async fn sort_latency_orders_fastest_first() {
+        let dir = test_dir("sort-latency");
+        let mut profile = String::from("proxies:\n");
+        for name in ["a", "b", "c", "d"] {
+            profile.push_str(&format!(
+                "  - {{ name: node-{name}, type: trojan, server: {name}.example.com, port: 443, password: secret }}\n"
+            ));
+        }
+        std::fs::write(dir.join("profile.yaml"), profile).unwrap();
+        let state = test_state(
+            "sort-latency",
+            r#"
+[common]
+default_url = ["{dir}/profile.yaml"]
+sort = true
+"#,
+        );
+        let measured = |name: &str, latency_ms: Option<u64>| reachability::ReachabilityResult {
+            name: format!("node-{name}"),
+            server: format!("{name}.example.com"),
+            port: 443,
+            reachable: latency_ms.is_some(),
+            latency_ms,
+            error: None,
+        };
+        state
+            .latency_cache
+            .record(&[
+                measured("a", Some(120)),
+                measured("b", None),
+                measured("c", Some(30)),
+            ])
+            .await;
+
+        let query = SubQuery {
+            sort: Some("latency".to_string()),
+            ..SubQuery::for_target("links")
+        };
+        let response = handle_sub(
+            State(state),
+            Query(query),
+            Uri::from_static("/sub?target=links&sort=latency"),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let names: Vec<&str> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .filter_map(|link| link.rsplit_once('#').map(|(_, name)| name))
+            .collect();
+        // Unreachable and untested nodes follow in name order.
+        assert_eq!(names, ["node-c", "node-a", "node-b", "node-d"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_group_in_filter_is_a_bad_request() {
+        let dir = test_dir("groups-unknown");
+        std::fs::write(dir.join("base.yml"), "mode: rule\n").unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            "proxies:\n  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }\n",
+        )
+        .unwrap();
+        let state = test_state(
+            "groups-unknown",
+            r#"
+[common]
+clash_rule_base = "{dir}/base.yml"
+default_url = ["{dir}/profile.yaml"]
+"#,
+        );
+
+        let query = SubQuery {
+            groups: Some("DoesNotExist".to_string()),
+            ..SubQuery::for_target("clash")
+        };
+        let err = handle_sub(
+            State(state),
+            Query(query),
+            Uri::from_static("/sub?target=clash&groups=DoesNotExist"),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert!(err.message.contains("DoesNotExist"), "{}", err.message);
+    }
+
+    #[test]
+    fn base_dir_flag_resolves_config_paths() {
+        let dir = std::env::temp_dir().join(format!("subcon-base-dir-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf/profiles")).unwrap();
+        std::fs::write(
+            dir.join("conf/profiles/a.yaml"),
+            "proxies:\n  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("conf/pref.toml"),
+            format!(
+                r#"[common]
+schema = "{schema}"
+default_url = ["conf/profiles/a.yaml"]
+
+[server]
+listen = "127.0.0.1"
+port = 0
+"#,
+                schema = concat!(env!("CARGO_MANIFEST_DIR"), "/schema"),
+            ),
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_from(["subcon", "--base-dir", dir.to_str().unwrap()]).unwrap();
+        assert_eq!(cli.base_dir, dir);
+        let pref_path = resolve_path(&cli.base_dir, &cli.pref);
+        assert_eq!(pref_path, dir.join("conf/pref.toml"));
+
+        let runtime = build_runtime(&pref_path, &cli.base_dir).unwrap();
+        let profiles = gather_profile_paths(&runtime.pref, None, false, &cli.base_dir).unwrap();
+        assert_eq!(profiles, [dir.join("conf/profiles/a.yaml")]);
+    }
+
+    #[test]
+    fn sub_responses_declare_type_and_filename_per_target() {
+        let pref: Pref =
+            toml::from_str("[common]\n\n[server]\nlisten = \"127.0.0.1\"\nport = 0\n").unwrap();
+        let targets = default_targets();
+        for (target, content_type, filename) in [
+            ("clash", "text/yaml; charset=utf-8", "clash.yaml"),
+            ("surge", "text/plain; charset=utf-8", "surge.conf"),
+            ("loon", "text/plain; charset=utf-8", "loon.conf"),
+            ("links", "text/plain; charset=utf-8", "links.txt"),
+        ] {
+            let response = sub_response(targets[target].as_ref(), &pref, String::new(), false);
+            let headers = response.headers();
+            assert_eq!(headers[header::CONTENT_TYPE], content_type, "{target}");
+            assert_eq!(
+                headers[header::CONTENT_DISPOSITION],
+                format!("attachment; filename=\"{filename}\""),
+                "{target}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn sub_is_gzipped_when_the_client_accepts_it() {
+        use std::io::Read;
+
+        let dir = test_dir("gzip");
+        std::fs::write(dir.join("base.yaml"), "mode: rule\n").unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            "proxies:\n  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }\n",
+        )
+        .unwrap();
+        let state = test_state(
+            "gzip",
+            r#"
+[common]
+clash_rule_base = "{dir}/base.yaml"
+default_url = ["{dir}/profile.yaml"]
+"#,
+        );
+        let request = |accept_encoding: Option<&'static str>| {
+            let query = SubQuery::for_target("clash");
+            let mut headers = HeaderMap::new();
+            if let Some(value) = accept_encoding {
+                headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+            }
+            let state = state.clone();
+            async move {
+                let response = handle_sub(
+                    State(state),
+                    Query(query),
+                    Uri::from_static("/sub?target=clash"),
+                    headers,
+                )
+                .await
+                .unwrap();
+                let encoding = response.headers().get(header::CONTENT_ENCODING).cloned();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (encoding, body.to_vec())
+            }
+        };
+
+        let (encoding, plain) = request(None).await;
+        assert!(encoding.is_none());
+        assert!(String::from_utf8_lossy(&plain).contains("name: node-a"));
+
+        let (encoding, gzipped) = request(Some("br;q=1.0, gzip;q=0.8")).await;
+        assert_eq!(encoding.unwrap(), "gzip");
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(gzipped.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, plain);
+
+        let (encoding, _) = request(Some("gzip;q=0, identity")).await;
+        assert!(encoding.is_none());
+    }
+
+    #[tokio::test]
+    async fn force_renders_not_implemented_protocols_with_token() {
+        let dir = test_dir("force");
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            "proxies:\n  - { name: node-t, type: trojan, server: t.example.com, port: 443, password: secret }\n  - { name: node-v, type: vless, server: v.example.com, port: 443, uuid: 00000000-0000-0000-0000-000000000000 }\n",
+        )
+        .unwrap();
+        let state = test_state(
+            "force",
+            r#"
+[common]
+surge_rule_base = "{dir}/base.cfg"
+default_url = ["{dir}/profile.yaml"]
+api_access_token = "secret-token"
+"#,
+        );
+        let render = |token: Option<&str>, force: Option<&str>| {
+            let query = SubQuery {
+                token: token.map(str::to_string),
+                force: force.map(str::to_string),
+                ..SubQuery::for_target("surge")
+            };
+            let state = state.clone();
+            async move {
+                let response = handle_sub(
+                    State(state),
+                    Query(query),
+                    Uri::from_static("/sub?target=surge"),
+                    HeaderMap::new(),
+                )
+                .await
+                .unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        // vless has no surge template yet, so it is skipped by default.
+        let body = render(Some("secret-token"), None).await;
+        assert!(body.contains("node-t = trojan"), "{body}");
+        assert!(!body.contains("node-v"), "{body}");
+
+        // Without the token, `force` is ignored.
+        let body = render(None, Some("1")).await;
+        assert!(!body.contains("node-v"), "{body}");
+
+        let body = render(Some("secret-token"), Some("1")).await;
+        assert!(body.contains("node-v = vless"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn custom_proxies_are_merged_around_profiles() {
+        let dir = test_dir("custom-proxy");
+        std::fs::write(
+            dir.join("profile.yaml"),
+            "proxies:\n  - { name: from-profile, type: trojan, server: p.example.com, port: 443, password: secret }\n",
+        )
+        .unwrap();
+        let state = test_state(
+            "custom-proxy",
+            r#"
+[common]
+default_url = ["{dir}/profile.yaml"]
+
+[[custom_proxy]]
+name = "relay"
+type = "trojan"
+server = "relay.example.com"
+port = 443
+password = "secret"
+prepend = true
+
+[[custom_proxy]]
+name = "fallback"
+type = "ss"
+server = "fallback.example.com"
+port = 8388
+cipher = "aes-128-gcm"
+password = "secret"
+"#,
+        );
+        let response = handle_sub(
+            State(state.clone()),
+            Query(SubQuery::for_target("links")),
+            Uri::from_static("/sub?target=links"),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let names: Vec<&str> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .filter_map(|link| link.rsplit_once('#').map(|(_, name)| name))
+            .collect();
+        assert_eq!(names, ["relay", "from-profile", "fallback"]);
+
+        // Entries are checked against the schema when the pref is loaded.
+        let mut pref = std::fs::read_to_string(&state.pref_path).unwrap();
+        pref.push_str(
+            "\n[[custom_proxy]]\nname = \"broken\"\ntype = \"trojan\"\nserver = \"b.example.com\"\nport = 443\n",
+        );
+        std::fs::write(&state.pref_path, pref).unwrap();
+        let Err(err) = build_runtime(&state.pref_path, &state.base_dir) else {
+            panic!("a custom proxy without a password should fail to load");
+        };
+        assert!(format!("{err:#}").contains("broken"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn healthz_is_ok_with_loaded_schema() {
+        let state = test_state("healthz", "");
+
+        let (status, body) = handle_healthz(State(state)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn metrics_count_sub_requests_and_renders() {
+        let upstream = Router::new().route(
+            "/sub",
+            get(|| async {
+                "proxies:\n  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }\n"
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let state = test_state(
+            "metrics",
+            r#"
+[network]
+allowed_domain = ["127.0.0.1"]
+"#,
+        );
+
+        let scrape = || async {
+            let response = handle_metrics(State(state.clone())).await;
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "text/plain; version=0.0.4; charset=utf-8"
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        for _ in 0..3 {
+            let query = SubQuery {
+                url: vec![format!("http://{upstream_addr}/sub")],
+                ..SubQuery::for_target("links")
+            };
+            handle_sub(
+                State(state.clone()),
+                Query(query),
+                Uri::from_static("/sub?target=links"),
+                HeaderMap::new(),
+            )
+            .await
+            .unwrap();
+        }
+        let text = scrape().await;
+
+        for line in [
+            r#"subcon_sub_requests_total{target="links"} 3"#,
+            "subcon_render_duration_seconds_count 3",
+            // Only the first request goes upstream; the others hit the cache.
+            r#"subcon_upstream_fetches_total{result="success"} 1"#,
+            r#"subcon_upstream_fetches_total{result="failure"} 0"#,
+            r#"subcon_cache_lookups_total{result="hit"} 2"#,
+            r#"subcon_cache_lookups_total{result="miss"} 1"#,
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing `{line}` in\n{text}"
+            );
+        }
     }
 }
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::{ApiError, AppState};
+
+/// How often idle buckets are dropped. A bucket untouched this long has
+/// refilled completely, so forgetting it changes nothing for the client.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Per-client token buckets for `/sub`.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Takes a token for `ip`, or returns how long until the next one is
+    /// available. Buckets hold `per_minute` tokens and refill continuously.
+    pub async fn check(&self, ip: IpAddr, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(per_minute);
+        let refill_per_second = capacity / 60.0;
+        let mut buckets = self.buckets.lock().await;
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * refill_per_second).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / refill_per_second,
+            ))
+        }
+    }
+
+    async fn prune(&self, now: Instant) -> usize {
+        let mut buckets = self.buckets.lock().await;
+        let before = buckets.len();
+        buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < PRUNE_INTERVAL);
+        before - buckets.len()
+    }
+}
+
+/// Drops idle buckets so clients that stopped polling do not pile up.
+pub fn spawn_pruning(limiter: std::sync::Arc<RateLimiter>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            let pruned = limiter.prune(Instant::now()).await;
+            if pruned > 0 {
+                debug!(pruned, "pruned idle rate limit buckets");
+            }
+        }
+    });
+}
+
+/// Enforces `server.rate_limit_per_minute` on `/sub`. The limit is read per
+/// request, so reloads take effect immediately.
+pub async fn limit_sub(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let per_minute = state
+        .runtime
+        .read()
+        .await
+        .pref
+        .server
+        .rate_limit_per_minute
+        .unwrap_or(0);
+    if per_minute == 0 {
+        return next.run(req).await;
+    }
+
+    let ip = client_ip(peer.ip(), req.headers());
+    match state
+        .rate_limiter
+        .check(ip, per_minute, Instant::now())
+        .await
+    {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded")
+            .with_retry_after(Some(retry_after))
+            .into_response(),
+    }
+}
+
+/// The peer address, or when the peer is a reverse proxy on loopback or a
+/// private network, the right-most `X-Forwarded-For` entry that is not such
+/// a proxy. Entries left of it were sent by the client and can be forged.
+fn client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !is_trusted_proxy(peer) {
+        return peer;
+    }
+    let entries = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .rev()
+        .flat_map(|value| value.to_str().unwrap_or_default().rsplit(','));
+    let mut client = peer;
+    for entry in entries {
+        let Ok(ip) = entry.trim().parse() else {
+            break;
+        };
+        client = ip;
+        if !is_trusted_proxy(ip) {
+            break;
+        }
+    }
+    client
+}
+
+fn is_trusted_proxy(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{serve, test_state};
+    use axum::{Router, http::header, routing::get};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_private_peers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.2".parse().unwrap());
+
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let public: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(
+            client_ip(proxy, &headers),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_ip(public, &headers), public);
+
+        // Entries left of the last untrusted one come from the client.
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.9, 203.0.113.7".parse().unwrap(),
+        );
+        headers.append("x-forwarded-for", "10.0.0.3".parse().unwrap());
+        assert_eq!(
+            client_ip(proxy, &headers),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        // A client on the private network is still told apart from the proxy.
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "192.168.1.5".parse().unwrap());
+        assert_eq!(
+            client_ip(proxy, &headers),
+            "192.168.1.5".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_over_limit_get_429() {
+        const LIMIT: u32 = 3;
+        let state = test_state(
+            "rate-limit",
+            &format!("[server]\nrate_limit_per_minute = {LIMIT}"),
+        );
+        let app = Router::new()
+            .route(
+                "/sub",
+                get(|| async { "ok" }).layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    limit_sub,
+                )),
+            )
+            .with_state(state);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            app,
+            Duration::from_secs(30),
+            std::future::pending(),
+            Duration::from_secs(1),
+        ));
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{addr}/sub");
+        let send = |forwarded: &str| client.get(&url).header("x-forwarded-for", forwarded).send();
+
+        for _ in 0..LIMIT {
+            assert_eq!(send("203.0.113.7").await.unwrap().status(), StatusCode::OK);
+        }
+        let limited = send("203.0.113.7").await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = limited.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after), "{retry_after}");
+
+        // Changing the client-supplied part of the header does not help.
+        for spoofed in ["198.51.100.1", "198.51.100.2"] {
+            let forwarded = format!("{spoofed}, 203.0.113.7");
+            let limited = send(&forwarded).await.unwrap();
+            assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        // Another client behind the same proxy has its own bucket.
+        assert_eq!(send("203.0.113.8").await.unwrap().status(), StatusCode::OK);
+    }
+}
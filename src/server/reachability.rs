@@ -0,0 +1,232 @@
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tokio::{
+    net::TcpStream,
+    sync::{RwLock, Semaphore},
+    task::JoinSet,
+    time::Instant,
+};
+
+use crate::proxy::{self, Proxy};
+
+pub const DEFAULT_CONCURRENCY: usize = 16;
+/// Upper bound for `?concurrency=` so a single request cannot exhaust file descriptors.
+pub const MAX_CONCURRENCY: usize = 64;
+pub const DEFAULT_TIMEOUT_MS: u64 = 3_000;
+pub const MAX_TIMEOUT_MS: u64 = 30_000;
+
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn from_proxy(proxy: &Proxy) -> Option<Self> {
+        let server = proxy.values.get("server")?.as_str()?.to_string();
+        let port = proxy.values.get("port")?.as_u64()?;
+        Some(Self {
+            name: proxy.name.clone(),
+            server,
+            port: u16::try_from(port).ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReachabilityResult {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub reachable: bool,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Last measured latency per `server:port`, fed by the test endpoints and
+/// consulted by `/sub?sort=latency`.
+#[derive(Default)]
+pub struct LatencyCache {
+    entries: RwLock<HashMap<(String, u16), u64>>,
+}
+
+impl LatencyCache {
+    /// Stores the latency of reachable endpoints and forgets unreachable ones.
+    pub async fn record(&self, results: &[ReachabilityResult]) {
+        let mut entries = self.entries.write().await;
+        for result in results {
+            let key = (result.server.clone(), result.port);
+            match result.latency_ms {
+                Some(latency) if result.reachable => {
+                    entries.insert(key, latency);
+                }
+                _ => {
+                    entries.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Orders proxies fastest first. Untested proxies follow in name order.
+    pub async fn sort(&self, proxies: &mut [Proxy]) {
+        let entries = self.entries.read().await;
+        proxy::sort_proxies(proxies);
+        // Stable, so equal latencies and untested proxies stay in name order.
+        proxies.sort_by_key(|proxy| {
+            Endpoint::from_proxy(proxy)
+                .and_then(|endpoint| entries.get(&(endpoint.server, endpoint.port)))
+                .map_or((1, 0), |latency| (0, *latency))
+        });
+    }
+}
+
+/// Clamp user supplied limits into the supported range.
+pub fn limits(concurrency: Option<usize>, timeout_ms: Option<u64>) -> (usize, Duration) {
+    let concurrency = concurrency
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .clamp(1, MAX_CONCURRENCY);
+    let timeout_ms = timeout_ms
+        .unwrap_or(DEFAULT_TIMEOUT_MS)
+        .clamp(1, MAX_TIMEOUT_MS);
+    (concurrency, Duration::from_millis(timeout_ms))
+}
+
+pub async fn check_all(
+    endpoints: Vec<Endpoint>,
+    concurrency: usize,
+    timeout: Duration,
+) -> Vec<ReachabilityResult> {
+    check_all_with(endpoints, concurrency, timeout, |server, port| async move {
+        TcpStream::connect((server.as_str(), port)).await.map(drop)
+    })
+    .await
+}
+
+/// Run `connect` for every endpoint with at most `concurrency` attempts in flight.
+/// Results keep the order of `endpoints`.
+async fn check_all_with<F, Fut>(
+    endpoints: Vec<Endpoint>,
+    concurrency: usize,
+    timeout: Duration,
+    connect: F,
+) -> Vec<ReachabilityResult>
+where
+    F: Fn(String, u16) -> Fut,
+    Fut: Future<Output = std::io::Result<()>> + Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for (idx, endpoint) in endpoints.into_iter().enumerate() {
+        let semaphore = semaphore.clone();
+        let attempt = connect(endpoint.server.clone(), endpoint.port);
+        tasks.spawn(async move {
+            // The semaphore is never closed, so acquiring cannot fail.
+            let _permit = semaphore.acquire_owned().await.ok();
+            let started = Instant::now();
+            let outcome = tokio::time::timeout(timeout, attempt).await;
+            let (reachable, latency_ms, error) = match outcome {
+                Ok(Ok(())) => (true, Some(started.elapsed().as_millis() as u64), None),
+                Ok(Err(err)) => (false, None, Some(err.to_string())),
+                Err(_) => (
+                    false,
+                    None,
+                    Some(format!("timed out after {}ms", timeout.as_millis())),
+                ),
+            };
+            (
+                idx,
+                ReachabilityResult {
+                    name: endpoint.name,
+                    server: endpoint.server,
+                    port: endpoint.port,
+                    reachable,
+                    latency_ms,
+                    error,
+                },
+            )
+        });
+    }
+
+    let mut results = Vec::with_capacity(tasks.len());
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok(result) = joined {
+            results.push(result);
+        }
+    }
+    results.sort_by_key(|(idx, _)| *idx);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn endpoint(name: &str) -> Endpoint {
+        Endpoint {
+            name: name.to_string(),
+            server: format!("{name}.example.com"),
+            port: 443,
+        }
+    }
+
+    #[test]
+    fn limits_are_clamped() {
+        assert_eq!(
+            limits(None, None),
+            (
+                DEFAULT_CONCURRENCY,
+                Duration::from_millis(DEFAULT_TIMEOUT_MS)
+            )
+        );
+        assert_eq!(
+            limits(Some(0), Some(u64::MAX)),
+            (1, Duration::from_millis(MAX_TIMEOUT_MS))
+        );
+        assert_eq!(limits(Some(10_000), Some(50)).0, MAX_CONCURRENCY);
+    }
+
+    #[tokio::test]
+    async fn concurrency_bounds_in_flight_connects() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let endpoints: Vec<Endpoint> = (0..8).map(|i| endpoint(&format!("node-{i}"))).collect();
+
+        let results = check_all_with(endpoints, 2, Duration::from_secs(5), |_, _| {
+            let in_flight = in_flight.clone();
+            let peak = peak.clone();
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_eq!(results.len(), 8);
+        assert!(results.iter().all(|result| result.reachable));
+        assert_eq!(results[3].name, "node-3");
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn slow_connects_time_out() {
+        let started = Instant::now();
+        let results = check_all_with(
+            vec![endpoint("slow")],
+            1,
+            Duration::from_millis(50),
+            |_, _| std::future::pending(),
+        )
+        .await;
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!results[0].reachable);
+        assert_eq!(results[0].error.as_deref(), Some("timed out after 50ms"));
+    }
+}
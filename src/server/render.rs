@@ -0,0 +1,95 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use clap::Args;
+
+use super::{TargetRenderer, build_runtime, render_offline};
+
+#[derive(Args, Debug)]
+pub struct RenderCommandArgs {
+    /// Target to render, for example `clash` or `surge`
+    #[arg(long, default_value = "clash")]
+    target: String,
+    /// File to write the result to instead of stdout
+    #[arg(long)]
+    out: Option<String>,
+}
+
+/// Renders `args.target` from the local profiles once, as a plain
+/// `/sub?target=...` request would, and writes it to `--out` or stdout.
+/// A relative `--out` is taken from the working directory, not `--base-dir`.
+pub fn run(
+    targets: &HashMap<String, Arc<dyn TargetRenderer>>,
+    pref_path: &Path,
+    base_dir: &Path,
+    args: &RenderCommandArgs,
+) -> Result<()> {
+    let runtime = build_runtime(pref_path, base_dir).context("failed to load config")?;
+    let output = render_offline(targets, &runtime, base_dir, &args.target)
+        .with_context(|| format!("failed to render {}", args.target))?;
+    match &args.out {
+        Some(out) => std::fs::write(out, output).with_context(|| format!("failed to write {out}")),
+        None => {
+            print!("{output}");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Cli, Command, default_targets, test_dir, test_pref};
+    use clap::Parser;
+
+    #[test]
+    fn render_subcommand_writes_the_target() {
+        let dir = test_dir("render-cmd");
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            "proxies:\n  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }\n",
+        )
+        .unwrap();
+        let pref_path = test_pref(
+            "render-cmd",
+            r#"
+[common]
+surge_rule_base = "{dir}/base.cfg"
+default_url = ["{dir}/profile.yaml"]
+"#,
+        );
+        let out = dir.join("surge.conf");
+
+        let cli = Cli::try_parse_from([
+            "subcon",
+            "--pref",
+            pref_path.to_str().unwrap(),
+            "render",
+            "--target",
+            "surge",
+            "--out",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+        let Some(Command::Render(args)) = &cli.command else {
+            panic!("expected the render subcommand");
+        };
+        let targets = default_targets();
+        run(&targets, &pref_path, &dir, args).unwrap();
+
+        let runtime = build_runtime(&pref_path, &dir).unwrap();
+        let expected = render_offline(&targets, &runtime, &dir, "surge").unwrap();
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(written, expected);
+        assert!(written.contains("node-a = trojan"), "{written}");
+
+        // Unknown targets fail instead of writing anything.
+        let args = RenderCommandArgs {
+            target: "quantumult".to_string(),
+            out: Some(dir.join("none.conf").display().to_string()),
+        };
+        assert!(run(&targets, &pref_path, &dir, &args).is_err());
+        assert!(!dir.join("none.conf").exists());
+    }
+}
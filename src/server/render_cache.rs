@@ -0,0 +1,107 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use super::{AppState, render_offline};
+
+/// Shortest pause between two warming passes.
+const MIN_WARM_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Pre-rendered `/sub?target=...` bodies for `common.warm_targets`.
+#[derive(Default)]
+pub struct RenderCache {
+    entries: RwLock<HashMap<String, String>>,
+}
+
+impl RenderCache {
+    pub async fn get(&self, target: &str) -> Option<String> {
+        self.entries.read().await.get(target).cloned()
+    }
+}
+
+/// Re-renders every warm target from the local profiles and replaces the
+/// cache, dropping targets that failed or are no longer listed.
+pub async fn refresh(state: &AppState) {
+    let runtime = state.runtime.read().await.clone();
+    let mut entries = HashMap::new();
+    for target in &runtime.pref.common.warm_targets {
+        match render_offline(&state.targets, &runtime, &state.base_dir, target) {
+            Ok(body) => {
+                entries.insert(target.clone(), body);
+            }
+            Err(err) => warn!(target = %target, error = %err, "failed to warm render cache"),
+        }
+    }
+    if !entries.is_empty() {
+        info!(targets = entries.len(), "render cache warmed");
+    }
+    *state.render_cache.entries.write().await = entries;
+}
+
+/// Warms the cache now and again every `network.ttl_seconds`, so files
+/// edited outside the API are picked up within one cache TTL.
+pub fn spawn_warming(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            refresh(&state).await;
+            let ttl = state.runtime.read().await.pref.network.ttl_seconds;
+            tokio::time::sleep(Duration::from_secs(ttl).max(MIN_WARM_INTERVAL)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{RENDER_CACHE_HEADER, SubQuery, handle_sub, test_dir, test_state};
+    use axum::{
+        extract::{Query, State},
+        http::{HeaderMap, Uri},
+    };
+
+    #[tokio::test]
+    async fn warmed_target_is_served_from_cache() {
+        let dir = test_dir("render-cache");
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            "proxies:\n  - { name: node-a, type: trojan, server: a.example.com, port: 443, password: secret }\n",
+        )
+        .unwrap();
+        let state = test_state(
+            "render-cache",
+            r#"
+[common]
+surge_rule_base = "{dir}/base.cfg"
+default_url = ["{dir}/profile.yaml"]
+warm_targets = ["surge"]
+"#,
+        );
+        refresh(&state).await;
+
+        let request = |target: &str, base64: Option<&str>| {
+            let query = SubQuery {
+                base64: base64.map(str::to_string),
+                ..SubQuery::for_target(target)
+            };
+            handle_sub(
+                State(state.clone()),
+                Query(query),
+                Uri::from_static("/sub?target=surge"),
+                HeaderMap::new(),
+            )
+        };
+
+        let response = request("surge", None).await.unwrap();
+        assert_eq!(response.headers()[RENDER_CACHE_HEADER], "hit");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("node-a = trojan"));
+
+        // Variations of the request are rendered as usual.
+        let response = request("surge", Some("1")).await.unwrap();
+        assert!(response.headers().get(RENDER_CACHE_HEADER).is_none());
+    }
+}
@@ -1,23 +1,43 @@
+use std::collections::HashSet;
 use std::fmt::Write as FmtWrite;
 use std::hash::{DefaultHasher, Hash, Hasher};
 
 use anyhow::{Context, Result, anyhow};
+use reqwest::Url;
 use serde_json::{Map as JsonMap, Value};
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::config::Pref;
+use crate::export::special_target_name;
 use crate::groups;
-use crate::paths::resolve_path;
+use crate::proxy;
+use crate::rules;
 use crate::schema::SchemaRegistry;
 
-use super::util::{load_group_specs_from_pref, load_rules_from_pref};
+use super::util::{
+    apply_keyword_as_regex, load_group_specs_from_pref, load_rules_from_pref, push_section,
+    read_rule_base, render_line_rules,
+};
 use super::{ApiError, RenderArgs};
 
 pub struct SurgeRenderer;
 
 impl super::TargetRenderer for SurgeRenderer {
     fn render(&self, args: RenderArgs<'_>) -> Result<String, ApiError> {
-        render_surge(args).map_err(ApiError::internal)
+        render_surge(args).map_err(ApiError::render)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    fn filename(&self) -> &'static str {
+        "surge.conf"
+    }
+
+    fn keeps_proxy(&self, registry: &SchemaRegistry, proxy: &proxy::Proxy) -> bool {
+        !registry.target_not_implemented(&proxy.protocol, "surge")
+            && render_surge_proxy(registry, proxy, false).is_ok()
     }
 }
 
@@ -27,54 +47,62 @@ fn render_surge(args: RenderArgs<'_>) -> Result<String> {
         base_dir,
         mut proxies,
         request_uri,
+        scope_token,
+        presorted,
+        force,
+        selected_groups,
+        ..
     } = args;
     let pref = &runtime.pref;
     let registry = &runtime.registry;
 
     let mut out = String::new();
-    if let Some(line) = build_managed_config_line(pref, request_uri.as_deref())? {
+    if let Some(line) =
+        build_managed_config_line(pref, request_uri.as_deref(), scope_token.as_deref())?
+    {
         out.push_str(&line);
         out.push('\n');
     }
 
-    let surge_base = pref
-        .common
-        .surge_rule_base
-        .as_deref()
-        .ok_or_else(|| anyhow::anyhow!("`common.surge_rule_base` must be set in pref.toml"))?;
-    let base_path = resolve_path(base_dir, surge_base);
-    let mut base_text = std::fs::read_to_string(&base_path)
-        .with_context(|| format!("failed to read base config {}", base_path.display()))?;
-    if !base_text.ends_with('\n') {
-        base_text.push('\n');
-    }
-    out.push_str(&base_text);
+    out.push_str(&read_rule_base(
+        &runtime.network,
+        base_dir,
+        pref.common.surge_rule_base.as_deref(),
+        "surge_rule_base",
+    )?);
     out.push('\n');
 
     super::util::apply_node_pref(pref, registry, &mut proxies);
-    proxies.retain(|proxy| !registry.target_not_implemented(&proxy.protocol, "surge"));
-    if pref.common.sort {
-        proxies.sort_by(|a, b| a.name.cmp(&b.name));
+    let placeholders = super::util::take_unsupported(pref, registry, &mut proxies, "surge", force);
+    if pref.common.sort && !presorted {
+        proxy::sort_proxies(&mut proxies);
     }
     info!(count = proxies.len(), "proxies loaded for surge render");
 
+    // A node Surge cannot express (say, a shadowsocks plugin it lacks) is
+    // left out like an unsupported protocol instead of failing the render.
+    let mut proxy_lines = Vec::new();
     let mut wg_sections = Vec::new();
+    proxies.retain(|proxy| match render_surge_proxy(registry, proxy, force) {
+        Ok((line, block)) => {
+            proxy_lines.push(line);
+            wg_sections.extend(block);
+            true
+        }
+        Err(err) => {
+            warn!(name = %proxy.name, error = %err, "skipping proxy surge cannot express");
+            false
+        }
+    });
 
-    if !proxies.is_empty() {
+    if !proxy_lines.is_empty() || !placeholders.is_empty() {
         out.push_str("[Proxy]\n");
-        for proxy in &proxies {
-            if proxy.protocol == "wireguard" {
-                let section_name = deterministic_hex_section(&proxy.name);
-                let (line, section_block) =
-                    render_surge_wireguard_proxy_line(registry, proxy, &section_name)?;
-                out.push_str(&line);
-                out.push('\n');
-                wg_sections.push(section_block);
-            } else {
-                let line = render_surge_proxy_line(registry, proxy)?;
-                out.push_str(&line);
-                out.push('\n');
-            }
+        for line in &proxy_lines {
+            out.push_str(line);
+            out.push('\n');
+        }
+        for proxy in &placeholders {
+            out.push_str(&reject_placeholder(proxy));
         }
         out.push('\n');
     }
@@ -89,51 +117,60 @@ fn render_surge(args: RenderArgs<'_>) -> Result<String> {
         }
     }
 
-    let group_specs = load_group_specs_from_pref(pref, base_dir)?;
-    let proxy_groups =
-        groups::build_groups(&group_specs, &proxies).context("failed to build proxy groups")?;
-    info!(groups = proxy_groups.len(), "proxy groups built for surge");
+    // Placeholders still take part in group matching, so groups keep
+    // referencing them by name.
+    let mut grouped = proxies.clone();
+    grouped.extend(placeholders);
+    if pref.common.sort && !presorted {
+        proxy::sort_proxies(&mut grouped);
+    }
 
-    if !proxy_groups.is_empty() {
-        out.push_str("[Proxy Group]\n");
-        for group in &proxy_groups {
-            let line = render_surge_group_line(group);
-            out.push_str(&line);
-            out.push('\n');
-        }
-        out.push('\n');
+    let group_specs = load_group_specs_from_pref(pref, base_dir)?;
+    let mut proxy_groups = groups::build_groups(&group_specs, &grouped, &pref.groups)
+        .context("failed to build proxy groups")?;
+    if let Some(selected) = &selected_groups {
+        groups::retain_groups(&mut proxy_groups, selected)?;
     }
+    info!(groups = proxy_groups.len(), "proxy groups built for surge");
 
-    let rules = load_rules_from_pref(pref, &runtime.network, base_dir)?;
-    let rendered_rules: Vec<String> = rules
+    let known: HashSet<&str> = proxy_groups
         .iter()
-        .map(|r| {
-            let mut line = r.render();
-            if line == "SRC-IP-CIDR" {
-                line = "IP-CIDR".to_string();
-            } else if let Some(rest) = line.strip_prefix("SRC-IP-CIDR,") {
-                line = format!("IP-CIDR,{rest},no-resolve");
-            }
-            if line.to_ascii_uppercase().starts_with("DST-PORT") {
-                line = format!("DEST-PORT{}", &line["DST-PORT".len()..]);
-            }
-            line
-        })
+        .map(|group| group.name.as_str())
+        .chain(SURGE_POLICIES)
         .collect();
-    info!(rules = rendered_rules.len(), "rules rendered for surge");
+    let group_lines: Vec<String> = proxy_groups
+        .iter()
+        .map(|group| render_surge_group_line(group, &known))
+        .collect();
+    push_section(&mut out, "Proxy Group", &group_lines);
+    if !group_lines.is_empty() {
+        out.push('\n');
+    }
 
-    if !rendered_rules.is_empty() {
-        out.push_str("[Rule]\n");
-        for line in rendered_rules {
-            out.push_str(&line);
-            out.push('\n');
-        }
+    let mut rules = load_rules_from_pref(pref, &runtime.network, base_dir)?;
+    if selected_groups.is_some() {
+        let kept: Vec<&str> = proxy_groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect();
+        rules::retain_rules_for_groups(&mut rules, &kept);
     }
+    apply_keyword_as_regex(pref, "surge", &mut rules);
+    let rendered_rules = render_line_rules(&rules);
+    info!(rules = rendered_rules.len(), "rules rendered for surge");
+    push_section(&mut out, "Rule", &rendered_rules);
 
     Ok(out)
 }
 
-fn build_managed_config_line(pref: &Pref, request_uri: Option<&str>) -> Result<Option<String>> {
+/// Builds `#!MANAGED-CONFIG` from the request URI. For token-scoped requests
+/// the URL always carries `scope_token`, so each consumer refreshes its own
+/// profile set.
+fn build_managed_config_line(
+    pref: &Pref,
+    request_uri: Option<&str>,
+    scope_token: Option<&str>,
+) -> Result<Option<String>> {
     let managed = &pref.managed_config;
     if !managed.write_managed_config {
         return Ok(None);
@@ -155,20 +192,70 @@ fn build_managed_config_line(pref: &Pref, request_uri: Option<&str>) -> Result<O
     } else {
         format!("/{uri}")
     };
+    let mut url = format!("{base}{path}");
+    if let Some(token) = scope_token {
+        url = with_token(&url, token)?;
+    }
 
     Ok(Some(format!(
-        "#!MANAGED-CONFIG {base}{path} interval={} strict={}",
+        "#!MANAGED-CONFIG {url} interval={} strict={}",
         managed.interval, managed.strict
     )))
 }
 
+/// Sets the `token` query parameter of `url`, leaving it untouched when it
+/// already matches.
+fn with_token(url: &str, token: &str) -> Result<String> {
+    let mut parsed =
+        Url::parse(url).with_context(|| format!("invalid managed config url {url}"))?;
+    if parsed
+        .query_pairs()
+        .any(|(key, value)| key == "token" && value == token)
+    {
+        return Ok(url.to_string());
+    }
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(key, _)| key != "token")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    parsed
+        .query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("token", token);
+    Ok(parsed.to_string())
+}
+
+/// A commented note plus a built-in `reject` proxy under the node's name.
+fn reject_placeholder(proxy: &crate::proxy::Proxy) -> String {
+    format!(
+        "# {}: {} is not supported by Surge\n{} = reject\n",
+        proxy.name, proxy.protocol, proxy.name
+    )
+}
+
+/// The `[Proxy]` line for `proxy`, plus the section a WireGuard node needs.
+fn render_surge_proxy(
+    registry: &SchemaRegistry,
+    proxy: &crate::proxy::Proxy,
+    force: bool,
+) -> Result<(String, Option<String>)> {
+    if proxy.protocol == "wireguard" {
+        let section_name = deterministic_hex_section(&proxy.name);
+        render_surge_wireguard_proxy_line(registry, proxy, &section_name, force)
+            .map(|(line, block)| (line, Some(block)))
+    } else {
+        render_surge_proxy_line(registry, proxy, force).map(|line| (line, None))
+    }
+}
+
 fn render_surge_proxy_line(
     registry: &SchemaRegistry,
     proxy: &crate::proxy::Proxy,
+    force: bool,
 ) -> Result<String> {
-    let rendered = registry
-        .convert(&proxy.protocol, "surge", &proxy.values)
-        .with_context(|| format!("failed to render surge proxy {}", proxy.name))?;
+    let rendered = proxy.to_target_with(registry, "surge", force)?;
     match rendered {
         Value::String(s) => Ok(s),
         other => Err(anyhow!("surge exporter must return string, got {other}")),
@@ -179,10 +266,9 @@ fn render_surge_wireguard_proxy_line(
     registry: &SchemaRegistry,
     proxy: &crate::proxy::Proxy,
     section_name: &str,
+    force: bool,
 ) -> Result<(String, String)> {
-    let rendered = registry
-        .convert(&proxy.protocol, "surge", &proxy.values)
-        .with_context(|| format!("failed to render surge proxy {}", proxy.name))?;
+    let rendered = proxy.to_target_with(registry, "surge", force)?;
     let map = rendered.as_object().cloned().unwrap_or_else(JsonMap::new);
 
     let mut line_parts = Vec::new();
@@ -316,23 +402,39 @@ fn render_surge_wireguard_proxy_line(
     Ok((line, section_block))
 }
 
-fn render_surge_group_line(group: &groups::ProxyGroup) -> String {
-    let mut line = String::new();
-    let mut group_type = match group.group_type.as_str() {
+/// Built-in policies a group may reference with `[]`.
+const SURGE_POLICIES: [&str; 2] = ["DIRECT", "REJECT"];
+
+/// Renders one `[Proxy Group]` line. `[]` references are kept only when they
+/// name a built-in policy or another group in `known`, and a group never lists
+/// itself. A group left without members renders as `select,DIRECT`, so groups
+/// referencing it still point at something valid.
+fn render_surge_group_line(group: &groups::ProxyGroup, known: &HashSet<&str>) -> String {
+    let mut members: Vec<&str> = group
+        .proxies
+        .iter()
+        .filter_map(|item| match item.strip_prefix("[]") {
+            Some(target) => known.contains(target).then_some(target),
+            None => Some(item.as_str()),
+        })
+        .filter(|name| *name != group.name)
+        .map(|name| special_target_name(name, "surge"))
+        .collect();
+    let group_type = match group.group_type.as_str() {
+        _ if members.is_empty() => "select",
+        // Surge's `smart` replaces url-test only; fallback and load-balance
+        // exist under their own names.
         "url-test" => "smart",
         other => other,
     };
-    if group.proxies.is_empty() {
-        group_type = "select";
+    if members.is_empty() {
+        members.push(special_target_name("DIRECT", "surge"));
     }
+
+    let mut line = String::new();
     let _ = write!(line, "{} = {}", group.name, group_type);
-    if group.proxies.is_empty() {
-        let _ = write!(line, ",DIRECT");
-    } else {
-        for item in &group.proxies {
-            let name = item.strip_prefix("[]").unwrap_or(item);
-            let _ = write!(line, ",{}", name);
-        }
+    for name in members {
+        let _ = write!(line, ",{}", name);
     }
     line
 }
@@ -356,3 +458,229 @@ fn deterministic_hex_section(name: &str) -> String {
     let n = (hasher.finish() & 0xFFFFF) as u32;
     format!("{n:05x}")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::Proxy;
+    use crate::server::{test_dir, test_pref};
+
+    #[test]
+    fn include_all_group_expands_filtered_nodes() {
+        let spec: groups::GroupSpec = toml::from_str(
+            r#"
+name = "HK"
+type = "select"
+include-all = true
+filter = "HK"
+exclude-filter = "IPLC"
+"#,
+        )
+        .unwrap();
+        let proxies = ["HK 01", "HK IPLC", "JP 01"].map(|name| Proxy {
+            name: name.to_string(),
+            protocol: "http".to_string(),
+            values: Default::default(),
+        });
+
+        let built = groups::build_groups(&[spec], &proxies, &Default::default()).unwrap();
+        assert_eq!(
+            render_surge_group_line(&built[0], &HashSet::new()),
+            "HK = select,HK 01"
+        );
+    }
+
+    #[test]
+    fn select_group_can_reference_an_empty_auto_group() {
+        let spec = |source: &str| -> groups::GroupSpec { toml::from_str(source).unwrap() };
+        let specs = [
+            spec("name = \"Auto\"\ntype = \"url-test\"\nrule = [\"^US\"]"),
+            spec("name = \"Backup\"\ntype = \"fallback\"\nrule = [\"^HK\"]"),
+            spec(
+                "name = \"Proxy\"\ntype = \"select\"\nrule = [\"[]Auto\", \"[]Backup\", \"Proxy\", \"[]DIRECT\"]",
+            ),
+        ];
+        // A node sharing a group's name must not make the group list itself.
+        let proxies = ["HK 01", "Proxy"].map(|name| Proxy {
+            name: name.to_string(),
+            protocol: "http".to_string(),
+            values: Default::default(),
+        });
+
+        let built = groups::build_groups(&specs, &proxies, &Default::default()).unwrap();
+        let known: HashSet<&str> = built
+            .iter()
+            .map(|group| group.name.as_str())
+            .chain(SURGE_POLICIES)
+            .collect();
+        let lines: Vec<String> = built
+            .iter()
+            .map(|group| render_surge_group_line(group, &known))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "Auto = select,DIRECT",
+                "Backup = fallback,HK 01",
+                "Proxy = select,Auto,Backup,DIRECT",
+            ]
+        );
+    }
+
+    #[test]
+    fn keyword_rules_are_escaped_as_regex_when_enabled() {
+        let pref: Pref = toml::from_str(
+            "[common]\n\n[ruleset]\nkeyword_as_regex = [\"surge\"]\n\n[server]\nlisten = \"127.0.0.1\"\nport = 0\n",
+        )
+        .unwrap();
+        let keyword = crate::rules::Rule {
+            rule_type: crate::rules::RuleType::new("DOMAIN-KEYWORD"),
+            content: Some("a.b+c(d)*".to_string()),
+            group: "G".to_string(),
+            flags: Default::default(),
+        };
+
+        let mut rules = vec![keyword.clone()];
+        apply_keyword_as_regex(&pref, "surge", &mut rules);
+        assert_eq!(
+            render_line_rules(&rules),
+            [r"DOMAIN-REGEX,a\.b\+c\(d\)\*,G"]
+        );
+
+        let mut rules = vec![keyword];
+        apply_keyword_as_regex(&pref, "loon", &mut rules);
+        assert_eq!(render_line_rules(&rules), ["DOMAIN-KEYWORD,a.b+c(d)*,G"]);
+    }
+
+    #[test]
+    fn unsupported_node_becomes_reject_placeholder() {
+        let dir = test_dir("surge-reject");
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        std::fs::write(
+            dir.join("groups.toml"),
+            "[[groups]]\nname = \"Proxy\"\ntype = \"select\"\nrule = [\".*\"]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            r#"proxies:
+  - { name: trojan-node, type: trojan, server: a.example.com, port: 443, password: secret }
+  - { name: vmess-node, type: vmess, server: b.example.com, port: 443, uuid: b831381d-6324-4d53-ad4f-8cda48b30811, servername: b.example.com }
+"#,
+        )
+        .unwrap();
+        let pref_path = test_pref(
+            "surge-reject",
+            r#"
+[common]
+surge_rule_base = "{dir}/base.cfg"
+default_url = ["{dir}/profile.yaml"]
+keep_unsupported_as_reject = true
+
+[[custom_groups]]
+import = "{dir}/groups.toml"
+"#,
+        );
+
+        let runtime = crate::server::build_runtime(&pref_path, &dir).unwrap();
+        let output = crate::server::render_offline(
+            &crate::server::default_targets(),
+            &runtime,
+            &dir,
+            "surge",
+        )
+        .unwrap();
+        assert!(
+            output.contains("# vmess-node: vmess is not supported by Surge\nvmess-node = reject\n"),
+            "{output}"
+        );
+        assert!(
+            output.contains("Proxy = select,trojan-node,vmess-node"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn node_with_unsupported_plugin_is_skipped() {
+        let dir = test_dir("surge-skip-plugin");
+        std::fs::write(dir.join("base.cfg"), "[General]\n").unwrap();
+        std::fs::write(
+            dir.join("groups.toml"),
+            "[[groups]]\nname = \"Proxy\"\ntype = \"select\"\nrule = [\".*\"]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("profile.yaml"),
+            r#"proxies:
+  - { name: ss-node, type: ss, server: a.example.com, port: 8388, cipher: aes-128-gcm, password: secret }
+  - { name: v2ray-node, type: ss, server: b.example.com, port: 8388, cipher: aes-128-gcm, password: secret, plugin: v2ray-plugin, plugin-opts: { mode: websocket } }
+"#,
+        )
+        .unwrap();
+        let pref_path = test_pref(
+            "surge-skip-plugin",
+            r#"
+[common]
+surge_rule_base = "{dir}/base.cfg"
+default_url = ["{dir}/profile.yaml"]
+
+[[custom_groups]]
+import = "{dir}/groups.toml"
+"#,
+        );
+
+        let runtime = crate::server::build_runtime(&pref_path, &dir).unwrap();
+        let output = crate::server::render_offline(
+            &crate::server::default_targets(),
+            &runtime,
+            &dir,
+            "surge",
+        )
+        .unwrap();
+        assert!(
+            output.contains("ss-node = ss, a.example.com, 8388, encrypt-method=aes-128-gcm"),
+            "{output}"
+        );
+        assert!(!output.contains("v2ray-node"), "{output}");
+        assert!(output.contains("Proxy = select,ss-node\n"), "{output}");
+    }
+
+    fn managed_pref() -> Pref {
+        toml::from_str(
+            r#"[common]
+
+[managed_config]
+write_managed_config = true
+base_url = "https://sub.example.com/"
+interval = 3600
+
+[[profile_sets]]
+token = "alice"
+profiles = ["alice.yaml"]
+
+[server]
+listen = "127.0.0.1"
+port = 0
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn managed_url_keeps_scope_token() {
+        let pref = managed_pref();
+        let line =
+            build_managed_config_line(&pref, Some("/sub?target=surge&token=alice"), Some("alice"))
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            line,
+            "#!MANAGED-CONFIG https://sub.example.com/sub?target=surge&token=alice interval=3600 strict=false"
+        );
+
+        let line = build_managed_config_line(&pref, Some("/sub?target=surge"), Some("alice"))
+            .unwrap()
+            .unwrap();
+        assert!(line.contains("/sub?target=surge&token=alice "), "{line}");
+    }
+}
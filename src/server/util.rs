@@ -1,27 +1,30 @@
+use std::cell::RefCell;
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result, anyhow, bail};
 use reqwest::Url;
-use tracing::warn;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
 
-use crate::config::Pref;
+use crate::config::{Pref, ProfileSet};
 use crate::network::Network;
 use crate::paths::resolve_path;
 use crate::{groups, rules};
 
 /// Collect profile paths in order with de-duplication and optional inserts.
+/// A token-scoped `profile_set` replaces `common.default_url`.
 pub fn gather_profile_paths(
     pref: &Pref,
+    profile_set: Option<&ProfileSet>,
     include_insert: bool,
     base_dir: &Path,
 ) -> Result<Vec<PathBuf>> {
     let mut paths = Vec::new();
     let mut seen = HashSet::new();
 
-    let defaults: Vec<_> = pref
-        .common
-        .default_url
+    let defaults: Vec<_> = profile_set
+        .map_or(&pref.common.default_url, |set| &set.profiles)
         .iter()
         .map(|p| resolve_path(base_dir, p))
         .collect();
@@ -73,6 +76,80 @@ pub fn gather_insert_paths(pref: &Pref, base_dir: &Path) -> Vec<PathBuf> {
     paths
 }
 
+/// Removes proxies whose protocol is not implemented for `target`. They are
+/// returned for placeholder rendering when `common.keep_unsupported_as_reject`
+/// is set, and dropped otherwise. `force` keeps every proxy in place.
+pub fn take_unsupported(
+    pref: &Pref,
+    registry: &crate::schema::SchemaRegistry,
+    proxies: &mut Vec<crate::proxy::Proxy>,
+    target: &str,
+    force: bool,
+) -> Vec<crate::proxy::Proxy> {
+    if force {
+        return Vec::new();
+    }
+    let (unsupported, supported): (Vec<_>, Vec<_>) = std::mem::take(proxies)
+        .into_iter()
+        .partition(|proxy| registry.target_not_implemented(&proxy.protocol, target));
+    *proxies = supported;
+    if !pref.common.keep_unsupported_as_reject {
+        return Vec::new();
+    }
+    if !unsupported.is_empty() {
+        info!(
+            target,
+            placeholders = unsupported.len(),
+            "unsupported proxies rendered as REJECT placeholders"
+        );
+    }
+    unsupported
+}
+
+/// Parses `[[custom_proxy]]` and checks each entry converts for every target
+/// its protocol implements, so a bad entry fails the load instead of a render.
+pub fn validate_custom_proxies(
+    pref: &Pref,
+    registry: &crate::schema::SchemaRegistry,
+) -> Result<()> {
+    for custom in &pref.custom_proxy {
+        let proxy = crate::proxy::from_map(&custom.values).context("invalid custom_proxy")?;
+        let schema = registry.get(&proxy.protocol).with_context(|| {
+            format!(
+                "custom_proxy `{}` uses unknown protocol `{}`",
+                proxy.name, proxy.protocol
+            )
+        })?;
+        for target in schema.targets.keys() {
+            if registry.supports_target(&proxy.protocol, target) {
+                proxy
+                    .to_target(registry, target)
+                    .context("invalid custom_proxy")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds `[[custom_proxy]]` entries around `proxies`, keeping their order:
+/// `prepend` entries first, the rest after the loaded proxies.
+pub fn merge_custom_proxies(pref: &Pref, proxies: &mut Vec<crate::proxy::Proxy>) -> Result<()> {
+    if pref.custom_proxy.is_empty() {
+        return Ok(());
+    }
+    let mut front = Vec::new();
+    for custom in &pref.custom_proxy {
+        let proxy = crate::proxy::from_map(&custom.values).context("invalid custom_proxy")?;
+        if custom.prepend {
+            front.push(proxy);
+        } else {
+            proxies.push(proxy);
+        }
+    }
+    proxies.splice(0..0, front);
+    Ok(())
+}
+
 /// Apply node_pref overrides to proxies if the schema supports those fields.
 pub fn apply_node_pref(
     pref: &Pref,
@@ -80,7 +157,7 @@ pub fn apply_node_pref(
     proxies: &mut [crate::proxy::Proxy],
 ) {
     let np = &pref.node_pref;
-    for proxy in proxies {
+    for proxy in proxies.iter_mut() {
         if let Some(schema) = registry.get(&proxy.protocol) {
             let fields = &schema.fields;
             if let Some(val) = np.udp {
@@ -106,6 +183,70 @@ pub fn apply_node_pref(
             }
         }
     }
+    apply_field_overrides(registry, proxies, &np.overrides);
+}
+
+/// Sets each field on the proxies whose schema declares it.
+pub fn apply_field_overrides(
+    registry: &crate::schema::SchemaRegistry,
+    proxies: &mut [crate::proxy::Proxy],
+    overrides: &serde_json::Map<String, serde_json::Value>,
+) {
+    for proxy in proxies {
+        let Some(schema) = registry.get(&proxy.protocol) else {
+            continue;
+        };
+        for (key, val) in overrides {
+            if schema.fields.contains_key(key) {
+                proxy.values.insert(key.clone(), val.clone());
+            }
+        }
+    }
+}
+
+/// Parses `/sub?override=skip-cert-verify:true,sni:example.com` into field
+/// values typed after the schema field of the same name.
+pub fn parse_override_query(
+    registry: &crate::schema::SchemaRegistry,
+    raw: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    use crate::schema::FieldType;
+    use serde_json::Value;
+
+    let mut overrides = serde_json::Map::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((key, value)) = entry.split_once(':') else {
+            bail!("override `{entry}` must be `field:value`");
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let Some(spec) = registry.field_spec(key) else {
+            bail!("unknown override field `{key}`");
+        };
+        let value = match spec.ty {
+            FieldType::Boolean => match value {
+                "true" | "1" => Value::Bool(true),
+                "false" | "0" => Value::Bool(false),
+                _ => bail!("override `{key}` expects true or false, got `{value}`"),
+            },
+            FieldType::Integer => match value.parse::<i64>() {
+                Ok(number) => Value::from(number),
+                Err(_) => bail!("override `{key}` expects an integer, got `{value}`"),
+            },
+            FieldType::String => Value::String(value.to_string()),
+            FieldType::Enum => {
+                let allowed = spec.allowed.as_deref().unwrap_or_default();
+                if !allowed.iter().any(|allowed| allowed == value) {
+                    bail!("override `{key}` does not allow `{value}`");
+                }
+                Value::String(value.to_string())
+            }
+            FieldType::List | FieldType::Map => {
+                bail!("override `{key}` cannot be set from the query")
+            }
+        };
+        overrides.insert(key.to_string(), value);
+    }
+    Ok(overrides)
 }
 
 pub fn load_group_specs_from_pref(pref: &Pref, base_dir: &Path) -> Result<Vec<groups::GroupSpec>> {
@@ -125,29 +266,124 @@ pub fn load_rules_from_pref(
     network: &Network,
     base_dir: &Path,
 ) -> Result<Vec<rules::Rule>> {
+    load_rules_with_providers(pref, network, base_dir, false).map(|(rules, _)| rules)
+}
+
+/// Parses `[[extra_rule]]`, failing on the first entry that is not a rule.
+pub fn load_extra_rules(pref: &Pref) -> Result<Vec<rules::Rule>> {
+    pref.extra_rule
+        .iter()
+        .map(|extra| {
+            rules::parse_extra_rule(&extra.rule, &extra.group)
+                .with_context(|| format!("invalid extra_rule `{}`", extra.rule))
+        })
+        .collect()
+}
+
+/// A remote ruleset left for the client to download, referenced by a
+/// `RULE-SET,<name>,<group>` rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleProvider {
+    pub name: String,
+    pub url: String,
+}
+
+/// Loads rules like [`load_rules_from_pref`]. With `as_providers`, remote
+/// rulesets are not fetched: each becomes a single `RULE-SET` rule and a
+/// [`RuleProvider`] the caller renders.
+pub fn load_rules_with_providers(
+    pref: &Pref,
+    network: &Network,
+    base_dir: &Path,
+    as_providers: bool,
+) -> Result<(Vec<rules::Rule>, Vec<RuleProvider>)> {
     let mut all_rules = Vec::new();
+    let providers = RefCell::new(Vec::<RuleProvider>::new());
     if pref.ruleset.as_ref().map(|r| r.enabled).unwrap_or(false) {
+        let ttl_override = pref.ruleset.as_ref().and_then(|r| r.cache_ttl_seconds);
         for entry in &pref.rulesets {
             let path = resolve_path(base_dir, &entry.import);
             let mut loaded = rules::load_rules_with_fetcher(&path, base_dir, |url| {
-                fetch_ruleset_text(network, url)
+                if !as_providers {
+                    return fetch_ruleset_text(network, url, ttl_override);
+                }
+                // Stand in for the remote list with a one-line ruleset that
+                // points at its provider.
+                let name = rule_provider_name(url);
+                let mut providers = providers.borrow_mut();
+                if !providers.iter().any(|provider| provider.url == url) {
+                    providers.push(RuleProvider {
+                        name: name.clone(),
+                        url: url.to_string(),
+                    });
+                }
+                Ok(format!("RULE-SET,{name}"))
             })?;
             all_rules.append(&mut loaded);
         }
     }
-    Ok(rules::reorder_rules_domain_before_ip(&all_rules))
+    rules::insert_before_fallback(&mut all_rules, load_extra_rules(pref)?);
+    let mut all_rules = rules::reorder_rules_domain_before_ip(&all_rules);
+    if pref.ruleset.as_ref().is_some_and(|r| r.sort_by_specificity) {
+        all_rules = rules::sort_rules_by_specificity(&all_rules);
+    }
+    Ok((all_rules, providers.into_inner()))
 }
 
-fn fetch_ruleset_text(network: &Network, url: &str) -> Result<String> {
-    let parsed = Url::parse(url)
-        .with_context(|| format!("invalid ruleset url {url}"))?;
+/// Provider name derived from the URL alone, so it is stable across renders:
+/// the file stem for readability plus a short hash of the full URL.
+fn rule_provider_name(url: &str) -> String {
+    let stem = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.trim_end_matches('/').rsplit('/').next())
+        .map(|file| file.split_once('.').map_or(file, |(stem, _)| stem))
+        .unwrap_or_default();
+    let stem: String = stem
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        .collect();
+    let digest = Sha256::digest(url.as_bytes());
+    let hash: String = digest[..4].iter().map(|b| format!("{b:02x}")).collect();
+    if stem.is_empty() {
+        format!("ruleset-{hash}")
+    } else {
+        format!("{stem}-{hash}")
+    }
+}
+
+fn fetch_ruleset_text(network: &Network, url: &str, ttl_override: Option<u64>) -> Result<String> {
+    fetch_remote_text(network, "ruleset", url, &RULESET_USER_AGENTS, ttl_override)
+}
+
+/// Fetches a `proxy-providers` node list with the subscription User-Agents.
+pub fn fetch_proxy_provider_text(network: &Network, url: &str) -> Result<String> {
+    fetch_remote_text(
+        network,
+        "proxy provider",
+        url,
+        &super::SUBSCRIPTION_USER_AGENTS,
+        None,
+    )
+}
+
+/// Fetches `url` through `network` from synchronous render code, so it is
+/// cached and checked against the domain allowlist like subscriptions.
+fn fetch_remote_text(
+    network: &Network,
+    kind: &str,
+    url: &str,
+    user_agents: &[&str],
+    ttl_override: Option<u64>,
+) -> Result<String> {
+    let parsed = Url::parse(url).with_context(|| format!("invalid {kind} url {url}"))?;
     if !matches!(parsed.scheme(), "http" | "https") {
-        bail!("unsupported ruleset url scheme {}", parsed.scheme());
+        bail!("unsupported {kind} url scheme {}", parsed.scheme());
     }
 
     let fetch = async {
         network
-            .get_or_fetch_with(&parsed, &RULESET_USER_AGENTS, false, |text| {
+            .get_or_fetch_with(&parsed, user_agents, false, ttl_override, |text| {
                 Ok(text.to_string())
             })
             .await
@@ -156,12 +392,243 @@ fn fetch_ruleset_text(network: &Network, url: &str) -> Result<String> {
     let result = if let Ok(handle) = tokio::runtime::Handle::try_current() {
         tokio::task::block_in_place(|| handle.block_on(fetch))
     } else {
-        let runtime = tokio::runtime::Runtime::new()
-            .context("failed to create tokio runtime")?;
+        let runtime = tokio::runtime::Runtime::new().context("failed to create tokio runtime")?;
         runtime.block_on(fetch)
     };
 
     result
         .map_err(|err| anyhow!(err.to_string()))
-        .with_context(|| format!("failed to fetch ruleset {}", url))
+        .with_context(|| format!("failed to fetch {kind} {}", url))
+}
+
+/// Rewrites `DOMAIN-KEYWORD` rules as `DOMAIN-REGEX` matching the escaped
+/// keyword when `target` is listed in `[ruleset].keyword_as_regex`.
+pub fn apply_keyword_as_regex(pref: &Pref, target: &str, rules: &mut [rules::Rule]) {
+    let enabled = pref
+        .ruleset
+        .as_ref()
+        .is_some_and(|r| r.keyword_as_regex.iter().any(|t| t == target));
+    if !enabled {
+        return;
+    }
+    let mut converted = 0;
+    for rule in rules.iter_mut() {
+        if !rule
+            .rule_type
+            .to_string()
+            .eq_ignore_ascii_case("DOMAIN-KEYWORD")
+        {
+            continue;
+        }
+        if let Some(keyword) = &rule.content {
+            rule.content = Some(fancy_regex::escape(keyword).into_owned());
+            rule.rule_type = rules::RuleType::new("DOMAIN-REGEX");
+            converted += 1;
+        }
+    }
+    if converted > 0 {
+        info!(
+            target,
+            converted, "DOMAIN-KEYWORD rules rewritten as DOMAIN-REGEX"
+        );
+    }
+}
+
+/// Whether a `common.*_rule_base` entry is an `http(s)` URL rather than a path.
+pub fn is_remote_base(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Reads a base config from a local path, or fetches it when `location` is
+/// an `http(s)` URL.
+pub fn read_base_text(network: &Network, base_dir: &Path, location: &str) -> Result<String> {
+    if is_remote_base(location) {
+        return fetch_remote_text(network, "base config", location, &RULESET_USER_AGENTS, None);
+    }
+    let base_path = resolve_path(base_dir, location);
+    std::fs::read_to_string(&base_path)
+        .with_context(|| format!("failed to read base config {}", base_path.display()))
+}
+
+/// Read a `common.*_rule_base` file or URL, ensuring it ends with a newline.
+pub fn read_rule_base(
+    network: &Network,
+    base_dir: &Path,
+    rule_base: Option<&str>,
+    key: &str,
+) -> Result<String> {
+    let rule_base = rule_base.ok_or_else(|| anyhow!("`common.{key}` must be set in pref.toml"))?;
+    let mut base_text = read_base_text(network, base_dir, rule_base)?;
+    if !base_text.ends_with('\n') {
+        base_text.push('\n');
+    }
+    Ok(base_text)
+}
+
+/// Render rules in the Surge-style line syntax shared by Surge and Loon.
+pub fn render_line_rules(rules: &[rules::Rule]) -> Vec<String> {
+    rules
+        .iter()
+        .map(|r| {
+            let mut line = r.render();
+            if line == "SRC-IP-CIDR" {
+                line = "IP-CIDR".to_string();
+            } else if let Some(rest) = line.strip_prefix("SRC-IP-CIDR,") {
+                line = format!("IP-CIDR,{rest},no-resolve");
+            }
+            if line.to_ascii_uppercase().starts_with("DST-PORT") {
+                line = format!("DEST-PORT{}", &line["DST-PORT".len()..]);
+            }
+            line
+        })
+        .collect()
+}
+
+/// Group members for line-based targets, falling back to `DIRECT` when empty.
+pub fn group_member_names(group: &groups::ProxyGroup) -> Vec<&str> {
+    if group.proxies.is_empty() {
+        return vec!["DIRECT"];
+    }
+    group
+        .proxies
+        .iter()
+        .map(|item| item.strip_prefix("[]").unwrap_or(item))
+        .collect()
+}
+
+/// Append an INI-style `[section]` with one entry per line.
+pub fn push_section(out: &mut String, header: &str, lines: &[String]) {
+    if lines.is_empty() {
+        return;
+    }
+    out.push_str(&format!("[{header}]\n"));
+    for line in lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::test_registry;
+    use crate::server::test_dir;
+
+    #[test]
+    fn node_pref_overrides_only_declared_fields() {
+        let pref: Pref = toml::from_str(
+            r#"[common]
+
+[server]
+listen = "127.0.0.1"
+port = 0
+
+[node_pref.override]
+ip-version = "ipv4"
+routing-mark = 6666
+sni = "cdn.example.com"
+not-a-field = true
+"#,
+        )
+        .unwrap();
+        let registry = test_registry();
+        let node =
+            |value: serde_json::Value| crate::proxy::from_map(value.as_object().unwrap()).unwrap();
+        let mut proxies = [
+            node(serde_json::json!({
+                "name": "trojan-node", "type": "trojan", "server": "t.example.com",
+                "port": 443, "password": "secret", "sni": "t.example.com",
+            })),
+            node(serde_json::json!({
+                "name": "snell-node", "type": "snell", "server": "s.example.com",
+                "port": 8443, "psk": "secret", "version": 3,
+            })),
+        ];
+
+        apply_node_pref(&pref, &registry, &mut proxies);
+        let [trojan, snell] = &proxies;
+        assert_eq!(trojan.values["ip-version"], "ipv4");
+        assert_eq!(trojan.values["routing-mark"], 6666);
+        assert_eq!(trojan.values["sni"], "cdn.example.com");
+        assert_eq!(snell.values["routing-mark"], 6666);
+        // snell has no TLS fields, and nothing declares `not-a-field`.
+        assert!(!snell.values.contains_key("sni"));
+        assert!(!trojan.values.contains_key("not-a-field"));
+    }
+
+    #[test]
+    fn override_query_is_typed_by_schema_fields() {
+        let registry = test_registry();
+
+        let overrides =
+            parse_override_query(&registry, "skip-cert-verify:true, sni:cdn.example.com,").unwrap();
+        assert_eq!(
+            serde_json::Value::Object(overrides),
+            serde_json::json!({ "skip-cert-verify": true, "sni": "cdn.example.com" })
+        );
+
+        for (query, error) in [
+            ("not-a-field:1", "unknown override field `not-a-field`"),
+            ("skip-cert-verify:yes", "expects true or false"),
+            ("port:https", "expects an integer"),
+            ("sni", "must be `field:value`"),
+        ] {
+            let err = parse_override_query(&registry, query).unwrap_err();
+            assert!(err.to_string().contains(error), "{query}: {err}");
+        }
+    }
+
+    #[test]
+    fn extra_rules_come_after_rulesets_and_before_final() {
+        let dir = test_dir("extra-rule");
+        std::fs::write(dir.join("proxy.list"), "DOMAIN-SUFFIX,google.com\n").unwrap();
+        std::fs::write(
+            dir.join("rulesets.toml"),
+            "[[rulesets]]\ngroup = \"Proxy\"\nruleset = \"proxy.list\"\n\n[[rulesets]]\ngroup = \"Proxy\"\nruleset = \"[]FINAL\"\n",
+        )
+        .unwrap();
+        let pref = |extra: &str| -> Pref {
+            toml::from_str(&format!(
+                r#"[common]
+
+[server]
+listen = "127.0.0.1"
+port = 0
+
+[ruleset]
+enabled = true
+
+[[rulesets]]
+import = "rulesets.toml"
+{extra}"#
+            ))
+            .unwrap()
+        };
+        let network = Network::new(&pref("").network, &dir).unwrap();
+
+        let pref =
+            pref("\n[[extra_rule]]\nrule = \"DOMAIN,intranet.example.com\"\ngroup = \"DIRECT\"\n");
+        let rules = load_rules_from_pref(&pref, &network, &dir).unwrap();
+        let rendered: Vec<String> = rules.iter().map(rules::Rule::render).collect();
+        assert_eq!(
+            rendered,
+            [
+                "DOMAIN-SUFFIX,google.com,Proxy",
+                "DOMAIN,intranet.example.com,DIRECT",
+                "FINAL,Proxy",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_extra_rules_are_rejected() {
+        for rule in ["NOT-A-RULE,example.com", "MATCH"] {
+            let pref: Pref = toml::from_str(&format!(
+                "[common]\n\n[server]\nlisten = \"127.0.0.1\"\nport = 0\n\n[[extra_rule]]\nrule = \"{rule}\"\ngroup = \"Proxy\"\n"
+            ))
+            .unwrap();
+            let err = load_extra_rules(&pref).unwrap_err();
+            assert!(format!("{err:#}").contains(rule), "{err:#}");
+        }
+    }
 }
@@ -9,7 +9,34 @@ use tracing::warn;
 
 static WEB_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/src/web/out");
 
+/// Served at `/` when the binary was built without the web UI.
+const FALLBACK_PAGE: &str = r#"<!doctype html>
+<html lang="en">
+<head><meta charset="utf-8"><title>subcon</title></head>
+<body>
+<h1>subcon</h1>
+<p>The web UI is not included in this build. Run <code>pnpm -C src/web build</code> and rebuild subcon to enable it.</p>
+<p>The API is still available, for example <a href="/api/ping">/api/ping</a>.</p>
+</body>
+</html>
+"#;
+
+/// Logs a warning at startup when the embedded web build is empty.
+pub fn warn_if_missing() {
+    if is_missing(&WEB_DIR) {
+        warn!("web UI build is missing; serving a fallback page at /");
+    }
+}
+
 pub async fn handle_web(req: Request<Body>) -> impl IntoResponse {
+    serve_web(&WEB_DIR, req)
+}
+
+fn is_missing(dir: &Dir<'_>) -> bool {
+    dir.get_file("index.html").is_none()
+}
+
+fn serve_web(dir: &Dir<'_>, req: Request<Body>) -> Response {
     if !matches!(*req.method(), Method::GET | Method::HEAD) {
         return (StatusCode::NOT_FOUND, "not found").into_response();
     }
@@ -21,19 +48,27 @@ pub async fn handle_web(req: Request<Body>) -> impl IntoResponse {
         path.push_str("index.html");
     }
 
-    if let Some(response) = serve_path(&path) {
+    if let Some(response) = serve_path(dir, &path) {
         return response;
     }
 
+    if path == "index.html" && is_missing(dir) {
+        return (
+            [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+            FALLBACK_PAGE,
+        )
+            .into_response();
+    }
+
     if !path.contains('.') {
         // Try to serve as a directory with index.html
         let index_path = format!("{}/index.html", path);
-        if let Some(response) = serve_path(&index_path) {
+        if let Some(response) = serve_path(dir, &index_path) {
             return response;
         }
 
         // Fall back to serving root index.html for SPA routing
-        if let Some(response) = serve_path("index.html") {
+        if let Some(response) = serve_path(dir, "index.html") {
             return response;
         }
     }
@@ -42,8 +77,8 @@ pub async fn handle_web(req: Request<Body>) -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "not found").into_response()
 }
 
-fn serve_path(path: &str) -> Option<Response> {
-    let file = WEB_DIR.get_file(path)?;
+fn serve_path(dir: &Dir<'_>, path: &str) -> Option<Response> {
+    let file = dir.get_file(path)?;
     let mime = from_path(path).first_or_octet_stream();
     let mut response = Response::new(Body::from(file.contents().to_vec()));
     let headers = response.headers_mut();
@@ -80,3 +115,27 @@ fn cache_control_for(path: &str) -> &'static str {
     }
     "public, max-age=3600"
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn empty_web_dir_serves_fallback_page() {
+        let empty = Dir::new("", &[]);
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let response = serve_web(&empty, req);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("/api/ping"));
+
+        let req = Request::get("/app.js").body(Body::empty()).unwrap();
+        assert_eq!(serve_web(&empty, req).status(), StatusCode::NOT_FOUND);
+    }
+}
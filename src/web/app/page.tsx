@@ -44,7 +44,11 @@ import {
   writeAuthConfig,
   type AuthConfig,
 } from "@/components/studio/api";
-import type { ConfigResponse, Status } from "@/components/studio/types";
+import type {
+  ConfigResponse,
+  ReloadResponse,
+  Status,
+} from "@/components/studio/types";
 import { cn } from "@/lib/utils";
 
 const TOKEN_LENGTH = 32;
@@ -674,12 +678,13 @@ export default function Home() {
                     <ControlPanel
                       onReload={async () => {
                         try {
-                          await fetchJson("/api/control/reload", {
-                            method: "POST",
-                          });
+                          const { summary } = await fetchJson<ReloadResponse>(
+                            "/api/control/reload",
+                            { method: "POST" }
+                          );
                           pushStatus({
                             kind: "ok",
-                            message: "Configuration reloaded",
+                            message: `Configuration reloaded: ${summary.protocols} protocols, ${summary.groups} groups, ${summary.rules} rules`,
                           });
                           await loadConfig();
                         } catch (err) {
@@ -13,7 +13,6 @@ import {
   CardTitle,
 } from "@/components/ui/card";
 import { fetchJson } from "@/components/studio/api";
-import { formatDuration } from "@/components/studio/utils";
 import type { CacheEntry, CacheResponse, PanelProps } from "@/components/studio/types";
 
 export function CachePanel({ onStatus }: PanelProps) {
@@ -65,13 +64,14 @@ export function CachePanel({ onStatus }: PanelProps) {
         <div className="space-y-3">
           {items.map((entry) => (
             <div
-              key={entry.url}
+              key={`${entry.user_agent}\n${entry.url}`}
               className="rounded-2xl border border-border/50 bg-card/60 px-4 py-3 text-xs text-muted-foreground backdrop-blur"
             >
               <div className="flex flex-wrap items-center justify-between gap-3">
                 <span className="text-foreground">{entry.url}</span>
-                <Badge variant="secondary">{formatDuration(entry.ttl_seconds)}</Badge>
+                <Badge variant="secondary">{entry.ttl_human}</Badge>
               </div>
+              <div className="mt-1">{entry.user_agent}</div>
             </div>
           ))}
         </div>
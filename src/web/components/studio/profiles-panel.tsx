@@ -71,6 +71,7 @@ const COMMON_PROXY_TYPES = [
   "http",
   "snell",
   "tuic",
+  "anytls",
   "ssh",
 ];
 
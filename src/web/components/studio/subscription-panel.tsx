@@ -111,6 +111,8 @@ export function SubscriptionPanel({ config }: SubscriptionPanelProps) {
           >
             <option value="clash">clash</option>
             <option value="surge">surge</option>
+            <option value="loon">loon</option>
+            <option value="links">links</option>
           </select>
         </div>
         <div className="space-y-2 lg:col-span-2">
@@ -45,6 +45,16 @@ export type ConfigResponse = {
   };
 };
 
+export type ReloadResponse = {
+  ok: boolean;
+  message: string;
+  summary: {
+    protocols: number;
+    groups: number;
+    rules: number;
+  };
+};
+
 export type UpdateFileResponse = {
   ok: boolean;
   path: string;
@@ -65,6 +75,7 @@ export type DeleteFileResponse = {
 
 export type LogResponse = {
   items: string[];
+  last_line: number;
 };
 
 export type GroupEntry = {
@@ -96,7 +107,9 @@ export type UpdateGroupMembersResponse = {
 
 export type CacheEntry = {
   url: string;
+  user_agent: string;
   ttl_seconds: number;
+  ttl_human: string;
 };
 
 export type CacheResponse = {